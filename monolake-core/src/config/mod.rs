@@ -16,6 +16,9 @@ use serde::{Deserialize, Serialize};
 // Default iouring/epoll entries: 32k
 const DEFAULT_ENTRIES: u32 = 32768;
 
+// Default time to wait for in-flight requests on shutdown: 30s
+pub(crate) const DEFAULT_DRAIN_TIMEOUT_SEC: u64 = 30;
+
 pub const FALLBACK_PARALLELISM: NonZeroUsize = NonZeroUsize::new(1).unwrap();
 
 /// Configuration structure for a service, combining listener and server configs.
@@ -60,6 +63,11 @@ pub struct RuntimeConfig {
 
     /// Optional thread pool size for specific runtime implementations.
     pub thread_pool: Option<usize>,
+
+    /// Seconds to wait for in-flight requests when a server is removed or the process shuts
+    /// down, before closing the remaining connections.
+    #[serde(default = "default_drain_timeout_sec")]
+    pub drain_timeout_sec: u64,
 }
 
 impl Default for RuntimeConfig {
@@ -71,6 +79,7 @@ impl Default for RuntimeConfig {
             runtime_type: Default::default(),
             cpu_affinity: default_cpu_affinity(),
             thread_pool: None,
+            drain_timeout_sec: default_drain_timeout_sec(),
         }
     }
 }
@@ -116,6 +125,7 @@ fn default_workers() -> usize {
 
 define_const!(default_entries, DEFAULT_ENTRIES, u32);
 define_const!(default_cpu_affinity, false, bool);
+define_const!(default_drain_timeout_sec, DEFAULT_DRAIN_TIMEOUT_SEC, u64);
 
 // #[cfg(test)]
 // mod tests {
@@ -49,6 +49,13 @@ pub trait HttpError<B> {
     fn to_response(&self) -> Option<Response<B>>;
 }
 
+impl<B> HttpError<B> for std::convert::Infallible {
+    #[inline]
+    fn to_response(&self) -> Option<Response<B>> {
+        match *self {}
+    }
+}
+
 #[derive(Debug, Clone, Default, Copy, PartialEq, Eq)]
 pub struct HttpFatalError<E>(pub E);
 impl<B, E> HttpError<B> for HttpFatalError<E> {
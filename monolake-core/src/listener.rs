@@ -13,6 +13,7 @@
 //! # Features
 //!
 //! - Support for both TCP and Unix domain sockets (Unix-only).
+//! - Listeners bound once and shared by all workers, e.g. inherited from another process.
 //! - Asynchronous I/O operations using the `monoio` runtime.
 //! - Optional pool based I/O for compatibility with Hyper
 //!
@@ -32,6 +33,8 @@
 //!     Ok(())
 //! }
 //! ```
+#[cfg(unix)]
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 use std::{io, net::SocketAddr, path::Path};
 
 use monoio::{
@@ -45,9 +48,14 @@ use service_async::{AsyncMakeService, MakeService};
 /// A builder for creating network listeners.
 ///
 /// This enum provides a unified interface for building TCP and Unix domain socket listeners.
+///
+/// `Tcp` binds a socket for each worker, while the other variants hold a bound socket which is
+/// cloned for each worker.
 pub enum ListenerBuilder {
     Tcp(SocketAddr, ListenerOpts),
     #[cfg(unix)]
+    SharedTcp(std::net::TcpListener),
+    #[cfg(unix)]
     Unix(std::os::unix::net::UnixListener),
 }
 
@@ -69,12 +77,64 @@ impl ListenerBuilder {
         Ok(Self::Tcp(addr, opts))
     }
 
+    /// Bind a TCP socket now, which is shared by all workers instead of binding one for each.
+    #[cfg(unix)]
+    pub fn bind_tcp_shared(addr: SocketAddr, opts: ListenerOpts) -> io::Result<ListenerBuilder> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.set_reuse_port(opts.reuse_port)?;
+        socket.set_reuse_address(opts.reuse_addr)?;
+        if let Some(send_buf_size) = opts.send_buf_size {
+            socket.set_send_buffer_size(send_buf_size)?;
+        }
+        if let Some(recv_buf_size) = opts.recv_buf_size {
+            socket.set_recv_buffer_size(recv_buf_size)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(opts.backlog)?;
+        Self::from_fd(socket.into())
+    }
+
+    /// Build from the fd of a listening TCP or Unix domain socket, e.g. inherited from another
+    /// process.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<ListenerBuilder> {
+        let socket = socket2::Socket::from(fd);
+        // Same as bind_unix, the async listeners are built from the std ones.
+        if monoio::utils::is_legacy() {
+            socket.set_nonblocking(true)?;
+        }
+        if socket.local_addr()?.as_socket().is_some() {
+            Ok(Self::SharedTcp(OwnedFd::from(socket).into()))
+        } else {
+            Ok(Self::Unix(OwnedFd::from(socket).into()))
+        }
+    }
+
+    /// The fd of the bound socket, if any.
+    #[cfg(unix)]
+    pub fn as_fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            ListenerBuilder::Tcp(..) => None,
+            ListenerBuilder::SharedTcp(listener) => Some(listener.as_fd()),
+            ListenerBuilder::Unix(listener) => Some(listener.as_fd()),
+        }
+    }
+
     pub fn build(&self) -> io::Result<Listener> {
         match self {
             ListenerBuilder::Tcp(addr, opts) => {
                 TcpListener::bind_with_config(addr, opts).map(Listener::Tcp)
             }
             #[cfg(unix)]
+            ListenerBuilder::SharedTcp(listener) => {
+                let sys_listener = listener.try_clone()?;
+                TcpListener::from_std(sys_listener).map(Listener::Tcp)
+            }
+            #[cfg(unix)]
             ListenerBuilder::Unix(listener) => {
                 let sys_listener = listener.try_clone()?;
                 monoio::net::UnixListener::from_std(sys_listener).map(Listener::Unix)
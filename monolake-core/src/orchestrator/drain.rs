@@ -0,0 +1,81 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_channel::oneshot::{channel as ochannel, Receiver as OReceiver, Sender as OSender};
+use futures_util::future::{FutureExt, Shared};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Drain>> = const { RefCell::new(None) };
+}
+
+/// Signal asking the connections of a service to finish their in-flight requests and close.
+///
+/// Each site deployed by the [`ServiceExecutor`](super::ServiceExecutor) has its own drain
+/// signal, which fires when the site is removed or the worker shuts down. The connections it
+/// serves can get the signal with [`Drain::current`], e.g. to stop reusing the connection.
+/// Connections still alive after the drain timeout are closed forcibly.
+#[derive(Clone, Default)]
+pub struct Drain(Option<Shared<OReceiver<()>>>);
+
+impl Drain {
+    /// Create a drain signal, which fires when the returned sender is used or dropped.
+    pub(crate) fn new() -> (OSender<()>, Self) {
+        let (tx, rx) = ochannel();
+        (tx, Self(Some(rx.shared())))
+    }
+
+    /// Get the drain signal of the connection being served.
+    ///
+    /// A signal that never fires is returned outside of a serve loop.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone().unwrap_or_default())
+    }
+
+    /// Whether the connections are asked to close.
+    pub fn is_draining(&self) -> bool {
+        match &self.0 {
+            Some(signal) => signal.clone().now_or_never().is_some(),
+            None => false,
+        }
+    }
+
+    /// Wait until the connections are asked to close.
+    pub async fn wait(&self) {
+        match &self.0 {
+            Some(signal) => {
+                let _ = signal.clone().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Run the future with this signal as the [current](Drain::current) one.
+    pub(crate) fn scope<F: Future>(&self, future: F) -> DrainScope<F> {
+        DrainScope {
+            drain: Some(self.clone()),
+            future,
+        }
+    }
+}
+
+pub(crate) struct DrainScope<F> {
+    drain: Option<Drain>,
+    future: F,
+}
+
+impl<F: Future> Future for DrainScope<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: the future is never moved out of the pinned struct.
+        let this = unsafe { self.get_unchecked_mut() };
+        let previous = CURRENT.with(|current| current.replace(this.drain.take()));
+        let output = unsafe { Pin::new_unchecked(&mut this.future) }.poll(cx);
+        this.drain = CURRENT.with(|current| current.replace(previous));
+        output
+    }
+}
@@ -20,6 +20,8 @@
 //!    - Precommit a service using [`Precommit`](ServiceCommand::Precommit).
 //!    - Update or commit using [`Update`](ServiceCommand::Update) or
 //!      [`Commit`](ServiceCommand::Commit).
+//!    - Finalize using [`Retire`](ServiceCommand::Retire) once every worker has committed, or
+//!      revert using [`Rollback`](ServiceCommand::Rollback) if some of them failed.
 //!
 //! 2. Single-Stage Deployment: For initial deployments or stateless updates.
 //!    - Deploy in one step using [`PrepareAndCommit`](ServiceCommand::PrepareAndCommit).
@@ -33,19 +35,22 @@
 //! This module is designed to work seamlessly with the `service_async` crate,
 //! leveraging its [`Service`] and [`AsyncMakeService`](service_async::AsyncMakeService)
 //! traits for efficient service creation and management.
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
-use futures_channel::oneshot::Sender as OSender;
+use futures_channel::{mpsc::channel, oneshot::Sender as OSender};
+use futures_util::StreamExt;
 use monoio::io::stream::Stream;
 use service_async::Service;
 use tracing::{debug, error, info, warn};
 
 use self::runtime::RuntimeWrapper;
 
+mod drain;
 mod runtime;
 mod service_executor;
 mod worker_manager;
 
+pub use drain::Drain;
 pub use service_executor::{
     Execute, ServiceCommand, ServiceCommandTask, ServiceDeploymentContainer, ServiceExecutor,
     ServiceSlot,
@@ -87,19 +92,30 @@ impl<E> ResultGroup<(), E> {
 ///
 /// # Behavior
 ///
-/// The function will run until one of the following occurs:
+/// The function will stop accepting when one of the following occurs:
 /// - The `stop` channel is triggered, indicating a graceful shutdown.
 /// - The listener closes, indicating no more incoming connections.
 ///
-/// For each accepted connection, a new task is spawned to handle it using the provided service.
-pub async fn serve<S, Svc, A, E>(mut listener: S, handler: ServiceSlot<Svc>, mut stop: OSender<()>)
-where
+/// For each accepted connection, a new task is spawned to handle it using the provided service,
+/// with `drain` as its [current](Drain::current) drain signal. The function returns once all
+/// connections are complete. After `drain` fires, connections still alive after
+/// `drain_timeout` are closed.
+pub async fn serve<S, Svc, A, E>(
+    mut listener: S,
+    handler: ServiceSlot<Svc>,
+    mut stop: OSender<()>,
+    drain: Drain,
+    drain_timeout: Duration,
+) where
     S: Stream<Item = Result<A, E>> + 'static,
     E: Debug,
     Svc: Service<A> + 'static,
     Svc::Error: Debug,
     A: 'static,
 {
+    // Every connection holds a sender, so the receiver ends when all connections complete.
+    let (conn_tx, mut conn_rx) = channel::<()>(0);
+    let (_kill_tx, kill) = Drain::new();
     let mut cancellation = stop.cancellation();
     loop {
         monoio::select! {
@@ -112,19 +128,28 @@ where
                     Some(accept) => accept,
                     None => {
                         info!("listener is closed, serve stopped");
-                        return;
+                        break;
                     }
                 };
                 match accept {
                     Ok(accept) => {
                         let svc = handler.get_svc();
+                        let conn_tx = conn_tx.clone();
+                        let drain = drain.clone();
+                        let kill = kill.clone();
                         monoio::spawn(async move {
-                            match svc.call(accept).await {
-                                Ok(_) => {
-                                    debug!("Connection complete");
-                                }
-                                Err(e) => {
-                                    error!("Connection error: {e:?}");
+                            let _conn_tx = conn_tx;
+                            monoio::select! {
+                                result = drain.scope(svc.call(accept)) => match result {
+                                    Ok(_) => {
+                                        debug!("Connection complete");
+                                    }
+                                    Err(e) => {
+                                        error!("Connection error: {e:?}");
+                                    }
+                                },
+                                _ = kill.wait() => {
+                                    debug!("Connection closed after drain timeout");
                                 }
                             }
                         });
@@ -134,4 +159,20 @@ where
             }
         }
     }
+    drop(listener);
+    drop(conn_tx);
+
+    monoio::select! {
+        _ = conn_rx.next() => return,
+        _ = drain.wait() => {}
+    }
+    debug!("draining connections");
+    if monoio::time::timeout(drain_timeout, conn_rx.next())
+        .await
+        .is_err()
+    {
+        warn!("connections are not complete after drain timeout, closing them");
+        drop(_kill_tx);
+        conn_rx.next().await;
+    }
 }
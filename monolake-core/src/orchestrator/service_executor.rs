@@ -26,10 +26,17 @@
 //!
 //! The system is designed to work with asynchronous service factories and supports
 //! asynchronous execution of service commands.
-use std::{cell::UnsafeCell, collections::HashMap, fmt::Debug, rc::Rc, sync::Arc};
+use std::{
+    cell::{RefCell, UnsafeCell},
+    collections::HashMap,
+    fmt::Debug,
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
 
 use futures_channel::{
-    mpsc::Receiver,
+    mpsc::{channel, Receiver, Sender},
     oneshot::{channel as ochannel, Receiver as OReceiver, Sender as OSender},
 };
 use futures_util::stream::StreamExt;
@@ -37,8 +44,8 @@ use monoio::io::stream::Stream;
 use service_async::{AsyncMakeService, Service};
 use tracing::error;
 
-use super::serve;
-use crate::AnyError;
+use super::{serve, Drain};
+use crate::{config::DEFAULT_DRAIN_TIMEOUT_SEC, AnyError};
 
 /// Manages multiple service deployments across different sites within a worker thread.
 ///
@@ -76,6 +83,11 @@ use crate::AnyError;
 /// b) Deployment: Either update an existing service or deploy a new one.
 ///    - For updates: [`ServiceCommand::Update`]
 ///    - For new deployments: [`ServiceCommand::Commit`]
+///    - For updates with a new listener: [`ServiceCommand::Commit`]
+///
+/// c) Finalization: Once every worker has deployed the service, [`ServiceCommand::Retire`]
+///    releases what the deployment replaced. Until then, [`ServiceCommand::Rollback`] can
+///    restore it, so a deployment that fails on some workers can be undone on the others.
 ///
 /// This process allows for careful preparation and validation of the new service
 /// before it replaces the existing one, minimizing downtime and preserving valuable state.
@@ -93,15 +105,23 @@ use crate::AnyError;
 /// execution loop, processing [`ServiceCommandTask`]s containing
 /// [`ServiceCommand`]s. It handles service creation, updates, and removal, coordinating with
 /// [`ServiceDeploymentContainer`] instances for each site.
+///
+/// # Draining
+///
+/// Removing a site, or closing the command channel of [`ServiceExecutor::run`], fires the
+/// [`Drain`] signal of the affected sites: they stop accepting and their connections get
+/// `drain_timeout` to finish in-flight requests before being closed.
 pub struct ServiceExecutor<S> {
     sites: Rc<UnsafeCell<HashMap<Arc<String>, ServiceDeploymentContainer<S>>>>,
+    drain_timeout: Duration,
+    // Held by every serve loop, so the receiver ends when all of them return.
+    serving: RefCell<Option<Sender<()>>>,
+    serve_done: RefCell<Option<Receiver<()>>>,
 }
 
 impl<S> Default for ServiceExecutor<S> {
     fn default() -> Self {
-        Self {
-            sites: Rc::new(UnsafeCell::new(HashMap::new())),
-        }
+        Self::new(Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SEC))
     }
 }
 
@@ -112,6 +132,18 @@ enum ServiceCommandError {
 }
 
 impl<S> ServiceExecutor<S> {
+    /// Create an executor which gives connections `drain_timeout` to complete when their site
+    /// is removed.
+    pub fn new(drain_timeout: Duration) -> Self {
+        let (serving, serve_done) = channel(0);
+        Self {
+            sites: Rc::new(UnsafeCell::new(HashMap::new())),
+            drain_timeout,
+            serving: RefCell::new(Some(serving)),
+            serve_done: RefCell::new(Some(serve_done)),
+        }
+    }
+
     // Lookup and clone service.
     fn get_svc(&self, name: &Arc<String>) -> Option<Rc<S>> {
         let sites = unsafe { &*self.sites.get() };
@@ -143,7 +175,9 @@ impl<S> ServiceExecutor<S> {
             .take()
             .ok_or(ServiceCommandError::ServiceNotStaged)?;
 
+        let previous_svc = hdr.slot.get_svc();
         hdr.slot.update_svc(Rc::new(precom_svc));
+        sh.checkpoint = Some(Checkpoint::Updated(previous_svc));
         Ok(())
     }
 
@@ -151,7 +185,7 @@ impl<S> ServiceExecutor<S> {
     fn deploy_staged_service(
         &self,
         name: &Arc<String>,
-    ) -> Result<(ServiceSlot<S>, OSender<()>), ServiceCommandError> {
+    ) -> Result<(ServiceSlot<S>, OSender<()>, Drain), ServiceCommandError> {
         let sites = unsafe { &mut *self.sites.get() };
         let sh = sites
             .get_mut(name)
@@ -163,8 +197,70 @@ impl<S> ServiceExecutor<S> {
 
         let (new_site, stop) = ServiceSlotContainer::create(precom_svc);
         let handler_slot = new_site.slot.clone();
-        sh.committed_service = Some(new_site);
-        Ok((handler_slot, stop))
+        // Keep serving the previous listener until the site is retired.
+        sh.checkpoint = Some(match sh.committed_service.replace(new_site) {
+            Some(previous) => Checkpoint::Moved(previous),
+            None => Checkpoint::Deployed,
+        });
+        Ok((handler_slot, stop, sh.drain.clone()))
+    }
+
+    // Serve the listener with the given slot until stopped and drained.
+    fn spawn_serve<L, A, E>(
+        &self,
+        listener: L,
+        slot: ServiceSlot<S>,
+        stop: OSender<()>,
+        drain: Drain,
+    ) where
+        L: Stream<Item = Result<A, E>> + 'static,
+        E: Debug,
+        S: Service<A> + 'static,
+        S::Error: Debug,
+        A: 'static,
+    {
+        let serving = self.serving.borrow().clone();
+        let drain_timeout = self.drain_timeout;
+        monoio::spawn(async move {
+            serve(listener, slot, stop, drain, drain_timeout).await;
+            drop(serving);
+        });
+    }
+
+    // Drop the checkpoint of the last update or commit.
+    fn retire(&self, name: &Arc<String>) -> Result<(), ServiceCommandError> {
+        let sites = unsafe { &mut *self.sites.get() };
+        let sh = sites
+            .get_mut(name)
+            .ok_or(ServiceCommandError::SiteLookupFailed)?;
+        sh.checkpoint = None;
+        Ok(())
+    }
+
+    // Restore the site to the checkpoint of the last update or commit.
+    fn rollback(&self, name: &Arc<String>) -> Result<(), ServiceCommandError> {
+        let sites = unsafe { &mut *self.sites.get() };
+        let sh = sites
+            .get_mut(name)
+            .ok_or(ServiceCommandError::SiteLookupFailed)?;
+        match sh.checkpoint.take() {
+            Some(Checkpoint::Deployed) => {
+                sh.committed_service = None;
+                if unsafe { &*sh.precommitted_service.get() }.is_none() {
+                    sites.remove(name);
+                }
+            }
+            Some(Checkpoint::Updated(previous_svc)) => {
+                if let Some(hdr) = sh.committed_service.as_ref() {
+                    hdr.slot.update_svc(previous_svc);
+                }
+            }
+            Some(Checkpoint::Moved(previous)) => {
+                sh.committed_service = Some(previous);
+            }
+            None => {}
+        }
+        Ok(())
     }
 
     // Remove site.
@@ -177,6 +273,17 @@ impl<S> ServiceExecutor<S> {
         }
     }
 
+    // Remove all sites and wait for their serve loops to drain.
+    async fn shutdown(&self) {
+        let sites = unsafe { &mut *self.sites.get() };
+        sites.clear();
+        self.serving.borrow_mut().take();
+        let serve_done = self.serve_done.borrow_mut().take();
+        if let Some(mut serve_done) = serve_done {
+            serve_done.next().await;
+        }
+    }
+
     fn abort(&self, name: &Arc<String>) -> Result<(), ServiceCommandError> {
         let sites = unsafe { &mut *self.sites.get() };
         let sh = sites
@@ -201,12 +308,30 @@ impl<S> ServiceExecutor<S> {
 /// # Fields
 ///
 /// * `deployed_service`: The currently deployed service, if any.
+/// * `checkpoint`: What the last update or commit replaced, if not retired yet.
 /// * `staged_service`: A service that has been prepared but not yet deployed.
+/// * `drain`: The drain signal of the site, fired when the container is dropped.
 pub struct ServiceDeploymentContainer<S> {
     /// The currently deployed service, if any.
     committed_service: Option<ServiceSlotContainer<S>>,
+    /// What the last update or commit replaced, kept until the site is retired.
+    checkpoint: Option<Checkpoint<S>>,
     /// A service that has been prepared but not yet deployed.
     precommitted_service: UnsafeCell<Option<S>>,
+    /// The drain signal of the connections served on the site.
+    drain: Drain,
+    _drain: OSender<()>,
+}
+
+/// The state restored by rolling back the last update or commit of a site.
+enum Checkpoint<S> {
+    /// The site was not deployed.
+    Deployed,
+    /// The site was deployed with the given service.
+    Updated(Rc<S>),
+    /// The site was deployed on another listener, which keeps being served until the site is
+    /// retired.
+    Moved(ServiceSlotContainer<S>),
 }
 
 struct ServiceSlotContainer<S> {
@@ -215,10 +340,14 @@ struct ServiceSlotContainer<S> {
 }
 
 impl<S> ServiceDeploymentContainer<S> {
-    const fn new() -> Self {
+    fn new() -> Self {
+        let (drain_tx, drain) = Drain::new();
         Self {
             committed_service: None,
+            checkpoint: None,
             precommitted_service: UnsafeCell::new(None),
+            drain,
+            _drain: drain_tx,
         }
     }
 
@@ -334,6 +463,10 @@ pub enum ServiceCommand<F, LF> {
     /// It's used when a new service has been precommitted and needs to be activated with
     /// its corresponding listener.
     ///
+    /// It can also move a deployed service to a new listener. In that case the previous
+    /// service keeps serving the previous listener until [`Retire`](ServiceCommand::Retire)
+    /// is dispatched, so the new listener can be bound on every worker first.
+    ///
     /// # Arguments
     /// * `Arc<String>` - The identifier for the service to commit.
     /// * `LF` - The listener factory for the service.
@@ -360,10 +493,32 @@ pub enum ServiceCommand<F, LF> {
     /// * `Arc<String>` - The identifier for the precommitted service to abort.
     Abort(Arc<String>),
 
+    /// Finalizes the last [`Update`](ServiceCommand::Update) or
+    /// [`Commit`](ServiceCommand::Commit) of a service, which can then no longer be rolled back.
+    ///
+    /// For a service moved to a new listener by [`Commit`](ServiceCommand::Commit), this stops
+    /// serving the previous listener. Connections already accepted on it keep running.
+    ///
+    /// # Arguments
+    /// * `Arc<String>` - The identifier for the service to retire.
+    Retire(Arc<String>),
+
+    /// Reverts the last [`Update`](ServiceCommand::Update) or
+    /// [`Commit`](ServiceCommand::Commit) of a service that is not retired yet.
+    ///
+    /// An updated service gets its previous service back in its [`ServiceSlot`], a service
+    /// moved to a new listener goes back to the previous one, and a newly committed service is
+    /// removed. This is a no-op if there is nothing to revert.
+    ///
+    /// # Arguments
+    /// * `Arc<String>` - The identifier for the service to roll back.
+    Rollback(Arc<String>),
+
     /// Removes a deployed service entirely.
     ///
     /// This directive is used to completely remove a service from the system,
-    /// cleaning up all associated resources.
+    /// cleaning up all associated resources. The listener is closed right away, and the
+    /// connections are drained as described in [`ServiceExecutor`].
     ///
     /// # Arguments
     /// * `Arc<String>` - The identifier for the service to remove.
@@ -461,8 +616,8 @@ where
                     .make()
                     .await
                     .map_err(CommandError::BuildListener)?;
-                let (hdr, stop) = controller.deploy_staged_service(&name)?;
-                monoio::spawn(serve(listener, hdr, stop));
+                let (hdr, stop, drain) = controller.deploy_staged_service(&name)?;
+                controller.spawn_serve(listener, hdr, stop, drain);
                 Ok(())
             }
             ServiceCommand::PrepareAndCommit(name, factory, listener_factory) => {
@@ -472,14 +627,23 @@ where
                     .await
                     .map_err(CommandError::BuildListener)?;
                 controller.precommit_svc(name.clone(), svc);
-                let (hdr, stop) = controller.deploy_staged_service(&name)?;
-                monoio::spawn(serve(listener, hdr, stop));
+                let (hdr, stop, drain) = controller.deploy_staged_service(&name)?;
+                controller.retire(&name)?;
+                controller.spawn_serve(listener, hdr, stop, drain);
                 Ok(())
             }
             ServiceCommand::Abort(name) => {
                 controller.abort(&name)?;
                 Ok(())
             }
+            ServiceCommand::Retire(name) => {
+                controller.retire(&name)?;
+                Ok(())
+            }
+            ServiceCommand::Rollback(name) => {
+                controller.rollback(&name)?;
+                Ok(())
+            }
             ServiceCommand::Remove(name) => {
                 controller.remove(&name)?;
                 Ok(())
@@ -504,7 +668,8 @@ impl<S> ServiceExecutor<S> {
     ///
     /// * `rx`: A receiver channel for `Update`s containing [`ServiceCommand`]s
     ///
-    /// This method will run until the receiver channel is closed.
+    /// This method will run until the receiver channel is closed. All sites are then removed,
+    /// and the method returns once their connections are drained.
     pub async fn run<F, LF, A>(&self, mut rx: Receiver<ServiceCommandTask<F, LF>>)
     where
         ServiceCommand<F, LF>: Execute<A, S>,
@@ -517,5 +682,6 @@ impl<S> ServiceExecutor<S> {
                 error!("unable to send back result: {e:?}");
             }
         }
+        self.shutdown().await;
     }
 }
@@ -1,4 +1,4 @@
-use std::{sync::Arc, thread::JoinHandle};
+use std::{sync::Arc, thread::JoinHandle, time::Duration};
 
 use futures_channel::{
     mpsc::{channel, Receiver, Sender},
@@ -87,10 +87,11 @@ where
         F: AsyncMakeService,
         ServiceCommand<F, LF>: Execute<A, F::Service>,
     {
+        let drain_timeout = Duration::from_secs(self.runtime_config.drain_timeout_sec);
         self.spawn_workers_inner(
-            |mut finish_rx, rx, _worker_id, _pre_f| {
+            move |mut finish_rx, rx, _worker_id, _pre_f| {
                 move |mut runtime: RuntimeWrapper| {
-                    let worker_controller = ServiceExecutor::<F::Service>::default();
+                    let worker_controller = ServiceExecutor::<F::Service>::new(drain_timeout);
                     runtime.block_on(async move {
                         worker_controller.run(rx).await;
                         finish_rx.close();
@@ -134,10 +135,11 @@ where
         FN: Fn(usize) -> (FNL, FNO),
         FNL: Fn() + Send + 'static,
     {
+        let drain_timeout = Duration::from_secs(self.runtime_config.drain_timeout_sec);
         self.spawn_workers_inner(
-            |mut finish_rx, rx, _worker_id, pre_f| {
+            move |mut finish_rx, rx, _worker_id, pre_f| {
                 move |mut runtime: RuntimeWrapper| {
-                    let worker_controller = ServiceExecutor::<F::Service>::default();
+                    let worker_controller = ServiceExecutor::<F::Service>::new(drain_timeout);
                     runtime.block_on(async move {
                         pre_f();
                         worker_controller.run(rx).await;
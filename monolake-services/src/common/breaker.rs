@@ -0,0 +1,286 @@
+//! Circuit breaking of the requests to the upstreams of a route.
+//!
+//! This module provides a [`CircuitBreaker`] limiting the requests sent to the upstreams of a
+//! route at once, the ones waiting for a connection, and the connections open to the upstreams.
+//! A request exceeding a limit fails at once instead of being queued, so that a slow upstream
+//! does not hold an ever growing number of requests and connections.
+//!
+//! # Key Components
+//!
+//! - [`CircuitBreakerConfig`]: The limits of a route.
+//! - [`CircuitBreaker`]: Counts the requests and the connections of a route against its limits.
+//! - [`Permit`]: A request counted by a [`CircuitBreaker`] while it lives.
+//! - [`BreakerConnector`]: Wraps a transport connector, counting the connections it opens for a
+//!   route against its [`CircuitBreaker`] until they are closed.
+//!
+//! # Performance Considerations
+//!
+//! - The counters are atomic, so a breaker can be shared with the request extensions, but each
+//!   worker has its own breakers and counts its own requests.
+//! - The connections of a route with a breaker are pooled apart from the ones of the other routes,
+//!   so that an idle connection counts against the route that opened it.
+use std::{
+    hash::{Hash, Hasher},
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use monoio::{
+    BufResult,
+    buf::{IoBuf, IoBufMut, IoVecBuf, IoVecBufMut},
+    io::{AsyncReadRent, AsyncWriteRent, Split},
+};
+use monoio_transports::{
+    TransportError,
+    connectors::{Connector, TransportConnMetadata},
+};
+use serde::{Deserialize, Serialize};
+
+/// The limits of the requests to the upstreams of a route, in each worker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// The maximum number of connections open to the upstreams at once, in use or idle.
+    #[serde(default = "default_max")]
+    pub max_connections: usize,
+    /// The maximum number of requests waiting for a connection to the upstreams at once.
+    #[serde(default = "default_max")]
+    pub max_pending_requests: usize,
+    /// The maximum number of requests sent to the upstreams at once, pending or connected.
+    #[serde(default = "default_max")]
+    pub max_requests: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max(),
+            max_pending_requests: default_max(),
+            max_requests: default_max(),
+        }
+    }
+}
+
+const fn default_max() -> usize {
+    1024
+}
+
+/// Counts the requests to the upstreams of a route and their connections against the limits of
+/// its [`CircuitBreakerConfig`].
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    connections: AtomicUsize,
+    pending: AtomicUsize,
+    requests: AtomicUsize,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            connections: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a request sent to an upstream, unless `max_requests` are already.
+    pub fn request(&self) -> Option<Permit<'_>> {
+        Permit::acquire(&self.requests, self.config.max_requests)
+    }
+
+    /// Counts a request waiting for a connection, unless `max_pending_requests` are already.
+    pub fn pending(&self) -> Option<Permit<'_>> {
+        Permit::acquire(&self.pending, self.config.max_pending_requests)
+    }
+
+    /// Counts a connection opened to an upstream, unless `max_connections` are already.
+    pub fn connection(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        count(&self.connections, self.config.max_connections)
+            .then(|| ConnectionPermit(self.clone()))
+    }
+}
+
+/// A request counted by a [`CircuitBreaker`] until it is dropped.
+#[derive(Debug)]
+pub struct Permit<'a>(&'a AtomicUsize);
+
+impl<'a> Permit<'a> {
+    fn acquire(counter: &'a AtomicUsize, max: usize) -> Option<Self> {
+        count(counter, max).then(|| Self(counter))
+    }
+}
+
+// Increments `counter` unless it is at `max` already.
+fn count(counter: &AtomicUsize, max: usize) -> bool {
+    if counter.fetch_add(1, Ordering::Relaxed) >= max {
+        counter.fetch_sub(1, Ordering::Relaxed);
+        return false;
+    }
+    true
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection counted by a [`CircuitBreaker`] until it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit(Arc<CircuitBreaker>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The error of a connection refused by the [`CircuitBreaker`] of its route, as the source of
+/// the I/O error returned by a [`BreakerConnector`].
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("upstream circuit breaker open")]
+pub struct Overflow;
+
+/// The key of a connection opened by a [`BreakerConnector`]: the key of the inner connector,
+/// and the breaker of the route it is opened for, if any.
+///
+/// The keys of different breakers differ, so that the pools keep the connections of each route
+/// apart.
+#[derive(Debug, Clone)]
+pub struct BreakerKey<K> {
+    pub key: K,
+    pub breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl<K: PartialEq> PartialEq for BreakerKey<K> {
+    fn eq(&self, other: &Self) -> bool {
+        let breaker = match (&self.breaker, &other.breaker) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        breaker && self.key == other.key
+    }
+}
+
+impl<K: Eq> Eq for BreakerKey<K> {}
+
+impl<K: Hash> Hash for BreakerKey<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+        self.breaker.as_ref().map(Arc::as_ptr).hash(state);
+    }
+}
+
+/// A transport connector counting the connections it opens against the breaker of their key,
+/// failing with an [`Overflow`] when its `max_connections` are open already.
+#[derive(Debug, Clone, Default)]
+pub struct BreakerConnector<C>(pub C);
+
+impl<C, K> Connector<BreakerKey<K>> for BreakerConnector<C>
+where
+    C: Connector<K>,
+    TransportError: From<C::Error>,
+{
+    type Connection = BreakerStream<C::Connection>;
+    type Error = TransportError;
+
+    async fn connect(&self, key: BreakerKey<K>) -> Result<Self::Connection, Self::Error> {
+        let permit = match &key.breaker {
+            Some(breaker) => Some(
+                breaker
+                    .connection()
+                    .ok_or_else(|| io::Error::other(Overflow))?,
+            ),
+            None => None,
+        };
+        let io = self.0.connect(key.key).await?;
+        Ok(BreakerStream {
+            io,
+            _permit: permit,
+        })
+    }
+}
+
+/// A connection opened by a [`BreakerConnector`], counted by the breaker of its route until it
+/// is closed.
+#[derive(Debug)]
+pub struct BreakerStream<IO> {
+    io: IO,
+    _permit: Option<ConnectionPermit>,
+}
+
+unsafe impl<IO: Split> Split for BreakerStream<IO> {}
+
+impl<IO: TransportConnMetadata> TransportConnMetadata for BreakerStream<IO> {
+    type Metadata = IO::Metadata;
+
+    fn get_conn_metadata(&self) -> Self::Metadata {
+        self.io.get_conn_metadata()
+    }
+}
+
+impl<IO: AsyncReadRent> AsyncReadRent for BreakerStream<IO> {
+    #[inline]
+    fn read<T: IoBufMut>(&mut self, buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        self.io.read(buf)
+    }
+
+    #[inline]
+    fn readv<T: IoVecBufMut>(&mut self, buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        self.io.readv(buf)
+    }
+}
+
+impl<IO: AsyncWriteRent> AsyncWriteRent for BreakerStream<IO> {
+    #[inline]
+    fn write<T: IoBuf>(&mut self, buf: T) -> impl Future<Output = BufResult<usize, T>> {
+        self.io.write(buf)
+    }
+
+    #[inline]
+    fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> impl Future<Output = BufResult<usize, T>> {
+        self.io.writev(buf_vec)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> impl Future<Output = io::Result<()>> {
+        self.io.flush()
+    }
+
+    #[inline]
+    fn shutdown(&mut self) -> impl Future<Output = io::Result<()>> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig {
+            max_pending_requests: 1,
+            max_requests: 2,
+            max_connections: 1,
+        }));
+        let first = breaker.request().unwrap();
+        let pending = breaker.pending().unwrap();
+        assert!(breaker.pending().is_none());
+        let _second = breaker.request().unwrap();
+        assert!(breaker.request().is_none());
+        drop((first, pending));
+        assert!(breaker.request().is_some());
+        assert!(breaker.pending().is_some());
+
+        let connection = breaker.connection().unwrap();
+        assert!(breaker.connection().is_none());
+        drop(connection);
+        assert!(breaker.connection().is_some());
+    }
+}
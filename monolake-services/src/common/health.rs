@@ -0,0 +1,549 @@
+//! Active health checking of the upstream endpoints.
+//!
+//! This module provides the [`Health`] of the endpoints of a [`LoadBalancer`], updated by a task
+//! probing them periodically, which the selection of the endpoints takes into account.
+//!
+//! # Key Components
+//!
+//! - [`HealthCheckConfig`]: Configuration of the probes, their interval, their timeout and the
+//!   thresholds after which an endpoint is marked healthy or unhealthy.
+//! - [`HealthProbe`]: How an endpoint is probed, with an HTTP `GET`, a TCP connection or a Thrift
+//!   call.
+//! - [`Health`]: The health of the endpoints, probed by the task of [`Health::set_checks`].
+//! - [`HealthProbe::check`]: Probes an endpoint over a connection to it.
+//!
+//! # Features
+//!
+//! - An endpoint is marked unhealthy after `unhealthy_threshold` consecutive failed probes, and
+//!   healthy again after `healthy_threshold` consecutive successful ones
+//! - The endpoints all start healthy, so they receive requests before their first probe
+//! - The probes start with the first selection, in the runtime of the worker, so that the services
+//!   can be built without one, like `monolake check` does
+//! - The unhealthy endpoints are skipped by the selection, unless all are unhealthy (panic mode),
+//!   as failing over to every endpoint is better than failing all requests
+//!
+//! # Performance Considerations
+//!
+//! - Each worker probes the endpoints and computes their health on its own, so no lock is taken, at
+//!   the cost of one probe per worker and interval
+//! - The endpoints are probed concurrently, so a slow endpoint does not delay the others
+//! - The task stops when the services holding the health are dropped, on reload for example
+use std::{
+    cell::{Cell, RefCell},
+    fmt::{self, Debug},
+    future::Future,
+    io,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{FutureExt, future::LocalBoxFuture};
+use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, sink::SinkExt, stream::Stream};
+use monoio_codec::Framed;
+use monoio_thrift::codec::ttheader::{
+    RawPayloadCodec, TTHeader, TTHeaderPayload, TTHeaderPayloadCodec,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use super::selector::LoadBalancer;
+
+// The maximum length of the status line of the responses to the HTTP probes.
+const MAX_STATUS_LINE: usize = 1024;
+
+/// Configuration of the active health checks of the endpoints of a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    /// How the endpoints are probed.
+    #[serde(flatten)]
+    pub probe: HealthProbe,
+    /// The interval between two probes of an endpoint, in milliseconds.
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// The time after which a probe fails, in milliseconds.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// The number of consecutive successful probes after which an unhealthy endpoint is healthy.
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    /// The number of consecutive failed probes after which a healthy endpoint is unhealthy.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+/// How the endpoints are probed, by its `type`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthProbe {
+    /// Sends a `GET` request for `path`, succeeding if the response has the `expected_status`.
+    Http {
+        #[serde(default = "default_path")]
+        path: String,
+        #[serde(default = "default_expected_status")]
+        expected_status: u16,
+    },
+    /// Opens a TCP connection, or a Unix domain socket one, succeeding if it is accepted.
+    Tcp,
+    /// Calls the `method`, succeeding on any answer, even an exception for an unknown method.
+    Thrift {
+        #[serde(default = "default_method")]
+        method: String,
+    },
+}
+
+impl HealthProbe {
+    /// Probes the endpoint connected by `io`, whose host is `host` for the HTTP probes.
+    ///
+    /// The TCP probe succeeds as soon as the connection is established, so `io` is dropped.
+    pub async fn check<IO>(&self, io: IO, host: &str) -> io::Result<()>
+    where
+        IO: AsyncReadRent + AsyncWriteRent,
+    {
+        match self {
+            HealthProbe::Http {
+                path,
+                expected_status,
+            } => probe_http(io, host, path, *expected_status).await,
+            HealthProbe::Tcp => Ok(()),
+            HealthProbe::Thrift { method } => probe_thrift(io, method).await,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HealthProbe::Http { .. } => "http",
+            HealthProbe::Tcp => "tcp",
+            HealthProbe::Thrift { .. } => "thrift",
+        }
+    }
+}
+
+const fn default_interval_ms() -> u64 {
+    5000
+}
+
+const fn default_timeout_ms() -> u64 {
+    1000
+}
+
+const fn default_healthy_threshold() -> u32 {
+    2
+}
+
+const fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+const fn default_expected_status() -> u16 {
+    200
+}
+
+fn default_method() -> String {
+    "ping".to_string()
+}
+
+/// Error of an invalid [`HealthCheckConfig`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheckError {
+    #[error("interval_ms: must be positive")]
+    Interval,
+    #[error("timeout_ms: must be positive")]
+    Timeout,
+    #[error("healthy_threshold: must be positive")]
+    HealthyThreshold,
+    #[error("unhealthy_threshold: must be positive")]
+    UnhealthyThreshold,
+    #[error("type: {0} probes cannot check these upstreams")]
+    Probe(&'static str),
+    #[error("path: {0:?} does not start with '/'")]
+    Path(String),
+}
+
+impl HealthCheckConfig {
+    /// Validates the configuration, for upstreams which can be checked by `probe` unless it is
+    /// `false`.
+    pub fn validate(&self, probe: impl Fn(&HealthProbe) -> bool) -> Result<(), HealthCheckError> {
+        if self.interval_ms == 0 {
+            return Err(HealthCheckError::Interval);
+        }
+        if self.timeout_ms == 0 {
+            return Err(HealthCheckError::Timeout);
+        }
+        if self.healthy_threshold == 0 {
+            return Err(HealthCheckError::HealthyThreshold);
+        }
+        if self.unhealthy_threshold == 0 {
+            return Err(HealthCheckError::UnhealthyThreshold);
+        }
+        if !probe(&self.probe) {
+            return Err(HealthCheckError::Probe(self.probe.name()));
+        }
+        match &self.probe {
+            HealthProbe::Http { path, .. } if !path.starts_with('/') => {
+                Err(HealthCheckError::Path(path.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The health of the endpoints of a [`LoadBalancer`], by their index in its endpoints.
+///
+/// It is not `Send`: each worker probes the endpoints of its routes on its own.
+#[derive(Debug)]
+pub struct Health {
+    healthy_threshold: u32,
+    unhealthy_threshold: u32,
+    endpoints: Vec<EndpointHealth>,
+    checks: Checks,
+}
+
+// The task probing the endpoints, until it is spawned by the first selection.
+#[derive(Default)]
+struct Checks(RefCell<Option<LocalBoxFuture<'static, ()>>>);
+
+impl Debug for Checks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0.borrow().is_some() {
+            true => "Checks(pending)",
+            false => "Checks",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct EndpointHealth {
+    label: String,
+    healthy: Cell<bool>,
+    // The number of consecutive probes whose result contradicts the health of the endpoint.
+    streak: Cell<u32>,
+}
+
+impl Health {
+    /// Creates the health of `endpoints`, all healthy.
+    pub fn new<T: Debug>(config: &HealthCheckConfig, endpoints: &[T]) -> Self {
+        Self {
+            healthy_threshold: config.healthy_threshold,
+            unhealthy_threshold: config.unhealthy_threshold,
+            endpoints: endpoints
+                .iter()
+                .map(|endpoint| EndpointHealth {
+                    label: format!("{endpoint:?}"),
+                    healthy: Cell::new(true),
+                    streak: Cell::new(0),
+                })
+                .collect(),
+            checks: Checks::default(),
+        }
+    }
+
+    /// Whether the endpoint at `index` is healthy.
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.endpoints
+            .get(index)
+            .is_none_or(|endpoint| endpoint.healthy.get())
+    }
+
+    /// Records the result of a probe of the endpoint at `index`, changing its health once the
+    /// threshold of consecutive results is reached.
+    pub fn record(&self, index: usize, result: io::Result<()>) {
+        let Some(endpoint) = self.endpoints.get(index) else {
+            return;
+        };
+        let healthy = endpoint.healthy.get();
+        if let Err(e) = &result {
+            debug!("health check of {} failed: {e}", endpoint.label);
+        }
+        if result.is_ok() == healthy {
+            endpoint.streak.set(0);
+            return;
+        }
+        let streak = endpoint.streak.get() + 1;
+        let threshold = match healthy {
+            true => self.unhealthy_threshold,
+            false => self.healthy_threshold,
+        };
+        if streak < threshold {
+            endpoint.streak.set(streak);
+            return;
+        }
+        endpoint.streak.set(0);
+        endpoint.healthy.set(!healthy);
+        match healthy {
+            true => info!("upstream {} is unhealthy", endpoint.label),
+            false => info!("upstream {} is healthy", endpoint.label),
+        }
+    }
+
+    /// Selects an endpoint of `lb` for which `available` is true, a healthy one unless none is, in
+    /// which case the health is ignored (panic mode).
+    pub fn select_where<'a, T, A: ?Sized>(
+        &self,
+        lb: &'a LoadBalancer<T>,
+        key: &A,
+        available: impl Fn(&T) -> bool,
+    ) -> Option<&'a T> {
+        if let Some(checks) = self.checks.0.borrow_mut().take() {
+            monoio::spawn(checks);
+        }
+        let healthy = |endpoint: &T| {
+            lb.position(endpoint)
+                .is_none_or(|index| self.is_healthy(index))
+        };
+        lb.select_where(key, |endpoint| available(endpoint) && healthy(endpoint))
+            .or_else(|| {
+                debug!("no healthy upstream available, selecting among all of them");
+                lb.select_where(key, available)
+            })
+    }
+
+    /// Sets the task probing the endpoints with `probe`, given their index, every interval of
+    /// `config`, until `health` is dropped. It is spawned by the first selection.
+    pub fn set_checks<P, F>(health: &Rc<Self>, config: &HealthCheckConfig, probe: P)
+    where
+        P: Fn(usize) -> F + 'static,
+        F: Future<Output = io::Result<()>> + 'static,
+    {
+        let checks = &health.checks;
+        let health: Weak<Self> = Rc::downgrade(health);
+        let interval = Duration::from_millis(config.interval_ms);
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let task = async move {
+            loop {
+                let Some(len) = health.upgrade().map(|health| health.endpoints.len()) else {
+                    return;
+                };
+                let results = futures::future::join_all(
+                    (0..len).map(|index| monoio::time::timeout(timeout, probe(index))),
+                )
+                .await;
+                let Some(health) = health.upgrade() else {
+                    return;
+                };
+                for (index, result) in results.into_iter().enumerate() {
+                    let result = result.unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "probe timed out"))
+                    });
+                    health.record(index, result);
+                }
+                drop(health);
+                monoio::time::sleep(interval).await;
+            }
+        };
+        *checks.0.borrow_mut() = Some(task.boxed_local());
+    }
+}
+
+/// Probes an HTTP server over `io` with a `GET` request for `path` to `host`, succeeding if the
+/// status of the response is `expected_status`.
+async fn probe_http<IO>(mut io: IO, host: &str, path: &str, expected_status: u16) -> io::Result<()>
+where
+    IO: AsyncReadRent + AsyncWriteRent,
+{
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: monolake-health-check\r\nConnection: \
+         close\r\n\r\n"
+    );
+    io.write_all(request.into_bytes()).await.0?;
+    // Only the status line is read, the connection is closed afterwards.
+    let mut response = Vec::new();
+    let line = loop {
+        if let Some(end) = response.windows(2).position(|w| w == b"\r\n") {
+            break &response[..end];
+        }
+        if response.len() > MAX_STATUS_LINE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "status line too long",
+            ));
+        }
+        let (result, buf) = io.read(Vec::with_capacity(MAX_STATUS_LINE)).await;
+        if result? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        response.extend_from_slice(&buf);
+    };
+    let status = std::str::from_utf8(line)
+        .ok()
+        .filter(|line| line.starts_with("HTTP/"))
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+    match status == expected_status {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("unexpected status {status}"))),
+    }
+}
+
+/// Probes a Thrift server over `io` with a TTHeader framed call of `method` without arguments,
+/// succeeding on any answer.
+async fn probe_thrift<IO>(io: IO, method: &str) -> io::Result<()>
+where
+    IO: AsyncReadRent + AsyncWriteRent,
+{
+    let mut framed = Framed::new(io, TTHeaderPayloadCodec::new(RawPayloadCodec));
+    let payload = thrift_call(method);
+    let mut ttheader = TTHeader::new_for_encode(payload.len() as u32);
+    ttheader.seq_id = 1;
+    framed
+        .send_and_flush(TTHeaderPayload {
+            ttheader,
+            payload: Some(payload),
+        })
+        .await?;
+    match framed.next().await {
+        Some(answer) => answer.map(|_| ()),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Encodes a call of `method` without arguments with the Thrift binary protocol.
+fn thrift_call(method: &str) -> Bytes {
+    // The strict version 1 of the protocol, with the message type CALL.
+    const VERSION_1_CALL: u32 = 0x8001_0001;
+    let mut buf = BytesMut::with_capacity(13 + method.len());
+    buf.put_u32(VERSION_1_CALL);
+    buf.put_u32(method.len() as u32);
+    buf.put_slice(method.as_bytes());
+    buf.put_i32(1);
+    // The empty struct of the arguments.
+    buf.put_u8(0);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::common::selector::LoadBalanceStrategy;
+
+    fn config() -> HealthCheckConfig {
+        HealthCheckConfig {
+            probe: HealthProbe::Tcp,
+            interval_ms: default_interval_ms(),
+            timeout_ms: default_timeout_ms(),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let lb =
+            LoadBalancer::try_from_upstreams(LoadBalanceStrategy::RoundRobin, [("a", 1), ("b", 1)])
+                .unwrap();
+        let health = Health::new(&config(), lb.endpoints());
+        let failed = || Err(io::ErrorKind::ConnectionRefused.into());
+        health.record(0, failed());
+        health.record(0, failed());
+        health.record(0, Ok(()));
+        health.record(0, failed());
+        health.record(0, failed());
+        assert!(health.is_healthy(0));
+        health.record(0, failed());
+        assert!(!health.is_healthy(0));
+        assert_eq!(health.select_where(&lb, "", |_| true), Some(&"b"));
+
+        // Panic mode.
+        for _ in 0..3 {
+            health.record(1, failed());
+        }
+        assert!(!health.is_healthy(1));
+        assert_eq!(health.select_where(&lb, "", |_| true), Some(&"a"));
+        assert_eq!(health.select_where(&lb, "", |ep| *ep != "a"), Some(&"b"));
+
+        health.record(0, Ok(()));
+        assert!(!health.is_healthy(0));
+        health.record(0, Ok(()));
+        assert!(health.is_healthy(0));
+    }
+
+    #[test]
+    fn test_checks_start() {
+        let lb =
+            LoadBalancer::try_from_upstreams(LoadBalanceStrategy::RoundRobin, [("a", 1), ("b", 1)])
+                .unwrap();
+        let health = Rc::new(Health::new(&config(), lb.endpoints()));
+        let probes = Rc::new(Cell::new(0));
+        // Outside of a runtime, like when the config is checked.
+        Health::set_checks(&health, &config(), {
+            let probes = probes.clone();
+            move |_| {
+                probes.set(probes.get() + 1);
+                async { Err(io::ErrorKind::ConnectionRefused.into()) }
+            }
+        });
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                monoio::time::sleep(Duration::from_millis(10)).await;
+                assert_eq!(probes.get(), 0);
+                health.select_where(&lb, "", |_| true);
+                monoio::time::sleep(Duration::from_millis(10)).await;
+                assert_eq!(probes.get(), 2);
+            });
+    }
+
+    #[test]
+    fn test_validate() {
+        let http = HealthCheckConfig {
+            probe: HealthProbe::Http {
+                path: "/healthz".to_string(),
+                expected_status: 200,
+            },
+            ..config()
+        };
+        let not_thrift = |probe: &HealthProbe| !matches!(probe, HealthProbe::Thrift { .. });
+        assert_eq!(http.validate(not_thrift), Ok(()));
+        assert_eq!(
+            http.validate(|probe| !matches!(probe, HealthProbe::Http { .. })),
+            Err(HealthCheckError::Probe("http"))
+        );
+        let invalid = HealthCheckConfig {
+            healthy_threshold: 0,
+            ..config()
+        };
+        assert_eq!(
+            invalid.validate(not_thrift),
+            Err(HealthCheckError::HealthyThreshold)
+        );
+    }
+
+    #[test]
+    fn test_probe_http() {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                monoio::spawn(async move {
+                    for status in ["200 OK", "503 Service Unavailable"] {
+                        let (mut stream, _) = listener.accept().await.unwrap();
+                        let (_, request) = stream.read(Vec::with_capacity(1024)).await;
+                        assert!(request.starts_with(b"GET /healthz HTTP/1.1\r\n"));
+                        let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                        stream.write_all(response.into_bytes()).await.0.unwrap();
+                    }
+                });
+                let stream = TcpStream::connect(addr).await.unwrap();
+                probe_http(stream, "localhost", "/healthz", 200)
+                    .await
+                    .unwrap();
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let err = probe_http(stream, "localhost", "/healthz", 200)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.to_string(), "unexpected status 503");
+            });
+    }
+}
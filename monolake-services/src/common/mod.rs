@@ -1,11 +1,16 @@
-//! Generic services for panic catching, context management, and timeouts.
+//! Generic services for panic catching, context management, and timeouts, and the resolution,
+//! health checking, outlier detection and circuit breaking of upstream hosts.
+pub mod breaker;
 pub mod cancel;
 pub mod context;
 pub mod delay;
 pub mod detect;
 pub mod erase;
+pub mod health;
 pub mod map;
+pub mod outlier;
 pub mod panic;
+pub mod resolver;
 pub mod selector;
 pub mod timeout;
 
@@ -0,0 +1,283 @@
+//! Passive outlier detection of the upstream endpoints.
+//!
+//! This module provides an [`OutlierDetector`] ejecting the endpoints of a [`LoadBalancer`]
+//! failing consecutive requests, so that the selection skips them for an ejection period.
+//!
+//! # Key Components
+//!
+//! - [`OutlierDetectionConfig`]: The consecutive failures ejecting an endpoint, and the ejection
+//!   periods.
+//! - [`OutlierDetector`]: Records the outcome of the requests to the endpoints, and ejects them.
+//! - [`Outcome`]: The outcome of a request to an endpoint.
+//!
+//! # Features
+//!
+//! - An endpoint is ejected after `consecutive_5xx` consecutive `5xx` responses, or after
+//!   `consecutive_connect_failures` consecutive failed connections
+//! - The ejection period grows with the number of times the endpoint was ejected, from
+//!   `base_ejection_ms` up to `max_ejection_ms`, and is reset once the endpoint stays in service
+//!   for `max_ejection_ms`
+//! - At most `max_ejection_percent` of the endpoints are ejected at once, and at least one
+//!
+//! # Performance Considerations
+//!
+//! - Each worker records the outcomes of its own requests, so no lock is taken
+//! - The endpoints return to service when selected after their ejection period, without a timer
+//!
+//! [`LoadBalancer`]: super::selector::LoadBalancer
+use std::{cell::Cell, fmt::Debug, time::Duration};
+
+use monoio::time::Instant;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Configuration of the outlier detection of the endpoints of a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierDetectionConfig {
+    /// The consecutive `5xx` responses ejecting an endpoint, never if 0.
+    #[serde(default = "default_consecutive")]
+    pub consecutive_5xx: u32,
+    /// The consecutive failed connections ejecting an endpoint, never if 0.
+    #[serde(default = "default_consecutive")]
+    pub consecutive_connect_failures: u32,
+    /// The period of the first ejection of an endpoint, in milliseconds, multiplied by the number
+    /// of times it was ejected for the next ones.
+    #[serde(default = "default_base_ejection_ms")]
+    pub base_ejection_ms: u64,
+    /// The maximum ejection period, in milliseconds.
+    #[serde(default = "default_max_ejection_ms")]
+    pub max_ejection_ms: u64,
+    /// The endpoints which can be ejected at once, as a percentage of the endpoints of the route.
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierDetectionConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_5xx: default_consecutive(),
+            consecutive_connect_failures: default_consecutive(),
+            base_ejection_ms: default_base_ejection_ms(),
+            max_ejection_ms: default_max_ejection_ms(),
+            max_ejection_percent: default_max_ejection_percent(),
+        }
+    }
+}
+
+const fn default_consecutive() -> u32 {
+    5
+}
+
+const fn default_base_ejection_ms() -> u64 {
+    30_000
+}
+
+const fn default_max_ejection_ms() -> u64 {
+    300_000
+}
+
+const fn default_max_ejection_percent() -> u32 {
+    10
+}
+
+/// Error of an invalid [`OutlierDetectionConfig`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum OutlierDetectionError {
+    #[error("base_ejection_ms: must be positive")]
+    BaseEjection,
+    #[error("max_ejection_ms: {0} is below base_ejection_ms")]
+    MaxEjection(u64),
+    #[error("max_ejection_percent: {0} is above 100")]
+    MaxEjectionPercent(u32),
+}
+
+/// The outcome of a request to an endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The endpoint answered without a server error.
+    Success,
+    /// The endpoint answered with a `5xx` response, or failed to.
+    ServerError,
+    /// The connection to the endpoint failed.
+    ConnectFailure,
+}
+
+/// Ejects the endpoints of a [`LoadBalancer`](super::selector::LoadBalancer) failing consecutive
+/// requests, by their index in its endpoints.
+///
+/// It is not `Send`: each worker records the outcomes of its own requests.
+#[derive(Debug)]
+pub struct OutlierDetector {
+    config: OutlierDetectionConfig,
+    max_ejected: usize,
+    endpoints: Vec<EndpointOutlier>,
+}
+
+#[derive(Debug)]
+struct EndpointOutlier {
+    label: String,
+    consecutive_5xx: Cell<u32>,
+    consecutive_connect_failures: Cell<u32>,
+    ejected_until: Cell<Option<Instant>>,
+    // The number of times the endpoint was ejected, multiplying the ejection period.
+    ejections: Cell<u32>,
+    // The end of the last ejection, after which the number of ejections is reset.
+    returned: Cell<Option<Instant>>,
+}
+
+impl OutlierDetector {
+    pub fn new<T: Debug>(
+        config: &OutlierDetectionConfig,
+        endpoints: &[T],
+    ) -> Result<Self, OutlierDetectionError> {
+        if config.base_ejection_ms == 0 {
+            return Err(OutlierDetectionError::BaseEjection);
+        }
+        if config.max_ejection_ms < config.base_ejection_ms {
+            return Err(OutlierDetectionError::MaxEjection(config.max_ejection_ms));
+        }
+        if config.max_ejection_percent > 100 {
+            return Err(OutlierDetectionError::MaxEjectionPercent(
+                config.max_ejection_percent,
+            ));
+        }
+        let max_ejected = (endpoints.len() * config.max_ejection_percent as usize / 100).max(1);
+        Ok(Self {
+            config: *config,
+            max_ejected,
+            endpoints: endpoints
+                .iter()
+                .map(|endpoint| EndpointOutlier {
+                    label: format!("{endpoint:?}"),
+                    consecutive_5xx: Cell::new(0),
+                    consecutive_connect_failures: Cell::new(0),
+                    ejected_until: Cell::new(None),
+                    ejections: Cell::new(0),
+                    returned: Cell::new(None),
+                })
+                .collect(),
+        })
+    }
+
+    /// Whether the endpoint at `index` is ejected, returning it to service once its ejection
+    /// period is over.
+    pub fn is_ejected(&self, index: usize) -> bool {
+        let Some(endpoint) = self.endpoints.get(index) else {
+            return false;
+        };
+        let Some(until) = endpoint.ejected_until.get() else {
+            return false;
+        };
+        if Instant::now() < until {
+            return true;
+        }
+        endpoint.ejected_until.set(None);
+        endpoint.returned.set(Some(until));
+        info!("upstream {} returns to service", endpoint.label);
+        false
+    }
+
+    /// Records the outcome of a request to the endpoint at `index`, ejecting it after the
+    /// configured consecutive failures.
+    pub fn record(&self, index: usize, outcome: Outcome) {
+        let Some(endpoint) = self.endpoints.get(index) else {
+            return;
+        };
+        let (count, threshold) = match outcome {
+            Outcome::Success => {
+                endpoint.consecutive_5xx.set(0);
+                endpoint.consecutive_connect_failures.set(0);
+                return;
+            }
+            Outcome::ServerError => (&endpoint.consecutive_5xx, self.config.consecutive_5xx),
+            Outcome::ConnectFailure => (
+                &endpoint.consecutive_connect_failures,
+                self.config.consecutive_connect_failures,
+            ),
+        };
+        count.set(count.get() + 1);
+        if threshold == 0 || count.get() < threshold || self.is_ejected(index) {
+            return;
+        }
+        let ejected = (0..self.endpoints.len())
+            .filter(|&i| self.is_ejected(i))
+            .count();
+        if ejected >= self.max_ejected {
+            return;
+        }
+        let now = Instant::now();
+        let max = Duration::from_millis(self.config.max_ejection_ms);
+        // The endpoints in service for the maximum ejection period are ejected as if for the
+        // first time.
+        if endpoint
+            .returned
+            .get()
+            .is_some_and(|returned| now.saturating_duration_since(returned) >= max)
+        {
+            endpoint.ejections.set(0);
+        }
+        let ejections = endpoint.ejections.get() + 1;
+        let period = Duration::from_millis(self.config.base_ejection_ms)
+            .saturating_mul(ejections)
+            .min(max);
+        endpoint.ejections.set(ejections);
+        endpoint.ejected_until.set(Some(now + period));
+        endpoint.consecutive_5xx.set(0);
+        endpoint.consecutive_connect_failures.set(0);
+        info!(
+            "upstream {} ejected for {period:?} after {} consecutive failures",
+            endpoint.label,
+            count.get().max(threshold)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ejection() {
+        let config = OutlierDetectionConfig {
+            consecutive_5xx: 2,
+            consecutive_connect_failures: 1,
+            base_ejection_ms: 20,
+            max_ejection_ms: 30,
+            max_ejection_percent: 50,
+        };
+        let detector = OutlierDetector::new(&config, &["a", "b", "c", "d"]).unwrap();
+        detector.record(0, Outcome::ServerError);
+        detector.record(0, Outcome::Success);
+        detector.record(0, Outcome::ServerError);
+        assert!(!detector.is_ejected(0));
+        detector.record(0, Outcome::ServerError);
+        assert!(detector.is_ejected(0));
+        detector.record(1, Outcome::ConnectFailure);
+        assert!(detector.is_ejected(1));
+        // At most half of the endpoints are ejected.
+        detector.record(2, Outcome::ConnectFailure);
+        assert!(!detector.is_ejected(2));
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(!detector.is_ejected(0));
+        // The second ejection lasts longer, up to the maximum.
+        detector.record(0, Outcome::ServerError);
+        detector.record(0, Outcome::ServerError);
+        let until = detector.endpoints[0].ejected_until.get().unwrap();
+        let period = until - Instant::now();
+        assert!(period > Duration::from_millis(20) && period <= Duration::from_millis(30));
+
+        assert_eq!(
+            OutlierDetector::new(
+                &OutlierDetectionConfig {
+                    max_ejection_percent: 101,
+                    ..config
+                },
+                &["a"]
+            )
+            .unwrap_err(),
+            OutlierDetectionError::MaxEjectionPercent(101)
+        );
+    }
+}
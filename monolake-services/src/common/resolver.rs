@@ -0,0 +1,900 @@
+//! Asynchronous DNS resolution of upstream hosts, cached per worker.
+//!
+//! This module provides a [`Resolver`] which resolves host names without blocking the worker
+//! thread, by querying nameservers over UDP with the monoio runtime, and caches the addresses
+//! for the time to live of their records.
+//!
+//! # Key Components
+//!
+//! - [`Resolver`]: Resolves host names to IP addresses, from the hosts file, its cache or the
+//!   nameservers.
+//! - [`ResolverConfig`]: Configuration of the nameservers, the hosts file, the caching and the
+//!   Happy Eyeballs connection attempts.
+//! - [`ResolveError`]: Error of a failed resolution.
+//! - [`connect_any`]: Connects to the first of the resolved addresses accepting the connection.
+//!
+//! # Features
+//!
+//! - Queries the A and AAAA records of a host concurrently, trying each nameserver in turn
+//! - Caches the addresses for the TTL of their records, at most `max_ttl_sec`, and the hosts
+//!   without addresses for `negative_ttl_sec`
+//! - Concurrent resolutions of a host share a single query
+//! - Resolves the hosts of the hosts file, `/etc/hosts` by default, without a query
+//! - Uses the nameservers of `/etc/resolv.conf` unless configured, and expands the names with its
+//!   `search` list (or `domain`) like the system resolver: the names with fewer dots than its
+//!   `ndots` option are tried with each search domain first, the others as they are first, and the
+//!   names ending with a dot only as they are
+//! - Uses the records of the truncated answers, logging them, as it does not retry over TCP
+//! - Orders the addresses for Happy Eyeballs (RFC 8305) when enabled, alternating IPv6 and IPv4
+//!
+//! # Performance Considerations
+//!
+//! - Each worker has its own resolver and cache, so no lock is taken
+//! - A slow nameserver only delays the connections to the hosts it resolves
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    pin::pin,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    FutureExt, StreamExt,
+    future::{Either, LocalBoxFuture, Shared, select},
+    stream::FuturesUnordered,
+};
+use monoio::net::udp::UdpSocket;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+const DNS_PORT: u16 = 53;
+// The maximum size of a DNS message over UDP without EDNS.
+const MAX_UDP_SIZE: usize = 512;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+// The number of cached hosts above which the expired ones are evicted.
+const CACHE_EVICTION_LEN: usize = 1024;
+// The `ndots` of the system resolver, by default and at most.
+const DEFAULT_NDOTS: usize = 1;
+const MAX_NDOTS: usize = 15;
+
+/// Configuration of the [`Resolver`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// The nameservers queried in turn, like `10.0.0.2:53`, the ones of `/etc/resolv.conf` if
+    /// empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<SocketAddr>,
+    /// The hosts file resolving hosts without a query, ignored if missing.
+    #[serde(default = "default_hosts_file")]
+    pub hosts_file: PathBuf,
+    /// The resolver configuration file, of the nameservers if none is configured and of the search
+    /// list expanding the names, ignored if missing.
+    #[serde(default = "default_resolv_conf")]
+    pub resolv_conf: PathBuf,
+    /// Timeout of a query to a nameserver, in milliseconds.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// The maximum time the addresses of a host are cached, in seconds, whatever the TTL of their
+    /// records.
+    #[serde(default = "default_max_ttl_sec")]
+    pub max_ttl_sec: u32,
+    /// The time the hosts without addresses are cached, in seconds.
+    #[serde(default = "default_negative_ttl_sec")]
+    pub negative_ttl_sec: u32,
+    /// Whether to race the connections to the IPv6 and IPv4 addresses of the hosts (RFC 8305),
+    /// instead of trying the IPv4 then the IPv6 addresses in turn.
+    #[serde(default)]
+    pub happy_eyeballs: bool,
+    /// The delay after which the connection to the next address is attempted alongside the
+    /// previous ones, in milliseconds.
+    #[serde(default = "default_happy_eyeballs_delay_ms")]
+    pub happy_eyeballs_delay_ms: u64,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            hosts_file: default_hosts_file(),
+            resolv_conf: default_resolv_conf(),
+            timeout_ms: default_timeout_ms(),
+            max_ttl_sec: default_max_ttl_sec(),
+            negative_ttl_sec: default_negative_ttl_sec(),
+            happy_eyeballs: false,
+            happy_eyeballs_delay_ms: default_happy_eyeballs_delay_ms(),
+        }
+    }
+}
+
+fn default_hosts_file() -> PathBuf {
+    PathBuf::from("/etc/hosts")
+}
+
+fn default_resolv_conf() -> PathBuf {
+    PathBuf::from("/etc/resolv.conf")
+}
+
+const fn default_timeout_ms() -> u64 {
+    2000
+}
+
+const fn default_max_ttl_sec() -> u32 {
+    300
+}
+
+const fn default_negative_ttl_sec() -> u32 {
+    5
+}
+
+const fn default_happy_eyeballs_delay_ms() -> u64 {
+    250
+}
+
+impl ResolverConfig {
+    /// The delay between the connection attempts to the addresses of a host with Happy Eyeballs,
+    /// if enabled.
+    pub fn happy_eyeballs_delay(&self) -> Option<Duration> {
+        self.happy_eyeballs
+            .then(|| Duration::from_millis(self.happy_eyeballs_delay_ms))
+    }
+}
+
+/// Error of a failed resolution.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The host does not exist or has no address.
+    #[error("no address found for {0:?}")]
+    NotFound(String),
+    /// The nameservers did not answer, or with an error.
+    #[error("no answer from the nameservers for {0:?}")]
+    NoAnswer(String),
+}
+
+type Lookup = Result<Rc<[IpAddr]>, ResolveError>;
+// The addresses of a name, with the smallest TTL of their records.
+type Query = Result<(Rc<[IpAddr]>, u32), ResolveError>;
+
+/// Resolves host names to IP addresses without blocking, caching them.
+///
+/// It is not `Send`: each worker has its own, shared by its services.
+pub struct Resolver {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    config: ResolverConfig,
+    nameservers: Vec<SocketAddr>,
+    search: Vec<String>,
+    ndots: usize,
+    hosts: HashMap<String, Rc<[IpAddr]>>,
+    cache: RefCell<HashMap<String, Cached>>,
+    // The queries in flight, shared by the concurrent resolutions of their host.
+    pending: RefCell<HashMap<String, Shared<LocalBoxFuture<'static, Lookup>>>>,
+}
+
+struct Cached {
+    lookup: Lookup,
+    expires: Instant,
+}
+
+enum Answer {
+    Records {
+        records: Vec<(IpAddr, u32)>,
+        truncated: bool,
+    },
+    NxDomain,
+}
+
+/// The settings of `resolv.conf` used by the resolver.
+#[derive(Debug, PartialEq, Eq)]
+struct ResolvConf {
+    nameservers: Vec<SocketAddr>,
+    search: Vec<String>,
+    ndots: usize,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(ResolverConfig::default())
+    }
+}
+
+impl Resolver {
+    /// Creates a resolver, reading the hosts file and the resolver configuration file.
+    pub fn new(config: ResolverConfig) -> Self {
+        let hosts = match std::fs::read_to_string(&config.hosts_file) {
+            Ok(content) => parse_hosts(&content),
+            Err(e) => {
+                debug!("read hosts file {:?} failed: {e}", config.hosts_file);
+                HashMap::new()
+            }
+        };
+        let resolv_conf = match std::fs::read_to_string(&config.resolv_conf) {
+            Ok(content) => parse_resolv_conf(&content),
+            Err(e) => {
+                debug!("read resolv.conf {:?} failed: {e}", config.resolv_conf);
+                parse_resolv_conf("")
+            }
+        };
+        let mut nameservers = config.nameservers.clone();
+        if nameservers.is_empty() {
+            nameservers = resolv_conf.nameservers;
+        }
+        if nameservers.is_empty() {
+            // Like the system resolver, without nameservers the local one is queried.
+            nameservers.push(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT));
+        }
+        Self {
+            inner: Rc::new(Inner {
+                config,
+                nameservers,
+                search: resolv_conf.search,
+                ndots: resolv_conf.ndots,
+                hosts,
+                cache: RefCell::new(HashMap::new()),
+                pending: RefCell::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// The configuration of the resolver.
+    pub fn config(&self) -> &ResolverConfig {
+        &self.inner.config
+    }
+
+    /// Resolves `host`, an IP address or a host name, to its addresses in the order they should
+    /// be connected to.
+    pub async fn lookup(&self, host: &str) -> Result<Rc<[IpAddr]>, ResolveError> {
+        // IPv6 addresses are bracketed in URIs.
+        let literal = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = literal.parse::<IpAddr>() {
+            return Ok(Rc::from([ip]));
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.inner.hosts.get(&name) {
+            return Ok(addrs.clone());
+        }
+        // The names ending with a dot are not expanded with the search list.
+        let name = match host.ends_with('.') && !self.inner.search.is_empty() {
+            true => format!("{name}."),
+            false => name,
+        };
+        if let Some(cached) = self.inner.cache.borrow().get(&name)
+            && cached.expires > Instant::now()
+        {
+            return cached.lookup.clone();
+        }
+        let pending = self.inner.pending.borrow().get(&name).cloned();
+        let pending = match pending {
+            Some(pending) => pending,
+            None => {
+                let inner = self.inner.clone();
+                let key = name.clone();
+                let lookup = async move {
+                    let query = inner.search(&key).await;
+                    inner.pending.borrow_mut().remove(&key);
+                    inner.store(key, &query);
+                    query.map(|(addrs, _)| addrs)
+                }
+                .boxed_local()
+                .shared();
+                self.inner.pending.borrow_mut().insert(name, lookup.clone());
+                lookup
+            }
+        };
+        pending.await
+    }
+}
+
+impl Inner {
+    fn store(&self, name: String, query: &Query) {
+        // The failures of the nameservers are not cached.
+        match query {
+            Ok((addrs, ttl)) => self.store_for(name, Ok(addrs.clone()), *ttl),
+            Err(e @ ResolveError::NotFound(_)) => {
+                self.store_for(name, Err(e.clone()), self.config.negative_ttl_sec)
+            }
+            Err(ResolveError::NoAnswer(_)) => {}
+        }
+    }
+
+    fn store_for(&self, name: String, lookup: Lookup, ttl: u32) {
+        let now = Instant::now();
+        let mut cache = self.cache.borrow_mut();
+        if cache.len() >= CACHE_EVICTION_LEN {
+            cache.retain(|_, cached| cached.expires > now);
+        }
+        let expires = now + Duration::from_secs(ttl.into());
+        cache.insert(name, Cached { lookup, expires });
+    }
+
+    /// The names queried in turn for `name`, expanded with the search list like the system
+    /// resolver does.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(name) = name.strip_suffix('.') {
+            return vec![name.to_string()];
+        }
+        let expanded = self.search.iter().map(|domain| format!("{name}.{domain}"));
+        match name.matches('.').count() >= self.ndots {
+            true => std::iter::once(name.to_string()).chain(expanded).collect(),
+            false => expanded.chain(std::iter::once(name.to_string())).collect(),
+        }
+    }
+
+    /// Queries the candidates of `name` in turn until one has addresses.
+    async fn search(&self, name: &str) -> Query {
+        let mut no_answer = false;
+        for candidate in self.candidates(name) {
+            match self.query(&candidate).await {
+                Ok(found) => return Ok(found),
+                Err(ResolveError::NoAnswer(_)) => no_answer = true,
+                Err(ResolveError::NotFound(_)) => {}
+            }
+        }
+        let name = name.trim_end_matches('.').to_string();
+        match no_answer {
+            true => Err(ResolveError::NoAnswer(name)),
+            false => Err(ResolveError::NotFound(name)),
+        }
+    }
+
+    async fn query(&self, name: &str) -> Query {
+        let (v4, v6) = futures::join!(
+            self.query_type(name, TYPE_A),
+            self.query_type(name, TYPE_AAAA)
+        );
+        let mut ttl = self.config.max_ttl_sec;
+        let mut split = |answer: &Result<Vec<(IpAddr, u32)>, ResolveError>| match answer {
+            Ok(records) => records
+                .iter()
+                .map(|(ip, record_ttl)| {
+                    ttl = ttl.min(*record_ttl);
+                    *ip
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let (v4_addrs, v6_addrs): (Vec<_>, Vec<_>) = (split(&v4), split(&v6));
+        if v4_addrs.is_empty() && v6_addrs.is_empty() {
+            return match (v4, v6) {
+                (Err(ResolveError::NoAnswer(_)), _) | (_, Err(ResolveError::NoAnswer(_))) => {
+                    Err(ResolveError::NoAnswer(name.to_string()))
+                }
+                _ => Err(ResolveError::NotFound(name.to_string())),
+            };
+        }
+        let addrs: Rc<[IpAddr]> = match self.config.happy_eyeballs {
+            true => interleave(v6_addrs, v4_addrs).into(),
+            false => v4_addrs.into_iter().chain(v6_addrs).collect(),
+        };
+        Ok((addrs, ttl))
+    }
+
+    /// Queries the records of type `qtype` of `name`, from each nameserver in turn until one
+    /// answers.
+    async fn query_type(&self, name: &str, qtype: u16) -> Result<Vec<(IpAddr, u32)>, ResolveError> {
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        for nameserver in &self.nameservers {
+            let id = rand::random::<u16>();
+            let Some(query) = encode_query(id, name, qtype) else {
+                return Err(ResolveError::NotFound(name.to_string()));
+            };
+            match monoio::time::timeout(timeout, exchange(*nameserver, query, id, qtype)).await {
+                Ok(Ok(Answer::Records { records, truncated })) => {
+                    if truncated {
+                        warn!(
+                            "truncated answer to {name:?} from nameserver {nameserver}, using its \
+                             {} records",
+                            records.len()
+                        );
+                    }
+                    return Ok(records);
+                }
+                Ok(Ok(Answer::NxDomain)) => return Err(ResolveError::NotFound(name.to_string())),
+                Ok(Err(e)) => debug!("query {name:?} to nameserver {nameserver} failed: {e}"),
+                Err(_) => debug!("query {name:?} to nameserver {nameserver} timed out"),
+            }
+        }
+        Err(ResolveError::NoAnswer(name.to_string()))
+    }
+}
+
+/// Sends a query to a nameserver and waits for its answer.
+async fn exchange(
+    nameserver: SocketAddr,
+    query: Vec<u8>,
+    id: u16,
+    qtype: u16,
+) -> io::Result<Answer> {
+    let local: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(nameserver).await?;
+    socket.send(query).await.0?;
+    let mut buf = Vec::with_capacity(MAX_UDP_SIZE);
+    loop {
+        let (result, message) = socket.recv(buf).await;
+        let len = result?;
+        // The messages which are not the answer to the query are ignored.
+        if let Some(answer) = parse_response(&message[..len], id, qtype)? {
+            return Ok(answer);
+        }
+        buf = message;
+        buf.clear();
+    }
+}
+
+/// Encodes a recursive query of the records of type `qtype` of `name`, if it is a valid name.
+fn encode_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+    let mut message = Vec::with_capacity(18 + name.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    message.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(message)
+}
+
+/// Parses the answer to the query `id`, or returns `None` if `message` is not.
+fn parse_response(message: &[u8], id: u16, qtype: u16) -> io::Result<Option<Answer>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid DNS response");
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        let bytes = message.get(pos..pos + 2).ok_or_else(invalid)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if message.len() < 12 || u16_at(0)? != id {
+        return Ok(None);
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok(Some(Answer::NxDomain)),
+        rcode => return Err(io::Error::other(format!("DNS response code {rcode}"))),
+    }
+    // The truncated answers have the records which fit, and possibly a part of the next one.
+    let truncated = flags & 0x0200 != 0;
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let record = skip_name(message, pos).and_then(|start| {
+            let header = message.get(start..start + 10)?;
+            let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+            Some((start, header, message.get(start + 10..start + 10 + len)?))
+        });
+        let (start, header, data) = match record {
+            Some(record) => record,
+            None if truncated => break,
+            None => return Err(invalid()),
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        pos = start + 10 + data.len();
+        // The records of the aliases of the name, like CNAME, are skipped.
+        let ip = match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(ip), _) if qtype == TYPE_A => IpAddr::from(ip),
+            (TYPE_AAAA, _, Ok(ip)) if qtype == TYPE_AAAA => IpAddr::from(ip),
+            _ => continue,
+        };
+        records.push((ip, ttl));
+    }
+    Ok(Some(Answer::Records { records, truncated }))
+}
+
+/// Returns the position following the name at `pos`, possibly compressed.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return message.get(pos + 1).map(|_| pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+fn parse_hosts(content: &str) -> HashMap<String, Rc<[IpAddr]>> {
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        for name in fields {
+            let addrs = hosts
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    hosts
+        .into_iter()
+        .map(|(name, addrs)| (name, addrs.into()))
+        .collect()
+}
+
+fn parse_resolv_conf(content: &str) -> ResolvConf {
+    let mut conf = ResolvConf {
+        nameservers: Vec::new(),
+        search: Vec::new(),
+        ndots: DEFAULT_NDOTS,
+    };
+    let domain = |domain: &str| domain.trim_end_matches('.').to_ascii_lowercase();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) {
+                    conf.nameservers.push(SocketAddr::new(ip, DNS_PORT));
+                }
+            }
+            // The last of the search and domain lines sets the search list.
+            Some("search") => {
+                conf.search = fields.map(domain).filter(|d| !d.is_empty()).collect();
+            }
+            Some("domain") => {
+                conf.search = fields
+                    .next()
+                    .map(domain)
+                    .filter(|d| !d.is_empty())
+                    .into_iter()
+                    .collect();
+            }
+            Some("options") => {
+                for option in fields {
+                    if let Some(Ok(ndots)) = option.strip_prefix("ndots:").map(str::parse::<usize>)
+                    {
+                        conf.ndots = ndots.min(MAX_NDOTS);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    conf
+}
+
+fn interleave(first: Vec<IpAddr>, second: Vec<IpAddr>) -> Vec<IpAddr> {
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return addrs,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to the first of `addrs` accepting the connection.
+///
+/// The addresses are tried in turn, or with `happy_eyeballs`, the connection to the next address
+/// is attempted alongside the previous ones when they have not succeeded after this delay, or as
+/// soon as they fail.
+pub async fn connect_any<A, C, E, F, Fut>(
+    addrs: &[A],
+    happy_eyeballs: Option<Duration>,
+    connect: F,
+) -> Result<C, E>
+where
+    A: Clone,
+    F: Fn(A) -> Fut,
+    Fut: Future<Output = Result<C, E>>,
+    E: From<io::Error>,
+{
+    let mut addrs = addrs.iter().cloned();
+    let mut attempts = FuturesUnordered::new();
+    let mut error = None;
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(connect(addr)),
+                None => {
+                    return Err(error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address to connect to").into()
+                    }));
+                }
+            }
+        }
+        let result = match happy_eyeballs {
+            Some(delay) if addrs.len() > 0 => {
+                match select(attempts.next(), pin!(monoio::time::sleep(delay))).await {
+                    Either::Left((result, _)) => result,
+                    Either::Right(_) => None,
+                }
+            }
+            _ => attempts.next().await,
+        };
+        match result {
+            Some(Ok(conn)) => return Ok(conn),
+            Some(Err(e)) => {
+                error = Some(e);
+                if happy_eyeballs.is_some()
+                    && !attempts.is_empty()
+                    && let Some(addr) = addrs.next()
+                {
+                    attempts.push(connect(addr));
+                }
+            }
+            // The delay elapsed.
+            None => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(connect(addr));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Answers the queries with the records `answer` returns for their name and type, or with
+    /// NXDOMAIN, recording the queried names.
+    async fn stub_nameserver(
+        socket: UdpSocket,
+        answer: impl Fn(&str, u16) -> Option<Vec<(IpAddr, u32)>>,
+        queries: Rc<RefCell<Vec<String>>>,
+    ) {
+        loop {
+            let (result, query) = socket.recv_from(vec![0; MAX_UDP_SIZE]).await;
+            let (len, peer) = result.unwrap();
+            let question_end = skip_name(&query[..len], 12).unwrap() + 4;
+            let mut labels = Vec::new();
+            let mut pos = 12;
+            while query[pos] != 0 {
+                let len = query[pos] as usize;
+                labels.push(String::from_utf8_lossy(&query[pos + 1..pos + 1 + len]).into_owned());
+                pos += 1 + len;
+            }
+            let name = labels.join(".");
+            let qtype = u16::from_be_bytes([query[question_end - 4], query[question_end - 3]]);
+            let records = answer(&name, qtype);
+            queries.borrow_mut().push(name);
+            let mut response = query[..question_end].to_vec();
+            response[2] = 0x81;
+            response[3] = if records.is_some() { 0x80 } else { 0x83 };
+            let records = records.unwrap_or_default();
+            response[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+            for (ip, ttl) in records {
+                // A pointer to the name of the question.
+                response.extend_from_slice(&[0xc0, 12]);
+                response.extend_from_slice(&qtype.to_be_bytes());
+                response.extend_from_slice(&CLASS_IN.to_be_bytes());
+                response.extend_from_slice(&ttl.to_be_bytes());
+                let data = match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                response.extend_from_slice(&data);
+            }
+            // The answers which do not fit are truncated.
+            if response.len() > MAX_UDP_SIZE {
+                response.truncate(MAX_UDP_SIZE);
+                response[2] |= 0x02;
+            }
+            socket.send_to(response, peer).await.0.unwrap();
+        }
+    }
+
+    fn stub_resolver(
+        answer: impl Fn(&str, u16) -> Option<Vec<(IpAddr, u32)>> + 'static,
+        config: ResolverConfig,
+    ) -> (Resolver, Rc<RefCell<Vec<String>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let nameserver = socket.local_addr().unwrap();
+        let queries = Rc::new(RefCell::new(Vec::new()));
+        monoio::spawn(stub_nameserver(socket, answer, queries.clone()));
+        let mut nameservers = config.nameservers.clone();
+        nameservers.push(nameserver);
+        let resolver = Resolver::new(ResolverConfig {
+            nameservers,
+            // The search list of the system is not used.
+            resolv_conf: PathBuf::from("/nonexistent/resolv.conf"),
+            ..config
+        });
+        (resolver, queries)
+    }
+
+    #[test]
+    fn test_resolver() {
+        block_on(async {
+            let answers = HashMap::from([
+                (TYPE_A, vec![("10.0.0.1".parse().unwrap(), 60)]),
+                (TYPE_AAAA, vec![("fd00::1".parse().unwrap(), 30)]),
+            ]);
+            let hosts_file = std::env::temp_dir().join("monolake-resolver-hosts");
+            std::fs::write(&hosts_file, "10.1.1.1 Local.Test # comment\n").unwrap();
+            let (resolver, queries) = stub_resolver(
+                move |_, qtype| answers.get(&qtype).cloned(),
+                ResolverConfig {
+                    // The first nameserver does not answer.
+                    nameservers: vec!["127.0.0.1:9".parse().unwrap()],
+                    hosts_file: hosts_file.clone(),
+                    timeout_ms: 100,
+                    happy_eyeballs: true,
+                    ..Default::default()
+                },
+            );
+            let _ = std::fs::remove_file(hosts_file);
+
+            let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+            assert_eq!(*resolver.lookup("[::1]").await.unwrap(), [ip("::1")]);
+            assert_eq!(
+                *resolver.lookup("local.test.").await.unwrap(),
+                [ip("10.1.1.1")]
+            );
+            let (a, b) = futures::join!(
+                resolver.lookup("api.example.com"),
+                resolver.lookup("API.example.com")
+            );
+            assert_eq!(a, b);
+            assert_eq!(*a.unwrap(), [ip("fd00::1"), ip("10.0.0.1")]);
+            // A single query of each type, cached for the smallest TTL.
+            assert_eq!(queries.borrow().len(), 2);
+            resolver.lookup("api.example.com").await.unwrap();
+            assert_eq!(queries.borrow().len(), 2);
+            let expires = resolver.inner.cache.borrow()["api.example.com"].expires;
+            let ttl = expires - Instant::now();
+            assert!(ttl <= Duration::from_secs(30) && ttl > Duration::from_secs(25));
+
+            assert_eq!(
+                resolver.lookup("bad..name").await.unwrap_err(),
+                ResolveError::NotFound("bad..name".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_search() {
+        let conf = parse_resolv_conf(
+            "# comment\nnameserver 10.0.0.2\ndomain old.local\nsearch NS.svc.cluster.local. \
+             svc.cluster.local\noptions ndots:5 timeout:1\nnameserver ::1\n",
+        );
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec!["10.0.0.2:53".parse().unwrap(), "[::1]:53".parse().unwrap()],
+                search: vec!["ns.svc.cluster.local".into(), "svc.cluster.local".into()],
+                ndots: 5,
+            }
+        );
+        assert_eq!(parse_resolv_conf("options ndots:20").ndots, MAX_NDOTS);
+
+        block_on(async {
+            let (mut resolver, queries) = stub_resolver(
+                |name, qtype| match (name, qtype) {
+                    ("api.svc.local" | "db" | "a.b.c" | "one.svc.local", TYPE_A) => {
+                        Some(vec![("10.0.0.1".parse().unwrap(), 60)])
+                    }
+                    ("api.svc.local" | "db" | "a.b.c" | "one.svc.local", _) => Some(Vec::new()),
+                    _ => None,
+                },
+                ResolverConfig::default(),
+            );
+            let inner = Rc::get_mut(&mut resolver.inner).unwrap();
+            inner.search = vec!["ns.local".into(), "svc.local".into()];
+            inner.ndots = 2;
+            let queried = |queries: &RefCell<Vec<String>>| {
+                let mut names = queries.take();
+                names.dedup();
+                names
+            };
+
+            // The names with fewer dots than ndots are tried with the search list first.
+            resolver.lookup("api").await.unwrap();
+            assert_eq!(queried(&queries), ["api.ns.local", "api.svc.local"]);
+            resolver.lookup("db").await.unwrap();
+            assert_eq!(queried(&queries), ["db.ns.local", "db.svc.local", "db"]);
+            // The others as they are first, and the names ending with a dot only as they are.
+            resolver.lookup("a.b.c").await.unwrap();
+            assert_eq!(queried(&queries), ["a.b.c"]);
+            assert_eq!(
+                resolver.lookup("one.").await.unwrap_err(),
+                ResolveError::NotFound("one".to_string())
+            );
+            assert_eq!(queried(&queries), ["one"]);
+            resolver.lookup("one").await.unwrap();
+            assert_eq!(queried(&queries), ["one.ns.local", "one.svc.local"]);
+            assert_eq!(
+                resolver.lookup("x.y.z").await.unwrap_err(),
+                ResolveError::NotFound("x.y.z".to_string())
+            );
+            assert_eq!(
+                queried(&queries),
+                ["x.y.z", "x.y.z.ns.local", "x.y.z.svc.local"]
+            );
+        });
+    }
+
+    #[test]
+    fn test_truncated() {
+        block_on(async {
+            // More records than fit in a UDP message.
+            let records = (0..40)
+                .map(|i| (IpAddr::from([10, 0, 0, i]), 60))
+                .collect::<Vec<_>>();
+            let (resolver, _) = stub_resolver(
+                move |_, qtype| match qtype {
+                    TYPE_A => Some(records.clone()),
+                    _ => Some(Vec::new()),
+                },
+                ResolverConfig::default(),
+            );
+            let addrs = resolver.lookup("big.test").await.unwrap();
+            assert!(!addrs.is_empty() && addrs.len() < 40);
+            assert_eq!(addrs[0], IpAddr::from([10, 0, 0, 0]));
+        });
+    }
+
+    #[test]
+    fn test_connect_any() {
+        block_on(async {
+            let attempts = RefCell::new(Vec::new());
+            let connect = |addr: u32| {
+                attempts.borrow_mut().push(addr);
+                async move {
+                    // The first address does not answer, the second refuses the connection.
+                    match addr {
+                        0 => monoio::time::sleep(Duration::from_secs(10)).await,
+                        1 => return Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                        _ => {}
+                    }
+                    Ok(addr)
+                }
+            };
+            let delay = Some(Duration::from_millis(10));
+            assert_eq!(connect_any(&[0, 1, 2, 3], delay, connect).await.unwrap(), 2);
+            assert_eq!(*attempts.borrow(), [0, 1, 2]);
+
+            attempts.borrow_mut().clear();
+            assert_eq!(connect_any(&[1, 3], None, connect).await.unwrap(), 3);
+            assert_eq!(*attempts.borrow(), [1, 3]);
+            let err = connect_any(&[1], None, connect).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+}
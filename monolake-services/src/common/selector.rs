@@ -293,6 +293,40 @@ impl<T> LoadBalancer<T> {
     }
 }
 
+impl<T> LoadBalancer<T> {
+    /// The endpoints selected from.
+    pub fn endpoints(&self) -> &[T] {
+        match self {
+            LoadBalancer::Random(random_selector) => &random_selector.0,
+            LoadBalancer::WeightedRandom(wr_selector) => &wr_selector.collection,
+            LoadBalancer::RoundRobin(round_robin_selector) => &round_robin_selector.collection,
+            LoadBalancer::Identity(identity_selector) => std::slice::from_ref(&identity_selector.0),
+        }
+    }
+
+    /// The index of `endpoint` among the endpoints, if it is one of them.
+    pub fn position(&self, endpoint: &T) -> Option<usize> {
+        self.endpoints()
+            .iter()
+            .position(|e| std::ptr::eq(e, endpoint))
+    }
+
+    /// Selects an endpoint for which `available` is true, the next available one after the
+    /// selected endpoint if it is not, or `None` if none is.
+    pub fn select_where<A: ?Sized>(&self, key: &A, available: impl Fn(&T) -> bool) -> Option<&T> {
+        let Ok(selected) = self.select(key);
+        if available(selected) {
+            return Some(selected);
+        }
+        let endpoints = self.endpoints();
+        let start = self.position(selected).unwrap_or_default();
+        endpoints[start..]
+            .iter()
+            .chain(&endpoints[..start])
+            .find(|endpoint| available(endpoint))
+    }
+}
+
 impl<T, A: ?Sized> Select<A> for LoadBalancer<T> {
     type Output<'a>
         = &'a T
@@ -386,3 +420,28 @@ where
             .map_err(SelectError::ServiceError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl IntoWeightedEndpoint for (&'static str, u16) {
+        type Endpoint = &'static str;
+
+        fn into_weighted_endpoint(self) -> (Self::Endpoint, u16) {
+            self
+        }
+    }
+
+    #[test]
+    fn test_select_where() {
+        let lb = LoadBalancer::try_from_upstreams(
+            LoadBalanceStrategy::RoundRobin,
+            [("a", 1), ("b", 1), ("c", 1)],
+        )
+        .unwrap();
+        assert_eq!(lb.select_where("", |ep| *ep != "a"), Some(&"b"));
+        assert_eq!(lb.select_where("", |ep| *ep == "a"), Some(&"a"));
+        assert_eq!(lb.select_where("", |_| false), None);
+    }
+}
@@ -21,6 +21,9 @@
 //! - Configurable timeout settings for different stages of request processing
 //! - Integration with `service_async` for easy composition in service stacks
 //! - Automatic response encoding and error handling
+//! - Graceful draining: when the [`Drain`] signal of the connection fires, HTTP/1 connections are
+//!   closed once idle or after the in-flight response with `Connection: close`, and HTTP/2
+//!   connections are sent a GOAWAY and closed after their in-flight streams complete
 //!
 //! # Usage
 //!
@@ -68,7 +71,7 @@ use std::{convert::Infallible, fmt::Debug, time::Duration};
 use bytes::Bytes;
 use certain_map::{Attach, Fork};
 use futures::{StreamExt, stream::FuturesUnordered};
-use http::StatusCode;
+use http::{HeaderValue, StatusCode, header};
 use monoio::io::{AsyncReadRent, AsyncWriteRent, Split, Splitable, sink::SinkExt, stream::Stream};
 use monoio_http::{
     common::{
@@ -85,6 +88,7 @@ use monolake_core::{
     AnyError,
     context::PeerAddr,
     http::{HttpAccept, HttpHandler},
+    orchestrator::Drain,
 };
 use service_async::{
     AsyncMakeService, MakeService, Param, ParamRef, Service,
@@ -133,23 +137,36 @@ impl<H> HttpCoreService<H> {
         let mut decoder = RequestDecoder::new(reader);
         let mut encoder = GenericEncoder::new(writer);
         decoder.set_timeout(self.http_timeout.keepalive_timeout);
+        let drain = Drain::current();
 
         loop {
-            // decode request with header timeout
-            let decoded = match self.http_timeout.read_header_timeout {
-                Some(header_timeout) => {
-                    match monoio::time::timeout(header_timeout, decoder.next()).await {
-                        Ok(inner) => inner,
-                        Err(_) => {
-                            info!(
-                                "Connection {:?} decode http header timed out",
-                                ParamRef::<PeerAddr>::param_ref(&ctx),
-                            );
-                            break;
-                        }
+            // decode request with header timeout, or close the idle connection when draining
+            let next_request = async {
+                match self.http_timeout.read_header_timeout {
+                    Some(header_timeout) => monoio::time::timeout(header_timeout, decoder.next())
+                        .await
+                        .ok(),
+                    None => Some(decoder.next().await),
+                }
+            };
+            let decoded = monoio::select! {
+                decoded = next_request => match decoded {
+                    Some(inner) => inner,
+                    None => {
+                        info!(
+                            "Connection {:?} decode http header timed out",
+                            ParamRef::<PeerAddr>::param_ref(&ctx),
+                        );
+                        break;
                     }
+                },
+                _ = drain.wait() => {
+                    info!(
+                        "Connection {:?} closed for draining",
+                        ParamRef::<PeerAddr>::param_ref(&ctx),
+                    );
+                    break;
                 }
-                None => decoder.next().await,
             };
 
             let req = match decoded {
@@ -180,7 +197,12 @@ impl<H> HttpCoreService<H> {
                 .as_mut()
                 .stage1(self.handler_chain.handle(req, forked_ctx));
             match s1.await {
-                Ok((resp, should_cont)) => {
+                Ok((mut resp, should_cont)) => {
+                    let draining = drain.is_draining();
+                    if draining {
+                        resp.headers_mut()
+                            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+                    }
                     // 2. do these things simultaneously: read body and send + handle response
                     let s2 = fut_base.as_mut().stage2(encoder.send_and_flush(resp));
                     match self.http_timeout.read_body_timeout {
@@ -206,7 +228,7 @@ impl<H> HttpCoreService<H> {
                         },
                     }
 
-                    if !should_cont {
+                    if !should_cont || draining {
                         break;
                     }
                     if let Err(e) = fut_base.as_mut().stage3().await {
@@ -314,9 +336,27 @@ impl<H> HttpCoreService<H> {
         let mut backend_resp_stream = FuturesUnordered::new();
         let mut frontend_resp_stream = FuturesUnordered::new();
 
+        let drain = Drain::current();
         monoio::spawn(async move {
             let tx = tx.clone();
-            while let Some(result) = connection.accept().await {
+            let mut draining = false;
+            loop {
+                // send GOAWAY when draining, and keep accepting until in-flight streams complete
+                let result = if draining {
+                    connection.accept().await
+                } else {
+                    monoio::select! {
+                        result = connection.accept() => result,
+                        _ = drain.wait() => {
+                            draining = true;
+                            connection.graceful_shutdown();
+                            continue;
+                        }
+                    }
+                };
+                let Some(result) = result else {
+                    break;
+                };
                 match tx.send(result) {
                     Ok(_) => {}
                     Err(e) => {
@@ -22,7 +22,7 @@
 //!
 //! ```rust
 //! use monolake_services::{
-//!     common::ContextService,
+//!     common::{ContextService, resolver::ResolverConfig},
 //!     http::{
 //!         HttpServerTimeout,
 //!         core::HttpCoreService,
@@ -49,6 +49,11 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<ResolverConfig> for DummyConfig {
+//!     fn param(&self) -> ResolverConfig {
+//!         ResolverConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
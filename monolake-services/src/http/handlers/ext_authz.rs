@@ -0,0 +1,675 @@
+//! External authorization of HTTP requests by an authorization service.
+//!
+//! [`ExtAuthzHandler`] asks an HTTP authorization service whether each request is allowed before
+//! it is routed, following the model of the Envoy `ext_authz` HTTP service:
+//!
+//! - The service receives a request with the method, the path prefixed with `path_prefix`, the
+//!   headers and, if `include_body` is set, the body of the request.
+//! - A 2xx response allows the request. The headers of the response listed in
+//!   `allowed_upstream_headers` are set in the request to the upstream.
+//! - Another response denies the request. It is sent to the client with its status, its body and
+//!   its headers listed in `allowed_client_headers`.
+//!
+//! The service is reached over HTTP, HTTPS or a Unix domain socket, with pooled connections
+//! provided by `HttpConnector`, as the upstreams are by the
+//! [`UpstreamHandler`](crate::http::handlers::UpstreamHandler). Its host is resolved with the
+//! [`Resolver`] configured by the `resolver` of the server.
+//!
+//! # Error Handling
+//!
+//! When the service cannot be reached, does not respond in `timeout_ms` or responds with a
+//! 5xx status, the request is allowed if `fail_open` is set, and denied with `status_on_error`
+//! otherwise. Bodies larger than `max_body_bytes` result in 413 Payload Too Large responses.
+use std::{net::SocketAddr, path::PathBuf, rc::Rc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri, header,
+    uri::{Authority, PathAndQuery, Scheme},
+};
+use monoio_http::common::body::{Body, FixedBody, HttpBody};
+use monoio_transports::connectors::Connector;
+#[cfg(feature = "tls")]
+use monoio_transports::connectors::TcpTlsAddr;
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use serde::{Deserialize, Serialize};
+use service_async::{
+    AsyncMakeService, MakeService, Param, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use super::upstream::{PooledHttpConnector, PooledUnixConnector};
+#[cfg(feature = "tls")]
+use super::upstream::{PooledResolvedHttpsConnector, ResolvedTlsAddr};
+use crate::{
+    common::resolver::{ResolveError, Resolver, ResolverConfig, connect_any},
+    http::{generate_response, handlers::route::Endpoint},
+};
+
+// Headers of the connection to the client, which are not sent to the authorization service.
+const HOP_BY_HOP_HEADERS: [HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExtAuthzConfig {
+    /// The authorization service. The path of a `uri` endpoint must be empty, as the path of the
+    /// requests to the service is the one of the authorized requests.
+    pub endpoint: Endpoint,
+    /// Prefix of the path of the requests to the service.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path_prefix: String,
+    /// Timeout of connecting to the service, and of reading its responses.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Allow the requests when the service fails, instead of denying them.
+    #[serde(default)]
+    pub fail_open: bool,
+    /// Status of the responses denying the requests when the service fails.
+    #[serde(default = "default_status_on_error")]
+    pub status_on_error: u16,
+    /// Send the bodies of the requests to the service.
+    #[serde(default)]
+    pub include_body: bool,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Headers of the allowing responses set in the requests to the upstream.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_upstream_headers: Vec<String>,
+    /// Headers of the denying responses sent to the client.
+    #[serde(default = "default_allowed_client_headers")]
+    pub allowed_client_headers: Vec<String>,
+}
+
+const fn default_timeout_ms() -> u64 {
+    1000
+}
+
+const fn default_status_on_error() -> u16 {
+    403
+}
+
+const fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_allowed_client_headers() -> Vec<String> {
+    ["content-type", "location", "set-cookie", "www-authenticate"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Errors of the external authorization config.
+#[derive(Debug, Error)]
+pub enum ExtAuthzConfigError {
+    #[error("endpoint: {0}")]
+    Endpoint(&'static str),
+    #[error("path_prefix: invalid path {0:?}")]
+    PathPrefix(String),
+    #[error("status_on_error: invalid status {0}")]
+    Status(u16),
+    #[error("{0}: invalid header {1:?}")]
+    Header(&'static str, String),
+}
+
+#[derive(Debug, Error)]
+pub enum ExtAuthzFactoryError<E> {
+    #[error("inner error: {0:?}")]
+    Inner(E),
+    #[error("ext_authz.{0}")]
+    Config(#[from] ExtAuthzConfigError),
+}
+
+#[derive(Debug, Error)]
+enum CheckError {
+    #[error("{0}")]
+    Resolve(#[from] ResolveError),
+    #[error("connect failed: {0}")]
+    Connect(String),
+    #[error("request failed: {0}")]
+    Request(String),
+    #[error("timeout")]
+    Timeout,
+    #[error("status {0}")]
+    Status(StatusCode),
+}
+
+enum Target {
+    Http(Authority),
+    #[cfg(feature = "tls")]
+    Https(TcpTlsAddr),
+    Socket(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Outcome of the authorization of a request.
+enum Decision {
+    Allow(HeaderMap),
+    Deny(Response<Bytes>),
+}
+
+struct ExtAuthz {
+    target: Target,
+    path_prefix: String,
+    timeout: Duration,
+    fail_open: bool,
+    status_on_error: StatusCode,
+    include_body: bool,
+    max_body_bytes: usize,
+    allowed_upstream_headers: Vec<HeaderName>,
+    allowed_client_headers: Vec<HeaderName>,
+    http_connector: PooledHttpConnector,
+    #[cfg(feature = "tls")]
+    https_connector: PooledResolvedHttpsConnector,
+    unix_connector: PooledUnixConnector,
+    resolver: Rc<Resolver>,
+}
+
+macro_rules! connect {
+    ($self:ident, $connect:expr) => {
+        monoio::time::timeout($self.timeout, $connect)
+            .await
+            .map_err(|_| CheckError::Timeout)?
+            .map_err(|e| CheckError::Connect(format!("{e:?}")))?
+    };
+}
+
+impl ExtAuthz {
+    fn new(
+        config: &ExtAuthzConfig,
+        resolver: &ResolverConfig,
+        old: Option<&ExtAuthz>,
+    ) -> Result<Self, ExtAuthzConfigError> {
+        let target = match &config.endpoint {
+            Endpoint::Uri(uri) => {
+                if uri.path_and_query().is_some_and(|p| p.as_str() != "/") {
+                    return Err(ExtAuthzConfigError::Endpoint(
+                        "the uri must have no path, set path_prefix instead",
+                    ));
+                }
+                let Some(authority) = uri.authority().cloned() else {
+                    return Err(ExtAuthzConfigError::Endpoint("the uri has no host"));
+                };
+                match uri.scheme() {
+                    Some(scheme) if *scheme == Scheme::HTTP => Target::Http(authority),
+                    #[cfg(feature = "tls")]
+                    Some(scheme) if *scheme == Scheme::HTTPS => Target::Https(
+                        uri.try_into()
+                            .map_err(|_| ExtAuthzConfigError::Endpoint("invalid https uri"))?,
+                    ),
+                    _ => return Err(ExtAuthzConfigError::Endpoint("unsupported uri scheme")),
+                }
+            }
+            Endpoint::Socket(addr) => Target::Socket(*addr),
+            Endpoint::Unix(path) => Target::Unix(path.clone()),
+        };
+        if !config.path_prefix.is_empty()
+            && (!config.path_prefix.starts_with('/')
+                || config.path_prefix.parse::<PathAndQuery>().is_err()
+                || config.path_prefix.contains('?'))
+        {
+            return Err(ExtAuthzConfigError::PathPrefix(config.path_prefix.clone()));
+        }
+        let status_on_error = StatusCode::from_u16(config.status_on_error)
+            .map_err(|_| ExtAuthzConfigError::Status(config.status_on_error))?;
+        let header_names = |field, names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::try_from(name.as_str())
+                        .map_err(|_| ExtAuthzConfigError::Header(field, name.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        // A connection whose response is not read in time is closed rather than put back in
+        // the pool, where it would serve the response to the next request.
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let mut http_connector = PooledHttpConnector::build_tcp_http1_only();
+        http_connector.set_read_timeout(Some(timeout));
+        #[cfg(feature = "tls")]
+        let mut https_connector = PooledResolvedHttpsConnector::build_tls_http1_only();
+        #[cfg(feature = "tls")]
+        https_connector.set_read_timeout(Some(timeout));
+        let mut unix_connector = PooledUnixConnector::default();
+        unix_connector.set_http1_only();
+        unix_connector.set_read_timeout(Some(timeout));
+        // Keep the connections to the service across the config reloads.
+        if let Some(old) = old {
+            let _ = PooledHttpConnector::transfer_pool(&old.http_connector, &mut http_connector);
+            #[cfg(feature = "tls")]
+            let _ = PooledResolvedHttpsConnector::transfer_pool(
+                &old.https_connector,
+                &mut https_connector,
+            );
+            let _ = PooledUnixConnector::transfer_pool(&old.unix_connector, &mut unix_connector);
+        }
+
+        Ok(Self {
+            target,
+            path_prefix: config.path_prefix.trim_end_matches('/').to_string(),
+            timeout,
+            fail_open: config.fail_open,
+            status_on_error,
+            include_body: config.include_body,
+            max_body_bytes: config.max_body_bytes,
+            allowed_upstream_headers: header_names(
+                "allowed_upstream_headers",
+                &config.allowed_upstream_headers,
+            )?,
+            allowed_client_headers: header_names(
+                "allowed_client_headers",
+                &config.allowed_client_headers,
+            )?,
+            http_connector,
+            #[cfg(feature = "tls")]
+            https_connector,
+            unix_connector,
+            resolver: match old {
+                Some(old) if old.resolver.config() == resolver => old.resolver.clone(),
+                _ => Rc::new(Resolver::new(resolver.clone())),
+            },
+        })
+    }
+
+    // The addresses of the service, resolved within the timeout.
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, CheckError> {
+        let ips = monoio::time::timeout(self.timeout, self.resolver.lookup(host))
+            .await
+            .map_err(|_| CheckError::Timeout)??;
+        Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    fn check_request<B>(&self, request: &Request<B>, body: Option<Bytes>) -> Request<HttpBody> {
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", PathAndQuery::as_str);
+        let uri = format!("{}{path}", self.path_prefix)
+            .parse::<Uri>()
+            .unwrap_or_else(|_| Uri::from_static("/"));
+
+        let mut headers = request.headers().clone();
+        for name in HOP_BY_HOP_HEADERS.iter().chain([&header::CONTENT_LENGTH]) {
+            headers.remove(name);
+        }
+        // HTTP/2 requests carry the host in the uri.
+        if !headers.contains_key(header::HOST)
+            && let Some(value) = request
+                .uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        {
+            headers.insert(header::HOST, value);
+        }
+        let length = body.as_ref().map_or(0, Bytes::len);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+        let mut check = Request::new(HttpBody::fixed_body(body));
+        *check.method_mut() = request.method().clone();
+        *check.uri_mut() = uri;
+        *check.headers_mut() = headers;
+        check
+    }
+
+    async fn check<B>(
+        &self,
+        request: &Request<B>,
+        body: Option<Bytes>,
+    ) -> Result<Decision, CheckError> {
+        let check = self.check_request(request, body);
+        let happy_eyeballs = self.resolver.config().happy_eyeballs_delay();
+        let response = match &self.target {
+            Target::Http(authority) => {
+                let port = authority.port_u16().unwrap_or(80);
+                let addrs = self.resolve(authority.host(), port).await?;
+                let mut conn = connect!(
+                    self,
+                    connect_any(&addrs, happy_eyeballs, |addr| self
+                        .http_connector
+                        .connect(addr))
+                );
+                conn.send_request(check).await.0
+            }
+            #[cfg(feature = "tls")]
+            Target::Https(TcpTlsAddr { host, port, sn }) => {
+                let addrs = self.resolve(host, *port).await?;
+                let mut conn = connect!(
+                    self,
+                    connect_any(&addrs, happy_eyeballs, |addr| {
+                        self.https_connector.connect(ResolvedTlsAddr {
+                            addr,
+                            sn: sn.clone(),
+                        })
+                    })
+                );
+                conn.send_request(check).await.0
+            }
+            Target::Socket(addr) => {
+                let mut conn = connect!(self, self.http_connector.connect(*addr));
+                conn.send_request(check).await.0
+            }
+            Target::Unix(path) => {
+                let mut conn = connect!(self, self.unix_connector.connect(path.clone()));
+                conn.send_request(check).await.0
+            }
+        }
+        .map_err(|e| CheckError::Request(format!("{e:?}")))?;
+
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(CheckError::Status(status));
+        }
+        if status.is_success() {
+            let mut headers = HeaderMap::new();
+            for name in &self.allowed_upstream_headers {
+                for value in response.headers().get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+            return Ok(Decision::Allow(headers));
+        }
+
+        let (parts, mut body) = response.into_parts();
+        let mut payload = BytesMut::new();
+        while let Some(data) = body.next_data().await {
+            payload.extend_from_slice(&data.map_err(|e| CheckError::Request(format!("{e:?}")))?);
+        }
+        let mut denial = Response::new(payload.freeze());
+        *denial.status_mut() = status;
+        for name in &self.allowed_client_headers {
+            for value in parts.headers.get_all(name) {
+                denial.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        Ok(Decision::Deny(denial))
+    }
+}
+
+pub struct ExtAuthzHandlerFactory<F> {
+    inner: F,
+    ext_authz_config: Option<ExtAuthzConfig>,
+    resolver: ResolverConfig,
+}
+
+pub struct ExtAuthzHandler<H> {
+    inner: H,
+    ext_authz: Option<ExtAuthz>,
+}
+
+impl<F: MakeService> MakeService for ExtAuthzHandlerFactory<F> {
+    type Service = ExtAuthzHandler<F::Service>;
+    type Error = ExtAuthzFactoryError<F::Error>;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let ext_authz = self
+            .ext_authz_config
+            .as_ref()
+            .map(|config| {
+                ExtAuthz::new(
+                    config,
+                    &self.resolver,
+                    old.and_then(|o| o.ext_authz.as_ref()),
+                )
+            })
+            .transpose()?;
+        Ok(ExtAuthzHandler {
+            inner: self
+                .inner
+                .make_via_ref(old.map(|o| &o.inner))
+                .map_err(ExtAuthzFactoryError::Inner)?,
+            ext_authz,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for ExtAuthzHandlerFactory<F> {
+    type Service = ExtAuthzHandler<F::Service>;
+    type Error = ExtAuthzFactoryError<F::Error>;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let ext_authz = self
+            .ext_authz_config
+            .as_ref()
+            .map(|config| {
+                ExtAuthz::new(
+                    config,
+                    &self.resolver,
+                    old.and_then(|o| o.ext_authz.as_ref()),
+                )
+            })
+            .transpose()?;
+        Ok(ExtAuthzHandler {
+            inner: self
+                .inner
+                .make_via_ref(old.map(|o| &o.inner))
+                .await
+                .map_err(ExtAuthzFactoryError::Inner)?,
+            ext_authz,
+        })
+    }
+}
+
+impl<F> ExtAuthzHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = ExtAuthzHandlerFactory<F>>
+    where
+        C: Param<Option<ExtAuthzConfig>> + Param<ResolverConfig>,
+    {
+        layer_fn(|c: &C, inner| ExtAuthzHandlerFactory {
+            inner,
+            ext_authz_config: Param::<Option<ExtAuthzConfig>>::param(c),
+            resolver: Param::<ResolverConfig>::param(c),
+        })
+    }
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for ExtAuthzHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+    B: FixedBody + Body<Data = Bytes>,
+    B::Error: std::fmt::Debug,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let Some(ext_authz) = &self.ext_authz else {
+            return self.inner.handle(request, ctx).await;
+        };
+
+        // The body is buffered to be sent to the service, and then to the upstream.
+        let (mut request, body) = if ext_authz.include_body {
+            let (parts, mut body) = request.into_parts();
+            let mut payload = BytesMut::new();
+            while let Some(data) = body.next_data().await {
+                match data {
+                    Ok(data) if payload.len() + data.len() <= ext_authz.max_body_bytes => {
+                        payload.extend_from_slice(&data)
+                    }
+                    Ok(_) => {
+                        return Ok((generate_response(StatusCode::PAYLOAD_TOO_LARGE, true), true));
+                    }
+                    Err(e) => {
+                        debug!("read request body failed: {e:?}");
+                        return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
+                    }
+                }
+            }
+            let payload = payload.freeze();
+            (
+                Request::from_parts(parts, B::fixed_body(Some(payload.clone()))),
+                Some(payload),
+            )
+        } else {
+            (request, None)
+        };
+
+        match ext_authz.check(&request, body).await {
+            Ok(Decision::Allow(headers)) => {
+                let mut name = None;
+                for (next, value) in headers {
+                    // Only the first value of a header comes with its name.
+                    if let Some(next) = next {
+                        request.headers_mut().remove(&next);
+                        name = Some(next);
+                    }
+                    if let Some(name) = &name {
+                        request.headers_mut().append(name.clone(), value);
+                    }
+                }
+                self.inner.handle(request, ctx).await
+            }
+            Ok(Decision::Deny(denial)) => {
+                debug!("request denied with status {}", denial.status());
+                let (mut parts, body) = denial.into_parts();
+                parts
+                    .headers
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                let body = (!body.is_empty()).then_some(body);
+                Ok((Response::from_parts(parts, H::Body::fixed_body(body)), true))
+            }
+            Err(e) if ext_authz.fail_open => {
+                warn!("authorization failed, request allowed: {e}");
+                self.inner.handle(request, ctx).await
+            }
+            Err(e) => {
+                warn!("authorization failed, request denied: {e}");
+                Ok((generate_response(ext_authz.status_on_error, false), true))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stub::{self, RecordingHandler, StubResponse};
+
+    fn config(endpoint: &str) -> ExtAuthzConfig {
+        ExtAuthzConfig {
+            endpoint: Endpoint::Uri(endpoint.parse().unwrap()),
+            path_prefix: "/authz".to_string(),
+            timeout_ms: default_timeout_ms(),
+            fail_open: false,
+            status_on_error: 503,
+            include_body: true,
+            max_body_bytes: default_max_body_bytes(),
+            allowed_upstream_headers: vec!["x-user".to_string()],
+            allowed_client_headers: default_allowed_client_headers(),
+        }
+    }
+
+    fn request(path: &str) -> Request<HttpBody> {
+        Request::builder()
+            .method("POST")
+            .uri(path)
+            .header(header::HOST, "example.com")
+            .header(header::AUTHORIZATION, "Basic YWxpY2U6")
+            .header(header::TE, "trailers")
+            .header("x-user", "mallory")
+            .body(HttpBody::fixed_body(Some(Bytes::from_static(b"payload"))))
+            .unwrap()
+    }
+
+    async fn body(response: Response<HttpBody>) -> String {
+        let mut body = response.into_body();
+        let mut payload = BytesMut::new();
+        while let Some(data) = body.next_data().await {
+            payload.extend_from_slice(&data.unwrap());
+        }
+        String::from_utf8_lossy(&payload).into_owned()
+    }
+
+    #[test]
+    fn test_authorization() {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (addr, requests) = stub::serve(|request| match request.path() {
+                    "/authz/allowed" => StubResponse::json("{}")
+                        .header("x-user", "alice")
+                        .header("x-internal", "secret"),
+                    "/authz/denied" => StubResponse::new(401, "denied")
+                        .header("www-authenticate", "Basic")
+                        .header("x-internal", "secret"),
+                    _ => StubResponse::new(500, ""),
+                });
+                // The host of the service is resolved from the hosts file of the resolver.
+                let hosts_file = std::env::temp_dir().join("monolake-ext-authz-hosts");
+                std::fs::write(&hosts_file, "127.0.0.1 authz.test\n").unwrap();
+                let resolver = ResolverConfig {
+                    hosts_file: hosts_file.clone(),
+                    ..Default::default()
+                };
+                let handler = |config: ExtAuthzConfig| ExtAuthzHandler {
+                    inner: RecordingHandler::default(),
+                    ext_authz: Some(ExtAuthz::new(&config, &resolver, None).unwrap()),
+                };
+                let endpoint = format!("http://authz.test:{}", addr.port());
+                let closed = handler(config(&endpoint));
+                let _ = std::fs::remove_file(hosts_file);
+
+                // The allowed requests reach the upstream with the allowed headers of the service.
+                let (response, _) = closed.handle(request("/allowed?x=1"), ()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let forwarded = closed.inner.requests.borrow_mut().remove(0);
+                assert_eq!(
+                    forwarded.get_all("x-user").iter().collect::<Vec<_>>(),
+                    ["alice"]
+                );
+                assert!(forwarded.get("x-internal").is_none());
+                assert_eq!(forwarded[header::AUTHORIZATION], "Basic YWxpY2U6");
+                // The service receives the request, without its hop-by-hop headers.
+                let check = requests.borrow_mut().remove(0);
+                assert_eq!(check.method, "POST");
+                assert_eq!(check.target, "/authz/allowed?x=1");
+                assert_eq!(check.header("host"), Some("example.com"));
+                assert_eq!(check.header("authorization"), Some("Basic YWxpY2U6"));
+                assert_eq!(check.header("x-user"), Some("mallory"));
+                assert!(check.header("te").is_none());
+                assert_eq!(check.body, "payload");
+
+                // The denials are sent to the client with the allowed headers of the service.
+                let (response, _) = closed.handle(request("/denied"), ()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Basic");
+                assert!(response.headers().get("x-internal").is_none());
+                assert_eq!(body(response).await, "denied");
+                assert!(closed.inner.requests.borrow().is_empty());
+
+                // The failures of the service deny the requests with status_on_error, or allow
+                // them with fail_open.
+                let (response, _) = closed.handle(request("/error"), ()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                assert!(closed.inner.requests.borrow().is_empty());
+                let open = handler(ExtAuthzConfig {
+                    fail_open: true,
+                    ..config(&endpoint)
+                });
+                let (response, _) = open.handle(request("/error"), ()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let forwarded = open.inner.requests.borrow_mut().remove(0);
+                assert_eq!(forwarded["x-user"], "mallory");
+
+                // An unknown host is a failure of the service.
+                let unknown = handler(config("http://unknown.test"));
+                let (response, _) = unknown.handle(request("/allowed"), ()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                assert!(unknown.inner.requests.borrow().is_empty());
+            });
+    }
+}
@@ -0,0 +1,152 @@
+//! HTTP client of the identity providers, for the discovery, the tokens and the keys.
+//!
+//! The hosts of the providers are resolved with the [`Resolver`] of the worker, so that refreshing
+//! the keys on the request path does not block it.
+use std::{net::SocketAddr, rc::Rc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use http::{Request, header, uri::Scheme};
+use monoio_http::common::body::{Body, FixedBody, HttpBody};
+use monoio_transports::connectors::{Connector, TcpTlsAddr};
+use openidconnect::{HttpRequest, HttpResponse};
+use thiserror::Error;
+
+use super::upstream::{PooledHttpConnector, PooledResolvedHttpsConnector, ResolvedTlsAddr};
+use crate::common::resolver::{Resolver, connect_any};
+
+// Timeout of the requests to the providers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Errors of the requests to the providers.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("connect failed: {0}")]
+    Connect(String),
+    #[error("request failed: {0}")]
+    Request(String),
+    #[error("request timeout")]
+    Timeout,
+}
+
+/// HTTP client of the providers, with pooled connections.
+pub(crate) struct IdpClient {
+    http: PooledHttpConnector,
+    https: PooledResolvedHttpsConnector,
+    resolver: Rc<Resolver>,
+}
+
+impl Default for IdpClient {
+    fn default() -> Self {
+        Self::new(Rc::default())
+    }
+}
+
+impl IdpClient {
+    pub(crate) fn new(resolver: Rc<Resolver>) -> Self {
+        Self {
+            http: PooledHttpConnector::build_tcp_http1_only(),
+            https: PooledResolvedHttpsConnector::build_tls_http1_only(),
+            resolver,
+        }
+    }
+
+    /// The resolver of the hosts of the providers.
+    pub(crate) fn resolver(&self) -> &Resolver {
+        &self.resolver
+    }
+
+    /// Resolves `host` to the socket addresses to connect to.
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        let ips = self
+            .resolver
+            .lookup(host)
+            .await
+            .map_err(|e| Error::Connect(e.to_string()))?;
+        Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    pub(crate) async fn request(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        monoio::time::timeout(REQUEST_TIMEOUT, self.send(request))
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let invalid = |e: &dyn std::fmt::Display| Error::InvalidRequest(e.to_string());
+        let uri = request
+            .url
+            .as_str()
+            .parse::<http::Uri>()
+            .map_err(|e| invalid(&e))?;
+        let Some(authority) = uri.authority() else {
+            return Err(Error::InvalidRequest(format!("no host in {uri}")));
+        };
+        let mut builder = Request::builder()
+            .method(request.method.as_str())
+            .uri(&uri)
+            .header(header::HOST, authority.as_str());
+        for (name, value) in &request.headers {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        let req = builder
+            .body(HttpBody::fixed_body(Some(Bytes::from(request.body))))
+            .map_err(|e| invalid(&e))?;
+
+        let happy_eyeballs = self.resolver.config().happy_eyeballs_delay();
+        let response = if uri.scheme() == Some(&Scheme::HTTPS) {
+            let TcpTlsAddr { host, port, sn } = (&uri).try_into().map_err(|e| invalid(&e))?;
+            let addrs = self.resolve(&host, port).await?;
+            let mut conn = connect_any(&addrs, happy_eyeballs, |addr| {
+                self.https.connect(ResolvedTlsAddr {
+                    addr,
+                    sn: sn.clone(),
+                })
+            })
+            .await
+            .map_err(|e| Error::Connect(format!("{e:?}")))?;
+            conn.send_request(req).await.0
+        } else {
+            let port = uri.port_u16().unwrap_or(80);
+            let addrs = self.resolve(authority.host(), port).await?;
+            let mut conn = connect_any(&addrs, happy_eyeballs, |addr| self.http.connect(addr))
+                .await
+                .map_err(|e| Error::Connect(format!("{e:?}")))?;
+            conn.send_request(req).await.0
+        }
+        .map_err(|e| Error::Request(format!("{e:?}")))?;
+
+        let mut headers = openidconnect::http::HeaderMap::new();
+        for (name, value) in response.headers() {
+            if let (Ok(name), Ok(value)) = (
+                openidconnect::http::HeaderName::from_bytes(name.as_str().as_bytes()),
+                openidconnect::http::HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        let status_code = openidconnect::http::StatusCode::from_u16(response.status().as_u16())
+            .map_err(|e| Error::Request(e.to_string()))?;
+        let mut body = response.into_body();
+        let mut payload = BytesMut::new();
+        while let Some(data) = body.next_data().await {
+            payload.extend_from_slice(&data.map_err(|e| Error::Request(format!("{e:?}")))?);
+        }
+        Ok(HttpResponse {
+            status_code,
+            headers,
+            body: payload.to_vec(),
+        })
+    }
+}
+
+pub(crate) fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message += &format!(": {cause}");
+        source = cause.source();
+    }
+    message
+}
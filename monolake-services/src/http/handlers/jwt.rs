@@ -0,0 +1,898 @@
+//! JWT bearer token validation of HTTP requests.
+//!
+//! [`JwtHandler`] validates the `Authorization: Bearer` tokens of the requests, for the API
+//! traffic of which the clients are programs rather than browsers to be redirected to sign in.
+//!
+//! # Validation
+//!
+//! - The signature is verified with the RS256, ES256, EdDSA or HS256 keys of a JWKS, loaded from
+//!   `jwks_file` when the config is applied, or fetched from `jwks_url`. Fetched keys are cached by
+//!   each worker and refreshed every `jwks_refresh_sec`, or when a token is signed with an unknown
+//!   key.
+//! - `exp` is required, and `nbf`, `iss` and `aud` are checked if present or configured.
+//! - The token must have the scopes and the claims required by the route, or else by the server.
+//!
+//! The claims listed in `forward_claims` are set in headers of the request to the upstream. These
+//! headers are always removed from the requests of the clients, so they cannot be forged.
+//!
+//! # Error Handling
+//!
+//! - Missing or invalid tokens result in 401 Unauthorized responses.
+//! - Tokens without the required scopes or claims result in 403 Forbidden responses.
+//! - Failures to fetch the keys result in 502 Bad Gateway responses.
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    path::PathBuf,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, header};
+use monoio_http::common::body::FixedBody;
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use openidconnect::{
+    JsonWebKey, JsonWebKeySetUrl,
+    core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use service_async::{
+    AsyncMakeService, MakeService, Param, Service,
+    layer::{FactoryLayer, layer_fn},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use super::idp::{IdpClient, error_chain};
+use crate::{
+    common::resolver::{Resolver, ResolverConfig},
+    http::{
+        generate_response,
+        handlers::route::{MatchedRoute, RouteConfig},
+    },
+};
+
+// Minimum delay between two fetches of the keys, when a token is signed with an unknown key or
+// after a failure.
+const JWKS_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct JwtConfig {
+    /// JWKS file of the keys, read when the config is applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_file: Option<PathBuf>,
+    /// URL the JWKS of the keys is fetched from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_url: Option<String>,
+    #[serde(default = "default_jwks_refresh_sec")]
+    pub jwks_refresh_sec: u64,
+    #[serde(default = "default_algorithms")]
+    pub algorithms: Vec<JwtAlgorithm>,
+    /// Expected `iss` claim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// Accepted `aud` claims, of which the token must have one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audiences: Vec<String>,
+    /// Clock skew tolerated checking `exp` and `nbf`.
+    #[serde(default = "default_leeway_sec")]
+    pub leeway_sec: u64,
+    /// Claims forwarded to the upstream, with the header to set them in.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub forward_claims: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "JwtRequirements::is_empty")]
+    pub require: JwtRequirements,
+}
+
+/// Scopes and claims a token must have.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct JwtRequirements {
+    /// Scopes the token must all have, in its `scope` or `scp` claim.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Claims the token must have, with one of the values if any is listed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub claims: BTreeMap<String, Vec<String>>,
+}
+
+/// JWT validation of a route, overriding the one of the server.
+///
+/// `false` disables the validation of the server for the route, and `true` keeps it. Requirements
+/// replace the ones of the server, and the tokens are still validated with its keys.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RouteJwtConfig {
+    Enabled(bool),
+    Require(JwtRequirements),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[serde(rename = "ES256")]
+    Es256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+    #[serde(rename = "HS256")]
+    Hs256,
+}
+
+impl JwtAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            JwtAlgorithm::Rs256 => "RS256",
+            JwtAlgorithm::Es256 => "ES256",
+            JwtAlgorithm::EdDsa => "EdDSA",
+            JwtAlgorithm::Hs256 => "HS256",
+        }
+    }
+
+    fn signing_algorithm(self) -> CoreJwsSigningAlgorithm {
+        match self {
+            JwtAlgorithm::Rs256 => CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            JwtAlgorithm::Es256 => CoreJwsSigningAlgorithm::EcdsaP256Sha256,
+            JwtAlgorithm::EdDsa => CoreJwsSigningAlgorithm::EdDsaEd25519,
+            JwtAlgorithm::Hs256 => CoreJwsSigningAlgorithm::HmacSha256,
+        }
+    }
+}
+
+const fn default_jwks_refresh_sec() -> u64 {
+    300
+}
+
+fn default_algorithms() -> Vec<JwtAlgorithm> {
+    vec![
+        JwtAlgorithm::Rs256,
+        JwtAlgorithm::Es256,
+        JwtAlgorithm::EdDsa,
+        JwtAlgorithm::Hs256,
+    ]
+}
+
+const fn default_leeway_sec() -> u64 {
+    60
+}
+
+/// Errors of the JWT config.
+#[derive(Debug, Error)]
+pub enum JwtConfigError {
+    #[error("one of jwks_file and jwks_url is required")]
+    NoKeys,
+    #[error("jwks_file: read {0:?} failed: {1}")]
+    ReadJwks(PathBuf, std::io::Error),
+    #[error("jwks_file: {0}")]
+    ParseJwks(serde_json::Error),
+    #[error("jwks_url: {0}")]
+    JwksUrl(url::ParseError),
+    #[error("forward_claims: invalid header {0:?}")]
+    Header(String),
+}
+
+#[derive(Debug, Error)]
+pub enum JwtFactoryError<E> {
+    #[error("inner error: {0:?}")]
+    Inner(E),
+    #[error("jwt.{0}")]
+    Config(#[from] JwtConfigError),
+    #[error("routes[{0}].jwt: the server has no jwt config to validate the tokens")]
+    Route(usize),
+}
+
+impl JwtRequirements {
+    fn is_empty(&self) -> bool {
+        self.scopes.is_empty() && self.claims.is_empty()
+    }
+
+    fn check(&self, claims: &Map<String, Value>) -> Result<(), JwtError> {
+        if !self.scopes.is_empty() {
+            let granted = string_values(claims.get("scope"))
+                .into_iter()
+                .chain(string_values(claims.get("scp")))
+                .collect::<Vec<_>>();
+            let granted = granted
+                .iter()
+                .flat_map(|scopes| scopes.split_whitespace())
+                .collect::<Vec<_>>();
+            if let Some(scope) = self.scopes.iter().find(|s| !granted.contains(&s.as_str())) {
+                debug!("token without scope {scope}");
+                return Err(JwtError::Forbidden);
+            }
+        }
+        for (name, values) in &self.claims {
+            let Some(claim) = claims.get(name) else {
+                debug!("token without claim {name}");
+                return Err(JwtError::Forbidden);
+            };
+            if !values.is_empty()
+                && !string_values(Some(claim))
+                    .iter()
+                    .any(|value| values.iter().any(|v| v == value))
+            {
+                debug!("token with unexpected claim {name}: {claim}");
+                return Err(JwtError::Forbidden);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The values of a claim, which is a value or an array of values.
+fn string_values(value: Option<&Value>) -> Vec<Cow<'_, str>> {
+    match value {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::String(s)) => vec![Cow::Borrowed(s)],
+        Some(Value::Array(values)) => values
+            .iter()
+            .flat_map(|value| string_values(Some(value)))
+            .collect(),
+        Some(value) => vec![Cow::Owned(value.to_string())],
+    }
+}
+
+#[derive(Debug, Error)]
+enum JwtError {
+    #[error("missing bearer token")]
+    Missing,
+    #[error("invalid token: {0}")]
+    Invalid(&'static str),
+    #[error("insufficient scopes or claims")]
+    Forbidden,
+    #[error("keys unavailable")]
+    KeysUnavailable,
+}
+
+impl JwtError {
+    fn to_response<B: FixedBody>(&self) -> Response<B> {
+        let (status, challenge) = match self {
+            JwtError::Missing => (StatusCode::UNAUTHORIZED, "Bearer"),
+            JwtError::Invalid(_) => (StatusCode::UNAUTHORIZED, r#"Bearer error="invalid_token""#),
+            JwtError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                r#"Bearer error="insufficient_scope""#,
+            ),
+            JwtError::KeysUnavailable => return generate_response(StatusCode::BAD_GATEWAY, false),
+        };
+        let mut response = generate_response(status, false);
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static(challenge),
+        );
+        response
+    }
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Keys fetched from `jwks_url`, cached by each worker.
+struct RemoteKeys {
+    source: String,
+    url: JsonWebKeySetUrl,
+    refresh_interval: Duration,
+    client: IdpClient,
+    keys: RefCell<Option<Rc<CoreJsonWebKeySet>>>,
+    refresh_at: Cell<Instant>,
+    fetched_at: Cell<Option<Instant>>,
+}
+
+impl RemoteKeys {
+    async fn get(&self, kid: Option<&str>) -> Result<Rc<CoreJsonWebKeySet>, JwtError> {
+        let now = Instant::now();
+        let cached = self.keys.borrow().clone();
+        if let Some(keys) = &cached {
+            let known = kid.is_none_or(|kid| {
+                keys.keys()
+                    .iter()
+                    .any(|key| key.key_id().is_some_and(|id| id.as_str() == kid))
+            });
+            // The key may have been added since the keys were fetched.
+            let refetch = !known
+                && self
+                    .fetched_at
+                    .get()
+                    .is_none_or(|t| now >= t + JWKS_RETRY_INTERVAL);
+            if self.refresh_at.get() > now && !refetch {
+                return Ok(keys.clone());
+            }
+        }
+
+        self.fetched_at.set(Some(now));
+        match CoreJsonWebKeySet::fetch_async(&self.url, |request| self.client.request(request))
+            .await
+        {
+            Ok(keys) => {
+                debug!("fetched jwks {}", self.source);
+                let keys = Rc::new(keys);
+                *self.keys.borrow_mut() = Some(keys.clone());
+                self.refresh_at.set(now + self.refresh_interval);
+                Ok(keys)
+            }
+            Err(e) => {
+                warn!("fetch jwks {} failed: {}", self.source, error_chain(&e));
+                match cached {
+                    Some(keys) => {
+                        self.refresh_at.set(now + JWKS_RETRY_INTERVAL);
+                        Ok(keys)
+                    }
+                    None => Err(JwtError::KeysUnavailable),
+                }
+            }
+        }
+    }
+}
+
+struct JwtValidator {
+    config: JwtConfig,
+    forward_headers: Vec<(String, HeaderName)>,
+    file_keys: Option<CoreJsonWebKeySet>,
+    remote_keys: Option<Rc<RemoteKeys>>,
+}
+
+impl JwtValidator {
+    fn new(
+        config: &JwtConfig,
+        resolver: &ResolverConfig,
+        old: Option<&JwtValidator>,
+    ) -> Result<Self, JwtConfigError> {
+        if config.jwks_file.is_none() && config.jwks_url.is_none() {
+            return Err(JwtConfigError::NoKeys);
+        }
+        let file_keys = match &config.jwks_file {
+            Some(path) => {
+                let content =
+                    std::fs::read(path).map_err(|e| JwtConfigError::ReadJwks(path.clone(), e))?;
+                Some(serde_json::from_slice(&content).map_err(JwtConfigError::ParseJwks)?)
+            }
+            None => None,
+        };
+        let remote_keys = match &config.jwks_url {
+            Some(source) => {
+                let refresh_interval = Duration::from_secs(config.jwks_refresh_sec);
+                // Keep the keys fetched before the config is reloaded.
+                let old = old.and_then(|o| o.remote_keys.as_ref()).filter(|remote| {
+                    remote.source == *source
+                        && remote.refresh_interval == refresh_interval
+                        && remote.client.resolver().config() == resolver
+                });
+                match old {
+                    Some(remote) => Some(remote.clone()),
+                    None => Some(Rc::new(RemoteKeys {
+                        source: source.clone(),
+                        url: JsonWebKeySetUrl::new(source.clone())
+                            .map_err(JwtConfigError::JwksUrl)?,
+                        refresh_interval,
+                        client: IdpClient::new(Rc::new(Resolver::new(resolver.clone()))),
+                        keys: RefCell::new(None),
+                        refresh_at: Cell::new(Instant::now()),
+                        fetched_at: Cell::new(None),
+                    })),
+                }
+            }
+            None => None,
+        };
+        let forward_headers = config
+            .forward_claims
+            .iter()
+            .map(|(claim, header)| {
+                HeaderName::try_from(header.as_str())
+                    .map(|header| (claim.clone(), header))
+                    .map_err(|_| JwtConfigError::Header(header.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config: config.clone(),
+            forward_headers,
+            file_keys,
+            remote_keys,
+        })
+    }
+
+    async fn validate(&self, headers: &HeaderMap) -> Result<Map<String, Value>, JwtError> {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or(JwtError::Missing)?;
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(JwtError::Invalid("malformed token"));
+        };
+
+        let jwt_header: JwtHeader = decode_json(header)?;
+        let Some(algorithm) = self
+            .config
+            .algorithms
+            .iter()
+            .find(|alg| alg.name() == jwt_header.alg)
+        else {
+            return Err(JwtError::Invalid("algorithm not allowed"));
+        };
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| JwtError::Invalid("malformed signature"))?;
+        let message = &token.as_bytes()[..header.len() + 1 + payload.len()];
+        let verified = self
+            .verify(
+                &algorithm.signing_algorithm(),
+                jwt_header.kid.as_deref(),
+                message,
+                &signature,
+            )
+            .await?;
+        if !verified {
+            return Err(JwtError::Invalid("invalid signature"));
+        }
+
+        let claims: Map<String, Value> = decode_json(payload)?;
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    async fn verify(
+        &self,
+        algorithm: &CoreJwsSigningAlgorithm,
+        kid: Option<&str>,
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<bool, JwtError> {
+        let verify = |keys: &CoreJsonWebKeySet| {
+            keys.keys()
+                .iter()
+                .filter(|key| {
+                    kid.is_none_or(|kid| key.key_id().is_none_or(|id| id.as_str() == kid))
+                })
+                .any(|key| key.verify_signature(algorithm, message, signature).is_ok())
+        };
+        if let Some(keys) = &self.file_keys
+            && verify(keys)
+        {
+            return Ok(true);
+        }
+        match &self.remote_keys {
+            Some(remote) => Ok(verify(&*remote.get(kid).await?)),
+            None => Ok(false),
+        }
+    }
+
+    fn check_claims(&self, claims: &Map<String, Value>) -> Result<(), JwtError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        let leeway = self.config.leeway_sec as f64;
+        match claims.get("exp").and_then(Value::as_f64) {
+            Some(exp) if now <= exp + leeway => {}
+            Some(_) => return Err(JwtError::Invalid("token expired")),
+            None => return Err(JwtError::Invalid("no exp claim")),
+        }
+        if let Some(nbf) = claims.get("nbf") {
+            match nbf.as_f64() {
+                Some(nbf) if nbf <= now + leeway => {}
+                _ => return Err(JwtError::Invalid("token not yet valid")),
+            }
+        }
+        if let Some(issuer) = &self.config.issuer
+            && claims.get("iss").and_then(Value::as_str) != Some(issuer)
+        {
+            return Err(JwtError::Invalid("unexpected issuer"));
+        }
+        if !self.config.audiences.is_empty()
+            && !string_values(claims.get("aud"))
+                .iter()
+                .any(|aud| self.config.audiences.iter().any(|a| a == aud))
+        {
+            return Err(JwtError::Invalid("unexpected audience"));
+        }
+        Ok(())
+    }
+}
+
+fn decode_json<T: DeserializeOwned>(part: &str) -> Result<T, JwtError> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| JwtError::Invalid("malformed token"))?;
+    serde_json::from_slice(&json).map_err(|_| JwtError::Invalid("malformed token"))
+}
+
+fn header_value(claim: &Value) -> Option<HeaderValue> {
+    let value = match claim {
+        Value::String(s) => Cow::Borrowed(s.as_str()),
+        Value::Array(_) => Cow::Owned(string_values(Some(claim)).join(",")),
+        claim => Cow::Owned(claim.to_string()),
+    };
+    HeaderValue::from_str(&value).ok()
+}
+
+pub struct JwtHandlerFactory<F> {
+    inner: F,
+    jwt_config: Option<JwtConfig>,
+    resolver: ResolverConfig,
+    // A route requiring a token while the server has no keys to validate it.
+    unconfigured_route: Option<usize>,
+}
+
+pub struct JwtHandler<H> {
+    inner: H,
+    validator: Option<JwtValidator>,
+}
+
+impl<F> JwtHandlerFactory<F> {
+    fn validator<H>(
+        &self,
+        old: Option<&JwtHandler<H>>,
+    ) -> Result<Option<JwtValidator>, JwtConfigError> {
+        self.jwt_config
+            .as_ref()
+            .map(|config| {
+                JwtValidator::new(
+                    config,
+                    &self.resolver,
+                    old.and_then(|o| o.validator.as_ref()),
+                )
+            })
+            .transpose()
+    }
+}
+
+impl<F: MakeService> MakeService for JwtHandlerFactory<F> {
+    type Service = JwtHandler<F::Service>;
+    type Error = JwtFactoryError<F::Error>;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        if let Some(index) = self.unconfigured_route {
+            return Err(JwtFactoryError::Route(index));
+        }
+        let validator = self.validator(old)?;
+        Ok(JwtHandler {
+            inner: self
+                .inner
+                .make_via_ref(old.map(|o| &o.inner))
+                .map_err(JwtFactoryError::Inner)?,
+            validator,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for JwtHandlerFactory<F> {
+    type Service = JwtHandler<F::Service>;
+    type Error = JwtFactoryError<F::Error>;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        if let Some(index) = self.unconfigured_route {
+            return Err(JwtFactoryError::Route(index));
+        }
+        let validator = self.validator(old)?;
+        Ok(JwtHandler {
+            inner: self
+                .inner
+                .make_via_ref(old.map(|o| &o.inner))
+                .await
+                .map_err(JwtFactoryError::Inner)?,
+            validator,
+        })
+    }
+}
+
+impl<F> JwtHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = JwtHandlerFactory<F>>
+    where
+        C: Param<Option<JwtConfig>> + Param<Vec<RouteConfig>> + Param<ResolverConfig>,
+    {
+        layer_fn(|c: &C, inner| {
+            let jwt_config: Option<JwtConfig> = Param::<Option<JwtConfig>>::param(c);
+            let unconfigured_route = match jwt_config {
+                Some(_) => None,
+                None => Param::<Vec<RouteConfig>>::param(c)
+                    .iter()
+                    .position(|route| {
+                        matches!(
+                            route.jwt,
+                            Some(RouteJwtConfig::Enabled(true) | RouteJwtConfig::Require(_))
+                        )
+                    }),
+            };
+            JwtHandlerFactory {
+                inner,
+                jwt_config,
+                resolver: Param::<ResolverConfig>::param(c),
+                unconfigured_route,
+            }
+        })
+    }
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for JwtHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let Some(validator) = &self.validator else {
+            return self.inner.handle(request, ctx).await;
+        };
+        for (_, header) in &validator.forward_headers {
+            request.headers_mut().remove(header);
+        }
+        let route = request.extensions().get::<MatchedRoute>().cloned();
+        let requirements = match route.as_ref().and_then(|route| route.0.jwt.as_ref()) {
+            None | Some(RouteJwtConfig::Enabled(true)) => &validator.config.require,
+            Some(RouteJwtConfig::Enabled(false)) => return self.inner.handle(request, ctx).await,
+            Some(RouteJwtConfig::Require(requirements)) => requirements,
+        };
+
+        let claims = match validator.validate(request.headers()).await {
+            Ok(claims) => requirements.check(&claims).map(|_| claims),
+            Err(e) => Err(e),
+        };
+        let claims = match claims {
+            Ok(claims) => claims,
+            Err(e) => {
+                debug!("reject bearer token: {e}");
+                return Ok((e.to_response(), true));
+            }
+        };
+        for (claim, header) in &validator.forward_headers {
+            if let Some(value) = claims.get(claim).and_then(header_value) {
+                request.headers_mut().insert(header.clone(), value);
+            }
+        }
+        self.inner.handle(request, ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio_http::common::body::HttpBody;
+    use openidconnect::{PrivateSigningKey, core::CoreHmacKey};
+
+    use super::*;
+    use crate::http::stub::{self, RecordingHandler, StubResponse};
+
+    #[test]
+    fn test_requirements() {
+        let claims: Map<String, Value> = serde_json::from_str(
+            r#"{"scope": "read write", "roles": ["admin", "dev"], "tenant": "a", "level": 3}"#,
+        )
+        .unwrap();
+        let requirements = |scopes: &[&str], claims: &[(&str, &[&str])]| JwtRequirements {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            claims: claims
+                .iter()
+                .map(|(name, values)| {
+                    (
+                        name.to_string(),
+                        values.iter().map(|v| v.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        };
+
+        assert!(requirements(&["read", "write"], &[]).check(&claims).is_ok());
+        assert!(requirements(&["admin"], &[]).check(&claims).is_err());
+        assert!(
+            requirements(&[], &[("roles", &["admin"]), ("tenant", &[])])
+                .check(&claims)
+                .is_ok()
+        );
+        assert!(
+            requirements(&[], &[("level", &["3"])])
+                .check(&claims)
+                .is_ok()
+        );
+        assert!(
+            requirements(&[], &[("tenant", &["b"])])
+                .check(&claims)
+                .is_err()
+        );
+        assert!(requirements(&[], &[("sub", &[])]).check(&claims).is_err());
+    }
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    // A token signed with the secret, or unsigned if `alg` is `none`.
+    fn token(alg: &str, kid: &str, claims: Value) -> String {
+        let header = serde_json::json!({"alg": alg, "typ": "JWT", "kid": kid});
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = match alg {
+            "none" => Vec::new(),
+            _ => CoreHmacKey::new(SECRET)
+                .sign(&CoreJwsSigningAlgorithm::HmacSha256, message.as_bytes())
+                .unwrap(),
+        };
+        format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn claims() -> Value {
+        serde_json::json!({
+            "iss": "https://idp", "aud": ["api", "other"], "sub": "alice",
+            "exp": now() + 600, "nbf": now() - 10,
+        })
+    }
+
+    fn request(token: Option<&str>) -> Request<HttpBody> {
+        let mut request = Request::builder()
+            .uri("/")
+            .header("x-user", "mallory")
+            .header("x-issuer", "forged");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(HttpBody::fixed_body(None)).unwrap()
+    }
+
+    #[test]
+    fn test_validation() {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                // The keys served at jwks_url, by key id.
+                let kids = Rc::new(RefCell::new(vec!["k1"]));
+                let (addr, requests) = stub::serve({
+                    let kids = kids.clone();
+                    move |request| {
+                        if request.method != "GET"
+                            || request.path() != "/jwks"
+                            || request.header("host").is_none()
+                            || !request.body.is_empty()
+                        {
+                            return StubResponse::new(404, "");
+                        }
+                        let keys = kids
+                            .borrow()
+                            .iter()
+                            .map(|kid| {
+                                format!(
+                                    r#"{{"kty": "oct", "kid": "{kid}", "alg": "HS256", "k": "{}"}}"#,
+                                    URL_SAFE_NO_PAD.encode(SECRET)
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        StubResponse::json(format!(r#"{{"keys": [{}]}}"#, keys.join(",")))
+                    }
+                });
+                let config = JwtConfig {
+                    jwks_file: None,
+                    jwks_url: Some(format!("http://{addr}/jwks")),
+                    jwks_refresh_sec: default_jwks_refresh_sec(),
+                    algorithms: vec![JwtAlgorithm::Hs256],
+                    issuer: Some("https://idp".to_string()),
+                    audiences: vec!["api".to_string()],
+                    leeway_sec: 0,
+                    forward_claims: [("sub", "x-user"), ("iss", "x-issuer")]
+                        .into_iter()
+                        .map(|(claim, header)| (claim.to_string(), header.to_string()))
+                        .collect(),
+                    require: JwtRequirements::default(),
+                };
+                let handler = JwtHandler {
+                    inner: RecordingHandler::default(),
+                    validator: Some(
+                        JwtValidator::new(&config, &ResolverConfig::default(), None).unwrap(),
+                    ),
+                };
+                let status = |token: Option<String>| {
+                    let handler = &handler;
+                    async move {
+                        let (response, _) = handler
+                            .handle(request(token.as_deref()), ())
+                            .await
+                            .unwrap();
+                        response.status()
+                    }
+                };
+                let with = |claim: &str, value: Value| {
+                    let mut claims = claims();
+                    claims[claim] = value;
+                    Some(token("HS256", "k1", claims))
+                };
+
+                assert_eq!(
+                    status(Some(token("HS256", "k1", claims()))).await,
+                    StatusCode::OK
+                );
+                // The claims are forwarded in place of the headers sent by the client.
+                let forwarded = handler.inner.requests.borrow_mut().remove(0);
+                assert_eq!(forwarded.get_all("x-user").iter().collect::<Vec<_>>(), ["alice"]);
+                assert_eq!(forwarded["x-issuer"], "https://idp");
+                assert_eq!(requests.borrow().len(), 1);
+
+                assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+                // The signature of other claims.
+                let signed = token("HS256", "k1", claims());
+                let forged = with("sub", "admin".into()).unwrap();
+                let tampered = format!(
+                    "{}.{}",
+                    forged.rsplit_once('.').unwrap().0,
+                    signed.rsplit_once('.').unwrap().1
+                );
+                assert_eq!(status(Some(tampered)).await, StatusCode::UNAUTHORIZED);
+                assert_eq!(
+                    status(Some(token("none", "k1", claims()))).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                // Only the configured algorithms are accepted, even with a valid signature.
+                let mut rs256 = token("HS256", "k1", claims());
+                let (_, rest) = rs256.split_once('.').unwrap();
+                rs256 = format!(
+                    "{}.{rest}",
+                    URL_SAFE_NO_PAD.encode(r#"{"alg": "RS256", "kid": "k1"}"#)
+                );
+                assert_eq!(status(Some(rs256)).await, StatusCode::UNAUTHORIZED);
+                assert_eq!(
+                    status(with("exp", (now() - 10).into())).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                assert_eq!(
+                    status(with("nbf", (now() + 600).into())).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                assert_eq!(
+                    status(with("iss", "https://evil".into())).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                assert_eq!(
+                    status(with("aud", "other".into())).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                // None of the rejected requests reached the inner handler.
+                assert!(handler.inner.requests.borrow().is_empty());
+                assert_eq!(requests.borrow().len(), 1);
+
+                // A token signed with an unknown key refetches the keys, at most once per retry
+                // interval.
+                kids.borrow_mut().push("k2");
+                let remote = handler
+                    .validator
+                    .as_ref()
+                    .and_then(|v| v.remote_keys.as_ref())
+                    .unwrap();
+                assert_eq!(
+                    status(Some(token("HS256", "k3", claims()))).await,
+                    StatusCode::UNAUTHORIZED
+                );
+                assert_eq!(requests.borrow().len(), 1);
+                remote
+                    .fetched_at
+                    .set(Some(Instant::now() - JWKS_RETRY_INTERVAL));
+                assert_eq!(
+                    status(Some(token("HS256", "k2", claims()))).await,
+                    StatusCode::OK
+                );
+                assert_eq!(requests.borrow().len(), 2);
+            });
+    }
+}
@@ -16,11 +16,14 @@
 //!   balancing and error handling.
 //! - [`RewriteAndRouteHandler`]: Handles request routing based on predefined rules, directing
 //!   requests to appropriate handlers or upstream servers.
+//! - [`ExtAuthzHandler`]: Asks an external authorization service whether requests are allowed
+//!   before they are routed.
 //!
 //! # Optional Components
 //!
 //! - [`OpenIdHandler`]: Provides OpenID Connect authentication functionality (available with the
 //!   "openid" feature).
+//! - [`JwtHandler`]: Validates JWT bearer tokens against a JWKS (available with the "jwt" feature).
 //!
 //! # HttpHandler Trait
 //!
@@ -58,7 +61,7 @@
 //!
 //! ```rust
 //! use monolake_services::{
-//!     common::ContextService,
+//!     common::{ContextService, resolver::ResolverConfig},
 //!     http::{
 //!         HttpServerTimeout,
 //!         core::HttpCoreService,
@@ -85,6 +88,11 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<ResolverConfig> for DummyConfig {
+//!     fn param(&self) -> ResolverConfig {
+//!         ResolverConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
@@ -116,15 +124,25 @@
 //! # Feature Flags
 //!
 //! - `openid`: Enables the OpenID Connect authentication functionality
+//! - `jwt`: Enables the JWT bearer token validation
 pub mod connection_persistence;
 pub mod content_handler;
+pub mod ext_authz;
+#[cfg(any(feature = "openid", feature = "jwt"))]
+mod idp;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 #[cfg(feature = "openid")]
 pub mod openid;
+pub mod retry;
 pub mod route;
 pub mod upstream;
 
 pub use connection_persistence::ConnectionReuseHandler;
 pub use content_handler::ContentHandler;
+pub use ext_authz::ExtAuthzHandler;
+#[cfg(feature = "jwt")]
+pub use jwt::JwtHandler;
 #[cfg(feature = "openid")]
 pub use openid::OpenIdHandler;
 pub use route::{RewriteAndRouteHandler, RoutingFactoryError};
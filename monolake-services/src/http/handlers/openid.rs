@@ -1,198 +1,824 @@
+//! OpenID Connect authentication of HTTP requests.
+//!
+//! [`OpenIdHandler`] signs users in with the authorization code flow of an OpenID Connect
+//! provider. Requests without a valid session are redirected to the provider, which redirects the
+//! users back to `redirect_url` once signed in. The handler exchanges the code for the tokens,
+//! verifies the ID token, and redirects the users to the path they first requested with a session
+//! cookie, or to `/` if it is not a local path.
+//!
+//! The handler runs before the requests are routed, so that the requests matching no route are
+//! authenticated too. It matches the routes itself to apply the `auth` overriding the one of the
+//! server, and serves the `redirect_url` and the `logout_path` of the server and of every route
+//! whichever route they match, so that the codes of the provider are never sent to an upstream.
+//!
+//! # State
+//!
+//! - The metadata and the keys of the providers are discovered by each worker on first use, and
+//!   refreshed every `metadata_refresh_sec`, or as soon as an ID token fails to be verified since
+//!   the keys may have been rotated. When a refresh fails, the cached ones are still used.
+//! - The sessions are shared by the workers and kept across config reloads. They expire after
+//!   `session_ttl_sec`, and the oldest ones are evicted beyond `max_sessions`. Expired access
+//!   tokens are refreshed with the refresh token if the provider issued one, or else the users sign
+//!   in again.
+//!
+//! # Error Handling
+//!
+//! - Failures to reach the provider result in 502 Bad Gateway responses.
+//! - Codes rejected by the provider and ID tokens failing the verification result in 401
+//!   Unauthorized responses.
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    rc::Rc,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
 };
 
-use bytes::{Bytes, BytesMut};
 use cookie::Cookie;
-use http::{HeaderName, HeaderValue, Request, Response, StatusCode};
-use lazy_static::lazy_static;
-use monoio::net::TcpStream;
-use monoio_http::common::body::{Body, FixedBody, HttpBody, StreamHint};
-use monoio_transports::{
-    connectors::{Connector, TcpConnector, TcpTlsAddr, TlsConnector, TlsStream},
-    http::H1Connector,
-};
+use http::{HeaderValue, Request, Response, StatusCode, header};
+use monoio_http::common::body::FixedBody;
 use monolake_core::http::{HttpHandler, ResponseWithContinue};
-#[allow(unused)]
-use openidconnect::core::{
-    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClient, CoreClientAuthMethod,
-    CoreGenderClaim, CoreGrantType, CoreIdTokenClaims, CoreIdTokenVerifier, CoreJsonWebKey,
-    CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
-    CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreResponseMode,
-    CoreResponseType, CoreRevocableToken, CoreSubjectIdentifierType,
-};
-#[allow(unused)]
 use openidconnect::{
-    AccessToken, AdditionalClaims, AdditionalProviderMetadata, AuthenticationFlow,
-    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse,
-    ProviderMetadata, RedirectUrl, RevocationUrl, Scope, UserInfoClaims,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndSessionUrl, HttpRequest, HttpResponse,
+    IssuerUrl, LogoutRequest, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier,
+    PostLogoutRedirectUrl, ProviderMetadataWithLogout, RedirectUrl, RefreshToken,
+    RequestTokenError, Scope, TokenResponse,
+    core::{
+        CoreAuthenticationFlow, CoreClient, CoreIdToken, CoreJwsSigningAlgorithm, CoreTokenResponse,
+    },
 };
-use openidconnect::{HttpRequest, HttpResponse};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use service_async::{
     AsyncMakeService, MakeService, Param, Service,
     layer::{FactoryLayer, layer_fn},
 };
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, warn};
 use url::Url;
 
-use crate::http::generate_response;
+pub use super::idp::Error;
+use super::idp::{IdpClient, error_chain};
+use crate::{
+    common::resolver::{Resolver, ResolverConfig},
+    http::{
+        generate_response,
+        handlers::route::{MatchedRoute, RouteConfig, Router, RoutingFactoryError},
+    },
+};
+
+// Time users have to sign in to the provider.
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+// Delay before retrying to refresh the metadata of a provider after a failure.
+const METADATA_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 
-type HttpsConnector = H1Connector<TlsConnector<TcpConnector>, TcpTlsAddr, TlsStream<TcpStream>>;
+/// Errors of the OpenID config.
+#[derive(Debug, Error)]
+pub enum OpenIdConfigError {
+    #[error("issuer_url: {0}")]
+    IssuerUrl(url::ParseError),
+    #[error("redirect_url: {0}")]
+    RedirectUrl(url::ParseError),
+}
 
 #[derive(Debug, Error)]
-pub enum Error {}
+pub enum OpenIdFactoryError<E> {
+    #[error("inner error: {0:?}")]
+    Inner(E),
+    #[error("auth.{0}")]
+    Config(#[from] OpenIdConfigError),
+    #[error("{0}")]
+    Route(#[from] RoutingFactoryError<Infallible>),
+}
+
+/// Send a request to a provider, without reusing the connection.
+pub async fn async_http_client(request: HttpRequest) -> Result<HttpResponse, Error> {
+    IdpClient::default().request(request).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct OpenIdConfig {
+    pub client_id: String,
+    /// Redacted when serialized, e.g. by the admin API and `monolake dump`.
+    #[serde(serialize_with = "redact")]
+    pub client_secret: String,
+    pub issuer_url: String,
+    pub redirect_url: String,
+    /// Scopes requested in addition to `openid`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// Path signing users out, e.g. `/logout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logout_path: Option<String>,
+    /// Where users are redirected once signed out, `/` by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_logout_redirect_url: Option<String>,
+    #[serde(default = "default_metadata_refresh_sec")]
+    pub metadata_refresh_sec: u64,
+    #[serde(default = "default_session_ttl_sec")]
+    pub session_ttl_sec: u64,
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    #[serde(default)]
+    pub cookie: SessionCookieConfig,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ExtraClaims {
-    // Deprecated and thus optional as it might be removed in the futre
-    // sub_legacy: Option<String>,
-    // groups: Vec<String>,
+/// Attributes of the session cookie.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct SessionCookieConfig {
+    #[serde(default = "default_cookie_name")]
+    pub name: String,
+    /// Whether the cookie is only sent over HTTPS, by default if `redirect_url` is HTTPS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+    #[serde(default = "default_true")]
+    pub http_only: bool,
+    #[serde(default)]
+    pub same_site: SameSite,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
 }
-impl AdditionalClaims for ExtraClaims {}
 
-fn handle_error<T: std::error::Error>(fail: &T, msg: &'static str) {
-    let mut err_msg = format!("ERROR: {}", msg);
-    let mut cur_fail: Option<&dyn std::error::Error> = Some(fail);
-    while let Some(cause) = cur_fail {
-        err_msg += &format!("\n    caused by: {}", cause);
-        cur_fail = cause.source();
+impl Default for SessionCookieConfig {
+    fn default() -> Self {
+        Self {
+            name: default_cookie_name(),
+            secure: None,
+            http_only: true,
+            same_site: SameSite::default(),
+            domain: None,
+            path: default_cookie_path(),
+        }
     }
-    debug!("{}", err_msg);
-    // exit(1);
 }
 
-pub async fn async_http_client(request: HttpRequest) -> Result<HttpResponse, Error> {
-    let uri = request.url.as_str().parse::<http::uri::Uri>().unwrap();
-    let method = request.method.as_str().parse::<http::Method>().unwrap();
-    let mut req = Request::builder()
-        .version(http::Version::HTTP_11)
-        .method(method)
-        .uri(&uri);
-
-    let headers = req.headers_mut().unwrap();
-    for (key, value) in request.headers.iter() {
-        headers.insert(
-            HeaderName::from_bytes(key.as_str().as_bytes()).unwrap(),
-            HeaderValue::from_str(value.to_str().unwrap()).unwrap(),
-        );
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+const fn default_metadata_refresh_sec() -> u64 {
+    3600
+}
+
+const fn default_session_ttl_sec() -> u64 {
+    86400
+}
+
+const fn default_max_sessions() -> usize {
+    100_000
+}
+
+fn default_cookie_name() -> String {
+    "session-id".to_string()
+}
+
+fn default_cookie_path() -> String {
+    "/".to_string()
+}
+
+/// The value serialized instead of a secret.
+pub const REDACTED: &str = "<redacted>";
+
+fn redact<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+impl OpenIdConfig {
+    pub fn validate(&self) -> Result<(), OpenIdConfigError> {
+        IssuerUrl::new(self.issuer_url.clone()).map_err(OpenIdConfigError::IssuerUrl)?;
+        RedirectUrl::new(self.redirect_url.clone()).map_err(OpenIdConfigError::RedirectUrl)?;
+        Ok(())
     }
-    let host = uri.host().unwrap();
-    headers.insert("Host", HeaderValue::from_bytes(host.as_bytes()).unwrap());
-
-    let request_payload: Bytes = request.body.into();
-    let req: http::Request<HttpBody> = req
-        .body(HttpBody::fixed_body(Some(request_payload)))
-        .unwrap();
-
-    let client = HttpsConnector::default().with_default_pool();
-    let key = req.uri().try_into().unwrap();
-    let mut client = client.connect(key).await.unwrap();
-    let (response, _) = client.send_request(req).await;
-    let response = response.unwrap();
-
-    let status = response.status().as_u16();
-    // let headers: HeaderMap = response.headers().clone();
-    let mut headers = openidconnect::http::HeaderMap::new();
-    for (key, value) in response.headers().iter() {
-        headers.insert(
-            openidconnect::http::HeaderName::from_bytes(key.as_str().as_bytes()).unwrap(),
-            openidconnect::http::HeaderValue::from_str(value.to_str().unwrap()).unwrap(),
-        );
+
+    fn session_cookie<'c>(&self, value: impl Into<std::borrow::Cow<'c, str>>) -> Cookie<'c> {
+        let cookie = &self.cookie;
+        let mut builder = Cookie::build((cookie.name.clone(), value))
+            .path(cookie.path.clone())
+            .http_only(cookie.http_only)
+            .secure(
+                cookie
+                    .secure
+                    .unwrap_or_else(|| self.redirect_url.starts_with("https:")),
+            )
+            .same_site(match cookie.same_site {
+                SameSite::Strict => cookie::SameSite::Strict,
+                SameSite::Lax => cookie::SameSite::Lax,
+                SameSite::None => cookie::SameSite::None,
+            });
+        if let Some(domain) = &cookie.domain {
+            builder = builder.domain(domain.clone());
+        }
+        builder.build()
+    }
+}
+
+/// Authentication of a route, overriding the one of the server.
+///
+/// `false` disables the authentication of the server for the route, and `true` keeps it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RouteAuthConfig {
+    Enabled(bool),
+    OpenId(Box<OpenIdConfig>),
+}
+
+/// Entries expiring after a TTL, of which the oldest are evicted beyond a capacity.
+struct ExpiringMap<V> {
+    entries: HashMap<String, Entry<V>>,
+    // Keys in insertion order, with the sequence number of the entry to tell the ones replaced or
+    // removed since.
+    order: VecDeque<(String, u64)>,
+    seq: u64,
+}
+
+struct Entry<V> {
+    seq: u64,
+    deadline: Instant,
+    value: V,
+}
+
+impl<V> Default for ExpiringMap<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            seq: 0,
+        }
+    }
+}
+
+impl<V> ExpiringMap<V> {
+    fn insert(&mut self, key: String, value: V, ttl: Duration, capacity: usize) {
+        let now = Instant::now();
+        self.seq += 1;
+        let entry = Entry {
+            seq: self.seq,
+            deadline: now + ttl,
+            value,
+        };
+        self.entries.insert(key.clone(), entry);
+        self.order.push_back((key, self.seq));
+        self.evict(now, capacity);
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.deadline <= Instant::now())
+        {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
     }
-    let mut body = response.into_body();
 
-    let payload = match body.stream_hint() {
-        StreamHint::None => Bytes::new(),
-        StreamHint::Fixed => body.next_data().await.unwrap().unwrap(),
-        StreamHint::Stream => {
-            let mut bytes = BytesMut::new();
-            while let Some(Ok(data)) = body.next_data().await {
-                bytes.extend_from_slice(&data[..]);
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let now = Instant::now();
+        self.entries
+            .remove(key)
+            .filter(|entry| entry.deadline > now)
+            .map(|entry| entry.value)
+    }
+
+    fn evict(&mut self, now: Instant, capacity: usize) {
+        while let Some((key, seq)) = self.order.front() {
+            match self.entries.get(key) {
+                Some(entry) if entry.seq == *seq => {
+                    if entry.deadline > now && self.entries.len() <= capacity {
+                        break;
+                    }
+                    self.entries.remove(key);
+                }
+                _ => {}
             }
-            bytes.freeze()
+            self.order.pop_front();
         }
-    };
+        // Drop the keys of the removed entries, which are otherwise kept until they expire.
+        if self.order.len() > 2 * self.entries.len().max(16) {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, seq)| entries.get(key).is_some_and(|entry| entry.seq == *seq));
+        }
+    }
+}
+
+struct PendingLogin {
+    nonce: Nonce,
+    pkce_verifier: PkceCodeVerifier,
+    // Where to redirect the user once signed in.
+    target: String,
+}
+
+struct Session {
+    expires_at: Option<Instant>,
+    refresh_token: Option<RefreshToken>,
+    id_token: Option<CoreIdToken>,
+}
+
+impl Session {
+    fn new(token: &CoreTokenResponse, id_token: Option<CoreIdToken>) -> Self {
+        Self {
+            expires_at: token.expires_in().map(|ttl| Instant::now() + ttl),
+            refresh_token: token.refresh_token().cloned(),
+            id_token,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ClientSessions {
+    logins: Mutex<ExpiringMap<PendingLogin>>,
+    sessions: Mutex<ExpiringMap<Session>>,
+}
+
+impl ClientSessions {
+    fn has_login(&self, state: &str) -> bool {
+        lock(&self.logins).get_mut(state).is_some()
+    }
+}
+
+/// Sessions of the clients, shared by the workers and kept across config reloads.
+#[derive(Default)]
+struct SessionStore(Mutex<Vec<(String, String, Arc<ClientSessions>)>>);
+
+impl SessionStore {
+    fn client(&self, config: &OpenIdConfig) -> Arc<ClientSessions> {
+        let mut clients = lock(&self.0);
+        if let Some((_, _, sessions)) = clients.iter().find(|(issuer_url, client_id, _)| {
+            *issuer_url == config.issuer_url && *client_id == config.client_id
+        }) {
+            return sessions.clone();
+        }
+        let sessions = Arc::<ClientSessions>::default();
+        clients.push((
+            config.issuer_url.clone(),
+            config.client_id.clone(),
+            sessions.clone(),
+        ));
+        sessions
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Provider {
+    client: CoreClient,
+    signing_algs: Vec<CoreJwsSigningAlgorithm>,
+    end_session_url: Option<EndSessionUrl>,
+    refresh_at: Cell<Instant>,
+}
 
-    let body: Vec<u8> = payload.to_vec();
-    Ok(HttpResponse {
-        status_code: status.try_into().unwrap(),
-        headers,
-        body,
-    })
+/// Metadata and keys of the providers, cached by each worker.
+#[derive(Default)]
+struct Providers {
+    http: IdpClient,
+    cache: RefCell<HashMap<OpenIdConfig, Rc<Provider>>>,
+}
+
+impl Providers {
+    fn new(resolver: Rc<Resolver>) -> Self {
+        Self {
+            http: IdpClient::new(resolver),
+            cache: RefCell::default(),
+        }
+    }
+
+    async fn get(&self, config: &OpenIdConfig) -> Result<Rc<Provider>, StatusCode> {
+        let cached = self.cache.borrow().get(config).cloned();
+        if let Some(provider) = &cached
+            && provider.refresh_at.get() > Instant::now()
+        {
+            return Ok(provider.clone());
+        }
+        match self.discover(config).await {
+            Ok(provider) => {
+                let provider = Rc::new(provider);
+                self.cache
+                    .borrow_mut()
+                    .insert(config.clone(), provider.clone());
+                Ok(provider)
+            }
+            Err(status) => match cached {
+                Some(provider) => {
+                    warn!("keep the cached metadata of {}", config.issuer_url);
+                    provider
+                        .refresh_at
+                        .set(Instant::now() + METADATA_RETRY_INTERVAL);
+                    Ok(provider)
+                }
+                None => Err(status),
+            },
+        }
+    }
+
+    async fn discover(&self, config: &OpenIdConfig) -> Result<Provider, StatusCode> {
+        let invalid_config = |e: OpenIdConfigError| {
+            warn!("invalid openid config: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        let issuer_url = IssuerUrl::new(config.issuer_url.clone())
+            .map_err(|e| invalid_config(OpenIdConfigError::IssuerUrl(e)))?;
+        let redirect_url = RedirectUrl::new(config.redirect_url.clone())
+            .map_err(|e| invalid_config(OpenIdConfigError::RedirectUrl(e)))?;
+
+        let metadata = ProviderMetadataWithLogout::discover_async(issuer_url, |request| {
+            self.http.request(request)
+        })
+        .await
+        .map_err(|e| {
+            warn!("discover {} failed: {}", config.issuer_url, error_chain(&e));
+            StatusCode::BAD_GATEWAY
+        })?;
+        debug!("discovered {}", config.issuer_url);
+
+        let signing_algs = metadata
+            .id_token_signing_alg_values_supported()
+            .iter()
+            .filter(|alg| **alg != CoreJwsSigningAlgorithm::None)
+            .cloned()
+            .collect();
+        let end_session_url = metadata.additional_metadata().end_session_endpoint.clone();
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id.clone()),
+            Some(ClientSecret::new(config.client_secret.clone())),
+        )
+        .set_redirect_uri(redirect_url);
+        Ok(Provider {
+            client,
+            signing_algs,
+            end_session_url,
+            refresh_at: Cell::new(
+                Instant::now() + Duration::from_secs(config.metadata_refresh_sec),
+            ),
+        })
+    }
+}
+
+/// Factory of [`OpenIdHandler`], of which the sessions outlive the handlers it makes.
+pub struct OpenIdHandlerFactory<F> {
+    inner: F,
+    openid_config: Option<OpenIdConfig>,
+    routes: Vec<RouteConfig>,
+    resolver: ResolverConfig,
+    sessions: Arc<SessionStore>,
 }
 
-#[derive(Clone)]
 pub struct OpenIdHandler<H> {
     inner: H,
     openid_config: Option<OpenIdConfig>,
+    // The routes, if some override the authentication of the server.
+    routes: Option<Router<MatchedRoute>>,
+    // The configs of the server and of the routes, of which the callback and logout paths are
+    // served.
+    configs: Vec<OpenIdConfig>,
+    sessions: Arc<SessionStore>,
+    providers: Rc<Providers>,
 }
 
-impl<F: MakeService> MakeService for OpenIdHandler<F> {
-    type Service = OpenIdHandler<F::Service>;
-    type Error = F::Error;
-
-    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+impl<F> OpenIdHandlerFactory<F> {
+    fn make_handler<H>(
+        &self,
+        inner: H,
+        old: Option<&OpenIdHandler<H>>,
+    ) -> Result<OpenIdHandler<H>, RoutingFactoryError<Infallible>> {
+        let routes = match self.routes.iter().any(|route| route.auth.is_some()) {
+            true => Some(Router::matcher_from_iter(self.routes.iter().cloned())?),
+            false => None,
+        };
+        let mut configs: Vec<OpenIdConfig> = self.openid_config.iter().cloned().collect();
+        for route in &self.routes {
+            if let Some(RouteAuthConfig::OpenId(config)) = &route.auth
+                && !configs.contains(config)
+            {
+                configs.push(config.as_ref().clone());
+            }
+        }
         Ok(OpenIdHandler {
-            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            inner,
             openid_config: self.openid_config.clone(),
+            routes,
+            configs,
+            sessions: old.map_or_else(|| self.sessions.clone(), |o| o.sessions.clone()),
+            // The cached providers are kept unless the resolver of their hosts changed.
+            providers: match old {
+                Some(old) if *old.providers.http.resolver().config() == self.resolver => {
+                    old.providers.clone()
+                }
+                _ => Rc::new(Providers::new(Rc::new(Resolver::new(
+                    self.resolver.clone(),
+                )))),
+            },
         })
     }
 }
 
-impl<F: AsyncMakeService> AsyncMakeService for OpenIdHandler<F> {
+impl<F: MakeService> MakeService for OpenIdHandlerFactory<F> {
+    type Service = OpenIdHandler<F::Service>;
+    type Error = OpenIdFactoryError<F::Error>;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        if let Some(config) = &self.openid_config {
+            config.validate()?;
+        }
+        let inner = self
+            .inner
+            .make_via_ref(old.map(|o| &o.inner))
+            .map_err(OpenIdFactoryError::Inner)?;
+        Ok(self.make_handler(inner, old)?)
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for OpenIdHandlerFactory<F> {
     type Service = OpenIdHandler<F::Service>;
-    type Error = F::Error;
+    type Error = OpenIdFactoryError<F::Error>;
 
     async fn make_via_ref(
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        Ok(OpenIdHandler {
-            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
-            openid_config: self.openid_config.clone(),
-        })
+        if let Some(config) = &self.openid_config {
+            config.validate()?;
+        }
+        let inner = self
+            .inner
+            .make_via_ref(old.map(|o| &o.inner))
+            .await
+            .map_err(OpenIdFactoryError::Inner)?;
+        Ok(self.make_handler(inner, old)?)
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct OpenIdConfig {
-    // TODO: Need to add openid scopes etc.
-    pub client_id: String,
-    pub client_secret: String,
-    pub issuer_url: String,
-    pub redirect_url: String,
-}
-
 impl<F> OpenIdHandler<F> {
-    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = OpenIdHandlerFactory<F>>
     where
-        C: Param<Option<OpenIdConfig>>,
+        C: Param<Option<OpenIdConfig>> + Param<Vec<RouteConfig>> + Param<ResolverConfig>,
     {
-        layer_fn(move |c: &C, inner| Self {
+        layer_fn(move |c: &C, inner| OpenIdHandlerFactory {
             inner,
-            openid_config: c.param(),
+            openid_config: Param::<Option<OpenIdConfig>>::param(c),
+            routes: Param::<Vec<RouteConfig>>::param(c),
+            resolver: Param::<ResolverConfig>::param(c),
+            sessions: Default::default(),
         })
     }
 }
 
-#[derive(Clone)]
-struct SessionState {
-    // Plenty more to add, eg. expiration time
-    pub nonce: Nonce,
-    pub access_token: Option<AccessToken>,
+impl<H> OpenIdHandler<H> {
+    // The config of the route matched by the request if any, or else the one of the server.
+    fn openid_config<'a>(&'a self, route: Option<&'a MatchedRoute>) -> Option<&'a OpenIdConfig> {
+        match route.and_then(|route| route.0.auth.as_ref()) {
+            None | Some(RouteAuthConfig::Enabled(true)) => self.openid_config.as_ref(),
+            Some(RouteAuthConfig::Enabled(false)) => None,
+            Some(RouteAuthConfig::OpenId(config)) => Some(config.as_ref()),
+        }
+    }
+
+    // Whether the session is valid, refreshing its access token if expired.
+    async fn authenticate(
+        &self,
+        config: &OpenIdConfig,
+        sessions: &ClientSessions,
+        session_id: &str,
+    ) -> Result<bool, StatusCode> {
+        let refresh_token = {
+            let mut store = lock(&sessions.sessions);
+            let Some(session) = store.get_mut(session_id) else {
+                return Ok(false);
+            };
+            if session.expires_at.is_none_or(|t| t > Instant::now()) {
+                return Ok(true);
+            }
+            match &session.refresh_token {
+                Some(refresh_token) => refresh_token.clone(),
+                None => {
+                    store.remove(session_id);
+                    return Ok(false);
+                }
+            }
+        };
+
+        let provider = self.providers.get(config).await?;
+        let result = provider
+            .client
+            .exchange_refresh_token(&refresh_token)
+            .request_async(|request| self.providers.http.request(request))
+            .await;
+        let mut store = lock(&sessions.sessions);
+        let Some(session) = store.get_mut(session_id) else {
+            return Ok(false);
+        };
+        let refreshed = session
+            .refresh_token
+            .as_ref()
+            .is_none_or(|t| t.secret() != refresh_token.secret());
+        match result {
+            Ok(token) => {
+                let id_token = token.id_token().cloned().or(session.id_token.take());
+                let mut refreshed = Session::new(&token, id_token);
+                if refreshed.refresh_token.is_none() {
+                    refreshed.refresh_token = Some(refresh_token);
+                }
+                *session = refreshed;
+                Ok(true)
+            }
+            // Refreshed concurrently by another request with a rotated refresh token.
+            Err(_) if refreshed => Ok(true),
+            Err(RequestTokenError::ServerResponse(e)) => {
+                debug!("refresh token rejected: {e}");
+                store.remove(session_id);
+                Ok(false)
+            }
+            Err(e) => {
+                warn!("refresh token failed: {}", error_chain(&e));
+                Err(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+
+    // Redirect to the provider to sign in.
+    async fn login<B: FixedBody>(
+        &self,
+        config: &OpenIdConfig,
+        sessions: &ClientSessions,
+        target: String,
+    ) -> Result<Response<B>, StatusCode> {
+        let provider = self.providers.get(config).await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = provider.client.authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        );
+        for scope in &config.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (url, csrf_token, nonce) = request.set_pkce_challenge(pkce_challenge).url();
+        let login = PendingLogin {
+            nonce,
+            pkce_verifier,
+            target,
+        };
+        lock(&sessions.logins).insert(
+            csrf_token.secret().clone(),
+            login,
+            LOGIN_TTL,
+            config.max_sessions,
+        );
+        Ok(redirect(url.as_str()))
+    }
+
+    // Complete the sign in redirected back from the provider.
+    async fn callback<B: FixedBody>(
+        &self,
+        config: &OpenIdConfig,
+        sessions: &ClientSessions,
+        code: String,
+        state: &str,
+    ) -> Result<Response<B>, StatusCode> {
+        let Some(login) = lock(&sessions.logins).remove(state) else {
+            debug!("unknown or expired openid state");
+            return self.login(config, sessions, "/".to_string()).await;
+        };
+        let provider = self.providers.get(config).await?;
+        let token = provider
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(login.pkce_verifier)
+            .request_async(|request| self.providers.http.request(request))
+            .await
+            .map_err(|e| match e {
+                RequestTokenError::ServerResponse(e) => {
+                    debug!("authorization code rejected: {e}");
+                    StatusCode::UNAUTHORIZED
+                }
+                e => {
+                    warn!("exchange authorization code failed: {}", error_chain(&e));
+                    StatusCode::BAD_GATEWAY
+                }
+            })?;
+
+        let Some(id_token) = token.id_token() else {
+            warn!("{} did not return an ID token", config.issuer_url);
+            return Err(StatusCode::BAD_GATEWAY);
+        };
+        let verifier = provider
+            .client
+            .id_token_verifier()
+            .set_allowed_algs(provider.signing_algs.clone());
+        if let Err(e) = id_token.claims(&verifier, &login.nonce) {
+            warn!("verify ID token failed: {}", error_chain(&e));
+            // The keys may have been rotated.
+            provider.refresh_at.set(Instant::now());
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        let session_id = CsrfToken::new_random().secret().clone();
+        let mut cookie = config.session_cookie(session_id.clone());
+        cookie.set_max_age(cookie::time::Duration::seconds(
+            config.session_ttl_sec.try_into().unwrap_or(i64::MAX),
+        ));
+        let cookie = cookie.to_string();
+        lock(&sessions.sessions).insert(
+            session_id,
+            Session::new(&token, Some(id_token.clone())),
+            Duration::from_secs(config.session_ttl_sec),
+            config.max_sessions,
+        );
+        let mut response = redirect(&login.target);
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().insert(header::SET_COOKIE, cookie);
+        }
+        Ok(response)
+    }
+
+    // Drop the session, and sign out of the provider if it supports it.
+    async fn logout<B: FixedBody>(
+        &self,
+        config: &OpenIdConfig,
+        sessions: &ClientSessions,
+        session_id: Option<&str>,
+    ) -> Result<Response<B>, StatusCode> {
+        let session = session_id.and_then(|id| lock(&sessions.sessions).remove(id));
+        let target = config.post_logout_redirect_url.as_deref().unwrap_or("/");
+        let mut location = target.to_string();
+        if let Some(session) = session
+            && let Ok(provider) = self.providers.get(config).await
+            && let Some(end_session_url) = &provider.end_session_url
+        {
+            let mut request = LogoutRequest::from(end_session_url.clone())
+                .set_client_id(ClientId::new(config.client_id.clone()));
+            if let Some(id_token) = &session.id_token {
+                request = request.set_id_token_hint(id_token);
+            }
+            if let Ok(url) = Url::parse(target) {
+                request =
+                    request.set_post_logout_redirect_uri(PostLogoutRedirectUrl::from_url(url));
+            }
+            location = request.http_get_url().to_string();
+        }
+
+        let mut response = redirect(&location);
+        let mut cookie = config.session_cookie("");
+        cookie.make_removal();
+        if let Ok(cookie) = HeaderValue::from_str(&cookie.to_string()) {
+            response.headers_mut().insert(header::SET_COOKIE, cookie);
+        }
+        Ok(response)
+    }
 }
 
-// TODO: This is only a PoC, eventually need to replace this with a backend store like Redis for
-// example.
-lazy_static! {
-    static ref SESSION_STORE: Arc<RwLock<HashMap<String, SessionState>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+fn redirect<B: FixedBody>(location: &str) -> Response<B> {
+    let mut response = generate_response(StatusCode::FOUND, false);
+    if let Ok(location) = HeaderValue::from_str(location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+fn session_cookie<'a, B>(request: &'a Request<B>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Where to redirect the user once signed in: the requested path if local, or else `/`, so that
+// e.g. `//evil.com` does not redirect to another site.
+fn login_target(uri: &http::Uri) -> &str {
+    let target = uri.path_and_query().map_or("/", |p| p.as_str());
+    match target.as_bytes() {
+        [b'/', b'/' | b'\\', ..] => "/",
+        [b'/', ..] => target,
+        _ => "/",
+    }
+}
+
+// The path of an absolute URL.
+fn url_path(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let path = rest.find('/').map_or("/", |i| &rest[i..]);
+    path.split(['?', '#']).next().unwrap_or(path)
 }
 
-// impl<H> HttpHandler for OpenIdHandler<H>
 impl<H, CX, B> Service<(Request<B>, CX)> for OpenIdHandler<H>
 where
     H: HttpHandler<CX, B>,
@@ -202,201 +828,305 @@ where
     type Error = H::Error;
 
     async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
-        if self.openid_config.is_none() {
-            return self.inner.handle(request, ctx).await;
-        }
+        let route = self
+            .routes
+            .as_ref()
+            .and_then(|routes| routes.route(&request));
+        let uri = request.uri();
+        // The config of the route first, then the ones of the server and of the other routes.
+        let configs = || self.openid_config(route).into_iter().chain(&self.configs);
 
-        let headers = request.headers();
-        let mut auth_cookie: Option<String> = None;
-        if headers.contains_key(http::header::COOKIE) {
-            let cookies = Cookie::split_parse(
-                (headers.get(http::header::COOKIE).unwrap())
-                    .to_str()
-                    .unwrap(),
-            );
-            for cookie in cookies {
-                let cookie = cookie.unwrap();
-                if cookie.name() == "session-id" {
-                    let session_store = SESSION_STORE.read().unwrap();
-                    if let Some(state) = session_store.get(cookie.value())
-                        && state.access_token.is_some()
-                    {
-                        auth_cookie = Some(cookie.value().to_string());
-                    }
-                    break;
+        let response = if let Some(config) =
+            configs().find(|config| config.logout_path.as_deref() == Some(uri.path()))
+        {
+            let sessions = self.sessions.client(config);
+            let session_id = session_cookie(&request, &config.cookie.name);
+            self.logout(config, &sessions, session_id).await
+        } else if let Some(query) = uri.query()
+            && let Some(state) = query_param(query, "state")
+            && let Some(config) = {
+                // The callback is completed with the config which started the login if several
+                // share its path.
+                let mut callbacks =
+                    configs().filter(|config| url_path(&config.redirect_url) == uri.path());
+                let first = callbacks.next();
+                first
+                    .into_iter()
+                    .chain(callbacks)
+                    .find(|config| self.sessions.client(config).has_login(&state))
+                    .or(first)
+            }
+        {
+            let sessions = self.sessions.client(config);
+            match (query_param(query, "code"), query_param(query, "error")) {
+                (Some(code), _) => self.callback(config, &sessions, code, &state).await,
+                (None, error) => {
+                    debug!("sign in failed: {error:?}");
+                    Err(StatusCode::UNAUTHORIZED)
                 }
             }
-        }
-
-        let mut authed = false;
-        if let Some(auth) = auth_cookie {
-            // authorized
-            let session_store = SESSION_STORE.read().unwrap();
-            if let Some(access) = session_store.get(&auth) {
-                authed = access.access_token.is_some()
+        } else {
+            let Some(config) = self.openid_config(route) else {
+                return self.inner.handle(request, ctx).await;
+            };
+            let sessions = self.sessions.client(config);
+            let authenticated = match session_cookie(&request, &config.cookie.name) {
+                Some(id) => self.authenticate(config, &sessions, id).await,
+                None => Ok(false),
+            };
+            match authenticated {
+                Ok(true) => return self.inner.handle(request, ctx).await,
+                Ok(false) => {
+                    let target = login_target(uri);
+                    self.login(config, &sessions, target.to_string()).await
+                }
+                Err(status) => Err(status),
             }
-        }
-        if authed {
-            return self.inner.handle(request, ctx).await;
-        }
+        };
+        Ok((
+            response.unwrap_or_else(|status| generate_response(status, false)),
+            true,
+        ))
+    }
+}
 
-        let openid_config = self.openid_config.clone().unwrap();
-        let client_id = ClientId::new(openid_config.client_id);
-        let client_secret = ClientSecret::new(openid_config.client_secret);
-        let issuer_url = IssuerUrl::new(openid_config.issuer_url).expect("Invalid issuer URL");
+fn query_param(query: &str, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
 
-        let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
-            .await
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to discover OpenID Provider");
-                unreachable!();
-            });
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use monoio_http::common::body::HttpBody;
+    use openidconnect::{
+        Audience, EmptyAdditionalClaims, StandardClaims, SubjectIdentifier,
+        core::{CoreHmacKey, CoreIdTokenClaims},
+    };
 
-        // Set up the config for the OAuth2 process.
-        let client =
-            CoreClient::from_provider_metadata(provider_metadata, client_id, Some(client_secret))
-                .set_redirect_uri(
-                    RedirectUrl::new(openid_config.redirect_url).expect("Invalid redirect URL"),
-                );
-
-        // Generate the authorization URL to which we'll redirect the user.
-        let (authorize_url, csrf_state, mut nonce) = client
-            .authorize_url(
-                AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-                CsrfToken::new_random,
-                Nonce::new_random,
-            )
-            // Should add scopes to the server config as well in order to set them up here.
-            //.add_scope(Scope::new("email".to_string()))
-            //.add_scope(Scope::new("profile".to_string()))
-            .url();
+    use super::*;
+    use crate::http::stub::{self, StubResponse};
 
-        debug!("CSRF: {}", csrf_state.secret());
+    #[test]
+    fn test_expiring_map() {
+        let ttl = Duration::from_secs(60);
+        let mut map = ExpiringMap::default();
+        map.insert("a".to_string(), 1, ttl, 2);
+        map.insert("b".to_string(), 2, ttl, 2);
+        map.insert("c".to_string(), 3, ttl, 2);
+        assert!(map.get_mut("a").is_none());
+        assert_eq!(map.get_mut("b"), Some(&mut 2));
 
-        let uri = request.uri().to_string();
-        let url = Url::parse(&("http://localhost".to_string() + &uri)).unwrap();
+        // Replacing an entry makes it the newest.
+        map.insert("b".to_string(), 4, ttl, 2);
+        map.insert("d".to_string(), 5, ttl, 2);
+        assert!(map.get_mut("c").is_none());
+        assert_eq!(map.remove("b"), Some(4));
+        assert_eq!(map.get_mut("d"), Some(&mut 5));
 
-        let code_pair = url.query_pairs().find(|pair| pair.0 == "code");
-        let state_pair = url.query_pairs().find(|pair| pair.0 == "state");
+        map.insert("e".to_string(), 6, Duration::ZERO, 2);
+        assert!(map.get_mut("e").is_none());
+    }
 
-        let code;
-        let state;
-        {
-            if code_pair.is_none() || state_pair.is_none() {
-                let mut redirect_response = Response::builder()
-                    .status(StatusCode::from_u16(301).unwrap())
-                    .body(H::Body::fixed_body(None))
-                    .unwrap();
-                redirect_response
-                    .headers_mut()
-                    .insert(http::header::LOCATION, unsafe {
-                        HeaderValue::from_maybe_shared_unchecked(format!("{}", authorize_url))
-                    });
-                SESSION_STORE.write().unwrap().insert(
-                    csrf_state.secret().clone(),
-                    SessionState {
-                        nonce,
-                        access_token: None,
-                    },
-                );
-                return Ok((redirect_response, false));
-            }
-            let session_store = SESSION_STORE.read().unwrap();
-            let (_, code_val) = code_pair.clone().unwrap();
-            code = AuthorizationCode::new(code_val.into_owned());
-            let (_, state_val) = state_pair.clone().unwrap();
-            state = CsrfToken::new(state_val.clone().into_owned());
-            if !session_store.contains_key(&state_val.to_string()) {
-                let mut redirect_response: Response<H::Body> = Response::builder()
-                    .status(StatusCode::from_u16(301).unwrap())
-                    .body(H::Body::fixed_body(None))
-                    .unwrap();
-                redirect_response
-                    .headers_mut()
-                    .insert(http::header::LOCATION, unsafe {
-                        HeaderValue::from_maybe_shared_unchecked(format!("{}", authorize_url))
-                    });
-                let mut session_store = SESSION_STORE.write().unwrap();
-                session_store.insert(
-                    state_val.to_string(),
-                    SessionState {
-                        nonce,
-                        access_token: None,
-                    },
-                );
-                return Ok((redirect_response, false));
-            }
-            nonce = session_store
-                .get(&state_val.to_string())
-                .unwrap()
-                .nonce
-                .clone();
-        }
+    #[test]
+    fn test_url_path() {
+        assert_eq!(url_path("https://example.com/callback?a=1"), "/callback");
+        assert_eq!(url_path("https://example.com"), "/");
+    }
 
-        debug!(
-            "OpenID provider returned the following code:\n{}\n",
-            code.secret()
-        );
-        debug!(
-            "OpenID provider returned the following state: {}",
-            state.secret()
+    #[test]
+    fn test_login_target() {
+        let target = |uri: &'static str| login_target(&http::Uri::from_static(uri)).to_string();
+        assert_eq!(target("/app?x=1"), "/app?x=1");
+        assert_eq!(target("https://proxy/app"), "/app");
+        assert_eq!(target("https://proxy//evil.com"), "/");
+        assert_eq!(target("https://proxy/%5Cevil.com"), "/%5Cevil.com");
+        assert_eq!(
+            login_target(&"https://proxy/\\evil.com".parse().unwrap()),
+            "/"
         );
+    }
 
-        // Exchange the code with a token.
-        let token_response = client
-            .exchange_code(code)
-            .request_async(async_http_client)
-            .await
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to contact token endpoint");
-                unreachable!();
-            });
-        debug!(
-            "OpenID provider returned access token:\n{}\n",
-            token_response.access_token().secret()
-        );
-        debug!(
-            "OpenID provider returned scopes: {:?}",
-            token_response.scopes()
-        );
+    const CLIENT_SECRET: &str = "0123456789abcdef0123456789abcdef";
 
-        // Need to save this as well
-        let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-        let id_token_claims: &CoreIdTokenClaims = token_response
-            .extra_fields()
-            .id_token()
-            .expect("Server did not return an ID token")
-            .claims(&id_token_verifier, &nonce)
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to verify ID token");
-                unreachable!();
-            });
-        debug!("OpenID provider returned ID token: {:?}\n", id_token_claims);
+    fn id_token(issuer: &str, nonce: &str) -> CoreIdToken {
+        let claims = CoreIdTokenClaims::new(
+            IssuerUrl::new(issuer.to_string()).unwrap(),
+            vec![Audience::new("monolake".to_string())],
+            Utc::now() + chrono::Duration::hours(1),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new("alice".to_string())),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(Some(Nonce::new(nonce.to_string())));
+        CoreIdToken::new(
+            claims,
+            &CoreHmacKey::new(CLIENT_SECRET),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            None,
+            None,
+        )
+        .unwrap()
+    }
 
-        {
-            let mut session_store = SESSION_STORE.write().unwrap();
-            session_store.get_mut(state.secret()).unwrap().access_token =
-                Some(token_response.access_token().clone());
+    fn get(uri: &str, cookie: Option<&str>) -> Request<HttpBody> {
+        let mut request = Request::builder().uri(uri);
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
         }
+        request.body(HttpBody::fixed_body(None)).unwrap()
+    }
 
-        match self.inner.handle(request, ctx).await {
-            Ok((mut response, cont)) => {
-                let headers = response.headers_mut();
-                // Use the state number (csrf) as the session-id for future auth. Need to add
-                // more cookies like expiration time.
-                headers.insert(http::header::SET_COOKIE, unsafe {
-                    HeaderValue::from_maybe_shared_unchecked(format!(
-                        "{}={}",
-                        "session-id",
-                        state.secret()
-                    ))
+    fn location<B>(response: &Response<B>) -> &str {
+        assert_eq!(response.status(), StatusCode::FOUND);
+        response.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    #[test]
+    fn test_login_flow() {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                // The PKCE challenge and the nonce of the pending login, set by the test.
+                let pending = Rc::new(RefCell::new((String::new(), String::new())));
+                let issuer = Rc::new(RefCell::new(String::new()));
+                let (addr, _) = stub::serve({
+                    let pending = pending.clone();
+                    let issuer = issuer.clone();
+                    move |request| {
+                        let issuer = issuer.borrow();
+                        match request.path() {
+                            "/.well-known/openid-configuration" => StubResponse::json(format!(
+                                r#"{{"issuer": "{issuer}", "authorization_endpoint": "{issuer}/authorize",
+                                "token_endpoint": "{issuer}/token", "jwks_uri": "{issuer}/jwks",
+                                "end_session_endpoint": "{issuer}/logout",
+                                "response_types_supported": ["code"], "subject_types_supported": ["public"],
+                                "id_token_signing_alg_values_supported": ["HS256"]}}"#
+                            )),
+                            "/jwks" => StubResponse::json(r#"{"keys": []}"#),
+                            "/token" => {
+                                let (challenge, nonce) = &*pending.borrow();
+                                let form = url::form_urlencoded::parse(request.body.as_bytes())
+                                    .into_owned()
+                                    .collect::<HashMap<_, _>>();
+                                let verifier = PkceCodeVerifier::new(
+                                    form.get("code_verifier").cloned().unwrap_or_default(),
+                                );
+                                // The client authenticates with its secret.
+                                if request.method != "POST"
+                                    || request.header("authorization").is_none()
+                                    || form.get("code").map(String::as_str) != Some("code")
+                                    || PkceCodeChallenge::from_code_verifier_sha256(&verifier)
+                                        .as_str()
+                                        != challenge
+                                {
+                                    return StubResponse::new(400, r#"{"error": "invalid_grant"}"#)
+                                        .header("content-type", "application/json");
+                                }
+                                StubResponse::json(format!(
+                                    r#"{{"access_token": "at", "token_type": "Bearer", "expires_in": 3600, "id_token": "{}"}}"#,
+                                    id_token(&issuer, nonce).to_string()
+                                ))
+                            }
+                            _ => StubResponse::new(404, ""),
+                        }
+                    }
                 });
-                Ok((response, cont))
-            }
-            Err(_e) => Ok((
-                generate_response(StatusCode::INTERNAL_SERVER_ERROR, false),
-                false,
-            )),
-        }
+                *issuer.borrow_mut() = format!("http://{addr}");
+                let config = OpenIdConfig {
+                        client_id: "monolake".to_string(),
+                        client_secret: CLIENT_SECRET.to_string(),
+                        issuer_url: issuer.borrow().clone(),
+                        redirect_url: "http://127.0.0.1/callback".to_string(),
+                        scopes: Vec::new(),
+                        logout_path: Some("/logout".to_string()),
+                        post_logout_redirect_url: None,
+                        metadata_refresh_sec: default_metadata_refresh_sec(),
+                        session_ttl_sec: default_session_ttl_sec(),
+                        max_sessions: default_max_sessions(),
+                        cookie: SessionCookieConfig::default(),
+                };
+                let handler = OpenIdHandler {
+                    inner: stub::RecordingHandler::default(),
+                    openid_config: Some(config.clone()),
+                    routes: None,
+                    configs: vec![config],
+                    sessions: Default::default(),
+                    providers: Default::default(),
+                };
+                // Signs in, returning the session cookie and where the user is redirected to.
+                let login = |uri: &'static str| {
+                    let handler = &handler;
+                    let pending = pending.clone();
+                    async move {
+                        let (response, _) = handler.call((get(uri, None), ())).await.unwrap();
+                        let authorize = Url::parse(location(&response)).unwrap();
+                        assert_eq!(authorize.path(), "/authorize");
+                        let query: HashMap<_, _> = authorize.query_pairs().into_owned().collect();
+                        assert_eq!(query["code_challenge_method"], "S256");
+                        *pending.borrow_mut() =
+                            (query["code_challenge"].clone(), query["nonce"].clone());
+
+                        let callback = format!("/callback?code=code&state={}", query["state"]);
+                        let (response, _) =
+                            handler.call((get(&callback, None), ())).await.unwrap();
+                        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+                        let cookie = cookie.split(';').next().unwrap().to_string();
+                        (cookie, location(&response).to_string())
+                    }
+                };
+
+                let (cookie, target) = login("/app?x=1").await;
+                assert_eq!(target, "/app?x=1");
+                let (response, _) = handler
+                    .call((get("/app?x=1", Some(&cookie)), ()))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(handler.inner.requests.borrow().len(), 1);
+
+                // A replayed state signs in again.
+                let (response, _) = handler
+                    .call((get("/callback?code=code&state=unknown", None), ()))
+                    .await
+                    .unwrap();
+                assert!(location(&response).starts_with(&format!("{}/authorize", issuer.borrow())));
+
+                // A wrong PKCE verifier is rejected by the provider.
+                let (response, _) = handler.call((get("/app", None), ())).await.unwrap();
+                let authorize = Url::parse(location(&response)).unwrap();
+                let state = authorize
+                    .query_pairs()
+                    .find(|(key, _)| key == "state")
+                    .unwrap()
+                    .1
+                    .into_owned();
+                pending.borrow_mut().0 = "wrong".to_string();
+                let callback = format!("/callback?code=code&state={state}");
+                let (response, _) = handler.call((get(&callback, None), ())).await.unwrap();
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+                let (_, target) = login("https://proxy//evil.com").await;
+                assert_eq!(target, "/");
+
+                let (response, _) = handler
+                    .call((get("/logout", Some(&cookie)), ()))
+                    .await
+                    .unwrap();
+                let logout = Url::parse(location(&response)).unwrap();
+                assert_eq!(logout.path(), "/logout");
+                assert!(logout.query_pairs().any(|(key, _)| key == "id_token_hint"));
+                let removal = response.headers()[header::SET_COOKIE].to_str().unwrap();
+                assert!(removal.contains("Max-Age=0"));
+                let (response, _) = handler
+                    .call((get("/app", Some(&cookie)), ()))
+                    .await
+                    .unwrap();
+                assert!(location(&response).contains("/authorize"));
+                assert_eq!(handler.inner.requests.borrow().len(), 1);
+            });
     }
 }
@@ -0,0 +1,391 @@
+//! Retries of the requests to the upstreams of a route.
+//!
+//! This module provides the retry policy of the routes, applied by the
+//! [`RewriteAndRouteHandler`](super::RewriteAndRouteHandler) once a request is routed: when an
+//! attempt fails with one of the conditions of the policy, the request is sent again to another
+//! endpoint of the route, selected by its load balancer among the ones not tried yet.
+//!
+//! # Key Components
+//!
+//! - [`RetryConfig`]: The retry policy of a route, as configured.
+//! - [`RetryOn`]: The failures of an attempt triggering a retry.
+//! - [`PerTryTimeout`]: The timeout of an attempt, set in the extensions of the request for the
+//!   [`UpstreamHandler`](super::UpstreamHandler).
+//!
+//! # Features
+//!
+//! - Retries on connection failures, connections reset by the upstreams, and `502`, `503` or `504`
+//!   responses
+//! - Connection failures are retried whatever the method, as the request was not sent; the other
+//!   failures only for the idempotent methods
+//! - Exponential backoff with full jitter between the attempts
+//! - A retry budget per route, limiting the requests being retried to a percentage of the active
+//!   requests of the route, so that retries do not overload failing upstreams
+//!
+//! # Replaying Requests
+//!
+//! The body of a request is read in full before its first attempt, to be sent again on a retry.
+//! Requests with a body larger than `max_body_bytes`, or whose length is unknown, are sent once,
+//! except for `GET`, `HEAD`, `OPTIONS` and `TRACE` requests, whose body is buffered up to this
+//! size: these requests have no body in practice, but come without length over HTTP/2, and are
+//! answered with `413 Payload Too Large` if their body is larger.
+use std::{cell::Cell, time::Duration};
+
+use http::{Method, Request, StatusCode};
+use monolake_core::http::{HttpError, ResponseWithContinue};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::upstream::{UpstreamError, result_status};
+
+/// The retry policy of a route.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// The number of attempts of a request, the first one included.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// The failures of an attempt triggering a retry.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<RetryOn>,
+    /// The methods considered idempotent, whose requests are retried on all the conditions, the
+    /// others being only retried on connection failures.
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    /// Timeout of each attempt, in milliseconds, bounding the upstream timeouts of the route.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub per_try_timeout_ms: Option<u64>,
+    /// The backoff before the first retry, in milliseconds, doubled for each next one.
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    /// The maximum backoff before a retry, in milliseconds.
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    /// The requests being retried, as a percentage of the active requests of the route.
+    #[serde(default = "default_budget_percent")]
+    pub budget_percent: u32,
+    /// The requests which can be retried at the same time whatever the budget.
+    #[serde(default = "default_min_retries")]
+    pub min_retries: u32,
+    /// The size of the largest body buffered to replay the requests.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: default_attempts(),
+            retry_on: default_retry_on(),
+            methods: default_methods(),
+            per_try_timeout_ms: None,
+            backoff_base_ms: default_backoff_base_ms(),
+            backoff_max_ms: default_backoff_max_ms(),
+            budget_percent: default_budget_percent(),
+            min_retries: default_min_retries(),
+            max_body_bytes: default_max_body_bytes(),
+        }
+    }
+}
+
+const fn default_attempts() -> u32 {
+    2
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![RetryOn::ConnectFailure, RetryOn::Reset]
+}
+
+fn default_methods() -> Vec<String> {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"]
+        .map(String::from)
+        .to_vec()
+}
+
+const fn default_backoff_base_ms() -> u64 {
+    25
+}
+
+const fn default_backoff_max_ms() -> u64 {
+    250
+}
+
+const fn default_budget_percent() -> u32 {
+    20
+}
+
+const fn default_min_retries() -> u32 {
+    3
+}
+
+const fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+/// A failure of an attempt triggering a retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The connection to the upstream failed, including its TLS handshake.
+    ConnectFailure,
+    /// The upstream reset the connection.
+    Reset,
+    /// The response is a `502 Bad Gateway`, from the upstream or for an invalid response.
+    #[serde(rename = "502")]
+    BadGateway,
+    /// The response is a `503 Service Unavailable`, from the upstream or for a refused
+    /// connection.
+    #[serde(rename = "503")]
+    ServiceUnavailable,
+    /// The response is a `504 Gateway Timeout`, from the upstream or for a timeout.
+    #[serde(rename = "504")]
+    GatewayTimeout,
+}
+
+/// Error of an invalid retry policy of a route.
+#[derive(thiserror::Error, Debug)]
+pub enum RetryError {
+    #[error("attempts: at least one attempt is needed")]
+    Attempts,
+    #[error("methods: invalid method {0:?}")]
+    Method(String),
+    #[error("budget_percent: {0} is above 100")]
+    Budget(u32),
+}
+
+/// The timeout of an attempt of a request to an upstream, set in its extensions by the retry
+/// policy of its route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerTryTimeout(pub Duration);
+
+/// The compiled [`RetryConfig`] of a route, with its budget.
+#[derive(Debug)]
+pub(crate) struct RetryPolicy {
+    pub(crate) attempts: u32,
+    retry_on: Vec<RetryOn>,
+    methods: Vec<Method>,
+    pub(crate) per_try_timeout: Option<PerTryTimeout>,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    budget_percent: usize,
+    min_retries: usize,
+    pub(crate) max_body_bytes: usize,
+    // The active requests of the route, and the ones being retried.
+    active: Cell<usize>,
+    retrying: Cell<usize>,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(config: &RetryConfig) -> Result<Self, RetryError> {
+        if config.attempts == 0 {
+            return Err(RetryError::Attempts);
+        }
+        if config.budget_percent > 100 {
+            return Err(RetryError::Budget(config.budget_percent));
+        }
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.as_bytes())
+                    .map_err(|_| RetryError::Method(method.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            attempts: config.attempts,
+            retry_on: config.retry_on.clone(),
+            methods,
+            per_try_timeout: config
+                .per_try_timeout_ms
+                .map(|ms| PerTryTimeout(Duration::from_millis(ms))),
+            backoff_base: Duration::from_millis(config.backoff_base_ms),
+            backoff_max: Duration::from_millis(config.backoff_max_ms),
+            budget_percent: config.budget_percent as usize,
+            min_retries: config.min_retries as usize,
+            max_body_bytes: config.max_body_bytes,
+            active: Cell::new(0),
+            retrying: Cell::new(0),
+        })
+    }
+
+    /// Returns whether the request with `method` is retried after an attempt ending with
+    /// `result`.
+    pub(crate) fn should_retry<B, E: HttpError<B>>(
+        &self,
+        method: &Method,
+        result: &Result<ResponseWithContinue<B>, E>,
+    ) -> bool {
+        // The errors without response are fatal to the connection.
+        let Some((status, error)) = result_status(result) else {
+            return false;
+        };
+        // The requests refused by the circuit breaker would only add to the load.
+        if error == Some(UpstreamError::Overflow) {
+            return false;
+        }
+        // A request is not sent when the connection fails, so it can be retried whatever its
+        // method.
+        if error.is_some_and(|e| e.is_connect_failure())
+            && self.retry_on.contains(&RetryOn::ConnectFailure)
+        {
+            return true;
+        }
+        if !self.methods.contains(method) {
+            return false;
+        }
+        self.retry_on.iter().any(|on| match on {
+            RetryOn::ConnectFailure => false,
+            RetryOn::Reset => error == Some(UpstreamError::Reset),
+            RetryOn::BadGateway => status == StatusCode::BAD_GATEWAY,
+            RetryOn::ServiceUnavailable => status == StatusCode::SERVICE_UNAVAILABLE,
+            RetryOn::GatewayTimeout => status == StatusCode::GATEWAY_TIMEOUT,
+        })
+    }
+
+    /// Returns whether the body of `request` can be buffered to replay it.
+    pub(crate) fn replayable<B>(&self, request: &Request<B>) -> bool {
+        let length = request
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok()?.parse::<usize>().ok());
+        match length {
+            Some(length) => length <= self.max_body_bytes,
+            None if request
+                .headers()
+                .contains_key(http::header::TRANSFER_ENCODING) =>
+            {
+                false
+            }
+            // The requests of these methods have no body in practice, but the HTTP/2 ones come
+            // without length, so their body is buffered up to the limit.
+            None => matches!(
+                *request.method(),
+                Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+            ),
+        }
+    }
+
+    /// Counts a request as active until the returned guard is dropped.
+    pub(crate) fn activate(&self) -> Counter<'_> {
+        Counter::new(&self.active)
+    }
+
+    /// Counts a request as being retried until the returned guard is dropped, unless the budget
+    /// of the route is exhausted.
+    pub(crate) fn retry(&self) -> Option<Counter<'_>> {
+        let budget = (self.active.get() * self.budget_percent / 100).max(self.min_retries);
+        (self.retrying.get() < budget).then(|| Counter::new(&self.retrying))
+    }
+
+    /// The backoff before the retry following the attempt `attempt`, starting from 1.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let max = self
+            .backoff_base
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.backoff_max);
+        if max.is_zero() {
+            return max;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..=max)
+    }
+}
+
+/// A guard counting a request in a [`Cell`] while it lives.
+pub(crate) struct Counter<'a>(&'a Cell<usize>);
+
+impl<'a> Counter<'a> {
+    fn new(count: &'a Cell<usize>) -> Self {
+        count.set(count.get() + 1);
+        Self(count)
+    }
+}
+
+impl Drop for Counter<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Response;
+    use monoio_http::common::body::HttpBody;
+
+    use super::*;
+
+    fn result(status: StatusCode) -> Result<ResponseWithContinue<HttpBody>, UpstreamError> {
+        let resp = Response::builder()
+            .status(status)
+            .body(HttpBody::default())
+            .unwrap();
+        Ok((resp, true))
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let config = RetryConfig {
+            retry_on: vec![
+                RetryOn::ConnectFailure,
+                RetryOn::Reset,
+                RetryOn::ServiceUnavailable,
+            ],
+            ..Default::default()
+        };
+        let policy = RetryPolicy::new(&config).unwrap();
+        let refused: Result<ResponseWithContinue<HttpBody>, _> = Err(UpstreamError::ConnectRefused);
+        let reset: Result<ResponseWithContinue<HttpBody>, _> = Err(UpstreamError::Reset);
+        assert!(policy.should_retry(&Method::POST, &refused));
+        assert!(!policy.should_retry(&Method::POST, &reset));
+        assert!(policy.should_retry(&Method::GET, &reset));
+        let timeout: Result<ResponseWithContinue<HttpBody>, _> = Err(UpstreamError::ReadTimeout);
+        assert!(!policy.should_retry(&Method::GET, &timeout));
+        let overflow: Result<ResponseWithContinue<HttpBody>, _> = Err(UpstreamError::Overflow);
+        assert!(!policy.should_retry(&Method::GET, &overflow));
+        assert!(policy.should_retry(&Method::GET, &result(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(!policy.should_retry(&Method::GET, &result(StatusCode::BAD_GATEWAY)));
+        assert!(!policy.should_retry(&Method::GET, &result(StatusCode::OK)));
+    }
+
+    #[test]
+    fn test_budget() {
+        let config = RetryConfig {
+            budget_percent: 50,
+            min_retries: 1,
+            ..Default::default()
+        };
+        let policy = RetryPolicy::new(&config).unwrap();
+        let active: Vec<_> = (0..4).map(|_| policy.activate()).collect();
+        let first = policy.retry().unwrap();
+        let second = policy.retry().unwrap();
+        assert!(policy.retry().is_none());
+        drop((first, active));
+        // One retry is still allowed without active requests.
+        assert!(policy.retry().is_none());
+        drop(second);
+        assert!(policy.retry().is_some());
+    }
+
+    #[test]
+    fn test_config() {
+        let config = RetryConfig {
+            attempts: 3,
+            per_try_timeout_ms: Some(500),
+            ..Default::default()
+        };
+        let policy = RetryPolicy::new(&config).unwrap();
+        assert_eq!(
+            policy.per_try_timeout,
+            Some(PerTryTimeout(Duration::from_millis(500)))
+        );
+        assert!(policy.backoff(1) <= Duration::from_millis(25));
+        assert!(policy.backoff(10) <= Duration::from_millis(250));
+        let config = RetryConfig {
+            methods: vec!["GE T".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            RetryPolicy::new(&config),
+            Err(RetryError::Method(_))
+        ));
+    }
+}
@@ -18,10 +18,22 @@
 //! The routing system is built around the following workflow:
 //!
 //! 1. A `RewriteAndRouteHandler` is created by its factory, initialized with a set of routes.
-//! 2. Incoming requests are matched against these routes using a [`matchit::Router`].
-//! 3. When a match is found, an upstream server is selected (with support for load balancing).
-//! 4. The request is rewritten as necessary for the selected upstream.
-//! 5. The rewritten request is passed to an inner handler for further processing
+//! 2. The virtual host of incoming requests is selected by their host, and they are matched against
+//!    the routes of this host using a [`matchit::Router`], then the regexes and prefixes of its
+//!    routes.
+//! 3. When a match is found, an upstream server is selected (with support for load balancing),
+//!    skipping the upstreams found unhealthy by the [health checks](crate::common::health) of the
+//!    route, and the ones ejected by its [outlier detection](crate::common::outlier) after
+//!    consecutive failures.
+//! 4. The request is rewritten as necessary for the selected upstream, with the `rewrite` of the
+//!    route applied to its path, and the matched route is set in its extensions as a
+//!    [`MatchedRoute`] for the handlers applying per-route settings, along with the URI as received
+//!    as an [`OriginalUri`] and the selected [`Endpoint`], which the upstream handler connects to
+//!    when it is a socket address or a Unix domain socket. The [circuit
+//!    breaker](crate::common::breaker) of the route is set there too, for the upstream handler to
+//!    limit the requests to its upstreams and the connections they use.
+//! 5. The rewritten request is passed to an inner handler for further processing, and sent again to
+//!    another upstream of the route when it fails and the route has a [retry policy](super::retry).
 //!
 //! # Usage
 //!
@@ -29,7 +41,7 @@
 //!
 //! ```rust
 //! use monolake_services::{
-//!     common::ContextService,
+//!     common::{ContextService, resolver::ResolverConfig},
 //!     http::{
 //!         HttpServerTimeout,
 //!         core::HttpCoreService,
@@ -56,6 +68,11 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<ResolverConfig> for DummyConfig {
+//!     fn param(&self) -> ResolverConfig {
+//!         ResolverConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
@@ -78,14 +95,28 @@
 //! associated upstreams. These configurations can be dynamically updated by recreating
 //! the handler through its factory.
 //!
+//! Routes with `hosts` only match the requests to these hosts, taken from the URI or else from the
+//! `Host` header. A host is exact, like `api.example.com`, or a wildcard matching its subdomains,
+//! like `*.example.com`. The routes without hosts, or with the `*` host, match the requests to the
+//! other hosts. The virtual host of a request is selected first, by exact host, then by the
+//! longest matching wildcard, then the default one, and its path is only matched against the
+//! routes of this virtual host.
+//!
+//! Besides `matchit` patterns, a route can match paths by prefix or by regex with its `match`.
+//! The routes of the `matchit` pattern matching a path are tried first, then the routes of the
+//! regexes matching it in configuration order, then the routes of its prefixes, longest first.
+//!
 //! # Error Handling
 //!
-//! - Routing errors (no matching route) result in a 404 Not Found response.
+//! - Routing errors (no matching host or route) result in a 404 Not Found response.
 //! - Other errors are propagated from the inner handler.
+//! - The failures to reach an upstream are logged as warnings with the `route`, `upstream`,
+//!   `status` and `error` fields, the latter telling which step of the exchange failed.
 //!
 //! # Performance Considerations
 //!
-//! - The module uses [`matchit::Router`] for efficient path matching.
+//! - The module uses [`matchit::Router`] for efficient path matching; the regexes and prefixes are
+//!   only tried when the routes of the `matchit` patterns do not match.
 //! - Upstream selection supports weighted load balancing.
 //!
 //! # Feature Flags
@@ -94,46 +125,831 @@
 //!
 //! # Future Directions
 //!
-//! - Support for more advanced routing patterns (e.g., regex-based routing).
 //! - Enhanced metrics and logging for better observability.
 //! - Integration with service discovery systems for dynamic upstream management.
-use http::{HeaderValue, Request, Response, StatusCode, uri::Scheme};
-use monoio_http::common::body::FixedBody;
+use std::{borrow::Cow, collections::HashMap, io, net::SocketAddr, rc::Rc, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use certain_map::{Attach, Fork};
+use http::{
+    HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+    uri::{Authority, PathAndQuery, Scheme},
+};
+use monoio::net::{TcpStream, UnixStream};
+use monoio_http::common::body::{Body, FixedBody, StreamHint};
+#[cfg(feature = "tls")]
+use monoio_transports::connectors::{Connector, TcpConnector, TcpTlsAddr, TlsConnector};
 use monolake_core::{
     AnyError,
-    http::{HttpError, HttpFatalError, HttpHandler, ResponseWithContinue},
+    http::{HttpError, HttpHandler, ResponseWithContinue},
     util::uri_serde,
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use service_async::{
     AsyncMakeService, MakeService, Param, Service,
     layer::{FactoryLayer, layer_fn},
 };
 
+#[cfg(feature = "tls")]
+use super::upstream::ResolvedTlsAddr;
+use super::{
+    retry::{RetryConfig, RetryError, RetryPolicy},
+    upstream::{UpstreamError, result_status},
+};
 use crate::{
-    common::selector::{
-        IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Mapping, Select,
-        ServiceRouter,
+    common::{
+        breaker::{CircuitBreaker, CircuitBreakerConfig},
+        health::{Health, HealthCheckConfig, HealthCheckError, HealthProbe},
+        outlier::{Outcome, OutlierDetectionConfig, OutlierDetectionError, OutlierDetector},
+        resolver::{Resolver, ResolverConfig, connect_any},
+        selector::{
+            IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Mapping,
+            Select, ServiceRouter,
+        },
     },
     http::{generate_response, util::HttpErrorResponder},
 };
 
+/// The routes of a server, by virtual host.
+#[derive(Debug)]
+pub struct Router<T> {
+    routes: Vec<(Conditions, T)>,
+    // Routes by exact host.
+    hosts: HashMap<String, VirtualHost>,
+    // Routes by wildcard host suffix, like `.example.com`, longest first.
+    wildcard_hosts: Vec<(String, VirtualHost)>,
+    default_host: VirtualHost,
+}
+
+/// The routes of a virtual host, by path.
+#[derive(Debug, Default)]
+struct VirtualHost {
+    // Index in `candidates` of the routes of each path.
+    paths: matchit::Router<usize>,
+    // Indexes of the routes sharing a path, in the order they are tried.
+    candidates: Vec<Vec<usize>>,
+    by_path: HashMap<String, usize>,
+    // Routes matching the paths by regex, in configuration order.
+    regexes: Vec<(Regex, usize)>,
+    // Routes matching the paths by prefix, longest first.
+    prefixes: Vec<(String, Vec<usize>)>,
+}
+
+/// How a route matches the paths of the requests, from its `path` or its `match`.
+#[derive(Debug, Clone)]
+enum PathPattern {
+    Pattern(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    fn new(route: &RouteConfig) -> Result<Self, RouteError> {
+        match (route.path.is_empty(), &route.path_match) {
+            (false, None) => Ok(PathPattern::Pattern(route.path.clone())),
+            (true, Some(PathMatch::Prefix(prefix))) if prefix.starts_with('/') => {
+                Ok(PathPattern::Prefix(prefix.clone()))
+            }
+            (true, Some(PathMatch::Prefix(prefix))) => Err(RouteError::Prefix(prefix.clone())),
+            (true, Some(PathMatch::Regex(regex))) => Ok(PathPattern::Regex(
+                Regex::new(regex).map_err(RouteError::Regex)?,
+            )),
+            _ => Err(RouteError::PathOrMatch),
+        }
+    }
+}
+
+impl VirtualHost {
+    fn insert(&mut self, path: &PathPattern, route: usize) -> Result<(), matchit::InsertError> {
+        let path = match path {
+            PathPattern::Pattern(path) => path,
+            PathPattern::Prefix(prefix) => {
+                match self.prefixes.iter_mut().find(|(p, _)| p == prefix) {
+                    Some((_, candidates)) => push_candidate(candidates, route),
+                    None => {
+                        let i = self
+                            .prefixes
+                            .partition_point(|(p, _)| p.len() >= prefix.len());
+                        self.prefixes.insert(i, (prefix.clone(), vec![route]));
+                    }
+                }
+                return Ok(());
+            }
+            PathPattern::Regex(regex) => {
+                if self.regexes.last().map(|(_, r)| *r) != Some(route) {
+                    self.regexes.push((regex.clone(), route));
+                }
+                return Ok(());
+            }
+        };
+        if let Some(&slot) = self.by_path.get(path) {
+            push_candidate(&mut self.candidates[slot], route);
+            return Ok(());
+        }
+        self.paths.insert(path, self.candidates.len())?;
+        self.by_path.insert(path.to_string(), self.candidates.len());
+        self.candidates.push(vec![route]);
+        Ok(())
+    }
+
+    /// Returns the routes matching `path`, in the order they are tried: the routes of the
+    /// `matchit` pattern matching it, then the ones of the regexes matching it, then the ones of
+    /// the prefixes of the path, longest first.
+    fn candidates<'a>(&'a self, path: &'a str) -> impl Iterator<Item = usize> + 'a {
+        let patterns = match self.paths.at(path) {
+            Ok(matched) => self.candidates[*matched.value].as_slice(),
+            Err(_) => &[],
+        };
+        let regexes = self
+            .regexes
+            .iter()
+            .filter(move |(regex, _)| regex.is_match(path))
+            .map(|(_, route)| *route);
+        let prefixes = self
+            .prefixes
+            .iter()
+            .filter(move |(prefix, _)| path.starts_with(prefix.as_str()))
+            .flat_map(|(_, candidates)| candidates.iter().copied());
+        patterns.iter().copied().chain(regexes).chain(prefixes)
+    }
+}
+
+fn push_candidate(candidates: &mut Vec<usize>, route: usize) {
+    if candidates.last() != Some(&route) {
+        candidates.push(route);
+    }
+}
+
+/// The conditions besides the path of a route, on the method, the headers and the query.
+#[derive(Debug, Default)]
+struct Conditions {
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, ValueMatcher)>,
+    query: Vec<(String, ValueMatcher)>,
+}
+
+#[derive(Debug)]
+enum ValueMatcher {
+    Present,
+    Absent,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Conditions {
+    fn new(route: &RouteConfig) -> Result<Self, RouteError> {
+        let methods = route
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.as_bytes())
+                    .map_err(|_| RouteError::Method(method.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let headers = route
+            .headers
+            .iter()
+            .enumerate()
+            .map(|(i, header)| {
+                let name = HeaderName::try_from(header.name.as_str())
+                    .map_err(|_| RouteError::Header(i, MatchError::Name(header.name.clone())))?;
+                let matcher = ValueMatcher::new(header).map_err(|e| RouteError::Header(i, e))?;
+                Ok((name, matcher))
+            })
+            .collect::<Result<_, RouteError>>()?;
+        let query = route
+            .query
+            .iter()
+            .enumerate()
+            .map(|(i, param)| {
+                let matcher = ValueMatcher::new(param).map_err(|e| RouteError::Query(i, e))?;
+                Ok((param.name.clone(), matcher))
+            })
+            .collect::<Result<_, RouteError>>()?;
+        Ok(Self {
+            methods,
+            headers,
+            query,
+        })
+    }
+
+    fn matches<B>(&self, request: &Request<B>) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(request.method()) {
+            return false;
+        }
+        let headers = self.headers.iter().all(|(name, matcher)| {
+            matcher.matches(
+                request
+                    .headers()
+                    .get_all(name)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok()),
+            )
+        });
+        if !headers {
+            return false;
+        }
+        self.query.iter().all(|(name, matcher)| {
+            let query = request.uri().query().unwrap_or_default();
+            matcher.matches(
+                form_urlencoded::parse(query.as_bytes())
+                    .filter(|(key, _)| key == name)
+                    .map(|(_, value)| value),
+            )
+        })
+    }
+}
+
+impl ValueMatcher {
+    fn new(config: &ValueMatch) -> Result<Self, MatchError> {
+        match (&config.exact, &config.regex, config.present) {
+            (Some(_), Some(_), _) => Err(MatchError::ExactAndRegex),
+            (Some(_), _, false) | (_, Some(_), false) => Err(MatchError::ValueOfAbsent),
+            (Some(exact), None, true) => Ok(ValueMatcher::Exact(exact.clone())),
+            // The regex matches the whole value.
+            (None, Some(regex), true) => {
+                Ok(ValueMatcher::Regex(Regex::new(&format!("^(?:{regex})$"))?))
+            }
+            (None, None, true) => Ok(ValueMatcher::Present),
+            (None, None, false) => Ok(ValueMatcher::Absent),
+        }
+    }
+
+    /// Returns whether one of the values of a header or a parameter matches.
+    fn matches<S: AsRef<str>>(&self, mut values: impl Iterator<Item = S>) -> bool {
+        match self {
+            ValueMatcher::Present => values.next().is_some(),
+            ValueMatcher::Absent => values.next().is_none(),
+            ValueMatcher::Exact(exact) => values.any(|value| value.as_ref() == exact),
+            ValueMatcher::Regex(regex) => values.any(|value| regex.is_match(value.as_ref())),
+        }
+    }
+}
+
+/// The config of the route matched by a request.
+///
+/// [`RewriteAndRouteHandler`] sets it in the request extensions, so the handlers after it can
+/// apply the settings of the route.
+#[derive(Debug, Clone)]
+pub struct MatchedRoute(pub Arc<RouteConfig>);
+
+/// The URI of a request as received, before it is rewritten for the upstream.
+///
+/// [`RewriteAndRouteHandler`] sets it in the request extensions along with the [`MatchedRoute`].
+#[derive(Debug, Clone)]
+pub struct OriginalUri(pub http::Uri);
+
+/// A route of the [`Router`], with the upstreams to select from.
 #[derive(Debug)]
-pub struct Router<T>(pub matchit::Router<T>);
+pub struct Route {
+    matched: MatchedRoute,
+    upstreams: LoadBalancer<Endpoint>,
+    rewrite: Option<Rewrite>,
+    retry: Option<RetryPolicy>,
+    health: Option<Rc<Health>>,
+    outlier: Option<OutlierDetector>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl Route {
+    /// The configuration of the route.
+    pub fn matched(&self) -> &MatchedRoute {
+        &self.matched
+    }
+
+    /// Returns the path to send to the upstream instead of `path` if the route rewrites it.
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        self.rewrite.as_ref().map(|rewrite| rewrite.apply(path))
+    }
+
+    /// Selects an upstream for which `available` is true and which is not ejected, a healthy one
+    /// if the route is health checked and one is.
+    fn select_where(&self, key: &str, available: impl Fn(&Endpoint) -> bool) -> Option<&Endpoint> {
+        let available = |endpoint: &Endpoint| {
+            available(endpoint)
+                && match (&self.outlier, self.upstreams.position(endpoint)) {
+                    (Some(outlier), Some(index)) => !outlier.is_ejected(index),
+                    _ => true,
+                }
+        };
+        match &self.health {
+            Some(health) => health.select_where(&self.upstreams, key, available),
+            None => self.upstreams.select_where(key, available),
+        }
+    }
+
+    /// Records the outcome of a request sent to `endpoint`: logs the failure to reach it, telling
+    /// which step failed, and counts it for the outlier detection of the route.
+    fn record_outcome<B, E: HttpError<B>>(
+        &self,
+        endpoint: &Endpoint,
+        result: &Result<ResponseWithContinue<B>, E>,
+    ) {
+        let status = result_status(result);
+        if let Some((status, Some(error))) = status {
+            tracing::warn!(
+                route = %self.matched.0.path,
+                upstream = ?endpoint,
+                status = status.as_u16(),
+                error = ?error,
+                "upstream request failed: {error}"
+            );
+        }
+        let (Some(outlier), Some(index)) = (&self.outlier, self.upstreams.position(endpoint))
+        else {
+            return;
+        };
+        let outcome = match status {
+            // The requests refused by the circuit breaker did not reach the endpoint.
+            None | Some((_, Some(UpstreamError::Overflow))) => return,
+            Some((_, Some(error))) if error.is_connect_failure() => Outcome::ConnectFailure,
+            Some((status, _)) if status.is_server_error() => Outcome::ServerError,
+            Some(_) => Outcome::Success,
+        };
+        outlier.record(index, outcome);
+    }
+}
+
+impl<B> Select<Request<B>> for Route {
+    type Output<'a>
+        = (&'a Route, &'a Endpoint)
+    where
+        Self: 'a;
+    type Error = <LoadBalancer<Endpoint> as Select<str>>::Error;
+
+    #[inline]
+    fn select(&self, request: &Request<B>) -> Result<Self::Output<'_>, Self::Error> {
+        let path = request.uri().path();
+        match self.select_where(path, |_| true) {
+            Some(endpoint) => Ok((self, endpoint)),
+            None => Ok((self, self.upstreams.select(path)?)),
+        }
+    }
+}
+
+/// The compiled [`PathRewrite`] of a route.
+#[derive(Debug)]
+enum Rewrite {
+    StripPrefix(String),
+    ReplacePrefix(String, String),
+    Template(Captures, Vec<Segment>),
+}
+
+/// How the parameters of a rewrite template are captured from the path.
+#[derive(Debug)]
+enum Captures {
+    None,
+    // The `matchit` pattern of the route, matched again to capture its parameters.
+    Pattern(matchit::Router<()>),
+    Regex(Regex),
+}
 
-impl Router<LoadBalancer<Endpoint>> {
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+impl Rewrite {
+    fn new(rewrite: &PathRewrite, path: &PathPattern) -> Result<Self, RewriteError> {
+        match rewrite {
+            PathRewrite::StripPrefix(prefix) if prefix.starts_with('/') => {
+                Ok(Rewrite::StripPrefix(prefix.clone()))
+            }
+            PathRewrite::ReplacePrefix { prefix, with } if prefix.starts_with('/') => {
+                if !is_valid_path(with) {
+                    return Err(RewriteError::Path("with", with.clone()));
+                }
+                Ok(Rewrite::ReplacePrefix(prefix.clone(), with.clone()))
+            }
+            PathRewrite::StripPrefix(prefix) | PathRewrite::ReplacePrefix { prefix, .. } => {
+                Err(RewriteError::Prefix(prefix.clone()))
+            }
+            PathRewrite::Template(template) => {
+                let segments = parse_template(template)
+                    .ok_or_else(|| RewriteError::Template(template.clone()))?;
+                // The parameters are captured from the path, which is valid.
+                if !is_valid_path(&render(&segments, |_| None)) {
+                    return Err(RewriteError::Path("template", template.clone()));
+                }
+                let captures = match path {
+                    PathPattern::Pattern(pattern) => {
+                        let mut router = matchit::Router::new();
+                        // The pattern is validated when inserted in the virtual hosts.
+                        let _ = router.insert(pattern.as_str(), ());
+                        Captures::Pattern(router)
+                    }
+                    PathPattern::Regex(regex) => Captures::Regex(regex.clone()),
+                    PathPattern::Prefix(_) => Captures::None,
+                };
+                for segment in &segments {
+                    if let Segment::Param(name) = segment
+                        && !captures.has(path, name)
+                    {
+                        return Err(RewriteError::Param(name.clone()));
+                    }
+                }
+                Ok(Rewrite::Template(captures, segments))
+            }
+        }
+    }
+
+    fn apply(&self, path: &str) -> String {
+        let path = match self {
+            Rewrite::StripPrefix(prefix) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.to_string(),
+                None => path.to_string(),
+            },
+            Rewrite::ReplacePrefix(prefix, with) => match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => format!("{with}{rest}"),
+                None => path.to_string(),
+            },
+            Rewrite::Template(Captures::None, segments) => render(segments, |_| None),
+            Rewrite::Template(Captures::Pattern(router), segments) => match router.at(path) {
+                Ok(matched) => render(segments, |name| matched.params.get(name)),
+                Err(_) => render(segments, |_| None),
+            },
+            Rewrite::Template(Captures::Regex(regex), segments) => {
+                let captures = regex.captures(path);
+                render(segments, |name| {
+                    let captures = captures.as_ref()?;
+                    let group = match name.parse::<usize>() {
+                        Ok(index) => captures.get(index),
+                        Err(_) => captures.name(name),
+                    };
+                    group.map(|group| group.as_str())
+                })
+            }
+        };
+        match path.starts_with('/') {
+            true => path,
+            false => format!("/{path}"),
+        }
+    }
+}
+
+impl Captures {
+    /// Returns whether the parameter `name` of a template is captured from the path.
+    fn has(&self, path: &PathPattern, name: &str) -> bool {
+        match (self, path) {
+            (Captures::Pattern(_), PathPattern::Pattern(pattern)) => parse_template(pattern)
+                .unwrap_or_default()
+                .iter()
+                .any(|segment| *segment == Segment::Param(name.to_string())),
+            (Captures::Regex(regex), _) => match name.parse::<usize>() {
+                Ok(index) => index < regex.captures_len(),
+                Err(_) => regex.capture_names().any(|n| n == Some(name)),
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Returns whether `path` only has characters allowed in the path and query of a URI.
+fn is_valid_path(path: &str) -> bool {
+    path.is_empty() || path.parse::<PathAndQuery>().is_ok()
+}
+
+/// Parses a template or a `matchit` pattern into its literals and its `{param}` or
+/// `{*wildcard}` parameters, with `{{` and `}}` escaping braces.
+fn parse_template(template: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next()? {
+                        '}' => break,
+                        '{' => return None,
+                        c => name.push(c),
+                    }
+                }
+                let name = name.strip_prefix('*').unwrap_or(&name);
+                if name.is_empty() {
+                    return None;
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Param(name.to_string()));
+            }
+            '}' => return None,
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Some(segments)
+}
+
+fn render<'a>(segments: &[Segment], param: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Literal(literal) => path.push_str(literal),
+            Segment::Param(name) => path.push_str(param(name).unwrap_or_default()),
+        }
+    }
+    path
+}
+
+impl Router<Route> {
     pub fn new_from_iter<I, E>(iter: I) -> Result<Self, RoutingFactoryError<E>>
     where
         I: IntoIterator<Item = RouteConfig>,
     {
-        let mut router = matchit::Router::new();
-        for route in iter {
-            router.insert(
-                &route.path,
-                LoadBalancer::try_from_upstreams(route.load_balancer, route.upstreams).unwrap(),
-            )?;
+        Self::build(iter, |route, path| {
+            let upstreams =
+                LoadBalancer::try_from_upstreams(route.load_balancer, route.upstreams.clone())?;
+            #[cfg(feature = "openid")]
+            if let Some(crate::http::handlers::openid::RouteAuthConfig::OpenId(config)) =
+                &route.auth
+            {
+                config.validate()?;
+            }
+            let rewrite = route
+                .rewrite
+                .as_ref()
+                .map(|rewrite| Rewrite::new(rewrite, path))
+                .transpose()?;
+            let retry = route.retry.as_ref().map(RetryPolicy::new).transpose()?;
+            let health = route
+                .health_check
+                .as_ref()
+                .map(|config| {
+                    config.validate(|probe| !matches!(probe, HealthProbe::Thrift { .. }))?;
+                    Ok::<_, HealthCheckError>(Rc::new(Health::new(config, upstreams.endpoints())))
+                })
+                .transpose()?;
+            let outlier = route
+                .outlier_detection
+                .as_ref()
+                .map(|config| OutlierDetector::new(config, upstreams.endpoints()))
+                .transpose()?;
+            let breaker = route
+                .circuit_breaker
+                .map(|config| Arc::new(CircuitBreaker::new(config)));
+            Ok(Route {
+                matched: MatchedRoute(Arc::new(route)),
+                upstreams,
+                rewrite,
+                retry,
+                health,
+                outlier,
+                breaker,
+            })
+        })
+    }
+
+    /// Sets the health checks of the routes, resolving the hosts of their upstreams with a
+    /// resolver configured by `resolver`.
+    fn set_health_checks(&self, resolver: &ResolverConfig) {
+        let mut shared_resolver = None;
+        for (_, route) in &self.routes {
+            let (Some(health), Some(config)) = (&route.health, &route.matched.0.health_check)
+            else {
+                continue;
+            };
+            let resolver = shared_resolver
+                .get_or_insert_with(|| Rc::new(Resolver::new(resolver.clone())))
+                .clone();
+            let prober = Rc::new(UpstreamProber {
+                endpoints: route.upstreams.endpoints().to_vec(),
+                probe: config.probe.clone(),
+                resolver,
+                #[cfg(feature = "tls")]
+                tls_connector: TlsConnector::new_with_tls_default(
+                    TcpConnector::default(),
+                    Some(vec!["http/1.1"]),
+                ),
+            });
+            Health::set_checks(health, config, move |index| {
+                let prober = prober.clone();
+                async move { prober.probe(index).await }
+            });
         }
-        Ok(Self(router))
+    }
+}
+
+/// Probes the upstreams of a route for its health checks.
+struct UpstreamProber {
+    endpoints: Vec<Endpoint>,
+    probe: HealthProbe,
+    resolver: Rc<Resolver>,
+    #[cfg(feature = "tls")]
+    tls_connector: TlsConnector<TcpConnector>,
+}
+
+impl UpstreamProber {
+    async fn probe(&self, index: usize) -> io::Result<()> {
+        match &self.endpoints[index] {
+            Endpoint::Uri(uri) => self.probe_uri(uri).await,
+            Endpoint::Socket(addr) => {
+                let io = TcpStream::connect(addr).await?;
+                self.probe.check(io, &addr.to_string()).await
+            }
+            Endpoint::Unix(path) => {
+                let io = UnixStream::connect(path).await?;
+                self.probe.check(io, "localhost").await
+            }
+        }
+    }
+
+    async fn probe_uri(&self, uri: &http::Uri) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid uri {uri}"));
+        let (Some(host), Some(authority)) = (uri.host(), uri.authority()) else {
+            return Err(invalid());
+        };
+        let https = uri.scheme() == Some(&Scheme::HTTPS);
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let addrs: Vec<_> = self
+            .resolver
+            .lookup(host)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+        let delay = self.resolver.config().happy_eyeballs_delay();
+        // The TCP probes of the HTTPS upstreams do not perform the TLS handshake.
+        if https && self.probe != HealthProbe::Tcp {
+            #[cfg(feature = "tls")]
+            {
+                let TcpTlsAddr { sn, .. } = uri.try_into().map_err(|_| invalid())?;
+                let io = connect_any(&addrs, delay, |addr| {
+                    let key = ResolvedTlsAddr {
+                        addr,
+                        sn: sn.clone(),
+                    };
+                    self.tls_connector.connect(key)
+                })
+                .await?;
+                return self.probe.check(io, authority.as_str()).await;
+            }
+            #[cfg(not(feature = "tls"))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tls feature is not enabled",
+            ));
+        }
+        let io = connect_any(&addrs, delay, TcpStream::connect).await?;
+        self.probe.check(io, authority.as_str()).await
+    }
+}
+
+impl Router<MatchedRoute> {
+    /// Builds a router only matching the routes, without their upstreams, for the handlers
+    /// applying per-route settings before the requests are routed.
+    pub fn matcher_from_iter<I, E>(iter: I) -> Result<Self, RoutingFactoryError<E>>
+    where
+        I: IntoIterator<Item = RouteConfig>,
+    {
+        Self::build(iter, |route, _| Ok(MatchedRoute(Arc::new(route))))
+    }
+}
+
+impl<T> Router<T> {
+    /// Builds a router of the routes made by `make` from their config and their path pattern.
+    fn build<I, E>(
+        iter: I,
+        mut make: impl FnMut(RouteConfig, &PathPattern) -> Result<T, RouteError>,
+    ) -> Result<Self, RoutingFactoryError<E>>
+    where
+        I: IntoIterator<Item = RouteConfig>,
+    {
+        let mut router = Router {
+            routes: Vec::new(),
+            hosts: HashMap::new(),
+            wildcard_hosts: Vec::new(),
+            default_host: VirtualHost::default(),
+        };
+        for (index, route) in iter.into_iter().enumerate() {
+            let route_error = |source: RouteError| RoutingFactoryError::Route { index, source };
+            let hosts = route
+                .hosts
+                .iter()
+                .map(|host| HostPattern::parse(host).ok_or_else(|| host.clone()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|host| route_error(RouteError::Host(host)))?;
+            let conditions = Conditions::new(&route).map_err(route_error)?;
+            let path = PathPattern::new(&route).map_err(route_error)?;
+            let route = make(route, &path).map_err(route_error)?;
+            router.routes.push((conditions, route));
+            if hosts.is_empty() {
+                router
+                    .default_host
+                    .insert(&path, index)
+                    .map_err(|e| route_error(e.into()))?;
+            }
+            for host in hosts {
+                let table = match host {
+                    HostPattern::Exact(host) => router.hosts.entry(host).or_default(),
+                    HostPattern::Wildcard(suffix) => {
+                        match router.wildcard_hosts.iter().position(|(s, _)| *s == suffix) {
+                            Some(i) => &mut router.wildcard_hosts[i].1,
+                            None => {
+                                router.wildcard_hosts.push((suffix, VirtualHost::default()));
+                                &mut router.wildcard_hosts.last_mut().unwrap().1
+                            }
+                        }
+                    }
+                    HostPattern::Any => &mut router.default_host,
+                };
+                table
+                    .insert(&path, index)
+                    .map_err(|e| route_error(e.into()))?;
+            }
+        }
+        router
+            .wildcard_hosts
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(router)
+    }
+
+    /// Returns the route matching the host, the path and the conditions of `request`.
+    pub fn route<B>(&self, request: &Request<B>) -> Option<&T> {
+        self.virtual_host(request_host(request))
+            .candidates(request.uri().path())
+            .map(|index| &self.routes[index])
+            .find(|(conditions, _)| conditions.matches(request))
+            .map(|(_, route)| route)
+    }
+
+    /// Returns the routes of the virtual host of `host`.
+    fn virtual_host(&self, host: Option<&str>) -> &VirtualHost {
+        let Some(host) = host else {
+            return &self.default_host;
+        };
+        let host = host.strip_suffix('.').unwrap_or(host);
+        let host = match host.bytes().any(|b| b.is_ascii_uppercase()) {
+            true => Cow::Owned(host.to_ascii_lowercase()),
+            false => Cow::Borrowed(host),
+        };
+        if let Some(table) = self.hosts.get(host.as_ref()) {
+            return table;
+        }
+        self.wildcard_hosts
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .map_or(&self.default_host, |(_, table)| table)
+    }
+}
+
+enum HostPattern {
+    Exact(String),
+    // The suffix matched by the subdomains, like `.example.com` for `*.example.com`.
+    Wildcard(String),
+    Any,
+}
+
+impl HostPattern {
+    fn parse(host: &str) -> Option<Self> {
+        if host == "*" {
+            return Some(HostPattern::Any);
+        }
+        let host = host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase();
+        let (wildcard, name) = match host.strip_prefix("*.") {
+            Some(name) => (true, name),
+            None => (false, host.as_str()),
+        };
+        if name.is_empty()
+            || name.contains(['*', '/'])
+            || (name.contains(':') && !name.starts_with('['))
+            || (!name.starts_with('[') && name.split('.').any(str::is_empty))
+            || name.parse::<Authority>().is_err()
+        {
+            return None;
+        }
+        Some(match wildcard {
+            true => HostPattern::Wildcard(format!(".{name}")),
+            false => HostPattern::Exact(host),
+        })
+    }
+}
+
+/// The host a request is sent to, from its URI or else from its `Host` header, without the port.
+fn request_host<B>(request: &Request<B>) -> Option<&str> {
+    if let Some(host) = request.uri().host() {
+        return Some(host);
+    }
+    let host = request.headers().get(http::header::HOST)?.to_str().ok()?;
+    // Strip the port, but not from an IPv6 address.
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => Some(&host[..i]),
+        _ => Some(host),
     }
 }
 
@@ -151,9 +967,9 @@ impl<B: FixedBody, E> HttpError<B> for RouterError<E> {
     }
 }
 
-impl<T> Select<str> for Router<T>
+impl<T, B> Select<Request<B>> for Router<T>
 where
-    T: Select<str>,
+    T: Select<Request<B>>,
 {
     type Output<'a>
         = T::Output<'a>
@@ -163,14 +979,11 @@ where
     type Error = RouterError<T::Error>;
 
     #[inline]
-    fn select(&self, path: &str) -> Result<Self::Output<'_>, Self::Error> {
-        let Ok(r) = self.0.at(path) else {
+    fn select(&self, request: &Request<B>) -> Result<Self::Output<'_>, Self::Error> {
+        let Some(route) = self.route(request) else {
             return Err(RouterError::RouteEmpty);
         };
-        // We are going to ignore the params since it borrows path,
-        // however, return it requires the lifetime of the request,
-        // which will breaks request ownership movement.
-        r.value.select(path).map_err(RouterError::SelectError)
+        route.select(request).map_err(RouterError::SelectError)
     }
 }
 
@@ -178,49 +991,203 @@ pub struct RewriteHandler<H> {
     inner: H,
 }
 
-impl<'a, H, CX, B> Service<(Request<B>, &'a Endpoint, CX)> for RewriteHandler<H>
+impl<'a, H, CX, CXStore, CXState, B, OB, E> Service<(Request<B>, (&'a Route, &'a Endpoint), CX)>
+    for RewriteHandler<H>
 where
-    H: HttpHandler<CX, B>,
+    CX: Fork<Store = CXStore, State = CXState>,
+    for<'b> CXState: Attach<CXStore>,
+    H: HttpHandler<CX, B, Body = OB, Error = E>,
+    for<'b> H: HttpHandler<<CXState as Attach<CXStore>>::Hdr<'b>, B, Body = OB, Error = E>,
+    B: FixedBody<Data = Bytes>,
+    OB: FixedBody,
+    E: HttpError<OB>,
 {
-    type Response = ResponseWithContinue<H::Body>;
-    type Error = HttpFatalError<H::Error>;
+    type Response = ResponseWithContinue<OB>;
+    type Error = E;
 
-    #[inline]
     async fn call(
         &self,
-        (mut request, ep, cx): (Request<B>, &'a Endpoint, CX),
+        (mut request, (route, ep), cx): (Request<B>, (&'a Route, &'a Endpoint), CX),
     ) -> Result<Self::Response, Self::Error> {
-        rewrite_request(&mut request, ep);
-        return self.inner.handle(request, cx).await.map_err(HttpFatalError);
+        let uri = OriginalUri(request.uri().clone());
+        let path = route.rewrite_path(uri.0.path());
+        request.extensions_mut().insert(route.matched.clone());
+        request.extensions_mut().insert(uri);
+        if let Some(breaker) = &route.breaker {
+            request.extensions_mut().insert(breaker.clone());
+        }
+        let retry = match &route.retry {
+            Some(retry) if retry.attempts > 1 && retry.replayable(&request) => retry,
+            _ => {
+                request.extensions_mut().insert(ep.clone());
+                if let Err(e) = rewrite_request(&mut request, ep, path.as_deref()) {
+                    tracing::error!("rewrite request {} for {ep:?} failed: {e}", request.uri());
+                    return Ok((generate_response(StatusCode::BAD_GATEWAY, true), false));
+                }
+                let result = self.inner.handle(request, cx).await;
+                route.record_outcome(ep, &result);
+                return result;
+            }
+        };
+
+        // The body is buffered to be sent again on retries.
+        let (parts, body) = request.into_parts();
+        let body = match buffer_body(body, retry.max_body_bytes).await {
+            Ok(body) => body,
+            Err(status) => return Ok((generate_response(status, true), false)),
+        };
+        let _active = retry.activate();
+        let mut retrying = None;
+        let mut tried = vec![ep];
+        let mut ep = ep;
+        let mut attempt = 1;
+        loop {
+            let mut request = Request::from_parts(parts.clone(), B::fixed_body(body.clone()));
+            request.extensions_mut().insert(ep.clone());
+            if let Some(timeout) = retry.per_try_timeout {
+                request.extensions_mut().insert(timeout);
+            }
+            if let Err(e) = rewrite_request(&mut request, ep, path.as_deref()) {
+                tracing::error!("rewrite request {} for {ep:?} failed: {e}", parts.uri);
+                return Ok((generate_response(StatusCode::BAD_GATEWAY, true), false));
+            }
+            let (mut store, state) = cx.fork();
+            let forked_ctx = unsafe { state.attach(&mut store) };
+            let result = self.inner.handle(request, forked_ctx).await;
+            route.record_outcome(ep, &result);
+            if attempt == retry.attempts || !retry.should_retry(&parts.method, &result) {
+                return result;
+            }
+            match retrying.take().or_else(|| retry.retry()) {
+                Some(counter) => retrying = Some(counter),
+                None => {
+                    tracing::info!("retry budget exhausted for {}", parts.uri);
+                    return result;
+                }
+            }
+            let backoff = retry.backoff(attempt);
+            tracing::info!("retry {} after attempt {attempt} in {backoff:?}", parts.uri);
+            monoio::time::sleep(backoff).await;
+            attempt += 1;
+            // Another endpoint is tried if the route has one left.
+            let key = parts.uri.path();
+            ep = match route.select_where(key, |ep| !tried.contains(&ep)) {
+                Some(ep) => ep,
+                None => {
+                    let Ok(ep) = route.upstreams.select(key);
+                    ep
+                }
+            };
+            tried.push(ep);
+        }
     }
 }
 
-pub struct PathExtractor;
-impl<B> Mapping<Request<B>> for PathExtractor {
-    type Out = str;
+/// Reads the body of a request to replay it, failing if larger than `max` bytes.
+async fn buffer_body<B: Body<Data = Bytes>>(
+    mut body: B,
+    max: usize,
+) -> Result<Option<Bytes>, StatusCode> {
+    if matches!(body.stream_hint(), StreamHint::None) {
+        return Ok(None);
+    }
+    let mut buf = BytesMut::new();
+    while let Some(data) = body.next_data().await {
+        let data = data.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + data.len() > max {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&data);
+    }
+    Ok((!buf.is_empty()).then(|| buf.freeze()))
+}
+
+/// Maps the requests to themselves, as the [`Router`] selects their routes by host and path.
+pub struct RequestExtractor;
+impl<B> Mapping<Request<B>> for RequestExtractor {
+    type Out = Request<B>;
     #[inline]
     fn map<'a>(&self, input: &'a Request<B>) -> &'a Self::Out {
-        input.uri().path()
+        input
     }
 }
 
 pub struct RewriteAndRouteHandlerFactory<F> {
     inner: F,
     routes: Vec<RouteConfig>,
+    resolver: ResolverConfig,
 }
 
-pub type RewriteAndRouteHandler<T> = HttpErrorResponder<
-    ServiceRouter<Router<LoadBalancer<Endpoint>>, RewriteHandler<T>, PathExtractor>,
->;
+pub type RewriteAndRouteHandler<T> =
+    HttpErrorResponder<ServiceRouter<Router<Route>, RewriteHandler<T>, RequestExtractor>>;
 
 #[derive(thiserror::Error, Debug)]
 pub enum RoutingFactoryError<E> {
     #[error("inner error: {0:?}")]
     Inner(E),
-    #[error("load balance error: {0:?}")]
-    LoadBalanceError(#[from] LoadBalanceError),
-    #[error("router error: {0:?}")]
-    Router(#[from] matchit::InsertError),
+    /// The route at `index` of the configured routes is invalid.
+    #[error("routes[{index}].{source}")]
+    Route { index: usize, source: RouteError },
+}
+
+/// Error of an invalid route, by the field of [`RouteConfig`] it comes from.
+#[derive(thiserror::Error, Debug)]
+pub enum RouteError {
+    #[error("upstreams: {0}")]
+    LoadBalance(#[from] LoadBalanceError),
+    #[error("path: {0}")]
+    Path(#[from] matchit::InsertError),
+    #[error("path: either path or match must be set")]
+    PathOrMatch,
+    #[error("match.prefix: {0:?} does not start with '/'")]
+    Prefix(String),
+    #[error("match.regex: {0}")]
+    Regex(regex::Error),
+    #[error("rewrite.{0}")]
+    Rewrite(#[from] RewriteError),
+    #[error("hosts: invalid host {0:?}")]
+    Host(String),
+    #[error("methods: invalid method {0:?}")]
+    Method(String),
+    #[error("headers[{0}].{1}")]
+    Header(usize, MatchError),
+    #[error("query[{0}].{1}")]
+    Query(usize, MatchError),
+    #[error("retry.{0}")]
+    Retry(#[from] RetryError),
+    #[error("health_check.{0}")]
+    HealthCheck(#[from] HealthCheckError),
+    #[error("outlier_detection.{0}")]
+    OutlierDetection(#[from] OutlierDetectionError),
+    #[cfg(feature = "openid")]
+    #[error("auth.{0}")]
+    Auth(#[from] crate::http::handlers::openid::OpenIdConfigError),
+}
+
+/// Error of an invalid path rewrite of a route.
+#[derive(thiserror::Error, Debug)]
+pub enum RewriteError {
+    #[error("prefix: {0:?} does not start with '/'")]
+    Prefix(String),
+    #[error("template: unbalanced braces in {0:?}")]
+    Template(String),
+    #[error("template: parameter {0:?} is not captured by the path of the route")]
+    Param(String),
+    #[error("{0}: invalid path {1:?}")]
+    Path(&'static str, String),
+}
+
+/// Error of an invalid header or query parameter condition of a route.
+#[derive(thiserror::Error, Debug)]
+pub enum MatchError {
+    #[error("name: invalid name {0:?}")]
+    Name(String),
+    #[error("regex: exact and regex cannot be both set")]
+    ExactAndRegex,
+    #[error("present: a value cannot be matched when absent")]
+    ValueOfAbsent,
+    #[error("regex: {0}")]
+    Regex(#[from] regex::Error),
 }
 
 impl<F: MakeService> MakeService for RewriteAndRouteHandlerFactory<F> {
@@ -229,6 +1196,7 @@ impl<F: MakeService> MakeService for RewriteAndRouteHandlerFactory<F> {
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         let router = Router::new_from_iter(self.routes.clone())?;
+        router.set_health_checks(&self.resolver);
         Ok(HttpErrorResponder(ServiceRouter {
             svc: RewriteHandler {
                 inner: self
@@ -237,7 +1205,7 @@ impl<F: MakeService> MakeService for RewriteAndRouteHandlerFactory<F> {
                     .map_err(RoutingFactoryError::Inner)?,
             },
             selector: router,
-            selector_mapper: PathExtractor,
+            selector_mapper: RequestExtractor,
         }))
     }
 }
@@ -254,6 +1222,7 @@ where
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
         let router = Router::new_from_iter(self.routes.clone())?;
+        router.set_health_checks(&self.resolver);
         Ok(HttpErrorResponder(ServiceRouter {
             svc: RewriteHandler {
                 inner: self
@@ -263,7 +1232,7 @@ where
                     .map_err(RoutingFactoryError::Inner)?,
             },
             selector: router,
-            selector_mapper: PathExtractor,
+            selector_mapper: RequestExtractor,
         }))
     }
 }
@@ -301,12 +1270,125 @@ pub struct RouteConfig {
     /// The path pattern to match incoming requests against.
     ///
     /// This can be an exact path or a pattern supported by the routing system.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub path: String,
 
+    /// Matches the paths by prefix or by regex instead of the `path` pattern.
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub path_match: Option<PathMatch>,
+
+    /// The hosts of the requests matching this route, exact like `api.example.com` or wildcards
+    /// like `*.example.com`. The route matches the requests to the other hosts if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+
+    /// The methods of the requests matching this route, any method if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+
+    /// Conditions on the headers of the requests matching this route, which must all be met.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<ValueMatch>,
+
+    /// Conditions on the query parameters of the requests matching this route, which must all be
+    /// met.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub query: Vec<ValueMatch>,
+
+    /// The rewrite of the paths of the requests matching this route, appended to the path of the
+    /// URI of the upstream. Without it, the path is replaced by the one of the upstream URI.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<PathRewrite>,
+
     /// A list of upstream servers that can handle requests matching this route.
     ///
     /// Multiple upstreams allow for load balancing and failover configurations.
     pub upstreams: Vec<Upstream>,
+
+    /// The upstream timeouts of this route, overriding the ones of the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<super::upstream::RouteTimeoutConfig>,
+
+    /// The retry policy of the requests matching this route, which are sent once without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+
+    /// The active health checks of the upstreams of this route, which skips the unhealthy ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// The outlier detection of the upstreams of this route, which ejects the ones failing
+    /// consecutive requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+
+    /// The limits of the requests to the upstreams of this route, over which they are answered
+    /// with 503 Service Unavailable at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Authentication of the requests matching this route, overriding the one of the server.
+    #[cfg(feature = "openid")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<crate::http::handlers::openid::RouteAuthConfig>,
+
+    /// Validation of the JWT bearer tokens of the requests matching this route, overriding the
+    /// one of the server.
+    #[cfg(feature = "jwt")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<crate::http::handlers::jwt::RouteJwtConfig>,
+}
+
+/// Matching of the paths of the requests by prefix or by regex.
+///
+/// The routes are tried in order of precedence: the `path` patterns first, then the regexes in
+/// configuration order, then the prefixes, longest first.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PathMatch {
+    /// Matches the paths starting with this prefix, like `/api`, which also matches `/apis`.
+    Prefix(String),
+    /// Matches the paths in which this regex finds a match, unless it is anchored like
+    /// `^/v[0-9]+/`.
+    Regex(String),
+}
+
+/// The rewrite of the paths of the requests matching a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PathRewrite {
+    /// Removes this prefix from the paths starting with it.
+    StripPrefix(String),
+    /// Replaces the `prefix` of the paths starting with it by `with`.
+    ReplacePrefix { prefix: String, with: String },
+    /// Builds the path from a template with the `{param}` and `{*wildcard}` parameters captured
+    /// by the `path` pattern of the route, or the named or numbered groups of its regex, like
+    /// `/internal/{rest}` for the pattern `/api/{*rest}`.
+    Template(String),
+}
+
+/// A condition on a header or a query parameter of the requests matching a route.
+///
+/// The header or parameter must be present, with a value equal to `exact` or wholly matching
+/// `regex` if set, or absent if `present` is false.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ValueMatch {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exact: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    #[serde(default = "default_present", skip_serializing_if = "is_present")]
+    pub present: bool,
+}
+
+const fn default_present() -> bool {
+    true
+}
+
+fn is_present(present: &bool) -> bool {
+    *present
 }
 
 const fn default_weight() -> u16 {
@@ -366,19 +1448,50 @@ pub enum Endpoint {
 impl<F> RewriteAndRouteHandler<F> {
     pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = RewriteAndRouteHandlerFactory<F>>
     where
-        C: Param<Vec<RouteConfig>>,
+        C: Param<Vec<RouteConfig>> + Param<ResolverConfig>,
     {
         layer_fn(|c: &C, inner| {
-            let routes = c.param();
-            RewriteAndRouteHandlerFactory { inner, routes }
+            let routes = Param::<Vec<RouteConfig>>::param(c);
+            let resolver = Param::<ResolverConfig>::param(c);
+            RewriteAndRouteHandlerFactory {
+                inner,
+                routes,
+                resolver,
+            }
         })
     }
 }
 
-fn rewrite_request<B>(request: &mut Request<B>, endpoint: &Endpoint) {
+/// Rewrites the request for the upstream `endpoint`, with the path of its URI, or the `path`
+/// rewritten by the route appended to the path of its URI.
+///
+/// The requests to a socket or a Unix endpoint keep their path unless rewritten, and their `Host`
+/// header. It fails if the rewritten URI is invalid.
+fn rewrite_request<B>(
+    request: &mut Request<B>,
+    endpoint: &Endpoint,
+    path: Option<&str>,
+) -> Result<(), http::Error> {
     let remote = match endpoint {
         Endpoint::Uri(uri) => uri,
-        _ => unimplemented!("not implement"),
+        Endpoint::Socket(addr) => {
+            keep_host(request, || addr.to_string());
+            let path_and_query = path_and_query(request.uri(), path);
+            *request.uri_mut() = http::Uri::builder()
+                .scheme(Scheme::HTTP)
+                .authority(addr.to_string())
+                .path_and_query(path_and_query)
+                .build()?;
+            return Ok(());
+        }
+        Endpoint::Unix(_) => {
+            keep_host(request, || "localhost".to_string());
+            let path_and_query = path_and_query(request.uri(), path);
+            *request.uri_mut() = http::Uri::builder()
+                .path_and_query(path_and_query)
+                .build()?;
+            return Ok(());
+        }
     };
 
     if let Some(authority) = remote.authority() {
@@ -401,11 +1514,15 @@ fn rewrite_request<B>(request: &mut Request<B>, endpoint: &Endpoint) {
             None => Scheme::HTTP,
         };
 
+        let remote_path = match path {
+            Some(path) => Cow::Owned(format!("{}{path}", remote.path().trim_end_matches('/'))),
+            None => Cow::Borrowed(remote.path()),
+        };
         let uri = request.uri_mut();
         let path_and_query = match uri.path_and_query() {
             Some(path_and_query) => match path_and_query.query() {
-                Some(query) => format!("{}?{}", remote.path(), query),
-                None => String::from(remote.path()),
+                Some(query) => format!("{}?{}", remote_path, query),
+                None => remote_path.into_owned(),
             },
             None => "/".to_string(),
         };
@@ -413,16 +1530,48 @@ fn rewrite_request<B>(request: &mut Request<B>, endpoint: &Endpoint) {
             .authority(authority.to_owned())
             .scheme(scheme)
             .path_and_query(path_and_query)
-            .build()
-            .unwrap();
+            .build()?;
+    }
+    Ok(())
+}
+
+/// Sets the `Host` header of a request if it has none, from the authority of its URI as it is
+/// replaced by the one of the upstream, or else to `default`.
+fn keep_host<B>(request: &mut Request<B>, default: impl FnOnce() -> String) {
+    if request.headers().contains_key(http::header::HOST) {
+        return;
+    }
+    let host = match request.uri().authority() {
+        Some(authority) => HeaderValue::from_str(authority.as_str()).ok(),
+        None => HeaderValue::from_str(&default()).ok(),
+    };
+    if let Some(host) = host {
+        request.headers_mut().insert(http::header::HOST, host);
+    }
+}
+
+/// The path and query of `uri`, with its path replaced by `path` if set.
+fn path_and_query(uri: &http::Uri, path: Option<&str>) -> String {
+    let path = path.unwrap_or(uri.path());
+    let path = if path.is_empty() { "/" } else { path };
+    match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
+
+    use monoio_http::common::body::{BodyExt, HttpBody};
 
     use super::*;
+    use crate::http::{
+        HttpVersion,
+        handlers::{UpstreamHandler, retry::RetryOn},
+        stub::{self, Peer, StubRequest, StubResponse},
+    };
 
     fn iterate_match<'a>(req_path: &str, routes: &'a [RouteConfig]) -> Option<&'a RouteConfig> {
         let mut target_route = None;
@@ -444,13 +1593,610 @@ mod tests {
             id: "testroute".to_string(),
             load_balancer: Default::default(),
             path: format!("/{n}"),
+            path_match: None,
+            rewrite: None,
+            timeout: None,
+            retry: None,
+            health_check: None,
+            outlier_detection: None,
+            circuit_breaker: None,
+            hosts: Vec::new(),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            query: Vec::new(),
             upstreams: Vec::from([Upstream {
                 endpoint: Endpoint::Uri(format!("http://test{n}.endpoint").parse().unwrap()),
                 weight: Default::default(),
             }]),
+            #[cfg(feature = "openid")]
+            auth: None,
+            #[cfg(feature = "jwt")]
+            jwt: None,
         })
     }
 
+    #[test]
+    fn test_route_error() {
+        let mut routes: Vec<RouteConfig> = create_routes().take(2).collect();
+        routes[0].path = "/{a}".to_string();
+        routes[1].path = "/{b}".to_string();
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert!(matches!(
+            err,
+            RoutingFactoryError::Route {
+                index: 1,
+                source: RouteError::Path(_)
+            }
+        ));
+
+        routes[1].path = "/other".to_string();
+        routes[0].health_check = Some(HealthCheckConfig {
+            probe: HealthProbe::Thrift {
+                method: "ping".to_string(),
+            },
+            interval_ms: 1000,
+            timeout_ms: 100,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        });
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "routes[0].health_check.type: thrift probes cannot check these upstreams"
+        );
+
+        routes[0].health_check = None;
+        routes[0].outlier_detection = Some(OutlierDetectionConfig {
+            base_ejection_ms: 0,
+            ..Default::default()
+        });
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "routes[0].outlier_detection.base_ejection_ms: must be positive"
+        );
+
+        routes[0].outlier_detection = None;
+        routes[1].upstreams.clear();
+        let err = Router::new_from_iter::<_, ()>(routes).unwrap_err();
+        assert_eq!(err.to_string(), "routes[1].upstreams: empty upstream");
+    }
+
+    #[test]
+    fn test_virtual_hosts() {
+        let mut routes: Vec<RouteConfig> = create_routes().take(5).collect();
+        for (route, hosts) in routes.iter_mut().zip([
+            &["api.example.com"][..],
+            &["*.example.com"],
+            &["*.eu.example.com", "EU.test."],
+            &[],
+            &["*"],
+        ]) {
+            route.path = "/{*p}".to_string();
+            route.hosts = hosts.iter().map(|h| h.to_string()).collect();
+        }
+        routes[4].path = "/".to_string();
+        let router = Router::new_from_iter::<_, ()>(routes.clone()).unwrap();
+
+        let select = |uri: &str, host: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(host) = host {
+                request = request.header(http::header::HOST, host);
+            }
+            let request = request.body(()).unwrap();
+            router
+                .select(&request)
+                .ok()
+                .map(|(route, _)| route.matched.0.hosts.clone())
+        };
+        let hosts = |index: usize| Some(routes[index].hosts.clone());
+        assert_eq!(select("/a", Some("api.example.com")), hosts(0));
+        assert_eq!(select("/a", Some("API.example.com:8443")), hosts(0));
+        assert_eq!(select("https://api.example.com/a", None), hosts(0));
+        assert_eq!(select("/a", Some("www.example.com")), hosts(1));
+        assert_eq!(select("/a", Some("a.b.example.com")), hosts(1));
+        assert_eq!(select("/a", Some("x.eu.example.com")), hosts(2));
+        assert_eq!(select("/a", Some("eu.test")), hosts(2));
+        assert_eq!(select("/a", Some("example.com")), hosts(3));
+        assert_eq!(select("/a", Some("[::1]:80")), hosts(3));
+        assert_eq!(select("/a", None), hosts(3));
+        assert_eq!(select("/", Some("other.com")), hosts(4));
+        // The routes of the default host are not matched by the requests to other hosts.
+        assert_eq!(select("/", Some("api.example.com")), None);
+
+        routes[0].hosts = vec!["api.*.com".to_string()];
+        let err = Router::new_from_iter::<_, ()>(routes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"routes[0].hosts: invalid host "api.*.com""#
+        );
+    }
+
+    #[test]
+    fn test_matchers() {
+        let mut routes: Vec<RouteConfig> = create_routes().take(5).collect();
+        for (index, route) in routes.iter_mut().enumerate() {
+            route.id = index.to_string();
+            route.path = "/v1/items".to_string();
+        }
+        routes[0].headers = vec![ValueMatch {
+            name: "X-Canary".to_string(),
+            exact: Some("1".to_string()),
+            regex: None,
+            present: true,
+        }];
+        routes[1].query = vec![ValueMatch {
+            name: "version".to_string(),
+            exact: None,
+            regex: Some("v[0-9]+".to_string()),
+            present: true,
+        }];
+        routes[2].methods = vec!["GET".to_string(), "HEAD".to_string()];
+        routes[3].methods = vec!["POST".to_string()];
+        routes[3].headers = vec![ValueMatch {
+            name: "authorization".to_string(),
+            exact: None,
+            regex: None,
+            present: false,
+        }];
+        routes[4].path = "/v1/other".to_string();
+        let router = Router::new_from_iter::<_, ()>(routes.clone()).unwrap();
+
+        let select = |method: &str, uri: &str, header: Option<(&str, &str)>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            let request = request.body(()).unwrap();
+            router
+                .select(&request)
+                .ok()
+                .map(|(route, _)| route.matched.0.id.parse::<usize>().unwrap())
+        };
+        assert_eq!(
+            select("POST", "/v1/items", Some(("x-canary", "1"))),
+            Some(0)
+        );
+        assert_eq!(select("GET", "/v1/items?a=b&version=v2", None), Some(1));
+        assert_eq!(select("GET", "/v1/items?version=v2x", None), Some(2));
+        assert_eq!(
+            select("HEAD", "/v1/items", Some(("x-canary", "2"))),
+            Some(2)
+        );
+        assert_eq!(select("POST", "/v1/items", None), Some(3));
+        assert_eq!(
+            select("POST", "/v1/items", Some(("authorization", "x"))),
+            None
+        );
+        assert_eq!(select("PUT", "/v1/items", None), None);
+        assert_eq!(select("PUT", "/v1/other", None), Some(4));
+
+        routes[1].query[0].regex = Some("(".to_string());
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert!(err.to_string().starts_with("routes[1].query[0].regex: "));
+        routes[1].query[0].exact = Some("v1".to_string());
+        let err = Router::new_from_iter::<_, ()>(routes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "routes[1].query[0].regex: exact and regex cannot be both set"
+        );
+    }
+
+    #[test]
+    fn test_path_match() {
+        let mut routes: Vec<RouteConfig> = create_routes().take(6).collect();
+        for (index, (route, path_match)) in routes
+            .iter_mut()
+            .zip([
+                None,
+                Some(PathMatch::Prefix("/api".to_string())),
+                Some(PathMatch::Prefix("/api/v1/".to_string())),
+                Some(PathMatch::Regex(r"^/api/v[0-9]+/items".to_string())),
+                Some(PathMatch::Regex(r"\.(png|jpg)$".to_string())),
+                Some(PathMatch::Prefix("/".to_string())),
+            ])
+            .enumerate()
+        {
+            route.id = index.to_string();
+            route.path = match path_match {
+                Some(_) => String::new(),
+                None => "/api/v1/items/{id}".to_string(),
+            };
+            route.path_match = path_match;
+        }
+        let router = Router::new_from_iter::<_, ()>(routes.clone()).unwrap();
+
+        let select = |uri: &str| {
+            let request = Request::builder().uri(uri).body(()).unwrap();
+            router
+                .select(&request)
+                .ok()
+                .map(|(route, _)| route.matched.0.id.parse::<usize>().unwrap())
+        };
+        assert_eq!(select("/api/v1/items/1"), Some(0));
+        assert_eq!(select("/api/v1/items/1/x.png"), Some(3));
+        assert_eq!(select("/api/v2/items"), Some(3));
+        assert_eq!(select("/api/v1/other.jpg"), Some(4));
+        assert_eq!(select("/api/v1/other"), Some(2));
+        assert_eq!(select("/apis"), Some(1));
+        assert_eq!(select("/"), Some(5));
+
+        routes[1].path = "/api".to_string();
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "routes[1].path: either path or match must be set"
+        );
+        routes[1].path = String::new();
+        routes[4].path_match = Some(PathMatch::Regex("(".to_string()));
+        let err = Router::new_from_iter::<_, ()>(routes).unwrap_err();
+        assert!(err.to_string().starts_with("routes[4].match.regex: "));
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut routes: Vec<RouteConfig> = create_routes().take(5).collect();
+        routes[0].path = "/api/{version}/{*rest}".to_string();
+        routes[0].rewrite = Some(PathRewrite::Template(
+            "/internal/{rest}?{{{version}}}".to_string(),
+        ));
+        routes[1].path = String::new();
+        routes[1].path_match = Some(PathMatch::Regex(r"^/v(?P<v>[0-9]+)/(.*)$".to_string()));
+        routes[1].rewrite = Some(PathRewrite::Template("/{2}/{v}".to_string()));
+        routes[2].path = String::new();
+        routes[2].path_match = Some(PathMatch::Prefix("/old".to_string()));
+        routes[2].rewrite = Some(PathRewrite::ReplacePrefix {
+            prefix: "/old".to_string(),
+            with: "/new".to_string(),
+        });
+        routes[3].path = "/strip/{*p}".to_string();
+        routes[3].rewrite = Some(PathRewrite::StripPrefix("/strip".to_string()));
+        routes[4].path = "/other".to_string();
+        let router = Router::new_from_iter::<_, ()>(routes.clone()).unwrap();
+
+        let rewrite = |uri: &str| {
+            let request = Request::builder().uri(uri).body(()).unwrap();
+            let (route, _) = router.select(&request).unwrap();
+            route.rewrite_path(request.uri().path())
+        };
+        assert_eq!(
+            rewrite("/api/v1/a/b").as_deref(),
+            Some("/internal/a/b?{v1}")
+        );
+        assert_eq!(rewrite("/v2/items/1").as_deref(), Some("/items/1/2"));
+        assert_eq!(rewrite("/older/x").as_deref(), Some("/newer/x"));
+        assert_eq!(rewrite("/strip/x/y").as_deref(), Some("/x/y"));
+        assert_eq!(rewrite("/other"), None);
+
+        let mut request = Request::builder().uri("/api/v1/a?q=1").body(()).unwrap();
+        let upstream = Endpoint::Uri("http://upstream/base/".parse().unwrap());
+        rewrite_request(&mut request, &upstream, Some("/a")).unwrap();
+        assert_eq!(request.uri(), "http://upstream/base/a?q=1");
+
+        routes[0].rewrite = Some(PathRewrite::Template("/{rest}/{other}".to_string()));
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"routes[0].rewrite.template: parameter "other" is not captured by the path of the route"#
+        );
+        routes[0].rewrite = Some(PathRewrite::Template("/{rest".to_string()));
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"routes[0].rewrite.template: unbalanced braces in "/{rest""#
+        );
+        routes[0].rewrite = Some(PathRewrite::Template("/a b/{rest}".to_string()));
+        let err = Router::new_from_iter::<_, ()>(routes.clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"routes[0].rewrite.template: invalid path "/a b/{rest}""#
+        );
+        routes[0].rewrite = None;
+        routes[2].rewrite = Some(PathRewrite::ReplacePrefix {
+            prefix: "/old".to_string(),
+            with: "/new path".to_string(),
+        });
+        let err = Router::new_from_iter::<_, ()>(routes).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"routes[2].rewrite.with: invalid path "/new path""#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_endpoints() {
+        let socket = Endpoint::Socket("127.0.0.1:8080".parse().unwrap());
+        let mut request = Request::builder()
+            .uri("/a?q=1")
+            .header(http::header::HOST, "example.com")
+            .body(())
+            .unwrap();
+        rewrite_request(&mut request, &socket, None).unwrap();
+        assert_eq!(request.uri(), "http://127.0.0.1:8080/a?q=1");
+        assert_eq!(request.headers()[http::header::HOST], "example.com");
+
+        let mut request = Request::builder().uri("/a").body(()).unwrap();
+        rewrite_request(&mut request, &socket, None).unwrap();
+        assert_eq!(request.headers()[http::header::HOST], "127.0.0.1:8080");
+
+        let unix = Endpoint::Unix("/tmp/app.sock".into());
+        let mut request = Request::builder()
+            .uri("https://example.com/a/b?q=1")
+            .body(())
+            .unwrap();
+        rewrite_request(&mut request, &unix, Some("/b")).unwrap();
+        assert_eq!(request.uri(), "/b?q=1");
+        assert_eq!(request.headers()[http::header::HOST], "example.com");
+    }
+
+    #[test]
+    fn test_proxy_endpoints() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (path, unix_requests) = stub::serve_unix(|_| StubResponse::new(200, "unix"));
+            let (addr, socket_requests) = stub::serve(|_| StubResponse::new(200, "socket"));
+            let mut routes: Vec<RouteConfig> = create_routes().take(2).collect();
+            routes[0].path = "/unix/{*p}".to_string();
+            routes[0].rewrite = Some(PathRewrite::StripPrefix("/unix".to_string()));
+            routes[0].upstreams[0].endpoint = Endpoint::Unix(path.clone());
+            routes[1].path = "/socket".to_string();
+            routes[1].upstreams[0].endpoint = Endpoint::Socket(addr);
+            let factory = RewriteAndRouteHandlerFactory {
+                inner: UpstreamHandler::factory(Default::default(), HttpVersion::Http11),
+                routes,
+                resolver: Default::default(),
+            };
+            let handler = MakeService::make(&factory).unwrap();
+
+            // The requests to a Unix domain socket keep their host.
+            let request = Request::get("/unix/a?q=1")
+                .header(http::header::HOST, "example.com")
+                .body(HttpBody::default())
+                .unwrap();
+            let (resp, _) = handler.handle(request, Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_body().bytes().await.unwrap(), "unix");
+            {
+                let requests = unix_requests.borrow();
+                assert_eq!(requests.len(), 1);
+                assert_eq!(requests[0].target, "/a?q=1");
+                assert_eq!(requests[0].header("host"), Some("example.com"));
+            }
+
+            // The requests to a socket address without host are sent with the address as host.
+            let request = Request::get("/socket").body(HttpBody::default()).unwrap();
+            let (resp, _) = handler.handle(request, Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_body().bytes().await.unwrap(), "socket");
+            let requests = socket_requests.borrow();
+            assert_eq!(requests.len(), 1);
+            assert_eq!(requests[0].target, "/socket");
+            assert_eq!(requests[0].header("host"), Some(addr.to_string().as_str()));
+            let _ = std::fs::remove_file(path);
+        });
+    }
+
+    #[test]
+    fn test_log_upstream_error() {
+        #[derive(Clone, Default)]
+        struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut routes: Vec<RouteConfig> = create_routes().take(1).collect();
+            routes[0].path = "/refused".to_string();
+            routes[0].upstreams[0].endpoint = Endpoint::Socket(refused);
+            let factory = RewriteAndRouteHandlerFactory {
+                inner: UpstreamHandler::factory(Default::default(), HttpVersion::Http11),
+                routes,
+                resolver: Default::default(),
+            };
+            let handler = MakeService::make(&factory).unwrap();
+
+            let request = Request::get("/refused").body(HttpBody::default()).unwrap();
+            let (resp, _) = handler.handle(request, Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        });
+
+        // The failure is logged with the step that failed, the route and the upstream.
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("upstream request failed"))
+            .unwrap();
+        assert!(line.contains("route=/refused"), "{line}");
+        assert!(
+            line.contains(&format!("upstream=Socket({refused})")),
+            "{line}"
+        );
+        assert!(line.contains("status=503"), "{line}");
+        assert!(line.contains("error=ConnectRefused"), "{line}");
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let refused = monoio::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            // Accepts the connections without ever answering.
+            let blackhole = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let silent = blackhole.local_addr().unwrap();
+            monoio::spawn(async move {
+                let mut streams = Vec::new();
+                while let Ok((stream, _)) = blackhole.accept().await {
+                    streams.push(stream);
+                }
+            });
+            let (unavailable, unavailable_requests) =
+                stub::serve(|_| StubResponse::new(503, "unavailable"));
+            let (overloaded, overloaded_requests) =
+                stub::serve(|_| StubResponse::new(503, "overloaded"));
+            let (available, requests) = stub::serve(|_| StubResponse::new(200, "available"));
+
+            let mut routes: Vec<RouteConfig> = create_routes().take(3).collect();
+            let upstreams = [
+                &[refused, available][..],
+                &[unavailable, overloaded, available],
+                &[silent, available],
+            ];
+            for ((route, path), upstreams) in routes
+                .iter_mut()
+                .zip(["/refused", "/unavailable", "/silent"])
+                .zip(upstreams)
+            {
+                route.path = path.to_string();
+                // The upstreams are tried in order.
+                route.load_balancer = LoadBalanceStrategy::RoundRobin;
+                route.upstreams = upstreams
+                    .iter()
+                    .map(|addr| Upstream {
+                        endpoint: Endpoint::Socket(*addr),
+                        weight: Default::default(),
+                    })
+                    .collect();
+            }
+            routes[0].retry = Some(RetryConfig::default());
+            // The budget allows one retry at a time, which holds across the attempts.
+            routes[1].retry = Some(RetryConfig {
+                attempts: 3,
+                retry_on: vec![RetryOn::ServiceUnavailable],
+                budget_percent: 0,
+                min_retries: 1,
+                ..Default::default()
+            });
+            routes[2].retry = Some(RetryConfig {
+                retry_on: vec![RetryOn::GatewayTimeout],
+                per_try_timeout_ms: Some(100),
+                ..Default::default()
+            });
+            let factory = RewriteAndRouteHandlerFactory {
+                inner: UpstreamHandler::factory(Default::default(), HttpVersion::Http11),
+                routes,
+                resolver: Default::default(),
+            };
+            let handler = MakeService::make(&factory).unwrap();
+            let request = |method: &str, path: &str| {
+                Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .header(http::header::HOST, "example.com")
+                    .header(http::header::CONTENT_LENGTH, "7")
+                    .body(HttpBody::fixed_body(Some(Bytes::from_static(b"payload"))))
+                    .unwrap()
+            };
+            let sent = |request: &StubRequest| {
+                (
+                    request.method.clone(),
+                    request.target.clone(),
+                    request.body.clone(),
+                )
+            };
+
+            // A refused connection is retried whatever the method, the request not being sent.
+            let (resp, _) = handler
+                .handle(request("POST", "/refused"), Peer::local())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_body().bytes().await.unwrap(), "available");
+            assert_eq!(
+                requests.borrow().iter().map(sent).collect::<Vec<_>>(),
+                [(
+                    "POST".to_string(),
+                    "/refused".to_string(),
+                    "payload".to_string()
+                )]
+            );
+
+            // The request is replayed to each endpoint not tried yet.
+            let (resp, _) = handler
+                .handle(request("PUT", "/unavailable"), Peer::local())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            for log in [&unavailable_requests, &overloaded_requests, &requests] {
+                let log = log.borrow();
+                let last = log.last().unwrap();
+                assert_eq!(
+                    sent(last),
+                    (
+                        "PUT".to_string(),
+                        "/unavailable".to_string(),
+                        "payload".to_string()
+                    )
+                );
+            }
+            assert_eq!(requests.borrow().len(), 2);
+
+            // A request failing on all the attempts gets the response of the last one.
+            let (resp, _) = handler
+                .handle(request("POST", "/unavailable"), Peer::local())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(unavailable_requests.borrow().len(), 2);
+            assert_eq!(overloaded_requests.borrow().len(), 1);
+
+            // An attempt is bounded by the timeout of each try.
+            let start = std::time::Instant::now();
+            let (resp, _) = handler
+                .handle(request("GET", "/silent"), Peer::local())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert_eq!(
+                sent(requests.borrow().last().unwrap()),
+                (
+                    "GET".to_string(),
+                    "/silent".to_string(),
+                    "payload".to_string()
+                )
+            );
+
+            // The body of a request without length is buffered up to the limit.
+            let mut request = request("GET", "/refused");
+            request.headers_mut().remove(http::header::CONTENT_LENGTH);
+            *request.body_mut() =
+                HttpBody::fixed_body(Some(Bytes::from(vec![b'a'; 64 * 1024 + 1])));
+            let (resp, _) = handler.handle(request, Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+
     #[test]
     fn test_iterate_match() {
         let mut router: matchit::Router<RouteConfig> = matchit::Router::new();
@@ -10,13 +10,22 @@
 //!   the `HttpConnector` for efficient connection management and request handling.
 //! - [`UpstreamHandlerFactory`]: A factory for creating and updating `UpstreamHandler` instances.
 //! - [`HttpUpstreamTimeout`]: Configuration for various timeout settings in upstream communication.
+//! - [`Resolver`]: Resolves the hosts of the upstreams without blocking, caching their addresses.
 //!
 //! # Features
 //!
 //! - HTTP and HTTPS request proxying using optimized connectors
+//! - Proxying to socket addresses and Unix domain sockets, for the requests routed to an
+//!   [`Endpoint::Socket`] or an [`Endpoint::Unix`]
 //! - Connection pooling for efficient resource usage, provided by `HttpConnector`
+//! - Asynchronous resolution of the upstream hosts, cached per worker for the TTL of their records,
+//!   with the connection falling back to the next address of a host when one fails, or racing them
+//!   with Happy Eyeballs
 //! - Support for both HTTP/1.1 and HTTP/2 protocols
 //! - Configurable timeout settings
+//! - Per-route limits of the requests to the upstreams, of the ones waiting for a connection and of
+//!   the connections open to the upstreams, with the [`CircuitBreaker`] set in the request
+//!   extensions by the routing handler
 //! - TLS support (enabled with the `tls` feature flag)
 //! - X-Forwarded-For header management
 //! - Leverages monoio's native IO traits built on top of io_uring for high performance
@@ -32,9 +41,20 @@
 //!
 //! # Error Handling
 //!
-//! - Connection errors result in 502 Bad Gateway responses
-//! - Invalid URIs or unresolvable hosts result in 400 Bad Request responses
-//! - Timeouts are handled gracefully, returning appropriate error responses
+//! Failures to reach the upstreams are returned as an [`UpstreamError`], telling which step
+//! failed, and turned into a response by its [`HttpError`](monolake_core::http::HttpError)
+//! implementation:
+//!
+//! - Refused or failed connections result in 503 Service Unavailable responses
+//! - Timeouts result in 504 Gateway Timeout responses
+//! - TLS handshake failures, connections reset by the upstreams, invalid responses, unresolvable
+//!   hosts and unanswered resolutions result in 502 Bad Gateway responses
+//! - Requests exceeding a limit of the [circuit breaker](crate::common::breaker) of their route
+//!   result in 503 Service Unavailable responses at once, without being queued
+//!
+//! The error is recorded in the extensions of the response, and the routing handler logs it with
+//! the route and the upstream of the request in the `error` field, so that a refused connection
+//! can be told from a timeout. Invalid URIs result in 400 Bad Request responses.
 //!
 //! # Performance Considerations
 //!
@@ -47,42 +67,106 @@
 //!
 //! - `tls`: Enables TLS support for HTTPS connections to upstream servers
 use std::{
+    cell::Cell,
     convert::Infallible,
-    net::{SocketAddr, ToSocketAddrs},
+    future::{Future, poll_fn},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    rc::Rc,
+    sync::Arc,
+    task::Poll,
     time::Duration,
 };
 
 use bytes::Bytes;
-use http::{HeaderMap, HeaderValue, Request, StatusCode, header};
-use monoio::net::TcpStream;
-use monoio_http::common::{
-    body::{Body, HttpBody},
-    error::HttpError,
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, header};
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, sink::Sink},
+    net::{TcpStream, UnixStream},
+    time::Instant,
+};
+use monoio_http::{
+    common::{
+        body::{Body, FixedBody, HttpBody, StreamHint},
+        error::HttpError,
+    },
+    h1::codec::{ClientCodec, decoder::DecodeError, encoder::EncodeError},
 };
 #[cfg(feature = "tls")]
-use monoio_transports::connectors::{TlsConnector, TlsStream};
+use monoio_transports::connectors::{ServerName, TcpTlsAddr, TlsConnector, TlsError, TlsStream};
 use monoio_transports::{
-    connectors::{Connector, TcpConnector},
+    TransportError,
+    connectors::{Connector, TcpConnector, UnixConnector},
     http::{HttpConnection, HttpConnector},
+    pool::Key,
 };
 use monolake_core::{
     context::{PeerAddr, RemoteAddr},
     http::ResponseWithContinue,
     listener::AcceptedAddr,
 };
+use serde::{Deserialize, Serialize};
 use service_async::{AsyncMakeService, MakeService, ParamMaybeRef, ParamRef, Service};
 use tracing::{debug, info};
 
-use crate::http::{HttpVersion, generate_response};
+use super::{
+    retry::PerTryTimeout,
+    route::{Endpoint, MatchedRoute},
+};
+use crate::{
+    common::{
+        breaker::{BreakerConnector, BreakerKey, BreakerStream, CircuitBreaker, Overflow, Permit},
+        resolver::{ResolveError, Resolver, ResolverConfig, connect_any},
+    },
+    http::{HttpVersion, generate_response},
+};
+
+pub(crate) type PooledHttpConnector = HttpConnector<TcpConnector, SocketAddr, TcpStream>;
+pub(crate) type PooledUnixConnector = HttpConnector<UnixConnector, PathBuf, UnixStream>;
 
-type PooledHttpConnector = HttpConnector<TcpConnector, SocketAddr, TcpStream>;
+/// The connectors of the upstream handler, counting the connections of each route against its
+/// circuit breaker.
+pub(crate) type UpstreamHttpConnector =
+    HttpConnector<BreakerConnector<TcpConnector>, BreakerKey<SocketAddr>, BreakerStream<TcpStream>>;
+pub(crate) type UpstreamUnixConnector =
+    HttpConnector<BreakerConnector<UnixConnector>, BreakerKey<PathBuf>, BreakerStream<UnixStream>>;
 #[cfg(feature = "tls")]
-type PooledHttpsConnector = HttpConnector<
-    TlsConnector<TcpConnector>,
-    monoio_transports::connectors::TcpTlsAddr,
-    TlsStream<TcpStream>,
+pub(crate) type UpstreamHttpsConnector = HttpConnector<
+    BreakerConnector<TlsConnector<TcpConnector>>,
+    BreakerKey<ResolvedTlsAddr>,
+    BreakerStream<TlsStream<TcpStream>>,
 >;
 
+/// The HTTPS connector of the upstreams, pooling the connections by resolved address.
+#[cfg(feature = "tls")]
+pub(crate) type PooledResolvedHttpsConnector =
+    HttpConnector<TlsConnector<TcpConnector>, ResolvedTlsAddr, TlsStream<TcpStream>>;
+
+/// A resolved address of an HTTPS upstream, with the server name to verify.
+#[cfg(feature = "tls")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResolvedTlsAddr {
+    pub addr: SocketAddr,
+    pub sn: ServerName<'static>,
+}
+
+#[cfg(feature = "tls")]
+impl std::net::ToSocketAddrs for ResolvedTlsAddr {
+    type Iter = std::option::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> std::io::Result<Self::Iter> {
+        Ok(Some(self.addr).into_iter())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl AsRef<ServerName<'static>> for ResolvedTlsAddr {
+    fn as_ref(&self) -> &ServerName<'static> {
+        &self.sn
+    }
+}
+
 /// Handles proxying of HTTP and HTTPS requests to upstream servers.
 ///
 /// `UpstreamHandler` is responsible for forwarding incoming requests to appropriate
@@ -93,45 +177,48 @@ type PooledHttpsConnector = HttpConnector<
 /// [module level documentation](crate::http::handlers::upstream).
 #[derive(Default)]
 pub struct UpstreamHandler {
-    http_connector: PooledHttpConnector,
+    http_connector: UpstreamHttpConnector,
     #[cfg(feature = "tls")]
-    https_connector: PooledHttpsConnector,
+    https_connector: UpstreamHttpsConnector,
+    unix_connector: UpstreamUnixConnector,
+    resolver: Rc<Resolver>,
     pub http_upstream_timeout: HttpUpstreamTimeout,
 }
 
 impl UpstreamHandler {
     #[cfg(not(feature = "tls"))]
     pub fn new(
-        http_connector: PooledHttpConnector,
+        http_connector: UpstreamHttpConnector,
         http_upstream_timeout: HttpUpstreamTimeout,
     ) -> Self {
         UpstreamHandler {
             http_connector,
+            unix_connector: UpstreamUnixConnector::default(),
+            resolver: Rc::default(),
             http_upstream_timeout,
         }
     }
 
     #[cfg(feature = "tls")]
     pub fn new(
-        connector: PooledHttpConnector,
-        tls_connector: PooledHttpsConnector,
+        connector: UpstreamHttpConnector,
+        tls_connector: UpstreamHttpsConnector,
         http_upstream_timeout: HttpUpstreamTimeout,
     ) -> Self {
         UpstreamHandler {
             http_connector: connector,
             https_connector: tls_connector,
+            unix_connector: UpstreamUnixConnector::default(),
+            resolver: Rc::default(),
             http_upstream_timeout,
         }
     }
 
-    pub const fn factory(
+    pub fn factory(
         http_upstream_timeout: HttpUpstreamTimeout,
         version: HttpVersion,
     ) -> UpstreamHandlerFactory {
-        UpstreamHandlerFactory {
-            http_upstream_timeout,
-            version,
-        }
+        UpstreamHandlerFactory::new(http_upstream_timeout, version)
     }
 }
 
@@ -139,72 +226,166 @@ impl<CX, B> Service<(Request<B>, CX)> for UpstreamHandler
 where
     CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
     // B: Body,
-    B: Body<Data = Bytes, Error = HttpError>,
+    B: Body<Data = Bytes, Error = HttpError> + 'static,
     HttpError: From<B::Error>,
 {
     type Response = ResponseWithContinue<HttpBody>;
-    type Error = Infallible;
+    type Error = UpstreamError;
 
     async fn call(&self, (mut req, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
         add_xff_header(req.headers_mut(), &ctx);
+        // The timeouts of the route override the ones of the server.
+        let timeout = match req.extensions().get::<MatchedRoute>() {
+            Some(MatchedRoute(route)) => match &route.timeout {
+                Some(timeout) => self.http_upstream_timeout.with_route(timeout),
+                None => self.http_upstream_timeout,
+            },
+            None => self.http_upstream_timeout,
+        };
+        // The timeout of an attempt bounds the total timeout.
+        let timeout = match req.extensions().get::<PerTryTimeout>() {
+            Some(PerTryTimeout(per_try)) => HttpUpstreamTimeout {
+                total_timeout: Some(timeout.total_timeout.map_or(*per_try, |t| t.min(*per_try))),
+                ..timeout
+            },
+            None => timeout,
+        };
+        let deadlines = Deadlines::new(timeout);
+        // The request counts against the limits of the circuit breaker of its route until it is
+        // answered, and as pending until connected. The connections opened for it count until
+        // they are closed.
+        let breaker = req.extensions().get::<Arc<CircuitBreaker>>().cloned();
+        let (_request, pending) = match &breaker {
+            Some(breaker) => {
+                let overflow = || {
+                    info!("upstream circuit breaker open for {}", req.uri());
+                    UpstreamError::Overflow
+                };
+                let request = breaker.request().ok_or_else(overflow)?;
+                let pending = breaker.pending().ok_or_else(overflow)?;
+                (Some(request), Some(pending))
+            }
+            None => (None, None),
+        };
+        // The requests routed to a socket or a Unix endpoint are sent to its address.
+        match req.extensions().get::<Endpoint>() {
+            Some(Endpoint::Socket(addr)) => {
+                let addr = *addr;
+                return self
+                    .send_http_request(req, Some(addr), deadlines, breaker.as_ref(), pending)
+                    .await;
+            }
+            Some(Endpoint::Unix(path)) => {
+                let path = path.clone();
+                return self
+                    .send_unix_request(req, path, deadlines, breaker.as_ref(), pending)
+                    .await;
+            }
+            _ => {}
+        }
         #[cfg(feature = "tls")]
         if req.uri().scheme() == Some(&http::uri::Scheme::HTTPS) {
-            return self.send_https_request(req).await;
+            return self
+                .send_https_request(req, deadlines, breaker.as_ref(), pending)
+                .await;
         }
-        self.send_http_request(req).await
+        self.send_http_request(req, None, deadlines, breaker.as_ref(), pending)
+            .await
     }
 }
 
 impl UpstreamHandler {
+    /// Sends a request to `addr`, or else to the addresses resolved from the host of its URI,
+    /// releasing `pending` once connected.
     async fn send_http_request<B>(
         &self,
-        mut req: Request<B>,
-    ) -> Result<ResponseWithContinue<HttpBody>, Infallible>
+        req: Request<B>,
+        addr: Option<SocketAddr>,
+        deadlines: Deadlines,
+        breaker: Option<&Arc<CircuitBreaker>>,
+        pending: Option<Permit<'_>>,
+    ) -> Result<ResponseWithContinue<HttpBody>, UpstreamError>
     where
-        B: Body<Data = Bytes, Error = HttpError>,
+        B: Body<Data = Bytes, Error = HttpError> + 'static,
         HttpError: From<B::Error>,
     {
-        let Some(host) = req.uri().host() else {
-            info!("invalid uri which does not contain host: {:?}", req.uri());
-            return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
-        };
-        let port = req.uri().port_u16().unwrap_or(80);
-        let mut iter = match (host, port).to_socket_addrs() {
-            Ok(iter) => iter,
-            Err(e) => {
-                info!("convert invalid uri: {:?} with error: {:?}", req.uri(), e);
-                return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
+        let addrs = match addr {
+            Some(addr) => vec![addr],
+            None => {
+                let Some(host) = req.uri().host() else {
+                    info!("invalid uri which does not contain host: {:?}", req.uri());
+                    return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
+                };
+                let port = req.uri().port_u16().unwrap_or(80);
+                self.resolve(host, port, deadlines).await?
             }
         };
-        let Some(key) = iter.next() else {
-            info!("unable to resolve host: {host}");
-            return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
+        debug!("addrs: {:?}", addrs);
+        let connect = connect_any(
+            &addrs,
+            self.resolver.config().happy_eyeballs_delay(),
+            |addr| {
+                let key = BreakerKey {
+                    key: addr,
+                    breaker: breaker.cloned(),
+                };
+                connect_within(&self.http_connector, key, deadlines)
+            },
+        )
+        .await;
+        drop(pending);
+        match connect {
+            Ok(conn) => exchange(conn, req, deadlines).await,
+            Err(e) => Err(UpstreamError::from_connect(&e)),
+        }
+    }
+
+    async fn send_unix_request<B>(
+        &self,
+        req: Request<B>,
+        path: PathBuf,
+        deadlines: Deadlines,
+        breaker: Option<&Arc<CircuitBreaker>>,
+        pending: Option<Permit<'_>>,
+    ) -> Result<ResponseWithContinue<HttpBody>, UpstreamError>
+    where
+        B: Body<Data = Bytes, Error = HttpError> + 'static,
+        HttpError: From<B::Error>,
+    {
+        debug!("key: {:?}", path);
+        let key = BreakerKey {
+            key: path,
+            breaker: breaker.cloned(),
         };
-        debug!("key: {:?}", key);
-        let mut conn = match self.http_connector.connect(key).await {
-            Ok(conn) => {
-                match &conn {
-                    HttpConnection::Http1(_) => {
-                        *req.version_mut() = http::Version::HTTP_11;
-                    }
-                    HttpConnection::Http2(_) => {
-                        *req.version_mut() = http::Version::HTTP_2;
-                        req.headers_mut().remove(http::header::HOST);
-                    }
-                }
-                conn
+        let connect = connect_within(&self.unix_connector, key, deadlines).await;
+        drop(pending);
+        match connect {
+            Ok(conn) => exchange(conn, req, deadlines).await,
+            Err(e) => Err(UpstreamError::from_connect(&e)),
+        }
+    }
+
+    /// Resolves `host` to the socket addresses to connect to.
+    async fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+        deadlines: Deadlines,
+    ) -> Result<Vec<SocketAddr>, UpstreamError> {
+        let Some(lookup) = until(deadlines.total, self.resolver.lookup(host)).await else {
+            info!("resolve upstream host {host} timeout");
+            return Err(UpstreamError::ConnectTimeout);
+        };
+        match lookup {
+            Ok(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            Err(e @ ResolveError::NotFound(_)) => {
+                info!("unable to resolve host: {e}");
+                Err(UpstreamError::HostNotFound)
             }
             Err(e) => {
-                info!("connect upstream error: {:?}", e);
-                return Ok((generate_response(StatusCode::BAD_GATEWAY, true), true));
+                info!("resolve upstream host error: {e}");
+                Err(UpstreamError::Resolve)
             }
-        };
-
-        match conn.send_request(req).await {
-            (Ok(resp), _) => Ok((resp, true)),
-            // Bad gateway should not affect inbound connection.
-            // It should still be keepalive.
-            (Err(_e), _) => Ok((generate_response(StatusCode::BAD_GATEWAY, false), true)),
         }
     }
 
@@ -212,54 +393,407 @@ impl UpstreamHandler {
     async fn send_https_request<B>(
         &self,
         req: Request<B>,
-    ) -> Result<ResponseWithContinue<HttpBody>, Infallible>
+        deadlines: Deadlines,
+        breaker: Option<&Arc<CircuitBreaker>>,
+        pending: Option<Permit<'_>>,
+    ) -> Result<ResponseWithContinue<HttpBody>, UpstreamError>
     where
-        B: Body<Data = Bytes, Error = HttpError>,
+        B: Body<Data = Bytes, Error = HttpError> + 'static,
         HttpError: From<B::Error>,
     {
-        let key = match req.uri().try_into() {
+        let TcpTlsAddr { host, port, sn } = match req.uri().try_into() {
             Ok(key) => key,
             Err(e) => {
                 info!("convert invalid uri: {:?} with error: {:?}", req.uri(), e);
                 return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
             }
         };
-        debug!("key: {:?}", key);
-        let connect = match self.http_upstream_timeout.connect_timeout {
-            Some(connect_timeout) => {
-                match monoio::time::timeout(connect_timeout, self.https_connector.connect(key))
-                    .await
-                {
-                    Ok(x) => x,
-                    Err(_) => {
-                        info!("connect upstream timeout");
-                        return Ok((generate_response(StatusCode::BAD_GATEWAY, true), true));
-                    }
-                }
-            }
-            None => self.https_connector.connect(key).await,
+        let addrs = self.resolve(&host, port, deadlines).await?;
+        debug!("addrs: {:?}, server name: {:?}", addrs, sn);
+        let connect = connect_any(
+            &addrs,
+            self.resolver.config().happy_eyeballs_delay(),
+            |addr| {
+                let key = BreakerKey {
+                    key: ResolvedTlsAddr {
+                        addr,
+                        sn: sn.clone(),
+                    },
+                    breaker: breaker.cloned(),
+                };
+                connect_within(&self.https_connector, key, deadlines)
+            },
+        )
+        .await;
+        drop(pending);
+        match connect {
+            Ok(conn) => exchange(conn, req, deadlines).await,
+            Err(e) => Err(UpstreamError::from_connect(&e)),
+        }
+    }
+}
+
+/// The deadlines of a request to an upstream, from its timeouts.
+#[derive(Debug, Clone, Copy)]
+struct Deadlines {
+    timeout: HttpUpstreamTimeout,
+    total: Option<Instant>,
+}
+
+impl Deadlines {
+    fn new(timeout: HttpUpstreamTimeout) -> Self {
+        Self {
+            timeout,
+            total: timeout
+                .total_timeout
+                .map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// The deadline of a step lasting at most `timeout` from `start`, or the total deadline if
+    /// earlier.
+    fn after(&self, start: Instant, timeout: Option<Duration>) -> Option<Instant> {
+        match (timeout.map(|timeout| start + timeout), self.total) {
+            (Some(step), Some(total)) => Some(step.min(total)),
+            (step, total) => step.or(total),
+        }
+    }
+
+    /// The deadline of the response headers, or of the full response for HTTP/1.1 upstreams,
+    /// once the request is sent at `sent`.
+    fn response_after(&self, sent: Instant) -> Option<Instant> {
+        let headers = self.after(sent, self.timeout.response_header_timeout);
+        let response = self.after(sent, self.timeout.response_timeout);
+        match (headers, response) {
+            (Some(headers), Some(response)) => Some(headers.min(response)),
+            (headers, response) => headers.or(response),
+        }
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.total.is_none()
+            && self.timeout.write_timeout.is_none()
+            && self.timeout.response_header_timeout.is_none()
+            && self.timeout.response_timeout.is_none()
+    }
+}
+
+/// Runs `future` until `deadline`, returning `None` if it elapses first.
+async fn until<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => monoio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Connects to `key` within the connect timeout, failing with a `TimedOut` error otherwise.
+async fn connect_within<C, K>(
+    connector: &C,
+    key: K,
+    deadlines: Deadlines,
+) -> Result<C::Connection, TransportError>
+where
+    C: Connector<K, Error = TransportError>,
+{
+    let deadline = deadlines.after(Instant::now(), deadlines.timeout.connect_timeout);
+    match until(deadline, connector.connect(key)).await {
+        Some(connect) => connect,
+        None => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+/// Sends `req` on `conn` within the write, response header, response and total timeouts,
+/// failing with [`UpstreamError::WriteTimeout`] or [`UpstreamError::ReadTimeout`] when one
+/// elapses.
+///
+/// A timed out HTTP/1.1 exchange leaves its connection in an unknown state, so the connection is
+/// closed instead of going back to the pool.
+async fn exchange<K, IO, B>(
+    mut conn: HttpConnection<K, IO>,
+    mut req: Request<B>,
+    deadlines: Deadlines,
+) -> Result<ResponseWithContinue<HttpBody>, UpstreamError>
+where
+    K: Key,
+    IO: AsyncReadRent + AsyncWriteRent + 'static,
+    ClientCodec<IO>: Sink<Request<SentBody<B>>> + Sink<Request<Aborted>>,
+    <ClientCodec<IO> as Sink<Request<SentBody<B>>>>::Error: std::fmt::Debug + Into<HttpError>,
+    <ClientCodec<IO> as Sink<Request<Aborted>>>::Error: std::fmt::Debug + Into<HttpError>,
+    B: Body<Data = Bytes, Error = HttpError> + 'static,
+{
+    let http1 = match &conn {
+        HttpConnection::Http1(_) => {
+            *req.version_mut() = http::Version::HTTP_11;
+            true
+        }
+        HttpConnection::Http2(_) => {
+            *req.version_mut() = http::Version::HTTP_2;
+            req.headers_mut().remove(http::header::HOST);
+            false
+        }
+    };
+    let sent = Rc::new(Cell::new(None));
+    let req = req.map(|body| SentBody::new(body, sent.clone()));
+    if deadlines.is_unbounded() {
+        return match conn.send_request(req).await {
+            (Ok(resp), _) => Ok((resp, true)),
+            (Err(e), _) => Err(UpstreamError::from_send(&e)),
         };
+    }
 
-        let mut conn = match connect {
-            Ok(conn) => conn,
-            Err(e) => {
-                info!("connect upstream error: {:?}", e);
-                return Ok((generate_response(StatusCode::BAD_GATEWAY, true), true));
+    let mut send = Box::pin(conn.send_request(req));
+    let mut writing = sent.get().is_none();
+    let deadline = match sent.get() {
+        Some(sent) => deadlines.response_after(sent),
+        None => deadlines.after(Instant::now(), deadlines.timeout.write_timeout),
+    };
+    let mut timer = deadline.map(|deadline| Box::pin(monoio::time::sleep_until(deadline)));
+    let result = poll_fn(|cx| {
+        if let Poll::Ready((result, _)) = send.as_mut().poll(cx) {
+            return Poll::Ready(Some(result));
+        }
+        // The request body is sent while the exchange is polled.
+        if writing && let Some(sent) = sent.get() {
+            writing = false;
+            timer = deadlines
+                .response_after(sent)
+                .map(|deadline| Box::pin(monoio::time::sleep_until(deadline)));
+        }
+        match timer.as_mut().map(|timer| timer.as_mut().poll(cx)) {
+            Some(Poll::Ready(())) => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    })
+    .await;
+    drop(send);
+
+    let mut resp = match result {
+        Some(Ok(resp)) => resp,
+        Some(Err(e)) => return Err(UpstreamError::from_send(&e)),
+        None => {
+            let error = match writing {
+                true => UpstreamError::WriteTimeout,
+                false => UpstreamError::ReadTimeout,
+            };
+            info!("{error}");
+            if http1 {
+                // A failed request marks the connection closed, so that the pool drops it. The
+                // body fails before anything is written, so the request does not wait on the
+                // upstream.
+                let _ = conn.send_request(Request::new(Aborted)).await;
+            }
+            return Err(error);
+        }
+    };
+    // The body of an HTTP/2 response is streamed after its headers, so it is read within the
+    // response and total timeouts.
+    if !http1 && (deadlines.timeout.response_timeout.is_some() || deadlines.total.is_some()) {
+        let sent = sent.get().unwrap_or_else(Instant::now);
+        let deadline = deadlines.after(sent, deadlines.timeout.response_timeout);
+        match until(deadline, resp.body_mut().to_ready()).await {
+            Some(Ok(data)) => *resp.body_mut() = HttpBody::Ready(data),
+            Some(Err(e)) => return Err(UpstreamError::from_send(&e)),
+            None => {
+                info!("upstream response body timeout");
+                return Err(UpstreamError::ReadTimeout);
+            }
+        }
+    }
+    Ok((resp, true))
+}
+
+/// The failure of a request to an upstream, telling which step of the exchange failed.
+///
+/// It is turned into a response by [`HttpError::to_response`](monolake_core::http::HttpError),
+/// which records it in the extensions of the response for the routing handler to log.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamError {
+    #[error("upstream host not found")]
+    HostNotFound,
+    #[error("upstream host resolution failed")]
+    Resolve,
+    #[error("upstream connection refused")]
+    ConnectRefused,
+    #[error("upstream connect timeout")]
+    ConnectTimeout,
+    #[error("upstream connect failed")]
+    ConnectFailed,
+    #[error("upstream tls handshake failed")]
+    TlsHandshake,
+    #[error("upstream write timeout")]
+    WriteTimeout,
+    #[error("upstream read timeout")]
+    ReadTimeout,
+    #[error("upstream connection reset")]
+    Reset,
+    #[error("upstream protocol error")]
+    Protocol,
+    #[error("upstream circuit breaker open")]
+    Overflow,
+}
+
+impl UpstreamError {
+    /// The status of the response to the request failing with this error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::ConnectRefused
+            | UpstreamError::ConnectFailed
+            | UpstreamError::Overflow => StatusCode::SERVICE_UNAVAILABLE,
+            UpstreamError::ConnectTimeout
+            | UpstreamError::WriteTimeout
+            | UpstreamError::ReadTimeout => StatusCode::GATEWAY_TIMEOUT,
+            UpstreamError::HostNotFound
+            | UpstreamError::Resolve
+            | UpstreamError::TlsHandshake
+            | UpstreamError::Reset
+            | UpstreamError::Protocol => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Whether the connection to the upstream failed, so that the request was not sent.
+    pub(crate) fn is_connect_failure(&self) -> bool {
+        matches!(
+            self,
+            UpstreamError::ConnectRefused
+                | UpstreamError::ConnectTimeout
+                | UpstreamError::ConnectFailed
+                | UpstreamError::TlsHandshake
+        )
+    }
+
+    /// Classifies a failure to connect to an upstream.
+    fn from_connect(e: &TransportError) -> Self {
+        let error = match e {
+            TransportError::Io(e) => Self::from_connect_io(e),
+            // The TCP connection of a TLS one fails with an I/O error of the handshake.
+            #[cfg(feature = "tls")]
+            TransportError::Rustls(TlsError::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::HostUnreachable
+                        | io::ErrorKind::NetworkUnreachable
+                ) =>
+            {
+                Self::from_connect_io(e)
             }
+            TransportError::Rustls(_) => Self::TlsHandshake,
+            _ => Self::Protocol,
         };
+        info!("connect upstream error: {e:?}, {error}");
+        error
+    }
 
-        match conn.send_request(req).await {
-            (Ok(resp), _) => Ok((resp, true)),
-            // Bad gateway should not affect inbound connection.
-            // It should still be keepalive.
-            (Err(_e), _) => Ok((generate_response(StatusCode::BAD_GATEWAY, false), true)),
+    fn from_connect_io(e: &io::Error) -> Self {
+        if e.get_ref().is_some_and(|e| e.is::<Overflow>()) {
+            return Self::Overflow;
+        }
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => Self::ConnectRefused,
+            io::ErrorKind::TimedOut => Self::ConnectTimeout,
+            _ => Self::ConnectFailed,
         }
     }
+
+    /// Classifies a failure to send a request to an upstream, or to receive its response.
+    fn from_send(e: &HttpError) -> Self {
+        let error = match e {
+            HttpError::H1DecodeError(DecodeError::TimedOut) => Self::ReadTimeout,
+            HttpError::H1DecodeError(DecodeError::UnexpectedEof) => Self::Reset,
+            HttpError::H1DecodeError(DecodeError::Io(e))
+            | HttpError::H1EncodeError(EncodeError::Io(e))
+            | HttpError::IOError(e) => match e.kind() {
+                io::ErrorKind::TimedOut => Self::ReadTimeout,
+                _ => Self::Reset,
+            },
+            HttpError::H2Error(e) if e.is_io() || e.is_reset() || e.is_go_away() => Self::Reset,
+            _ => Self::Protocol,
+        };
+        info!("send upstream request error: {e:?}, {error}");
+        error
+    }
+}
+
+impl<B: FixedBody> monolake_core::http::HttpError<B> for UpstreamError {
+    fn to_response(&self) -> Option<Response<B>> {
+        // The connection to the client is kept alive, its request being read in full.
+        let mut resp = generate_response(self.status(), false);
+        resp.extensions_mut().insert(*self);
+        Some(resp)
+    }
+}
+
+/// The status of the response to a request handled with `result`, and the [`UpstreamError`] it
+/// failed with, or `None` if it failed without a response.
+pub(crate) fn result_status<B, E: monolake_core::http::HttpError<B>>(
+    result: &Result<ResponseWithContinue<B>, E>,
+) -> Option<(StatusCode, Option<UpstreamError>)> {
+    match result {
+        Ok((resp, _)) => Some((resp.status(), None)),
+        Err(e) => e.to_response().map(|resp| {
+            (
+                resp.status(),
+                resp.extensions().get::<UpstreamError>().copied(),
+            )
+        }),
+    }
+}
+
+/// The body of a request to an upstream, recording when it is fully sent.
+struct SentBody<B> {
+    body: B,
+    sent: Rc<Cell<Option<Instant>>>,
+}
+
+impl<B: Body> SentBody<B> {
+    fn new(body: B, sent: Rc<Cell<Option<Instant>>>) -> Self {
+        // A request without body is sent at once.
+        if matches!(body.stream_hint(), StreamHint::None) {
+            sent.set(Some(Instant::now()));
+        }
+        Self { body, sent }
+    }
+}
+
+impl<B: Body> Body for SentBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    async fn next_data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        let stream = matches!(self.body.stream_hint(), StreamHint::Stream);
+        let data = self.body.next_data().await;
+        if !stream || !matches!(data, Some(Ok(_))) {
+            self.sent.set(Some(Instant::now()));
+        }
+        data
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        self.body.stream_hint()
+    }
+}
+
+/// The body of the request failing a timed out HTTP/1.1 connection, erroring at once.
+struct Aborted;
+
+impl Body for Aborted {
+    type Data = Bytes;
+    type Error = HttpError;
+
+    async fn next_data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        Some(Err(io::Error::from(io::ErrorKind::TimedOut).into()))
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        StreamHint::Stream
+    }
 }
 
 pub struct UpstreamHandlerFactory {
     http_upstream_timeout: HttpUpstreamTimeout,
     version: HttpVersion,
+    resolver: ResolverConfig,
 }
 
 impl UpstreamHandlerFactory {
@@ -270,48 +804,69 @@ impl UpstreamHandlerFactory {
         UpstreamHandlerFactory {
             http_upstream_timeout,
             version,
+            resolver: ResolverConfig::default(),
+        }
+    }
+
+    /// Sets the configuration of the resolver of the upstream hosts.
+    pub fn with_resolver(mut self, resolver: ResolverConfig) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Keeps the resolver of the old service, and its cache, unless its configuration changed.
+    fn make_resolver(&self, old: Option<&UpstreamHandler>) -> Rc<Resolver> {
+        match old {
+            Some(old) if *old.resolver.config() == self.resolver => old.resolver.clone(),
+            _ => Rc::new(Resolver::new(self.resolver.clone())),
         }
     }
 }
 
+/// A connector of the upstream handler over `connector`, for the HTTP `version`.
+fn upstream_connector<C, K: 'static, IO: AsyncWriteRent + 'static>(
+    connector: C,
+    version: HttpVersion,
+) -> HttpConnector<BreakerConnector<C>, BreakerKey<K>, BreakerStream<IO>> {
+    let mut connector = HttpConnector::new(BreakerConnector(connector));
+    match version {
+        HttpVersion::Http2 => connector.set_http2_only(),
+        // No support for upgrades to HTTP/2
+        HttpVersion::Http11 => connector.set_http1_only(),
+        // Default to HTTP/1.1
+        HttpVersion::Auto => {}
+    }
+    connector
+}
+
 macro_rules! create_connectors {
-    ($self:ident, $http_connector:ident, $https_connector:ident, $old_service:ident) => {
-        let mut $http_connector = match $self.version {
-            HttpVersion::Http2 => PooledHttpConnector::build_tcp_http2_only(),
-            HttpVersion::Http11 => {
-                // No support for upgrades to HTTP/2
-                PooledHttpConnector::build_tcp_http1_only()
-            }
-            HttpVersion::Auto => {
-                // Default to HTTP/1.1
-                PooledHttpConnector::default()
-            }
-        };
+    ($self:ident, $http_connector:ident, $https_connector:ident, $unix_connector:ident, $old_service:ident) => {
+        let mut $http_connector = upstream_connector(TcpConnector::default(), $self.version);
         $http_connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
 
+        // The protocol of the TLS connections is negotiated with ALPN.
         #[cfg(feature = "tls")]
-        let mut $https_connector = match $self.version {
-            HttpVersion::Http2 => {
-                // ALPN advertised with h2
-                PooledHttpsConnector::build_tls_http2_only()
-            }
-            HttpVersion::Http11 => {
-                // ALPN advertised with http1.1
-                PooledHttpsConnector::build_tls_http1_only()
-            }
-            HttpVersion::Auto => {
-                // ALPN advertised with h2/http1.1
-                PooledHttpsConnector::default()
-            }
+        let alpn = match $self.version {
+            HttpVersion::Http2 => vec!["h2"],
+            HttpVersion::Http11 => vec!["http/1.1"],
+            HttpVersion::Auto => vec!["h2", "http/1.1"],
         };
         #[cfg(feature = "tls")]
+        let mut $https_connector = upstream_connector(
+            TlsConnector::new_with_tls_default(TcpConnector::default(), Some(alpn)),
+            HttpVersion::Auto,
+        );
+        #[cfg(feature = "tls")]
         $https_connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
 
+        let mut $unix_connector = upstream_connector(UnixConnector::default(), $self.version);
+        $unix_connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
+
         // If there is an old service, transfer the pool from the old service to the new one
         // to avoid creating new connections.
         if let Some($old_service) = $old_service {
             // Pool transfer is only supported when the protocol and timeout settings are the same.
-            match PooledHttpConnector::transfer_pool(
+            match UpstreamHttpConnector::transfer_pool(
                 &$old_service.http_connector,
                 &mut $http_connector,
             ) {
@@ -321,7 +876,7 @@ macro_rules! create_connectors {
                 }
             }
             #[cfg(feature = "tls")]
-            match PooledHttpsConnector::transfer_pool(
+            match UpstreamHttpsConnector::transfer_pool(
                 &$old_service.https_connector,
                 &mut $https_connector,
             ) {
@@ -330,6 +885,15 @@ macro_rules! create_connectors {
                     tracing::error!("Failed to transfer pool: {:?}", e);
                 }
             }
+            match UpstreamUnixConnector::transfer_pool(
+                &$old_service.unix_connector,
+                &mut $unix_connector,
+            ) {
+                Ok(_) => tracing::trace!("Transferred UDS pool from old service to new service"),
+                Err(e) => {
+                    tracing::error!("Failed to transfer pool: {:?}", e);
+                }
+            }
         }
     };
 }
@@ -338,11 +902,13 @@ impl MakeService for UpstreamHandlerFactory {
     type Service = UpstreamHandler;
     type Error = Infallible;
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        create_connectors!(self, http_connector, https_connector, old);
+        create_connectors!(self, http_connector, https_connector, unix_connector, old);
         Ok(UpstreamHandler {
             http_connector,
             #[cfg(feature = "tls")]
             https_connector,
+            unix_connector,
+            resolver: self.make_resolver(old),
             http_upstream_timeout: self.http_upstream_timeout,
         })
     }
@@ -356,11 +922,13 @@ impl AsyncMakeService for UpstreamHandlerFactory {
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        create_connectors!(self, http_connector, https_connector, old);
+        create_connectors!(self, http_connector, https_connector, unix_connector, old);
         Ok(UpstreamHandler {
             http_connector,
             #[cfg(feature = "tls")]
             https_connector,
+            unix_connector,
+            resolver: self.make_resolver(old),
             http_upstream_timeout: self.http_upstream_timeout,
         })
     }
@@ -368,11 +936,58 @@ impl AsyncMakeService for UpstreamHandlerFactory {
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
 pub struct HttpUpstreamTimeout {
-    // Connect timeout
+    // Connect timeout, to each address of the upstream
     // Link Nginx `proxy_connect_timeout`
     pub connect_timeout: Option<Duration>,
-    // Response read timeout
+    // Response read timeout, between two reads
+    // Like Nginx `proxy_read_timeout`
     pub read_timeout: Option<Duration>,
+    // Sending the full request, with its body
+    pub write_timeout: Option<Duration>,
+    // Receiving the response headers once the request is sent
+    pub response_header_timeout: Option<Duration>,
+    // Receiving the full response, with its body, once the request is sent
+    pub response_timeout: Option<Duration>,
+    // The whole exchange with the upstream, from the resolution of its host to the response
+    pub total_timeout: Option<Duration>,
+}
+
+impl HttpUpstreamTimeout {
+    /// Overrides the timeouts set by the route.
+    pub fn with_route(mut self, route: &RouteTimeoutConfig) -> Self {
+        let ms = |timeout: Option<u64>| timeout.map(Duration::from_millis);
+        self.connect_timeout = ms(route.connect_ms).or(self.connect_timeout);
+        self.write_timeout = ms(route.write_ms).or(self.write_timeout);
+        self.response_header_timeout =
+            ms(route.response_header_ms).or(self.response_header_timeout);
+        self.response_timeout = ms(route.response_ms).or(self.response_timeout);
+        self.total_timeout = ms(route.total_ms).or(self.total_timeout);
+        self
+    }
+}
+
+/// The upstream timeouts of a route, in milliseconds, overriding the ones of the server.
+///
+/// The read timeout is set on the connections to the upstreams, so it cannot be overridden.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteTimeoutConfig {
+    /// Timeout of connecting to each address of the upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// Timeout of sending the full request, with its body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_ms: Option<u64>,
+    /// Timeout of receiving the response headers once the request is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_header_ms: Option<u64>,
+    /// Timeout of receiving the full response, with its body, once the request is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_ms: Option<u64>,
+    /// Timeout of the whole exchange with the upstream, from the resolution of its host to the
+    /// response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_ms: Option<u64>,
 }
 
 fn add_xff_header<CX>(headers: &mut HeaderMap, ctx: &CX)
@@ -400,3 +1015,223 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use monoio::{
+        io::{AsyncReadRent, AsyncWriteRentExt},
+        net::TcpListener,
+    };
+    use monoio_http::common::body::BodyExt;
+    use monolake_core::http::HttpHandler;
+
+    use super::*;
+    use crate::{
+        common::breaker::CircuitBreakerConfig,
+        http::{
+            stub::{self, Peer, StubResponse},
+            util::HttpErrorResponder,
+        },
+    };
+
+    #[test]
+    fn test_response_timeout() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            // Never answers the first request, and answers the next one on a new connection.
+            let server = monoio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (read, _) = stream.read(vec![0; 1024]).await;
+                assert!(read.unwrap() > 0);
+                let (read, _) = stream.read(vec![0; 1024]).await;
+                assert_eq!(read.unwrap(), 0);
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (read, _) = stream.read(vec![0; 1024]).await;
+                assert!(read.unwrap() > 0);
+                let response = "HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\nnext";
+                stream.write_all(response).await.0.unwrap();
+            });
+
+            let connector = PooledHttpConnector::build_tcp_http1_only();
+            let request = || {
+                Request::get(format!("http://{addr}/"))
+                    .body(HttpBody::default())
+                    .unwrap()
+            };
+            let timeout = HttpUpstreamTimeout {
+                response_header_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            };
+            let conn = connector.connect(addr).await.unwrap();
+            let result = exchange(conn, request(), Deadlines::new(timeout)).await;
+            assert_eq!(result.unwrap_err(), UpstreamError::ReadTimeout);
+
+            // The timed out connection is closed instead of being reused.
+            let conn = connector.connect(addr).await.unwrap();
+            let (resp, _) = exchange(conn, request(), Deadlines::new(timeout))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_body().bytes().await.unwrap(), "next");
+            server.await;
+        });
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let connector = PooledHttpConnector::build_tcp_http1_only();
+            let deadlines = Deadlines::new(HttpUpstreamTimeout::default());
+            let Err(e) = connect_within(&connector, addr, deadlines).await else {
+                panic!("connected to a closed port");
+            };
+            let error = UpstreamError::from_connect(&e);
+            assert_eq!(error, UpstreamError::ConnectRefused);
+
+            let resp: Response<HttpBody> =
+                monolake_core::http::HttpError::to_response(&error).unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                resp.extensions().get(),
+                Some(&UpstreamError::ConnectRefused)
+            );
+            // The hosts of the upstreams are configured, so that a missing one is not a client
+            // error.
+            assert_eq!(
+                UpstreamError::HostNotFound.status(),
+                StatusCode::BAD_GATEWAY
+            );
+        });
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (addr, requests) = stub::serve(|_| StubResponse::new(200, "upstream"));
+            let handler = HttpErrorResponder(UpstreamHandler {
+                http_connector: upstream_connector(TcpConnector::default(), HttpVersion::Http11),
+                ..Default::default()
+            });
+            let breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig {
+                max_requests: 1,
+                ..Default::default()
+            }));
+            let request = || {
+                let mut request = Request::get("http://example.com/")
+                    .body(HttpBody::default())
+                    .unwrap();
+                request.extensions_mut().insert(Endpoint::Socket(addr));
+                request.extensions_mut().insert(breaker.clone());
+                request
+            };
+
+            // The breaker is open while another request of the route is sent.
+            let permit = breaker.request().unwrap();
+            let (resp, _) = handler.handle(request(), Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.extensions().get(), Some(&UpstreamError::Overflow));
+            assert!(requests.borrow().is_empty());
+
+            // The requests reach the upstream again once it is answered.
+            drop(permit);
+            let (resp, _) = handler.handle(request(), Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.into_body().bytes().await.unwrap(), "upstream");
+            assert_eq!(requests.borrow().len(), 1);
+            // The permits of the answered request are released.
+            assert!(breaker.request().is_some());
+            assert!(breaker.pending().is_some());
+        });
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (addr, requests) = stub::serve(|_| StubResponse::new(200, "upstream"));
+            let handler = HttpErrorResponder(UpstreamHandler {
+                http_connector: upstream_connector(TcpConnector::default(), HttpVersion::Http11),
+                ..Default::default()
+            });
+            let breaker = Arc::new(CircuitBreaker::new(CircuitBreakerConfig {
+                max_connections: 1,
+                ..Default::default()
+            }));
+            let request = || {
+                let mut request = Request::get("http://example.com/")
+                    .body(HttpBody::default())
+                    .unwrap();
+                request.extensions_mut().insert(Endpoint::Socket(addr));
+                request.extensions_mut().insert(breaker.clone());
+                request
+            };
+
+            // The requests of the route share its pooled connection.
+            for _ in 0..2 {
+                let (resp, _) = handler.handle(request(), Peer::local()).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+                assert_eq!(resp.into_body().bytes().await.unwrap(), "upstream");
+            }
+            assert_eq!(requests.borrow().len(), 2);
+
+            // A request needing another connection exceeds the limit while it is in use.
+            let key = BreakerKey {
+                key: addr,
+                breaker: Some(breaker.clone()),
+            };
+            let conn = handler.0.http_connector.connect(key).await.unwrap();
+            let (resp, _) = handler.handle(request(), Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.extensions().get(), Some(&UpstreamError::Overflow));
+            assert_eq!(requests.borrow().len(), 2);
+
+            // The connections of the other routes are not counted.
+            let mut other = request();
+            other.extensions_mut().remove::<Arc<CircuitBreaker>>();
+            let (resp, _) = handler.handle(other, Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            drop(conn);
+            let (resp, _) = handler.handle(request(), Peer::local()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(requests.borrow().len(), 4);
+        });
+    }
+
+    #[test]
+    fn test_route_timeout() {
+        let server = HttpUpstreamTimeout {
+            connect_timeout: Some(Duration::from_secs(2)),
+            total_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let route = RouteTimeoutConfig {
+            total_ms: Some(500),
+            ..Default::default()
+        };
+        let timeout = server.with_route(&route);
+        assert_eq!(timeout.connect_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(timeout.total_timeout, Some(Duration::from_millis(500)));
+    }
+}
@@ -49,6 +49,8 @@ pub mod handlers;
 
 pub mod core;
 pub mod detect;
+#[cfg(test)]
+pub(crate) mod stub;
 pub mod util;
 
 pub(crate) const CLOSE: &str = "close";
@@ -0,0 +1,270 @@
+//! A stub HTTP/1.1 server, for the tests of the handlers calling other servers.
+use std::{cell::RefCell, convert::Infallible, net::SocketAddr, path::PathBuf, rc::Rc};
+
+use certain_map::{Attach, Fork};
+use http::{HeaderMap, Request, StatusCode};
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
+    net::{TcpListener, UnixListener},
+};
+use monoio_http::common::body::HttpBody;
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    http::ResponseWithContinue,
+};
+use service_async::{ParamMaybeRef, ParamRef, Service};
+
+use crate::http::generate_response;
+
+/// A request received by the stub server.
+#[derive(Debug, Clone)]
+pub(crate) struct StubRequest {
+    pub method: String,
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl StubRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+}
+
+/// A response of the stub server.
+#[derive(Debug, Clone)]
+pub(crate) struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl StubResponse {
+    pub fn new(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn json(body: impl Into<String>) -> Self {
+        Self::new(200, body).header("content-type", "application/json")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Serves the requests with `handler` on a local port until the runtime stops. The received
+/// requests are recorded in the returned log.
+pub(crate) fn serve<F>(handler: F) -> (SocketAddr, Rc<RefCell<Vec<StubRequest>>>)
+where
+    F: Fn(&StubRequest) -> StubResponse + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let requests = log.clone();
+    let handler = Rc::new(handler);
+    monoio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            monoio::spawn(serve_connection(stream, handler.clone(), requests.clone()));
+        }
+    });
+    (addr, log)
+}
+
+/// Serves the requests with `handler` on a Unix domain socket until the runtime stops, like
+/// [`serve`].
+pub(crate) fn serve_unix<F>(handler: F) -> (PathBuf, Rc<RefCell<Vec<StubRequest>>>)
+where
+    F: Fn(&StubRequest) -> StubResponse + 'static,
+{
+    static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "monolake-stub-{}-{}.sock",
+        std::process::id(),
+        NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_file(&path);
+    // Bound without the address reuse of monoio, which the Unix domain sockets do not support.
+    let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let listener = UnixListener::from_std(listener).unwrap();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let requests = log.clone();
+    let handler = Rc::new(handler);
+    monoio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            monoio::spawn(serve_connection(stream, handler.clone(), requests.clone()));
+        }
+    });
+    (path, log)
+}
+
+// The connections are kept alive, as the clients pool them.
+async fn serve_connection<S, F>(
+    mut stream: S,
+    handler: Rc<F>,
+    requests: Rc<RefCell<Vec<StubRequest>>>,
+) where
+    S: AsyncReadRent + AsyncWriteRent,
+    F: Fn(&StubRequest) -> StubResponse,
+{
+    while let Some(request) = read_request(&mut stream).await {
+        let response = handler(&request);
+        requests.borrow_mut().push(request);
+        if !write_response(&mut stream, response).await {
+            return;
+        }
+    }
+}
+
+async fn read_request(stream: &mut impl AsyncReadRent) -> Option<StubRequest> {
+    let mut data = Vec::new();
+    let (head, body_start) = loop {
+        let (res, buf) = stream.read(Vec::with_capacity(4096)).await;
+        if res.ok()? == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf);
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break (String::from_utf8_lossy(&data[..end]).into_owned(), end + 4);
+        }
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let chunked = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked")
+    });
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or_default();
+    let body = loop {
+        let body = &data[body_start..];
+        let complete = match chunked {
+            true => decode_chunked(body),
+            false => (body.len() >= length).then(|| body[..length].to_vec()),
+        };
+        if let Some(body) = complete {
+            break body;
+        }
+        let (res, buf) = stream.read(Vec::with_capacity(4096)).await;
+        if res.ok()? == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf);
+    };
+    Some(StubRequest {
+        method,
+        target,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+// The decoded chunked body, if complete.
+fn decode_chunked(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let end = data.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&data[..end]).ok()?.split(';').next()?;
+        let size = usize::from_str_radix(size.trim(), 16).ok()?;
+        data = &data[end + 2..];
+        if size == 0 {
+            return data.starts_with(b"\r\n").then_some(body);
+        }
+        if data.len() < size + 2 {
+            return None;
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+async fn write_response(stream: &mut impl AsyncWriteRent, response: StubResponse) -> bool {
+    let mut head = format!(
+        "HTTP/1.1 {} Stub\r\ncontent-length: {}\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    head.push_str(&response.body);
+    stream.write_all(head.into_bytes()).await.0.is_ok()
+}
+
+/// An inner handler answering 200, recording the headers of the requests it receives.
+#[derive(Default)]
+pub(crate) struct RecordingHandler {
+    pub requests: RefCell<Vec<HeaderMap>>,
+}
+
+impl<B, CX> Service<(Request<B>, CX)> for RecordingHandler {
+    type Response = ResponseWithContinue<HttpBody>;
+    type Error = Infallible;
+
+    async fn call(&self, (request, _): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        self.requests.borrow_mut().push(request.headers().clone());
+        Ok((generate_response(StatusCode::OK, false), true))
+    }
+}
+
+/// The context of a request accepted from a client, without a proxy protocol header.
+#[derive(Clone)]
+pub(crate) struct Peer(pub PeerAddr);
+
+impl Peer {
+    pub fn local() -> Self {
+        Self(PeerAddr(SocketAddr::from(([127, 0, 0, 1], 1)).into()))
+    }
+}
+
+impl ParamRef<PeerAddr> for Peer {
+    fn param_ref(&self) -> &PeerAddr {
+        &self.0
+    }
+}
+
+impl ParamMaybeRef<Option<RemoteAddr>> for Peer {
+    fn param_maybe_ref(&self) -> Option<&Option<RemoteAddr>> {
+        None
+    }
+}
+
+impl Fork for Peer {
+    type Store = ();
+    type State = Peer;
+
+    fn fork(&self) -> (Self::Store, Self::State) {
+        ((), self.clone())
+    }
+}
+
+impl Attach<()> for Peer {
+    type Hdr<'a> = Peer;
+
+    unsafe fn attach(self, _: &mut ()) -> Self::Hdr<'_> {
+        self
+    }
+}
@@ -31,6 +31,9 @@
 //! - [`RewriteAndRouteHandler`](http::handlers::route): Directs requests to appropriate handlers
 //!   based on predefined rules. It allows for flexible URL-based routing and request dispatching.
 //!
+//! - [`ExtAuthzHandler`](http::handlers::ext_authz): Asks an external authorization service whether
+//!   requests are allowed, allowing, denying or adding headers to them.
+//!
 //! - [`UpstreamHandler`](http::handlers::upstream): Manages proxying of requests to upstream
 //!   servers. It supports load balancing, connection pooling, and error handling for backend
 //!   services.
@@ -39,6 +42,9 @@
 //!   authentication (optional feature). It enables secure user authentication using OpenID Connect
 //!   protocols.
 //!
+//! - [`JwtHandler`](crate::http::handlers::JwtHandler): Validates JWT bearer tokens (optional
+//!   feature), for API clients authenticated by a token rather than by signing in.
+//!
 //! ### Thrift Services
 //!
 //! - [`TtheaderCoreService`](thrift::ttheader): Core service for handling Thrift THeader protocol
@@ -20,6 +20,7 @@
 //! - Connection pooling for efficient resource management
 //! - Integration with `service_async` for easy composition in service stacks
 //! - Support for both TCP and Unix socket connections to upstream servers
+//! - Active health checks of the upstream servers, which skips the unhealthy ones
 //!
 //! # Usage
 //!
@@ -46,9 +47,12 @@
 //! - Implements connection pooling to reduce connection establishment overhead
 //! - Efficient request and response handling using the THeader protocol
 
-use std::io;
+use std::{io, rc::Rc};
 
-use monoio::io::{sink::SinkExt, stream::Stream};
+use monoio::{
+    io::{sink::SinkExt, stream::Stream},
+    net::{TcpStream, UnixStream},
+};
 use monoio_codec::Framed;
 use monoio_thrift::codec::ttheader::{RawPayloadCodec, TTHeaderPayloadCodec};
 use monoio_transports::{
@@ -62,8 +66,9 @@ use monolake_core::{
 use serde::{Deserialize, Serialize};
 use service_async::{AsyncMakeService, MakeService, ParamMaybeRef, ParamRef, Service};
 
-use crate::common::selector::{
-    IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Select,
+use crate::common::{
+    health::{Health, HealthCheckConfig, HealthCheckError, HealthProbe},
+    selector::{IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Select},
 };
 
 pub type PoolThriftConnector = PooledConnector<
@@ -105,14 +110,27 @@ impl<C, E> ConnectorMapper<C, E> for ThriftConnectorMapper {
 pub struct ProxyHandler {
     connector: PoolThriftConnector,
     endpoints: LoadBalancer<Endpoint>,
+    health: Option<Rc<Health>>,
 }
 
 impl RouteConfig {
-    fn proxy_handler(&self) -> Result<ProxyHandler, LoadBalanceError> {
-        Ok(ProxyHandler::new(
+    fn proxy_handler(&self) -> Result<ProxyHandler, RouteError> {
+        let mut handler = ProxyHandler::new(
             new_connector(),
             LoadBalancer::try_from_upstreams(self.load_balancer, self.upstreams.clone())?,
-        ))
+        );
+        if let Some(config) = &self.health_check {
+            config.validate(|probe| !matches!(probe, HealthProbe::Http { .. }))?;
+            let health = Rc::new(Health::new(config, handler.endpoints.endpoints()));
+            let endpoints = Rc::new(handler.endpoints.endpoints().to_vec());
+            let probe = Rc::new(config.probe.clone());
+            Health::set_checks(&health, config, move |index| {
+                let (endpoints, probe) = (endpoints.clone(), probe.clone());
+                async move { endpoints[index].probe(&probe).await }
+            });
+            handler.health = Some(health);
+        }
+        Ok(handler)
     }
 }
 
@@ -121,6 +139,7 @@ impl ProxyHandler {
         ProxyHandler {
             connector,
             endpoints,
+            health: None,
         }
     }
 
@@ -149,7 +168,12 @@ impl ProxyHandler {
         &self,
         req: ThriftRequest<ThriftBody>,
     ) -> Result<ThriftResponse<ThriftBody>, io::Error> {
-        let endpoint = self.endpoints.select(&req).unwrap();
+        let endpoint = match &self.health {
+            Some(health) => health
+                .select_where(&self.endpoints, &req, |_| true)
+                .unwrap(),
+            None => self.endpoints.select(&req).unwrap(),
+        };
         let key = match endpoint {
             Endpoint::Socket(addr) => UnifiedL4Addr::Tcp(*addr),
             Endpoint::Unix(path) => UnifiedL4Addr::Unix(path.clone()),
@@ -189,9 +213,18 @@ pub struct ProxyHandlerFactory {
     config: RouteConfig,
 }
 
+/// Error of an invalid [`RouteConfig`], by the field it comes from.
+#[derive(thiserror::Error, Debug)]
+pub enum RouteError {
+    #[error("upstreams: {0}")]
+    LoadBalance(#[from] LoadBalanceError),
+    #[error("health_check.{0}")]
+    HealthCheck(#[from] HealthCheckError),
+}
+
 impl MakeService for ProxyHandlerFactory {
     type Service = ProxyHandler;
-    type Error = LoadBalanceError;
+    type Error = RouteError;
 
     fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         self.config.proxy_handler()
@@ -200,7 +233,7 @@ impl MakeService for ProxyHandlerFactory {
 
 impl AsyncMakeService for ProxyHandlerFactory {
     type Service = ProxyHandler;
-    type Error = LoadBalanceError;
+    type Error = RouteError;
 
     async fn make_via_ref(
         &self,
@@ -222,6 +255,10 @@ pub struct RouteConfig {
     ///
     /// Multiple upstreams allow for load balancing and failover configurations.
     pub upstreams: Vec<Upstream>,
+
+    /// The active health checks of the upstreams, which skips the unhealthy ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
 }
 
 const fn default_weight() -> u16 {
@@ -271,3 +308,20 @@ pub enum Endpoint {
     /// This is typically used for local inter-process communication on Unix-like systems.
     Unix(std::path::PathBuf),
 }
+
+impl Endpoint {
+    /// Probes the endpoint for the health checks.
+    async fn probe(&self, probe: &HealthProbe) -> io::Result<()> {
+        match self {
+            Endpoint::Socket(addr) => {
+                let io = TcpStream::connect(addr).await?;
+                probe.check(io, &addr.to_string()).await
+            }
+            Endpoint::Unix(path) => {
+                probe
+                    .check(UnixStream::connect(path).await?, "localhost")
+                    .await
+            }
+        }
+    }
+}
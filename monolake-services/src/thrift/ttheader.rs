@@ -18,6 +18,8 @@
 //! - Efficient handling of concurrent requests using asynchronous I/O
 //! - Configurable timeout settings for different stages of request processing
 //! - Automatic message framing and error handling
+//! - Graceful draining: when the [`Drain`] signal of the connection fires, connections are closed
+//!   once idle or after the in-flight response
 //!
 //! # Usage
 //!
@@ -58,7 +60,7 @@ use certain_map::{Attach, Fork};
 use monoio::io::{AsyncReadRent, AsyncWriteRent, sink::SinkExt, stream::Stream};
 use monoio_codec::Framed;
 use monoio_thrift::codec::ttheader::{RawPayloadCodec, TTHeaderPayloadCodec};
-use monolake_core::{AnyError, context::PeerAddr, thrift::ThriftHandler};
+use monolake_core::{AnyError, context::PeerAddr, orchestrator::Drain, thrift::ThriftHandler};
 use service_async::{
     AsyncMakeService, MakeService, Param, ParamRef, Service,
     layer::{FactoryLayer, layer_fn},
@@ -101,30 +103,51 @@ where
 
     async fn call(&self, (stream, ctx): (Stream, CXIn)) -> Result<Self::Response, Self::Error> {
         let mut codec = Framed::new(stream, TTHeaderPayloadCodec::new(RawPayloadCodec::new()));
+        let drain = Drain::current();
         loop {
-            if let Some(keepalive_timeout) = self.thrift_timeout.keepalive_timeout {
-                match monoio::time::timeout(keepalive_timeout, codec.peek_data()).await {
-                    Ok(Ok([])) => {
-                        // Connection closed normally.
-                        info!("Connection closed due to keepalive timeout");
-                        break;
-                    }
-                    Ok(Err(io_error)) => {
-                        error!(
-                            "Connection {:?} io error: {io_error}",
-                            ParamRef::<PeerAddr>::param_ref(&ctx)
-                        );
-                        break;
-                    }
-                    Err(_) => {
-                        info!(
-                            "Connection {:?} keepalive timed out",
-                            ParamRef::<PeerAddr>::param_ref(&ctx),
-                        );
-                        break;
+            // wait for the next request with keepalive timeout, or close the idle connection when
+            // draining
+            let peek = async {
+                match self.thrift_timeout.keepalive_timeout {
+                    Some(keepalive_timeout) => {
+                        monoio::time::timeout(keepalive_timeout, codec.peek_data())
+                            .await
+                            .ok()
                     }
-                    _ => {}
+                    None => Some(codec.peek_data().await),
+                }
+            };
+            let peeked = monoio::select! {
+                peeked = peek => peeked,
+                _ = drain.wait() => {
+                    info!(
+                        "Connection {:?} closed for draining",
+                        ParamRef::<PeerAddr>::param_ref(&ctx),
+                    );
+                    break;
+                }
+            };
+            match peeked {
+                Some(Ok([])) => {
+                    // Connection closed normally.
+                    trace!("Connection closed normally due to read EOF");
+                    break;
+                }
+                Some(Err(io_error)) => {
+                    error!(
+                        "Connection {:?} io error: {io_error}",
+                        ParamRef::<PeerAddr>::param_ref(&ctx)
+                    );
+                    break;
                 }
+                None => {
+                    info!(
+                        "Connection {:?} keepalive timed out",
+                        ParamRef::<PeerAddr>::param_ref(&ctx),
+                    );
+                    break;
+                }
+                _ => {}
             }
 
             // decode request with message timeout
@@ -170,6 +193,9 @@ where
                         break;
                     }
                     trace!("sent thrift response");
+                    if drain.is_draining() {
+                        break;
+                    }
                 }
                 Err(e) => {
                     // something error when process request(not a biz error)
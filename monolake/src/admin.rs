@@ -0,0 +1,388 @@
+//! Admin API served by the main thread.
+//!
+//! The admin API is a small HTTP interface to operate a running monolake instance:
+//!
+//! - `GET /servers`: list the online servers with their listener, routes and upstreams.
+//! - `PUT /config`: push a service config, in the same format as the `servers` section of the
+//!   config file, and apply it to every worker.
+//! - `GET /log_filter`: get the current log filter.
+//! - `PUT /log_filter`: replace the log filter, e.g. `info,monolake_services=debug`.
+use std::{collections::BTreeMap, convert::Infallible, rc::Rc, sync::Arc};
+
+use bytes::Bytes;
+use http::{header, Method, Request, StatusCode};
+use local_sync::{mpsc::unbounded::Tx, oneshot};
+use monoio::io::stream::Stream;
+use monoio_http::common::{
+    body::{BodyExt, FixedBody, HttpBody},
+    response::Response,
+};
+use monolake_core::{http::ResponseWithContinue, listener::ListenerBuilder};
+use monolake_services::{
+    common::ContextService,
+    http::{
+        core::HttpCoreService,
+        detect::H2Detect,
+        handlers::{route::RouteConfig as HttpRouteConfig, ConnectionReuseHandler},
+    },
+    thrift::RouteConfig as ThriftRouteConfig,
+};
+use serde::Serialize;
+use service_async::{stack::FactoryStack, MakeService, Service};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{
+    config::{
+        manager::{ManagerCommand, ServicesState},
+        AdminConfig, ListenerConfig, ServerProtocolConfig,
+    },
+    context::Context,
+};
+
+/// Handle to replace the log filter at runtime.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Spawn the serve loop of the admin listener on the current thread.
+pub fn spawn(
+    config: AdminConfig,
+    listener_builder: Arc<ListenerBuilder>,
+    commands: Tx<ManagerCommand>,
+    log_filter: LogFilterHandle,
+) -> anyhow::Result<()> {
+    let mut listener = listener_builder.build()?;
+    let svc = FactoryStack::new(config)
+        .replace(AdminHandler {
+            commands,
+            log_filter,
+        })
+        .push(ConnectionReuseHandler::layer())
+        .push(HttpCoreService::layer())
+        .push(H2Detect::layer())
+        .push(ContextService::<Context, _>::layer())
+        .make()?;
+    let svc = Rc::new(svc);
+
+    monoio::spawn(async move {
+        // keep the socket registered for upgrade while serving
+        let _listener_builder = listener_builder;
+        while let Some(accept) = listener.next().await {
+            match accept {
+                Ok(accept) => {
+                    let svc = svc.clone();
+                    monoio::spawn(async move {
+                        if let Err(e) = svc.call(accept).await {
+                            tracing::error!("admin connection error: {e:?}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("admin accept connection failed: {e:?}"),
+            }
+        }
+        tracing::info!("admin listener is closed");
+    });
+    Ok(())
+}
+
+/// Handler serving the admin API.
+#[derive(Clone)]
+pub struct AdminHandler {
+    commands: Tx<ManagerCommand>,
+    log_filter: LogFilterHandle,
+}
+
+impl<CX> Service<(Request<HttpBody>, CX)> for AdminHandler {
+    type Response = ResponseWithContinue<HttpBody>;
+    type Error = Infallible;
+
+    async fn call(
+        &self,
+        (request, _): (Request<HttpBody>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let (parts, body) = request.into_parts();
+        let response = match (&parts.method, parts.uri.path()) {
+            (&Method::GET, "/servers") => self.get_servers().await,
+            (&Method::PUT, "/config") => match body.bytes().await {
+                Ok(content) => self.put_config(content).await,
+                Err(e) => text_response(StatusCode::BAD_REQUEST, e.to_string()),
+            },
+            (&Method::GET, "/log_filter") => text_response(
+                StatusCode::OK,
+                self.log_filter
+                    .with_current(ToString::to_string)
+                    .unwrap_or_default(),
+            ),
+            (&Method::PUT, "/log_filter") => match body.bytes().await {
+                Ok(content) => self.put_log_filter(&content),
+                Err(e) => text_response(StatusCode::BAD_REQUEST, e.to_string()),
+            },
+            (_, "/servers" | "/config" | "/log_filter") => {
+                text_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        };
+        Ok((response, true))
+    }
+}
+
+impl AdminHandler {
+    async fn get_servers(&self) -> Response<HttpBody> {
+        let (state_tx, state_rx) = oneshot::channel();
+        if self
+            .commands
+            .send(ManagerCommand::GetServices(state_tx))
+            .is_err()
+        {
+            return manager_unavailable();
+        }
+        let Ok(state) = state_rx.await else {
+            return manager_unavailable();
+        };
+        match serde_json::to_vec(&ServersView::from(&state)) {
+            Ok(json) => {
+                let mut response = text_response(StatusCode::OK, json);
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("application/json"),
+                );
+                response
+            }
+            Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        }
+    }
+
+    async fn put_config(&self, content: Bytes) -> Response<HttpBody> {
+        let (result_tx, result_rx) = oneshot::channel();
+        if self
+            .commands
+            .send(ManagerCommand::ApplyServices(content.to_vec(), result_tx))
+            .is_err()
+        {
+            return manager_unavailable();
+        }
+        match result_rx.await {
+            Ok(Ok(_)) => text_response(StatusCode::OK, "config applied"),
+            Ok(Err(e)) => text_response(StatusCode::BAD_REQUEST, format!("{e:#}")),
+            Err(_) => manager_unavailable(),
+        }
+    }
+
+    fn put_log_filter(&self, content: &[u8]) -> Response<HttpBody> {
+        let directives = String::from_utf8_lossy(content);
+        let filter = match EnvFilter::try_new(directives.trim()) {
+            Ok(filter) => filter,
+            Err(e) => return text_response(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        match self.log_filter.reload(filter) {
+            Ok(_) => {
+                tracing::info!("log filter is set to {}", directives.trim());
+                text_response(StatusCode::OK, "log filter updated")
+            }
+            Err(e) => text_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        }
+    }
+}
+
+impl MakeService for AdminHandler {
+    type Service = Self;
+    type Error = Infallible;
+
+    fn make_via_ref(&self, _old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(self.clone())
+    }
+}
+
+fn text_response(status: StatusCode, body: impl Into<Bytes>) -> Response<HttpBody> {
+    let mut response = Response::new(HttpBody::fixed_body(Some(body.into())));
+    *response.status_mut() = status;
+    response
+}
+
+fn manager_unavailable() -> Response<HttpBody> {
+    text_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "config manager is unavailable",
+    )
+}
+
+#[derive(Serialize)]
+struct ServersView<'a> {
+    workers: usize,
+    servers: BTreeMap<&'a str, ServerView<'a>>,
+}
+
+#[derive(Serialize)]
+struct ServerView<'a> {
+    name: &'a str,
+    listener: &'a ListenerConfig,
+    #[serde(flatten)]
+    protocol: ProtocolView<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "proxy_type", rename_all = "snake_case")]
+enum ProtocolView<'a> {
+    Http { routes: &'a [HttpRouteConfig] },
+    Thrift { route: &'a ThriftRouteConfig },
+}
+
+impl<'a> From<&'a ServicesState> for ServersView<'a> {
+    fn from(state: &'a ServicesState) -> Self {
+        let servers = state
+            .services
+            .iter()
+            .map(|(key, service)| {
+                let protocol = match &service.server.protocol {
+                    ServerProtocolConfig::Http { routes, .. } => ProtocolView::Http { routes },
+                    ServerProtocolConfig::Thrift { route, .. } => ProtocolView::Thrift { route },
+                };
+                let server = ServerView {
+                    name: &service.server.name,
+                    listener: &service.listener,
+                    protocol,
+                };
+                (key.as_str(), server)
+            })
+            .collect();
+        ServersView {
+            workers: state.workers,
+            servers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use local_sync::mpsc::unbounded;
+
+    use super::*;
+    use crate::config::Config;
+
+    // A handler of which the commands are answered as by the config manager, without applying
+    // the services.
+    fn handler() -> (AdminHandler, reload::Layer<EnvFilter, Registry>) {
+        let (commands, mut rx) = unbounded::channel();
+        monoio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                match command {
+                    ManagerCommand::ApplyServices(content, result_tx) => {
+                        let result = Config::parse_service_config(&content).map(drop);
+                        let _ = result_tx.send(result);
+                    }
+                    ManagerCommand::GetServices(state_tx) => {
+                        let _ = state_tx.send(ServicesState {
+                            workers: 1,
+                            services: Default::default(),
+                        });
+                    }
+                }
+            }
+        });
+        let (layer, log_filter) = reload::Layer::new(EnvFilter::new("info"));
+        let handler = AdminHandler {
+            commands,
+            log_filter,
+        };
+        (handler, layer)
+    }
+
+    async fn call(
+        handler: &AdminHandler,
+        method: Method,
+        path: &str,
+        body: &'static str,
+    ) -> (StatusCode, String) {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(HttpBody::fixed_body(Some(Bytes::from_static(
+                body.as_bytes(),
+            ))))
+            .unwrap();
+        let (response, _) = handler.call((request, ())).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().bytes().await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn test_admin_api() {
+        let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (handler, _layer) = handler();
+
+            let config = r#"
+                [servers.demo]
+                name = "demo"
+                proxy_type = "http"
+                listener = { type = "socket", value = "127.0.0.1:18080" }
+
+                [[servers.demo.routes]]
+                path = "/"
+                upstreams = [{ endpoint = { type = "uri", value = "http://127.0.0.1:9080" } }]
+                "#;
+            let (status, body) = call(&handler, Method::PUT, "/config", config).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "config applied"));
+            let invalid = "[servers.demo]\nname = \"demo\"\nproxy_type = \"http\"";
+            let (status, body) = call(&handler, Method::PUT, "/config", invalid).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(body.contains("missing field `listener`"), "{body}");
+
+            let (status, body) = call(&handler, Method::GET, "/servers", "").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, r#"{"workers":1,"servers":{}}"#);
+
+            let (status, body) = call(&handler, Method::GET, "/log_filter", "").await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, "info"));
+            let filter = "warn,monolake_services=debug\n";
+            let (status, _) = call(&handler, Method::PUT, "/log_filter", filter).await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, body) = call(&handler, Method::GET, "/log_filter", "").await;
+            assert_eq!(body, "monolake_services=debug,warn");
+            let (status, _) = call(&handler, Method::PUT, "/log_filter", "[").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let (_, body) = call(&handler, Method::GET, "/log_filter", "").await;
+            assert_eq!(body, "monolake_services=debug,warn");
+
+            let (status, _) = call(&handler, Method::POST, "/config", "").await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+            let (status, _) = call(&handler, Method::DELETE, "/log_filter", "").await;
+            assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+            let (status, _) = call(&handler, Method::GET, "/unknown", "").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+
+            // Without the config manager, the commands are answered with 503.
+            let (commands, _) = unbounded::channel();
+            let handler = AdminHandler {
+                commands,
+                ..handler
+            };
+            let (status, _) = call(&handler, Method::PUT, "/config", config).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            let (status, _) = call(&handler, Method::GET, "/servers", "").await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        });
+    }
+
+    #[cfg(feature = "openid")]
+    #[test]
+    fn test_secret_redacted() {
+        let routes: BTreeMap<String, Vec<HttpRouteConfig>> = toml::from_str(
+            r#"
+            [[routes]]
+            path = "/"
+            upstreams = [{ endpoint = { type = "uri", value = "http://127.0.0.1:8080" } }]
+            auth = { client_id = "monolake", client_secret = "s3cr3t", issuer_url = "http://127.0.0.1:8081", redirect_url = "http://127.0.0.1/callback" }
+            "#,
+        )
+        .unwrap();
+        let view = ProtocolView::Http {
+            routes: &routes["routes"],
+        };
+        let json = serde_json::to_string(&view).unwrap();
+        assert!(!json.contains("s3cr3t"));
+        assert!(json.contains(monolake_services::http::handlers::openid::REDACTED));
+    }
+}
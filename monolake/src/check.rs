@@ -0,0 +1,139 @@
+//! Offline validation of the config file, for `monolake check`.
+//!
+//! The config is loaded the same way as when it is applied, and the service stack of every
+//! server is built, so the errors that would make a reload fail are found without a running
+//! proxy. Listeners are checked without binding, since the addresses may be in use by the
+//! running one.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use anyhow::bail;
+use monolake_services::{common::selector::LoadBalancer, http::handlers::route::Router};
+use service_async::MakeService;
+
+use crate::{
+    config::{Config, ListenerConfig, ServerProtocolConfig},
+    factory::l7_factory,
+};
+
+/// Check the config file, and report every error found with the field it comes from.
+pub fn check(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let config = Config::load(path)?;
+    let mut errors = Vec::new();
+
+    // Listeners
+    let mut listeners: HashMap<&ListenerConfig, Vec<String>> = HashMap::new();
+    if let Some(admin) = &config.admin {
+        listeners
+            .entry(&admin.listener)
+            .or_default()
+            .push("admin.listener".to_string());
+    }
+    let servers: BTreeMap<_, _> = config.servers.iter().collect();
+    for (key, service) in &servers {
+        listeners
+            .entry(&service.listener)
+            .or_default()
+            .push(format!("servers.{key}.listener"));
+    }
+    for (listener, fields) in &listeners {
+        if let Err(e) = check_listener(listener) {
+            errors.extend(fields.iter().map(|field| format!("{field}: {e}")));
+        }
+        if fields.len() > 1 {
+            errors.push(format!(
+                "{}: listener {listener:?} is used more than once",
+                fields.join(", ")
+            ));
+        }
+    }
+
+    // Services
+    for (key, service) in servers {
+        let routes_valid = match &service.server.protocol {
+            ServerProtocolConfig::Http { routes, .. } => {
+                Router::new_from_iter::<_, ()>(routes.iter().cloned())
+                    .map_err(|e| errors.push(format!("servers.{key}.{e}")))
+                    .is_ok()
+            }
+            ServerProtocolConfig::Thrift { route, .. } => {
+                LoadBalancer::try_from_upstreams(route.load_balancer, route.upstreams.clone())
+                    .map_err(|e| errors.push(format!("servers.{key}.route.upstreams: {e}")))
+                    .is_ok()
+            }
+        };
+        if routes_valid {
+            if let Err(e) = l7_factory(service.server.clone()).make() {
+                errors.push(format!("servers.{key}: build service failed: {e:?}"));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort();
+        for error in &errors {
+            eprintln!("{error}");
+        }
+        bail!("{} error(s) found in config {path:?}", errors.len());
+    }
+    Ok(())
+}
+
+// Dry run of building the listener.
+fn check_listener(listener: &ListenerConfig) -> anyhow::Result<()> {
+    match listener {
+        ListenerConfig::Socket(_) => {}
+        ListenerConfig::Unix(path) => match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                bail!("directory {dir:?} of the socket does not exist")
+            }
+            _ => {}
+        },
+        // The sockets are only passed to the service started by systemd.
+        ListenerConfig::Systemd(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_health_checks() {
+        // The services are built outside of a runtime, which the health checks must not need.
+        let path = std::env::temp_dir().join(format!("monolake-check-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [runtime]
+            runtime_type = "legacy"
+            worker_threads = 1
+
+            [servers.http]
+            name = "http"
+            proxy_type = "http"
+            listener = { type = "socket", value = "127.0.0.1:18080" }
+
+            [[servers.http.routes]]
+            path = "/"
+            health_check = { type = "http", path = "/healthz" }
+            upstreams = [{ endpoint = { type = "uri", value = "http://127.0.0.1:9080" } }]
+
+            [servers.thrift]
+            name = "thrift"
+            proxy_type = "thrift"
+            listener = { type = "socket", value = "127.0.0.1:18081" }
+            route.health_check = { type = "thrift" }
+            route.upstreams = [{ endpoint = { type = "socket", value = "127.0.0.1:9081" } }]
+            "#,
+        )
+        .unwrap();
+        let result = check(&path);
+        let _ = std::fs::remove_file(&path);
+        result.unwrap();
+    }
+}
@@ -1,15 +1,28 @@
 use certain_map::Param;
+#[cfg(feature = "jwt")]
+use monolake_services::http::handlers::jwt::JwtConfig;
 #[cfg(feature = "openid")]
 use monolake_services::http::handlers::openid::OpenIdConfig;
 use monolake_services::{
+    common::resolver::ResolverConfig,
     http::{
-        handlers::{route::RouteConfig as HttpRouteConfig, upstream::HttpUpstreamTimeout},
+        handlers::{
+            ext_authz::ExtAuthzConfig, route::RouteConfig as HttpRouteConfig,
+            upstream::HttpUpstreamTimeout,
+        },
         HttpServerTimeout, HttpVersion,
     },
     thrift::{ttheader::ThriftServerTimeout, RouteConfig as ThriftRouteConfig},
 };
 
-use super::ServerConfig;
+use super::{AdminConfig, ServerConfig};
+
+impl Param<HttpServerTimeout> for AdminConfig {
+    #[inline]
+    fn param(&self) -> HttpServerTimeout {
+        HttpServerTimeout::default()
+    }
+}
 
 impl Param<HttpServerTimeout> for ServerConfig {
     #[inline]
@@ -56,6 +69,13 @@ impl Param<Option<OpenIdConfig>> for ServerConfig {
     }
 }
 
+#[cfg(feature = "jwt")]
+impl Param<Option<JwtConfig>> for ServerConfig {
+    fn param(&self) -> Option<JwtConfig> {
+        self.jwt_config.clone()
+    }
+}
+
 impl Param<Vec<HttpRouteConfig>> for ServerConfig {
     #[inline]
     fn param(&self) -> Vec<HttpRouteConfig> {
@@ -68,6 +88,28 @@ impl Param<Vec<HttpRouteConfig>> for ServerConfig {
     }
 }
 
+impl Param<Option<ExtAuthzConfig>> for ServerConfig {
+    #[inline]
+    fn param(&self) -> Option<ExtAuthzConfig> {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { ext_authz, .. } => ext_authz.as_deref().cloned(),
+            super::ServerProtocolConfig::Thrift { .. } => None,
+        }
+    }
+}
+
+impl Param<ResolverConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> ResolverConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { resolver, .. } => resolver.as_ref().clone(),
+            super::ServerProtocolConfig::Thrift { .. } => {
+                panic!("extract resolver config from thrift config")
+            }
+        }
+    }
+}
+
 impl Param<ThriftRouteConfig> for ServerConfig {
     #[inline]
     fn param(&self) -> ThriftRouteConfig {
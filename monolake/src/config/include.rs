@@ -0,0 +1,274 @@
+//! Config split into several files.
+//!
+//! The main config file can include other files with `include = ["servers.d/*.toml"]`. The
+//! patterns are relative to the directory of the main file, and a directory includes all the
+//! `.toml` and `.json` files in it. Included files only have a `servers` section, which is merged
+//! into the one of the main file. A server key can only be defined once.
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use glob::{MatchOptions, Pattern};
+use monolake_core::config::ServiceConfig;
+use serde::Deserialize;
+
+use super::{interpolated_paths, parse_from_slice, ListenerConfig, ServerUserConfig, UserConfig};
+
+// `*` does not match `/`, as when expanding the patterns.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// The main config file and the files it includes, with their content.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigFiles {
+    main: (PathBuf, Vec<u8>),
+    includes: Vec<Include>,
+    included: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl ConfigFiles {
+    pub fn read_sync(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = monolake_core::util::file_read_sync(path)?;
+        let (includes, paths) = resolve_includes(path, &content)?;
+        let mut included = Vec::with_capacity(paths.len());
+        for path in paths {
+            let content = monolake_core::util::file_read_sync(&path)
+                .with_context(|| format!("read included config {path:?} failed"))?;
+            included.push((path, content));
+        }
+        Ok(Self {
+            main: (path.to_path_buf(), content),
+            includes,
+            included,
+        })
+    }
+
+    pub async fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = monolake_core::util::file_read(path).await?;
+        let (includes, paths) = resolve_includes(path, &content)?;
+        let mut included = Vec::with_capacity(paths.len());
+        for path in paths {
+            let content = monolake_core::util::file_read(&path)
+                .await
+                .with_context(|| format!("read included config {path:?} failed"))?;
+            included.push((path, content));
+        }
+        Ok(Self {
+            main: (path.to_path_buf(), content),
+            includes,
+            included,
+        })
+    }
+
+    /// The include patterns of the main file.
+    pub fn includes(&self) -> &[Include] {
+        &self.includes
+    }
+
+    /// Parse the config, with the servers of the included files merged.
+    pub fn user_config(&self) -> anyhow::Result<UserConfig> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct IncludedConfig {
+            #[serde(default)]
+            servers: BTreeMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
+        }
+
+        let (main_path, content) = &self.main;
+        let mut config = parse_from_slice::<UserConfig>(content)?;
+        config.include.clear();
+        let mut origins: HashMap<String, &Path> = config
+            .servers
+            .keys()
+            .map(|key| (key.clone(), main_path.as_path()))
+            .collect();
+        for (path, content) in &self.included {
+            let included = parse_from_slice::<IncludedConfig>(content)
+                .with_context(|| format!("parse included config {path:?} failed"))?;
+            for (key, server) in included.servers {
+                if let Some(origin) = origins.insert(key.clone(), path) {
+                    bail!("server {key} in {path:?} is already defined in {origin:?}");
+                }
+                config.servers.insert(key, server);
+            }
+        }
+        Ok(config)
+    }
+
+    /// The paths in the [`UserConfig`] of the string values with values to interpolate, which
+    /// may be secrets.
+    pub fn interpolated(&self) -> anyhow::Result<Vec<Vec<String>>> {
+        let (_, content) = &self.main;
+        let mut paths = interpolated_paths(content)?;
+        for (path, content) in &self.included {
+            let included = interpolated_paths(content)
+                .with_context(|| format!("parse included config {path:?} failed"))?;
+            paths.extend(included);
+        }
+        Ok(paths)
+    }
+}
+
+/// A pattern of included files, resolved against the directory of the main config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    pattern: Pattern,
+    // The deepest directory of the pattern without wildcards.
+    dir: PathBuf,
+    // Whether the pattern goes through subdirectories of `dir`.
+    recursive: bool,
+    // Whether the include is a directory, of which the config files are included.
+    directory: bool,
+}
+
+impl Include {
+    fn resolve(base: &Path, include: &str) -> anyhow::Result<Self> {
+        let path = base.join(include);
+        if path.is_dir() {
+            let path = path.canonicalize()?;
+            let pattern = format!("{}/*", Pattern::escape(path_str(&path)?));
+            return Ok(Self {
+                pattern: Pattern::new(&pattern)?,
+                dir: path,
+                recursive: false,
+                directory: true,
+            });
+        }
+
+        let mut dir = PathBuf::new();
+        let mut rest = Vec::new();
+        for component in path.components() {
+            let component = component.as_os_str();
+            if rest.is_empty() && !has_wildcard(component.to_string_lossy().as_ref()) {
+                dir.push(component);
+            } else {
+                rest.push(component.to_string_lossy().into_owned());
+            }
+        }
+        if rest.is_empty() {
+            if !path.is_file() {
+                bail!("included config {path:?} does not exist");
+            }
+            if let Some(file_name) = path.file_name() {
+                rest.push(file_name.to_string_lossy().into_owned());
+                dir.pop();
+            }
+        }
+        // Watched paths are canonical, so are the patterns matching them.
+        let dir = dir.canonicalize().unwrap_or(dir);
+        let pattern = format!("{}/{}", Pattern::escape(path_str(&dir)?), rest.join("/"));
+        Ok(Self {
+            pattern: Pattern::new(&pattern)
+                .with_context(|| format!("invalid include pattern {include:?}"))?,
+            dir,
+            recursive: rest.len() > 1,
+            directory: false,
+        })
+    }
+
+    /// The directory to watch for changes of the included files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether the subdirectories of [`Include::dir`] are to be watched as well.
+    pub fn is_recursive(&self) -> bool {
+        self.recursive
+    }
+
+    /// Whether the file is included.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.pattern.matches_path_with(path, MATCH_OPTIONS)
+            && (!self.directory || is_config_file(path))
+    }
+
+    fn paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for path in glob::glob_with(self.pattern.as_str(), MATCH_OPTIONS)? {
+            let path = path?;
+            if path.is_file() && (!self.directory || is_config_file(&path)) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+// The includes of the main file, and the files they include in order.
+fn resolve_includes(path: &Path, content: &[u8]) -> anyhow::Result<(Vec<Include>, Vec<PathBuf>)> {
+    #[derive(Deserialize)]
+    struct IncludeContainer {
+        #[serde(default)]
+        include: Vec<String>,
+    }
+
+    let container = parse_from_slice::<IncludeContainer>(content)?;
+    if container.include.is_empty() {
+        return Ok(Default::default());
+    }
+    let base = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+    .canonicalize()?;
+    let main = path.canonicalize()?;
+
+    let mut includes = Vec::with_capacity(container.include.len());
+    let mut paths = Vec::new();
+    for include in &container.include {
+        let include = Include::resolve(&base, include)?;
+        for path in include.paths()? {
+            if path != main && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        includes.push(include);
+    }
+    Ok((includes, paths))
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?', '['])
+}
+
+fn is_config_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("toml" | "json")
+    )
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| anyhow!("invalid include path {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_matches() {
+        let base = Path::new("/nonexistent/monolake");
+        let include = Include::resolve(base, "servers.d/*.toml").unwrap();
+        assert_eq!(include.dir(), base.join("servers.d"));
+        assert!(!include.is_recursive());
+        assert!(include.matches(&base.join("servers.d/a.toml")));
+        assert!(!include.matches(&base.join("servers.d/a.json")));
+        assert!(!include.matches(&base.join("servers.d/team/a.toml")));
+
+        let include = Include::resolve(base, "teams/**/*.toml").unwrap();
+        assert_eq!(include.dir(), base.join("teams"));
+        assert!(include.is_recursive());
+        assert!(include.matches(&base.join("teams/a/b/servers.toml")));
+
+        assert!(Include::resolve(base, "servers.toml").is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Interpolation of the string values of the config.
+//!
+//! In the string values, `${NAME}` is replaced with the value of the environment variable `NAME`,
+//! and `${file:/path}` with the content of the file, without its trailing newline, e.g. a secret
+//! mounted by the deployment. `$${` is replaced with a literal `${`.
+//!
+//! The values are interpolated once the config is parsed, so the comments, the keys and the
+//! values other than strings are left as written, and an interpolated value cannot change the
+//! structure of the config whatever it contains. Since they may be secrets, the interpolated
+//! values are redacted by `monolake dump`.
+use anyhow::{anyhow, bail, Context};
+
+const FILE_PREFIX: &str = "file:";
+
+/// The value printed in place of the interpolated values.
+const REDACTED: &str = "<redacted>";
+
+/// Whether `content` may have values to interpolate, so that it needs to be parsed before it is
+/// deserialized.
+pub fn needed(content: &[u8]) -> bool {
+    content.windows(2).any(|w| w == b"${")
+}
+
+/// A parsed config, of which the string values can be interpolated.
+pub trait Strings {
+    /// Calls `f` with the path and the content of every string value.
+    fn visit(
+        &mut self,
+        path: &mut Vec<String>,
+        f: &mut dyn FnMut(&[String], &mut String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>;
+}
+
+impl Strings for serde_json::Value {
+    fn visit(
+        &mut self,
+        path: &mut Vec<String>,
+        f: &mut dyn FnMut(&[String], &mut String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::String(value) => f(path, value),
+            Self::Array(values) => visit_all(path, values.iter_mut().enumerate(), f),
+            Self::Object(values) => visit_all(path, values.iter_mut(), f),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Strings for toml::Value {
+    fn visit(
+        &mut self,
+        path: &mut Vec<String>,
+        f: &mut dyn FnMut(&[String], &mut String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::String(value) => f(path, value),
+            Self::Array(values) => visit_all(path, values.iter_mut().enumerate(), f),
+            Self::Table(values) => visit_all(path, values.iter_mut(), f),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn visit_all<'a, K: ToString, V: Strings + 'a>(
+    path: &mut Vec<String>,
+    values: impl Iterator<Item = (K, &'a mut V)>,
+    f: &mut dyn FnMut(&[String], &mut String) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    for (key, value) in values {
+        path.push(key.to_string());
+        let result = value.visit(path, f);
+        path.pop();
+        result?;
+    }
+    Ok(())
+}
+
+/// Interpolate the string values of `config`.
+pub fn interpolate(config: &mut impl Strings) -> anyhow::Result<()> {
+    config.visit(&mut Vec::new(), &mut |path, value| {
+        replace(value, resolve)
+            .with_context(|| format!("interpolate {} failed", path.join(".")))
+            .map(drop)
+    })
+}
+
+/// The paths of the string values of `config` with values to interpolate.
+pub fn interpolated(config: &mut impl Strings) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut paths = Vec::new();
+    config.visit(&mut Vec::new(), &mut |path, value| {
+        if replace(&mut value.clone(), |_| Ok(String::new()))? {
+            paths.push(path.to_vec());
+        }
+        Ok(())
+    })?;
+    Ok(paths)
+}
+
+/// Replace the string values of `config` at `paths` with [`REDACTED`].
+pub fn redact(config: &mut impl Strings, paths: &[Vec<String>]) {
+    let _ = config.visit(&mut Vec::new(), &mut |path, value| {
+        if paths.iter().any(|redacted| redacted == path) {
+            *value = REDACTED.to_string();
+        }
+        Ok(())
+    });
+}
+
+// Replaces the expressions of `value` with what `resolve` returns for them, and `$${` with `${`.
+// Returns whether `value` has expressions.
+fn replace(
+    value: &mut String,
+    mut resolve: impl FnMut(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<bool> {
+    if !value.contains("${") {
+        return Ok(false);
+    }
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value.as_str();
+    let mut interpolated = false;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated interpolation at {:?}", &rest[start..]))?;
+        output.push_str(&resolve(&rest[start + 2..start + end])?);
+        interpolated = true;
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+    *value = output;
+    Ok(interpolated)
+}
+
+fn resolve(expr: &str) -> anyhow::Result<String> {
+    if let Some(path) = expr.strip_prefix(FILE_PREFIX) {
+        let mut value = std::fs::read_to_string(path)
+            .with_context(|| format!("read file {path:?} of ${{{expr}}} failed"))?;
+        let len = value.trim_end_matches(['\r', '\n']).len();
+        value.truncate(len);
+        return Ok(value);
+    }
+
+    let valid = expr.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && expr.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("invalid interpolation ${{{expr}}}");
+    }
+    std::env::var(expr)
+        .with_context(|| format!("environment variable {expr} of ${{{expr}}} is not set"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        std::env::set_var("MONOLAKE_TEST_HOST", "example.com");
+        std::env::set_var("MONOLAKE_TEST_SECRET", "a\"b\\c\n[x]");
+        let path = std::env::temp_dir().join(format!("monolake-test-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+
+        // Only the string values are interpolated, whatever their quotes.
+        let content = format!(
+            r#"
+            # ${{MONOLAKE_TEST_UNSET}} in a comment
+            uri = "http://${{MONOLAKE_TEST_HOST}}/"
+            secret = '${{MONOLAKE_TEST_SECRET}}'
+            file = "${{file:{}}}"
+            literal = "$${{HOME}}"
+            list = ["${{MONOLAKE_TEST_HOST}}", 1]
+            "#,
+            path.display()
+        );
+        let mut config = toml::Value::Table(toml::from_str(&content).unwrap());
+        let paths = interpolated(&mut config).unwrap();
+        assert_eq!(
+            paths,
+            [vec!["file"], vec!["list", "0"], vec!["secret"], vec!["uri"]]
+        );
+        interpolate(&mut config).unwrap();
+        let expected = toml::Value::Table(
+            toml::from_str(
+                r#"
+                uri = "http://example.com/"
+                secret = "a\"b\\c\n[x]"
+                file = "secret"
+                literal = "${HOME}"
+                list = ["example.com", 1]
+                "#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(config, expected);
+        std::fs::remove_file(path).unwrap();
+
+        redact(&mut config, &paths);
+        assert_eq!(config["uri"].as_str(), Some(REDACTED));
+        assert_eq!(config["list"][0].as_str(), Some(REDACTED));
+        assert_eq!(config["literal"].as_str(), Some("${HOME}"));
+
+        let mut config = serde_json::json!({ "a": { "b": ["${MONOLAKE_TEST_HOST}"] } });
+        interpolate(&mut config).unwrap();
+        assert_eq!(config, serde_json::json!({ "a": { "b": ["example.com"] } }));
+
+        let error = interpolate(&mut serde_json::json!({ "a": ["${MONOLAKE_TEST_UNSET}"] }));
+        assert!(error.unwrap_err().to_string().contains("a.0"));
+        assert!(interpolate(&mut serde_json::json!("${MONOLAKE_TEST_HOST")).is_err());
+        assert!(interpolate(&mut serde_json::json!("${not valid}")).is_err());
+    }
+}
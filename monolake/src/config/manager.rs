@@ -3,27 +3,56 @@ use std::{
     collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::Arc,
-    time::Duration,
 };
 
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::StreamExt;
+use local_sync::{mpsc::unbounded::Rx, oneshot};
 use monoio::spawn;
 use monolake_core::{
     config::ServiceConfig,
     orchestrator::{ServiceCommand, WorkerManager},
+    AnyError,
 };
 use service_async::AsyncMakeService;
+use signal_hook::consts::SIGHUP;
 
-use crate::config::{Config, ListenerConfig, ServerConfig};
+use crate::config::{
+    build_server_config,
+    watcher::{is_terminate_signal, ConfigWatcher, WatchEvent},
+    Config, ConfigFiles, ListenerConfig, ServerConfig,
+};
+
+pub type ServiceConfigMap = HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>;
 
-type ServiceConfigMap = HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>;
+/// Commands accepted by a running [`StaticFileConfigManager`], e.g. from the admin API.
+pub enum ManagerCommand {
+    /// Replace the online services with the given content, which has the same format as the
+    /// `servers` section of the config file. Pushed services stay online until the config file
+    /// changes.
+    ApplyServices(Vec<u8>, oneshot::Sender<anyhow::Result<()>>),
+    /// Get the online services.
+    GetServices(oneshot::Sender<ServicesState>),
+}
+
+/// Snapshot of the services deployed on every worker.
+pub struct ServicesState {
+    pub workers: usize,
+    pub services: ServiceConfigMap,
+}
 
 pub struct StaticFileConfigManager<F, LF, FP, LFP>
 where
     FP: Fn(ServerConfig) -> F,
-    LFP: Fn(ListenerConfig) -> LF,
+    LFP: Fn(ListenerConfig) -> anyhow::Result<LF>,
 {
-    online_config_content: RefCell<Vec<u8>>,
+    online_config_files: RefCell<ConfigFiles>,
     online_services: RefCell<ServiceConfigMap>,
+    // Listener factories of the online services, which keep their sockets open if they hold
+    // any, e.g. to hand them off on upgrade.
+    online_listeners: HashMap<String, LF>,
+    // Listener factories of the reload being committed.
+    staged_listeners: HashMap<String, LF>,
     worker_manager: WorkerManager<F, LF>,
     listener_factory_provider: LFP,
     server_factory_provider: FP,
@@ -37,7 +66,7 @@ where
     LFP: 'static,
     F: AsyncMakeService,
     FP: Fn(ServerConfig) -> F,
-    LFP: Fn(ListenerConfig) -> LF,
+    LFP: Fn(ListenerConfig) -> anyhow::Result<LF>,
 {
     pub fn new(
         worker_manager: WorkerManager<F, LF>,
@@ -45,55 +74,89 @@ where
         server_factory_provider: FP,
     ) -> Self {
         Self {
-            online_config_content: Default::default(),
+            online_config_files: Default::default(),
             online_services: Default::default(),
+            online_listeners: Default::default(),
+            staged_listeners: Default::default(),
             worker_manager,
             listener_factory_provider,
             server_factory_provider,
         }
     }
 
-    pub async fn load_and_watch(mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
-        self.reload_file(&path).await?;
-        self.watch(path.as_ref().to_path_buf()).await;
+    /// Load the config file and apply it to the workers.
+    pub async fn load(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.reload_file(&path, false).await?;
+        tracing::info!("init config broadcast successfully");
         Ok(())
     }
 
-    async fn reload_file(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
-        let latest_content = monolake_core::util::file_read(path).await?;
-        if self.online_config_content.borrow().eq(&latest_content) {
+    /// Reload the config file and the files it includes. Unless `force` is set, the reload is
+    /// skipped if their content is unchanged.
+    async fn reload_file(&mut self, path: impl AsRef<Path>, force: bool) -> anyhow::Result<()> {
+        let latest_files = ConfigFiles::read(path).await?;
+        if !force && self.online_config_files.borrow().eq(&latest_files) {
             return Ok(());
         }
 
         tracing::info!("config change detected, reloading");
-        let new_services = Config::parse_service_config(&latest_content)?;
+        let new_services = build_server_config(latest_files.user_config()?.servers)?;
+        self.apply_services(new_services).await?;
+        self.online_config_files.replace(latest_files);
+        Ok(())
+    }
+
+    async fn apply_services(&mut self, new_services: ServiceConfigMap) -> anyhow::Result<()> {
         self.reload_services(&new_services).await?;
 
         tracing::info!("config reload success");
-        self.online_config_content.replace(latest_content);
         self.online_services.replace(new_services);
         Ok(())
     }
 
-    async fn reload_services(&mut self, new_services: &ServiceConfigMap) -> anyhow::Result<()> {
-        let patches = Self::diff(&self.online_services.borrow(), new_services);
-        match self.prepare(&patches).await {
-            Ok(_) => {
-                self.commit(&patches)
-                    .await
-                    .expect("config reload failed at commit stage");
-                Ok(())
+    async fn handle_command(&mut self, command: ManagerCommand) {
+        match command {
+            ManagerCommand::ApplyServices(content, result_tx) => {
+                tracing::info!("service config pushed, reloading");
+                let result = match Config::parse_service_config(&content) {
+                    Ok(new_services) => self.apply_services(new_services).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = &result {
+                    tracing::error!("apply pushed config failed: {}", e);
+                }
+                let _ = result_tx.send(result);
             }
-            Err(e) => {
-                tracing::error!("config reload failed at prepare stage: {}, aborting", e);
-                self.abort(&patches)
-                    .await
-                    .expect("abort config reload failed");
-                Err(e)
+            ManagerCommand::GetServices(state_tx) => {
+                let _ = state_tx.send(ServicesState {
+                    workers: self.worker_manager.config().worker_threads,
+                    services: self.online_services.borrow().clone(),
+                });
             }
         }
     }
 
+    async fn reload_services(&mut self, new_services: &ServiceConfigMap) -> anyhow::Result<()> {
+        let patches = Self::diff(&self.online_services.borrow(), new_services);
+        if let Err(e) = self.prepare(&patches).await {
+            tracing::error!("config reload failed at prepare stage: {}, aborting", e);
+            self.abort(&patches)
+                .await
+                .expect("abort config reload failed");
+            return Err(e);
+        }
+        if let Err(e) = self.commit(&patches).await {
+            tracing::error!("config reload failed at commit stage: {}, rolling back", e);
+            self.rollback(&patches).await;
+            self.abort(&patches)
+                .await
+                .expect("abort config reload failed");
+            return Err(e.into());
+        }
+        self.finalize(&patches).await;
+        Ok(())
+    }
+
     fn diff(old_services: &ServiceConfigMap, new_services: &ServiceConfigMap) -> Vec<Patch> {
         let mut patches = Vec::new();
 
@@ -104,9 +167,13 @@ where
             let patch = match (old_keys.contains(key), new_keys.contains(key)) {
                 (true, true) => {
                     // TODO: Skip keys whose configuration didn't change
+                    let old_config = old_services.get(*key).unwrap();
                     let new_config = new_services.get(*key).unwrap();
+                    let listener_config = (old_config.listener != new_config.listener)
+                        .then(|| new_config.listener.clone());
                     Patch::Update {
                         key: key.to_string(),
+                        listener_config,
                         server_config: new_config.server.clone(),
                     }
                 }
@@ -155,37 +222,127 @@ where
         Ok(())
     }
 
-    async fn commit(&mut self, patches: &[Patch]) -> anyhow::Result<()> {
+    /// Deploy the prepared services on every worker. It stops at the first service that fails
+    /// on any worker, and the services committed so far can then be rolled back.
+    async fn commit(&mut self, patches: &[Patch]) -> Result<(), CommitError> {
         for patch in patches {
-            match patch {
+            let (key, command) = match patch {
                 Patch::Insert {
                     key,
                     listener_config,
                     ..
+                } => (
+                    key,
+                    ServiceCommand::Commit(
+                        Arc::new(key.to_string()),
+                        self.stage_listener_factory(key, listener_config)?,
+                    ),
+                ),
+                Patch::Update {
+                    key,
+                    listener_config: None,
+                    ..
+                } => (key, ServiceCommand::Update(Arc::new(key.to_string()))),
+                Patch::Update {
+                    key,
+                    listener_config: Some(listener_config),
+                    ..
                 } => {
-                    self.worker_manager
-                        .dispatch_service_command(ServiceCommand::Commit(
+                    tracing::info!("listener of {key} changed to {listener_config:?}");
+                    (
+                        key,
+                        ServiceCommand::Commit(
                             Arc::new(key.to_string()),
-                            (self.listener_factory_provider)(listener_config.clone()),
-                        ))
-                        .await
-                        .err()?;
+                            self.stage_listener_factory(key, listener_config)?,
+                        ),
+                    )
                 }
-                Patch::Update { key, .. } => {
-                    self.worker_manager
-                        .dispatch_service_command(ServiceCommand::Update(Arc::new(key.to_string())))
-                        .await
-                        .err()?;
+                Patch::Delete { .. } => {
+                    // removal can not be rolled back, so it is done at finalize stage
+                    continue;
                 }
-                Patch::Delete { key } => {
-                    self.worker_manager
-                        .dispatch_service_command(ServiceCommand::Remove(Arc::new(key.to_string())))
+            };
+            let errors: Vec<_> =
+                Vec::from(self.worker_manager.dispatch_service_command(command).await)
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(worker, result)| result.err().map(|e| (worker, e)))
+                    .collect();
+            if !errors.is_empty() {
+                return Err(CommitError::Workers {
+                    key: key.to_string(),
+                    errors,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn stage_listener_factory(
+        &mut self,
+        key: &str,
+        listener_config: &ListenerConfig,
+    ) -> Result<LF, CommitError> {
+        let listener_factory =
+            (self.listener_factory_provider)(listener_config.clone()).map_err(|error| {
+                CommitError::Listener {
+                    key: key.to_string(),
+                    error,
+                }
+            })?;
+        self.staged_listeners
+            .insert(key.to_string(), listener_factory.clone());
+        Ok(listener_factory)
+    }
+
+    /// Restore the services replaced at commit stage on every worker.
+    async fn rollback(&mut self, patches: &[Patch]) {
+        self.staged_listeners.clear();
+        for patch in patches {
+            match patch {
+                Patch::Insert { key, .. } | Patch::Update { key, .. } => {
+                    // workers that did not commit the patch have nothing to roll back
+                    if let Err(e) = self
+                        .worker_manager
+                        .dispatch_service_command(ServiceCommand::Rollback(Arc::new(
+                            key.to_string(),
+                        )))
                         .await
-                        .err()?;
+                        .err()
+                    {
+                        tracing::error!("rollback {key} failed: {e}");
+                    }
+                }
+                Patch::Delete { .. } => {
+                    // nothing to do at rollback stage
                 }
             }
         }
-        Ok(())
+    }
+
+    /// Release the services replaced at commit stage and remove deleted services. Previous
+    /// listeners stop only now, after the new ones are serving on every worker.
+    async fn finalize(&mut self, patches: &[Patch]) {
+        for patch in patches {
+            let (key, command) = match patch {
+                Patch::Insert { key, .. } | Patch::Update { key, .. } => {
+                    (key, ServiceCommand::Retire(Arc::new(key.to_string())))
+                }
+                Patch::Delete { key } => (key, ServiceCommand::Remove(Arc::new(key.to_string()))),
+            };
+            if let Err(e) = self
+                .worker_manager
+                .dispatch_service_command(command)
+                .await
+                .err()
+            {
+                tracing::error!("finalize {key} failed: {e}");
+            }
+            if let Patch::Delete { key } = patch {
+                self.online_listeners.remove(key);
+            }
+        }
+        self.online_listeners.extend(self.staged_listeners.drain());
     }
 
     async fn abort(&mut self, patches: &[Patch]) -> anyhow::Result<()> {
@@ -204,19 +361,78 @@ where
         Ok(())
     }
 
-    async fn watch(mut self, path: PathBuf) {
+    /// Apply the changes of the config file and the commands until `SIGTERM` or `SIGINT` is
+    /// received or the listeners are handed off. The workers are asked to shut down when this
+    /// returns.
+    pub async fn watch(
+        mut self,
+        path: PathBuf,
+        (watcher, mut events): (ConfigWatcher, UnboundedReceiver<WatchEvent>),
+        mut commands: Rx<ManagerCommand>,
+    ) {
         spawn(async move {
+            let mut watcher = watcher;
+            watcher.watch_includes(self.online_config_files.borrow().includes());
             loop {
-                if let Err(e) = self.reload_file(&path).await {
-                    tracing::error!("reload config failed: {}", e);
+                monoio::select! {
+                    Some(event) = events.next() => {
+                        // Events usually come in bursts, e.g. several writes for one save, so
+                        // the pending ones are merged into a single reload.
+                        let mut force = false;
+                        let mut event = Some(event);
+                        while let Some(e) = event {
+                            match e {
+                                WatchEvent::Signal(SIGHUP) => {
+                                    tracing::info!("SIGHUP received, reloading config");
+                                    force = true;
+                                }
+                                WatchEvent::Signal(signal) if is_terminate_signal(signal) => {
+                                    tracing::info!("signal {signal} received, shutting down");
+                                    return;
+                                }
+                                WatchEvent::Upgraded => {
+                                    tracing::info!("listeners are handed off, shutting down");
+                                    return;
+                                }
+                                _ => {}
+                            }
+                            event = events.try_next().ok().flatten();
+                        }
+                        if let Err(e) = self.reload_file(&path, force).await {
+                            tracing::error!("reload config failed: {:#}", e);
+                        }
+                        watcher.watch_includes(self.online_config_files.borrow().includes());
+                    }
+                    Some(command) = commands.recv() => self.handle_command(command).await,
                 }
-                monoio::time::sleep(Duration::from_secs(1)).await;
             }
         })
-        .await;
+        .await
     }
 }
 
+/// Error of the commit stage of a config reload. The reload is rolled back on every worker.
+#[derive(thiserror::Error, Debug)]
+pub enum CommitError {
+    /// The listener of a server can not be created.
+    #[error("build listener of {key} failed: {error}")]
+    Listener { key: String, error: AnyError },
+    /// A server failed to commit on some workers, with the worker index of each error.
+    #[error("commit {key} failed on worker(s) {}", display_worker_errors(.errors))]
+    Workers {
+        key: String,
+        errors: Vec<(usize, AnyError)>,
+    },
+}
+
+fn display_worker_errors(errors: &[(usize, AnyError)]) -> String {
+    errors
+        .iter()
+        .map(|(worker, e)| format!("{worker}: {e}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 enum Patch {
     Insert {
         key: String,
@@ -225,7 +441,9 @@ enum Patch {
     },
     Update {
         key: String,
-        server_config: ServerConfig, // ListenerConfig dynamic update not supported yet
+        // Set if the listener is changed.
+        listener_config: Option<ListenerConfig>,
+        server_config: ServerConfig,
     },
     Delete {
         key: String,
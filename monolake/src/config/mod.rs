@@ -1,36 +1,57 @@
-use std::{collections::HashMap, path::Path, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+#[cfg(feature = "tls")]
+use anyhow::Context;
 use monolake_core::{
     config::{RuntimeConfig, ServiceConfig},
     listener::ListenerBuilder,
 };
 use monolake_services::{
+    common::resolver::ResolverConfig,
     http::{
-        handlers::{route::RouteConfig as HttpRouteConfig, upstream::HttpUpstreamTimeout},
+        handlers::{
+            ext_authz::ExtAuthzConfig, route::RouteConfig as HttpRouteConfig,
+            upstream::HttpUpstreamTimeout,
+        },
         HttpServerTimeout, HttpVersion,
     },
     thrift::{ttheader::ThriftServerTimeout, RouteConfig as ThriftRouteConfig},
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
+pub use self::{
+    include::{ConfigFiles, Include},
+    interpolate::redact,
+};
+
 mod extractor;
+mod include;
+mod interpolate;
 pub mod manager;
+pub mod watcher;
 
 #[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct Config {
     pub runtime: RuntimeConfig,
+    pub admin: Option<AdminConfig>,
+    pub upgrade: Option<UpgradeConfig>,
     pub servers: HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-    #[allow(unused)]
     pub name: String,
     #[cfg(feature = "tls")]
     pub tls: monolake_services::tls::TlsConfig,
     #[cfg(feature = "openid")]
     pub auth_config: Option<AuthConfig>,
+    #[cfg(feature = "jwt")]
+    pub jwt_config: Option<monolake_services::http::handlers::jwt::JwtConfig>,
     pub protocol: ServerProtocolConfig,
 }
 
@@ -51,6 +72,8 @@ pub enum ServerProtocolConfig {
         upstream_timeout: HttpUpstreamTimeout,
         upstream_http_version: HttpVersion,
         opt_handlers: HttpOptHandlers,
+        ext_authz: Option<Box<ExtAuthzConfig>>,
+        resolver: Box<ResolverConfig>,
     },
     Thrift {
         route: ThriftRouteConfig,
@@ -68,12 +91,26 @@ pub enum ServerProtocolUserConfig {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerHttpUserConfig {
     pub routes: Vec<HttpRouteConfig>,
-    #[serde(default)]
+    #[serde(default, alias = "http_timeout")]
     pub timeout: HttpTimeout,
     #[serde(default)]
     pub upstream_http_version: HttpVersion,
     #[serde(default)]
     pub http_opt_handlers: HttpOptHandlers,
+    /// External authorization of the requests, before they are routed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ext_authz: Option<Box<ExtAuthzConfig>>,
+    /// Resolution of the hosts of the upstreams.
+    #[serde(default)]
+    pub resolver: Box<ResolverConfig>,
+    /// OpenID Connect authentication of the server, which routes can override.
+    #[cfg(feature = "openid")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Box<AuthConfig>>,
+    /// JWT bearer token validation of the server, which routes can override.
+    #[cfg(feature = "jwt")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt: Option<Box<monolake_services::http::handlers::jwt::JwtConfig>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -105,8 +142,17 @@ pub struct HttpTimeout {
     // Connect timeout
     // Like Nginx 'proxy_connect_timeout'
     upstream_connect_timeout_sec: Option<u64>,
-    // Read response timeout
+    // Read response timeout, between two reads
+    // Like Nginx `proxy_read_timeout`
     upstream_read_timeout_sec: Option<u64>,
+    // Sending the full request, with its body
+    upstream_write_timeout_sec: Option<u64>,
+    // Receiving the response headers once the request is sent
+    upstream_response_header_timeout_sec: Option<u64>,
+    // Receiving the full response once the request is sent
+    upstream_response_timeout_sec: Option<u64>,
+    // The whole exchange with the upstream
+    upstream_total_timeout_sec: Option<u64>,
 }
 
 impl From<HttpTimeout> for HttpServerTimeout {
@@ -123,6 +169,12 @@ impl From<HttpTimeout> for HttpUpstreamTimeout {
         HttpUpstreamTimeout {
             connect_timeout: t.upstream_connect_timeout_sec.map(Duration::from_secs),
             read_timeout: t.upstream_read_timeout_sec.map(Duration::from_secs),
+            write_timeout: t.upstream_write_timeout_sec.map(Duration::from_secs),
+            response_header_timeout: t
+                .upstream_response_header_timeout_sec
+                .map(Duration::from_secs),
+            response_timeout: t.upstream_response_timeout_sec.map(Duration::from_secs),
+            total_timeout: t.upstream_total_timeout_sec.map(Duration::from_secs),
         }
     }
 }
@@ -163,11 +215,42 @@ pub struct HttpOptHandlers {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AuthConfig(pub monolake_services::http::handlers::openid::OpenIdConfig);
 
+/// Config of the admin API, which is served on its own listener by the main thread.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AdminConfig {
+    pub listener: ListenerConfig,
+}
+
+/// Config of the binary upgrade, see [`crate::upgrade`].
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UpgradeConfig {
+    /// Path of the Unix domain socket a new process takes over the listeners from.
+    pub socket: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(tag = "type", content = "value", rename_all = "snake_case")]
 pub enum ListenerConfig {
     Socket(std::net::SocketAddr),
     Unix(std::path::PathBuf),
+    Systemd(SystemdSocket),
+}
+
+/// A socket passed by systemd socket activation, by its `FileDescriptorName=` or its index.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum SystemdSocket {
+    Index(usize),
+    Name(String),
+}
+
+impl std::fmt::Display for SystemdSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SystemdSocket::Index(index) => write!(f, "#{index}"),
+            SystemdSocket::Name(name) => write!(f, "{name:?}"),
+        }
+    }
 }
 
 impl TryFrom<ListenerConfig> for ListenerBuilder {
@@ -177,32 +260,54 @@ impl TryFrom<ListenerConfig> for ListenerBuilder {
         match value {
             ListenerConfig::Socket(addr) => ListenerBuilder::bind_tcp(addr, Default::default()),
             ListenerConfig::Unix(addr) => ListenerBuilder::bind_unix(addr),
+            ListenerConfig::Systemd(socket) => {
+                ListenerBuilder::from_fd(crate::systemd::socket_fd(&socket)?)
+            }
         }
     }
 }
 
+/// The config file as written by the user, with the defaults filled in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserConfig {
+    /// Patterns of the files whose servers are merged, see [`ConfigFiles`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    pub admin: Option<AdminConfig>,
+    pub upgrade: Option<UpgradeConfig>,
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
+}
+
 impl Config {
-    #[allow(unused)]
     pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
-        #[derive(Deserialize)]
-        struct UserConfig {
-            #[serde(default)]
-            runtime: RuntimeConfig,
-            servers: HashMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
-        }
         // 1. load from file -> UserConfig
-        let file_context = monolake_core::util::file_read_sync(path)?;
-        let user_config = parse_from_slice::<UserConfig>(&file_context)?;
+        let user_config = Self::load_user_config(path)?;
 
         // 2. UserConfig -> Config
-        let UserConfig { runtime, servers } = user_config;
+        let UserConfig {
+            include: _,
+            runtime,
+            admin,
+            upgrade,
+            servers,
+        } = user_config;
         let servers_new = build_server_config(servers)?;
         Ok(Config {
             runtime,
+            admin,
+            upgrade,
             servers: servers_new,
         })
     }
 
+    /// Load the config file, with the servers of the files it includes.
+    pub fn load_user_config(path: impl AsRef<Path>) -> anyhow::Result<UserConfig> {
+        ConfigFiles::read_sync(path)?.user_config()
+    }
+
     pub fn load_runtime_config(path: impl AsRef<Path>) -> anyhow::Result<RuntimeConfig> {
         #[derive(Deserialize)]
         struct RuntimeConfigContainer {
@@ -213,11 +318,32 @@ impl Config {
         Ok(container.runtime)
     }
 
+    pub fn load_admin_config(path: impl AsRef<Path>) -> anyhow::Result<Option<AdminConfig>> {
+        #[derive(Deserialize)]
+        struct AdminConfigContainer {
+            admin: Option<AdminConfig>,
+        }
+        let file_content = monolake_core::util::file_read_sync(path)?;
+        let container = parse_from_slice::<AdminConfigContainer>(&file_content)?;
+        Ok(container.admin)
+    }
+
+    pub fn load_upgrade_config(path: impl AsRef<Path>) -> anyhow::Result<Option<UpgradeConfig>> {
+        #[derive(Deserialize)]
+        struct UpgradeConfigContainer {
+            upgrade: Option<UpgradeConfig>,
+        }
+        let file_content = monolake_core::util::file_read_sync(path)?;
+        let container = parse_from_slice::<UpgradeConfigContainer>(&file_content)?;
+        Ok(container.upgrade)
+    }
+
     pub fn parse_service_config(
         file_content: &[u8],
     ) -> anyhow::Result<HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>> {
         #[derive(Deserialize)]
         struct UserConfigContainer {
+            #[serde(default)]
             servers: HashMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
         }
 
@@ -227,28 +353,27 @@ impl Config {
 }
 
 pub fn build_server_config(
-    servers: HashMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
+    servers: impl IntoIterator<Item = (String, ServiceConfig<ListenerConfig, ServerUserConfig>)>,
 ) -> anyhow::Result<HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>> {
-    let mut servers_new = HashMap::with_capacity(servers.len());
+    let mut servers_new = HashMap::new();
     for (key, server) in servers.into_iter() {
         let ServiceConfig { listener, server } = server;
         #[cfg(feature = "tls")]
         let tls = match server.tls {
-            Some(inner) => {
-                let chain = monolake_core::util::file_read_sync(&inner.chain)?;
-                let key = monolake_core::util::file_read_sync(&inner.key)?;
-                match inner.stack {
-                    TlsStack::Rustls => {
-                        monolake_services::tls::TlsConfig::Rustls((chain, key)).try_into()?
-                    }
-                    TlsStack::NativeTls => {
-                        monolake_services::tls::TlsConfig::Native((chain, key)).try_into()?
-                    }
-                }
-            }
+            Some(inner) => load_tls_config(inner).with_context(|| format!("servers.{key}.tls"))?,
             None => monolake_services::tls::TlsConfig::None,
         };
 
+        #[cfg(feature = "openid")]
+        let auth_config = match &server.protocol_config {
+            ServerProtocolUserConfig::Http(http) => http.auth.as_deref().cloned(),
+            ServerProtocolUserConfig::Thrift(_) => None,
+        };
+        #[cfg(feature = "jwt")]
+        let jwt_config = match &server.protocol_config {
+            ServerProtocolUserConfig::Http(http) => http.jwt.as_deref().cloned(),
+            ServerProtocolUserConfig::Thrift(_) => None,
+        };
         let protocol = match server.protocol_config {
             ServerProtocolUserConfig::Http(http) => {
                 let routes = http.routes;
@@ -262,6 +387,8 @@ pub fn build_server_config(
                     upstream_timeout,
                     upstream_http_version,
                     opt_handlers,
+                    ext_authz: http.ext_authz,
+                    resolver: http.resolver,
                 }
             }
             ServerProtocolUserConfig::Thrift(thrift) => ServerProtocolConfig::Thrift {
@@ -277,7 +404,9 @@ pub fn build_server_config(
                 #[cfg(feature = "tls")]
                 tls,
                 #[cfg(feature = "openid")]
-                auth_config: None,
+                auth_config,
+                #[cfg(feature = "jwt")]
+                jwt_config,
                 protocol,
             },
         };
@@ -286,17 +415,63 @@ pub fn build_server_config(
     Ok(servers_new)
 }
 
+#[cfg(feature = "tls")]
+fn load_tls_config(config: TlsUserConfig) -> anyhow::Result<monolake_services::tls::TlsConfig> {
+    let chain = monolake_core::util::file_read_sync(&config.chain)
+        .with_context(|| format!("read chain {:?} failed", config.chain))?;
+    let key = monolake_core::util::file_read_sync(&config.key)
+        .with_context(|| format!("read key {:?} failed", config.key))?;
+    let tls = match config.stack {
+        TlsStack::Rustls => monolake_services::tls::TlsConfig::Rustls((chain, key)).try_into()?,
+        TlsStack::NativeTls => {
+            monolake_services::tls::TlsConfig::Native((chain, key)).try_into()?
+        }
+    };
+    Ok(tls)
+}
+
 pub fn parse_from_slice<T: DeserializeOwned>(content: &[u8]) -> anyhow::Result<T> {
+    // The content is only parsed before being deserialized when it may have values to
+    // interpolate, so that the errors keep their position otherwise.
+    let interpolate = interpolate::needed(content);
+    match is_json(content) {
+        true if interpolate => {
+            let mut value: serde_json::Value = serde_json::from_slice(content)?;
+            interpolate::interpolate(&mut value)?;
+            serde_json::from_value(value).map_err(Into::into)
+        }
+        true => serde_json::from_slice::<T>(content).map_err(Into::into),
+        false if interpolate => {
+            let mut value = toml::Value::Table(toml::from_str(&String::from_utf8_lossy(content))?);
+            interpolate::interpolate(&mut value)?;
+            value.try_into().map_err(Into::into)
+        }
+        false => toml::from_str::<T>(&String::from_utf8_lossy(content)).map_err(Into::into),
+    }
+}
+
+/// The paths of the string values of a config file with values to interpolate.
+fn interpolated_paths(content: &[u8]) -> anyhow::Result<Vec<Vec<String>>> {
+    if !interpolate::needed(content) {
+        return Ok(Vec::new());
+    }
+    match is_json(content) {
+        true => {
+            interpolate::interpolated(&mut serde_json::from_slice::<serde_json::Value>(content)?)
+        }
+        false => interpolate::interpolated(&mut toml::Value::Table(toml::from_str(
+            &String::from_utf8_lossy(content),
+        )?)),
+    }
+}
+
+fn is_json(content: &[u8]) -> bool {
     // read first non-space u8
-    let is_json = match content
+    match content
         .iter()
         .find(|&&b| b != b' ' && b != b'\r' && b != b'\n' && b != b'\t')
     {
         Some(first) => *first == b'{',
         None => false,
-    };
-    match is_json {
-        true => serde_json::from_slice::<T>(content).map_err(Into::into),
-        false => toml::from_str::<T>(&String::from_utf8_lossy(content)).map_err(Into::into),
     }
 }
@@ -0,0 +1,236 @@
+//! Notifications that trigger a config reload.
+//!
+//! Changes of the config file and the files it includes are detected with the platform file
+//! notification mechanism (inotify on Linux), and a reload can also be requested explicitly with
+//! `SIGHUP`. Both are sent to the config manager through the same channel, so idle instances do no
+//! file I/O at all.
+//!
+//! The config file is also checked again on any event in its directory, since it may change
+//! without an event of its own when it is a symlink whose target is swapped, like the files of a
+//! Kubernetes ConfigMap.
+//!
+//! `SIGTERM` and `SIGINT` are sent the same way to start a graceful shutdown. Once the config
+//! manager is gone, they terminate the process right away.
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+    low_level::emulate_default_handler,
+};
+
+use super::Include;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The config file or a file it includes is created, modified or replaced.
+    FileChanged,
+    /// A signal is received.
+    Signal(i32),
+    /// The listeners are handed off to a new process, see [`crate::upgrade`].
+    Upgraded,
+}
+
+/// Watches the config file, the files it includes and signals until dropped.
+pub struct ConfigWatcher {
+    watcher: RecommendedWatcher,
+    tx: UnboundedSender<WatchEvent>,
+    includes: Arc<Mutex<Vec<Include>>>,
+    config_dir: PathBuf,
+    // Watched directories, with whether they are watched recursively.
+    watched_dirs: HashMap<PathBuf, bool>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> anyhow::Result<(Self, UnboundedReceiver<WatchEvent>)> {
+        let (tx, rx) = unbounded();
+        let includes = Arc::new(Mutex::new(Vec::new()));
+        let (watcher, config_dir) = watch_file(path, includes.clone(), tx.clone())?;
+        watch_signals(&[SIGHUP, SIGTERM, SIGINT], tx.clone())?;
+        Ok((
+            Self {
+                watcher,
+                tx,
+                includes,
+                watched_dirs: HashMap::from([(config_dir.clone(), false)]),
+                config_dir,
+            },
+            rx,
+        ))
+    }
+
+    /// Sender to notify events from elsewhere, e.g. other threads.
+    pub fn sender(&self) -> UnboundedSender<WatchEvent> {
+        self.tx.clone()
+    }
+
+    /// Watch the files included by the config file, replacing the previous includes.
+    pub fn watch_includes(&mut self, includes: &[Include]) {
+        let mut dirs = HashMap::from([(self.config_dir.clone(), false)]);
+        for include in includes {
+            let recursive = dirs.entry(include.dir().to_path_buf()).or_default();
+            *recursive |= include.is_recursive();
+        }
+        *self.includes.lock().unwrap() = includes.to_vec();
+
+        for (dir, recursive) in self.watched_dirs.iter() {
+            if dirs.get(dir) != Some(recursive) {
+                let _ = self.watcher.unwatch(dir);
+            }
+        }
+        for (dir, recursive) in dirs.iter() {
+            if self.watched_dirs.get(dir) == Some(recursive) {
+                continue;
+            }
+            let mode = match recursive {
+                true => RecursiveMode::Recursive,
+                false => RecursiveMode::NonRecursive,
+            };
+            if let Err(e) = self.watcher.watch(dir, mode) {
+                tracing::warn!("watch included config directory {dir:?} failed: {e}");
+            }
+        }
+        self.watched_dirs = dirs;
+    }
+}
+
+/// What identifies a version of the config file, following symlinks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileVersion {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+// The parent directory is watched rather than the file itself, so the file can be replaced
+// atomically, e.g. by renaming a new file over it.
+fn watch_file(
+    path: &Path,
+    includes: Arc<Mutex<Vec<Include>>>,
+    tx: UnboundedSender<WatchEvent>,
+) -> anyhow::Result<(RecommendedWatcher, PathBuf)> {
+    let file_name: OsString = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid config path {path:?}"))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+    .canonicalize()?;
+    let config_path = dir.join(&file_name);
+    let mut version = FileVersion::of(&config_path);
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let named = (event.kind.is_create() || event.kind.is_modify())
+                    && event
+                        .paths
+                        .iter()
+                        .any(|p| p.file_name() == Some(file_name.as_os_str()));
+                // A symlink in the path of the file, like the `..data` one of a ConfigMap, may
+                // have been swapped to another target.
+                let current = FileVersion::of(&config_path);
+                let swapped = current.is_some() && current != version;
+                version = current;
+                let changed = named || swapped;
+                // Removing an included file changes the config as well.
+                let included_changed =
+                    (event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove())
+                        && event.paths.iter().any(|p| {
+                            includes
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .any(|include| include.matches(p))
+                        });
+                if changed || included_changed {
+                    let _ = tx.unbounded_send(WatchEvent::FileChanged);
+                }
+            }
+            Err(e) => tracing::error!("config watcher error: {e}"),
+        })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, dir))
+}
+
+/// Whether the signal asks for a graceful shutdown.
+pub fn is_terminate_signal(signal: i32) -> bool {
+    signal == SIGTERM || signal == SIGINT
+}
+
+fn watch_signals(signals: &[i32], tx: UnboundedSender<WatchEvent>) -> anyhow::Result<()> {
+    let mut signals = Signals::new(signals)?;
+    std::thread::Builder::new()
+        .name("monolake-signal".to_string())
+        .spawn(move || {
+            for signal in signals.forever() {
+                if tx.unbounded_send(WatchEvent::Signal(signal)).is_err() {
+                    // Nobody is left to shut down gracefully, e.g. a second Ctrl-C while
+                    // draining.
+                    if is_terminate_signal(signal) {
+                        let _ = emulate_default_handler(signal);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::symlink, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn test_symlink_swap() {
+        // The layout of a mounted Kubernetes ConfigMap.
+        let dir = std::env::temp_dir().join(format!("monolake-watcher-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("..v1")).unwrap();
+        std::fs::create_dir_all(dir.join("..v2")).unwrap();
+        std::fs::write(dir.join("..v1/config.toml"), "a = 1").unwrap();
+        std::fs::write(dir.join("..v2/config.toml"), "a = 2").unwrap();
+        symlink("..v1", dir.join("..data")).unwrap();
+        symlink("..data/config.toml", dir.join("config.toml")).unwrap();
+
+        let (_watcher, mut rx) = ConfigWatcher::new(&dir.join("config.toml")).unwrap();
+        symlink("..v2", dir.join("..data_tmp")).unwrap();
+        std::fs::rename(dir.join("..data_tmp"), dir.join("..data")).unwrap();
+
+        let mut changed = false;
+        for _ in 0..50 {
+            if let Ok(Some(event)) = rx.try_next() {
+                changed = event == WatchEvent::FileChanged;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(changed);
+    }
+}
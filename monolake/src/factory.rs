@@ -4,6 +4,8 @@ use std::fmt::Debug;
 use certain_map::Param;
 use monoio::net::TcpStream;
 use monolake_core::listener::{AcceptedAddr, AcceptedStream};
+#[cfg(feature = "jwt")]
+use monolake_services::http::handlers::JwtHandler;
 #[cfg(feature = "openid")]
 use monolake_services::http::handlers::OpenIdHandler;
 #[cfg(feature = "proxy-protocol")]
@@ -14,7 +16,7 @@ use monolake_services::{
         core::HttpCoreService,
         detect::H2Detect,
         handlers::{
-            upstream::HttpUpstreamTimeout, ConnectionReuseHandler, ContentHandler,
+            upstream::HttpUpstreamTimeout, ConnectionReuseHandler, ContentHandler, ExtAuthzHandler,
             RewriteAndRouteHandler, UpstreamHandler,
         },
         HttpVersion,
@@ -45,14 +47,25 @@ pub fn l7_factory(
             let http_upstream_timeout: HttpUpstreamTimeout = config.param();
             let enable_content_handler = opt_handlers.content_handler;
             let stacks = FactoryStack::new(config.clone())
-                .replace(UpstreamHandler::factory(http_upstream_timeout, version))
-                .push(ContentHandler::opt_layer(enable_content_handler))
-                .push(RewriteAndRouteHandler::layer());
+                .replace(
+                    UpstreamHandler::factory(http_upstream_timeout, version)
+                        .with_resolver(config.param()),
+                )
+                .push(ContentHandler::opt_layer(enable_content_handler));
 
+            // Routes can override the validation of the tokens, so it is done once routed.
+            #[cfg(feature = "jwt")]
+            let stacks = stacks.push(JwtHandler::layer());
+
+            let stacks = stacks.push(RewriteAndRouteHandler::layer());
+
+            // The callback and logout paths are served whichever route they match, and the
+            // requests matching no route are authenticated too, so it is done before routing.
             #[cfg(feature = "openid")]
             let stacks = stacks.push(OpenIdHandler::layer());
 
             let stacks = stacks
+                .push(ExtAuthzHandler::layer())
                 .push(ConnectionReuseHandler::layer())
                 .push(HttpCoreService::layer())
                 .push(H2Detect::layer());
@@ -90,3 +103,163 @@ pub fn l7_factory(
         }
     }
 }
+
+#[cfg(all(test, feature = "openid"))]
+mod tests {
+    use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+
+    use monoio::{
+        io::{AsyncReadRent, AsyncWriteRentExt},
+        net::TcpListener,
+    };
+    use service_async::MakeService;
+
+    use super::*;
+    use crate::config::Config;
+
+    /// Reads an HTTP/1.1 message without a chunked body from `stream`, returning its head and its
+    /// body, and keeping what follows in `buf`.
+    async fn read_message(stream: &mut TcpStream, buf: &mut Vec<u8>) -> Option<(String, String)> {
+        loop {
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+                let len = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                if buf.len() >= end + 4 + len {
+                    let body = String::from_utf8_lossy(&buf[end + 4..end + 4 + len]).into_owned();
+                    buf.drain(..end + 4 + len);
+                    return Some((head, body));
+                }
+            }
+            let (read, chunk) = stream.read(vec![0; 4096]).await;
+            match read {
+                Ok(n) if n > 0 => buf.extend_from_slice(&chunk[..n]),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Serves the metadata of a provider, and answers the other requests as an upstream,
+    /// recording their targets.
+    fn serve_stub() -> (SocketAddr, Rc<RefCell<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let targets = Rc::new(RefCell::new(Vec::new()));
+        let recorded = targets.clone();
+        monoio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let targets = recorded.clone();
+                monoio::spawn(async move {
+                    let mut buf = Vec::new();
+                    while let Some((head, _)) = read_message(&mut stream, &mut buf).await {
+                        let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+                        let body = match target.as_str() {
+                            "/.well-known/openid-configuration" => format!(
+                                r#"{{"issuer": "http://{addr}", "authorization_endpoint": "http://{addr}/authorize",
+                                "token_endpoint": "http://{addr}/token", "jwks_uri": "http://{addr}/jwks",
+                                "response_types_supported": ["code"], "subject_types_supported": ["public"],
+                                "id_token_signing_alg_values_supported": ["RS256"]}}"#
+                            ),
+                            "/jwks" => r#"{"keys": []}"#.to_string(),
+                            _ => {
+                                targets.borrow_mut().push(target);
+                                "upstream".to_string()
+                            }
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: \
+                             {}\r\n\r\n{body}",
+                            body.len()
+                        );
+                        if stream.write_all(response.into_bytes()).await.0.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, targets)
+    }
+
+    #[test]
+    fn test_openid_before_routing() {
+        monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+            .enable_timer()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (stub, targets) = serve_stub();
+                // The only route is a public catch-all of a host.
+                let config = format!(
+                    r#"
+                    [servers.demo]
+                    name = "demo"
+                    proxy_type = "http"
+                    listener = {{ type = "socket", value = "127.0.0.1:18080" }}
+                    auth = {{ client_id = "monolake", client_secret = "secret", issuer_url = "http://{stub}", redirect_url = "http://public.test/callback", logout_path = "/logout" }}
+
+                    [[servers.demo.routes]]
+                    path = "/{{*p}}"
+                    hosts = ["public.test"]
+                    auth = false
+                    upstreams = [{{ endpoint = {{ type = "socket", value = "{stub}" }} }}]
+                    "#
+                );
+                let mut services = Config::parse_service_config(config.as_bytes()).unwrap();
+                let server = services.remove("demo").unwrap().server;
+                let svc = Rc::new(l7_factory(server).make().unwrap());
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+                monoio::spawn(async move {
+                    while let Ok((stream, peer)) = listener.accept().await {
+                        let svc = svc.clone();
+                        monoio::spawn(async move {
+                            let _ = svc
+                                .call((AcceptedStream::Tcp(stream), AcceptedAddr::from(peer)))
+                                .await;
+                        });
+                    }
+                });
+
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                let mut buf = Vec::new();
+                let mut get = async |host: &str, target: &str| {
+                    let request = format!("GET {target} HTTP/1.1\r\nhost: {host}\r\n\r\n");
+                    client.write_all(request.into_bytes()).await.0.unwrap();
+                    read_message(&mut client, &mut buf).await.unwrap()
+                };
+                let header = |head: &str, name: &str| {
+                    head.lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                        .map(|(_, value)| value.trim().to_string())
+                        .unwrap_or_default()
+                };
+                let authorize = format!("http://{stub}/authorize?");
+
+                // The public route is proxied without signing in.
+                let (head, body) = get("public.test", "/app").await;
+                assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+                assert_eq!(body, "upstream");
+
+                // The requests matching no route require to sign in, instead of a 404.
+                let (head, _) = get("other.test", "/app").await;
+                assert!(head.starts_with("HTTP/1.1 302"), "{head}");
+                assert!(header(&head, "location").starts_with(&authorize));
+
+                // The callback and the logout are served, although the public route matches them.
+                let (head, _) = get("public.test", "/callback?code=code&state=unknown").await;
+                assert!(head.starts_with("HTTP/1.1 302"), "{head}");
+                assert!(header(&head, "location").starts_with(&authorize));
+                let (head, _) = get("public.test", "/logout").await;
+                assert!(head.starts_with("HTTP/1.1 302"), "{head}");
+                assert_eq!(header(&head, "location"), "/");
+                assert!(header(&head, "set-cookie").contains("Max-Age=0"));
+
+                assert_eq!(*targets.borrow(), ["/app"]);
+            });
+    }
+}
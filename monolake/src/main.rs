@@ -1,24 +1,33 @@
+#![recursion_limit = "256"]
 use std::{path::Path, sync::Arc};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use monolake_core::{
     config::{RuntimeConfig, RuntimeType},
-    listener::ListenerBuilder,
     orchestrator::WorkerManager,
 };
 use service_async::AsyncMakeServiceWrapper;
-use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload, EnvFilter};
 
 use crate::{
-    config::{manager::StaticFileConfigManager, Config},
+    admin::LogFilterHandle,
+    config::{
+        manager::StaticFileConfigManager, redact, watcher::ConfigWatcher, AdminConfig, Config,
+        ConfigFiles, UpgradeConfig,
+    },
     factory::l7_factory,
+    upgrade::{Handoff, ListenerRegistry},
     util::print_logo,
 };
 
+mod admin;
+mod check;
 mod config;
 mod context;
 mod factory;
+mod systemd;
+mod upgrade;
 mod util;
 
 #[derive(Parser, Debug)]
@@ -27,23 +36,96 @@ struct Args {
     /// Path of the config file
     #[clap(short, long, value_parser)]
     config: String,
+
+    /// Take over the listeners of the running process through the upgrade socket
+    #[clap(long)]
+    upgrade: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate the config file without starting the proxy
+    Check,
+    /// Print the effective config with the defaults filled in
+    Dump {
+        /// Output format
+        #[clap(long, value_enum, default_value_t = DumpFormat::Toml)]
+        format: DumpFormat,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DumpFormat {
+    Toml,
+    Json,
 }
 
 fn main() -> Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Check) => {
+            #[cfg(feature = "tls")]
+            monoio_native_tls::init();
+            check::check(&args.config)?;
+            println!("config {:?} is valid", args.config);
+            return Ok(());
+        }
+        Some(Command::Dump { format }) => {
+            let files = ConfigFiles::read_sync(&args.config)?;
+            let config = files.user_config()?;
+            // The interpolated values may be secrets.
+            let redacted = files.interpolated()?;
+            let output = match format {
+                DumpFormat::Toml => {
+                    let mut config = toml::Value::try_from(&config)?;
+                    redact(&mut config, &redacted);
+                    toml::to_string_pretty(&config)?
+                }
+                DumpFormat::Json => {
+                    let mut config = serde_json::to_value(&config)?;
+                    redact(&mut config, &redacted);
+                    serde_json::to_string_pretty(&config)?
+                }
+            };
+            println!("{output}");
+            return Ok(());
+        }
+        None => {}
+    }
+
+    let (log_filter, log_filter_handle) = reload::Layer::new(
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+    );
     tracing_subscriber::registry()
+        .with(log_filter)
         .with(fmt::layer())
-        .with(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
         .init();
+    systemd::take_activated_sockets().context("take sockets passed by systemd failed")?;
     #[cfg(feature = "tls")]
     monoio_native_tls::init();
     print_logo();
 
-    let args = Args::parse();
     let mut runtime_config = Config::load_runtime_config(&args.config)?;
+    let admin_config = Config::load_admin_config(&args.config)?;
+    let upgrade_config = Config::load_upgrade_config(&args.config)?;
+    let listener_registry = Arc::new(ListenerRegistry::new(upgrade_config.is_some()));
+    let handoff = match &upgrade_config {
+        Some(upgrade_config) if args.upgrade => Some(Handoff::take_over(
+            &upgrade_config.socket,
+            &listener_registry,
+        )?),
+        Some(upgrade_config) => {
+            upgrade::check_not_running(&upgrade_config.socket)?;
+            None
+        }
+        None if args.upgrade => bail!("--upgrade requires the [upgrade] config section"),
+        None => None,
+    };
     #[cfg(target_os = "linux")]
     if matches!(runtime_config.runtime_type, RuntimeType::IoUring) && !monoio::utils::detect_uring()
     {
@@ -56,7 +138,15 @@ fn main() -> Result<()> {
                 .enable_timer()
                 .build()
                 .expect("Failed building the Runtime with IoUringDriver")
-                .block_on(run(runtime_config, &args.config));
+                .block_on(run(
+                    runtime_config,
+                    admin_config,
+                    upgrade_config,
+                    handoff,
+                    listener_registry,
+                    log_filter_handle,
+                    &args.config,
+                ));
         }
         monolake_core::config::RuntimeType::Legacy => {
             monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
@@ -65,13 +155,29 @@ fn main() -> Result<()> {
                 .attach_thread_pool(Box::new(monoio::blocking::DefaultThreadPool::new(4)))
                 .build()
                 .expect("Failed building the Runtime with LegacyDriver")
-                .block_on(run(runtime_config, &args.config));
+                .block_on(run(
+                    runtime_config,
+                    admin_config,
+                    upgrade_config,
+                    handoff,
+                    listener_registry,
+                    log_filter_handle,
+                    &args.config,
+                ));
         }
     }
     Ok(())
 }
 
-async fn run(runtime_config: RuntimeConfig, service_config_path: impl AsRef<Path>) {
+async fn run(
+    runtime_config: RuntimeConfig,
+    admin_config: Option<AdminConfig>,
+    upgrade_config: Option<UpgradeConfig>,
+    handoff: Option<Handoff>,
+    listener_registry: Arc<ListenerRegistry>,
+    log_filter_handle: LogFilterHandle,
+    service_config_path: impl AsRef<Path>,
+) {
     // Start workers
     let mut manager = WorkerManager::new(runtime_config);
     let join_handlers = manager.spawn_workers_async();
@@ -83,23 +189,62 @@ async fn run(runtime_config: RuntimeConfig, service_config_path: impl AsRef<Path
         manager.config().sqpoll_idle
     );
 
+    // Start admin API
+    let (command_tx, command_rx) = local_sync::mpsc::unbounded::channel();
+    if let Some(admin_config) = admin_config {
+        tracing::info!("Start admin API on {:?}", admin_config.listener);
+        let listener_builder = listener_registry
+            .get_or_bind(admin_config.listener.clone())
+            .expect("bind admin listener failed");
+        admin::spawn(
+            admin_config,
+            listener_builder,
+            command_tx,
+            log_filter_handle,
+        )
+        .expect("start admin API failed");
+    }
+
     // Create config manager
-    let config_manager = StaticFileConfigManager::new(
+    let mut config_manager = StaticFileConfigManager::new(
         manager,
-        |config| {
-            AsyncMakeServiceWrapper(Arc::new(
-                ListenerBuilder::try_from(config).expect("build listener failed"),
-            ))
+        {
+            let listener_registry = listener_registry.clone();
+            move |config| {
+                Ok(AsyncMakeServiceWrapper(
+                    listener_registry.get_or_bind(config)?,
+                ))
+            }
         },
         |config| AsyncMakeServiceWrapper(l7_factory(config)),
     );
+    let service_config_path = service_config_path.as_ref();
+    let watcher = ConfigWatcher::new(service_config_path).expect("watch config failed");
     config_manager
-        .load_and_watch(&service_config_path)
+        .load(service_config_path)
         .await
         .expect("apply init config failed");
-    tracing::info!("init config broadcast successfully");
 
-    // Wait for workers
+    // Let the previous process go, and serve the next upgrade
+    if let Some(handoff) = handoff {
+        handoff.ready().expect("notify previous process failed");
+    }
+    listener_registry.release_inherited();
+    if let Some(upgrade_config) = upgrade_config {
+        upgrade::serve(
+            &upgrade_config.socket,
+            listener_registry,
+            watcher.0.sender(),
+        )
+        .expect("serve upgrade socket failed");
+    }
+
+    config_manager
+        .watch(service_config_path.to_path_buf(), watcher, command_rx)
+        .await;
+
+    // Wait for workers to drain their connections
+    tracing::info!("waiting for workers to drain connections");
     for (_, mut close) in join_handlers.into_iter() {
         close.cancellation().await;
     }
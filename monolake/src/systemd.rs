@@ -0,0 +1,78 @@
+//! systemd socket activation.
+//!
+//! systemd passes the sockets of the `.socket` units activating the service as fds starting
+//! from 3, with `LISTEN_FDS` set to their number and `LISTEN_FDNAMES` to their
+//! `FileDescriptorName=`. They are taken once at startup and kept open, so a listener using them
+//! can be removed and added again by a config reload.
+use std::{
+    io,
+    os::fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    sync::OnceLock,
+};
+
+use crate::config::SystemdSocket;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+static ACTIVATED_SOCKETS: OnceLock<Vec<(String, OwnedFd)>> = OnceLock::new();
+
+/// Take the sockets passed by systemd, if any.
+///
+/// This must be called before threads are spawned, since the environment variables are removed
+/// so they are not seen by child processes.
+pub fn take_activated_sockets() -> io::Result<()> {
+    let sockets = activated_sockets()?;
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if !sockets.is_empty() {
+        tracing::info!("{} socket(s) passed by systemd", sockets.len());
+    }
+    let _ = ACTIVATED_SOCKETS.set(sockets);
+    Ok(())
+}
+
+/// Get a duplicate of the fd of a socket passed by systemd.
+pub fn socket_fd(socket: &SystemdSocket) -> io::Result<OwnedFd> {
+    let sockets = ACTIVATED_SOCKETS
+        .get()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let fd = match socket {
+        SystemdSocket::Index(index) => sockets.get(*index),
+        SystemdSocket::Name(name) => sockets.iter().find(|(socket_name, _)| socket_name == name),
+    }
+    .map(|(_, fd)| fd)
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("systemd socket {socket} is not passed"),
+        )
+    })?;
+    fd.try_clone()
+}
+
+fn activated_sockets() -> io::Result<Vec<(String, OwnedFd)>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_default();
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|raw_fd| {
+            // The passed fds are inherited by child processes, while the duplicates are not.
+            // Safety: systemd passes the fds to this process, and nothing else owns them. They
+            // are owned only once duplicated, so an invalid fd is an error rather than closed.
+            let fd = unsafe { BorrowedFd::borrow_raw(raw_fd) }.try_clone_to_owned()?;
+            drop(unsafe { OwnedFd::from_raw_fd(raw_fd) });
+            let name = names.next().unwrap_or_default().to_string();
+            Ok((name, fd))
+        })
+        .collect()
+}
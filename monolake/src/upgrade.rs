@@ -0,0 +1,273 @@
+//! Zero-downtime binary upgrade by handing off the listening sockets.
+//!
+//! With the `[upgrade]` section, the running process serves a Unix domain socket. A new process
+//! started with `--upgrade` connects to it and receives the listening sockets of the running one
+//! with `SCM_RIGHTS`. It builds its listeners from them and applies its config, then tells the
+//! running process it is ready. The running process stops accepting, drains its connections and
+//! exits, while the connections waiting in the accept queues are accepted by the new process.
+//!
+//! TCP listeners are bound once and shared by all workers when upgrade is enabled, so there is a
+//! single socket to hand off for each of them.
+use std::{
+    collections::HashMap,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::{bail, Context};
+use futures_channel::mpsc::UnboundedSender;
+use monolake_core::listener::ListenerBuilder;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+
+use crate::config::{watcher::WatchEvent, ListenerConfig};
+
+// Max number of fds in a SCM_RIGHTS message on Linux.
+const MAX_FDS: usize = 253;
+const READY: u8 = 1;
+
+/// Listener builders of the process, by listener config.
+///
+/// The builders are owned by the services using them, and the registry finds the live ones to
+/// reuse them or hand them off.
+pub struct ListenerRegistry {
+    shared_tcp: bool,
+    inner: Mutex<RegistryInner>,
+}
+
+#[derive(Default)]
+struct RegistryInner {
+    inherited: HashMap<ListenerConfig, OwnedFd>,
+    bound: Vec<(ListenerConfig, Weak<ListenerBuilder>)>,
+}
+
+impl ListenerRegistry {
+    /// With `shared_tcp`, TCP sockets are bound once for all workers, so they can be handed off.
+    pub fn new(shared_tcp: bool) -> Self {
+        Self {
+            shared_tcp,
+            inner: Default::default(),
+        }
+    }
+
+    /// Get the listener builder of the config: the live one if any, or the one inherited from
+    /// the previous process, or else a newly bound one.
+    pub fn get_or_bind(&self, config: ListenerConfig) -> io::Result<Arc<ListenerBuilder>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .bound
+            .retain(|(_, builder)| builder.strong_count() > 0);
+        if let Some(builder) = inner
+            .bound
+            .iter()
+            .find(|(bound, _)| *bound == config)
+            .and_then(|(_, builder)| builder.upgrade())
+        {
+            return Ok(builder);
+        }
+
+        let builder = match inner.inherited.remove(&config) {
+            Some(fd) => {
+                tracing::info!("listener {config:?} is inherited");
+                ListenerBuilder::from_fd(fd)?
+            }
+            None => match &config {
+                ListenerConfig::Socket(addr) if self.shared_tcp => {
+                    ListenerBuilder::bind_tcp_shared(*addr, Default::default())?
+                }
+                _ => ListenerBuilder::try_from(config.clone())?,
+            },
+        };
+        let builder = Arc::new(builder);
+        inner.bound.push((config, Arc::downgrade(&builder)));
+        Ok(builder)
+    }
+
+    /// Close the inherited listeners which are not used.
+    pub fn release_inherited(&self) {
+        for (config, _) in self.inner.lock().unwrap().inherited.drain() {
+            tracing::info!("inherited listener {config:?} is not used, closing it");
+        }
+    }
+
+    fn inherit(&self, listeners: impl IntoIterator<Item = (ListenerConfig, OwnedFd)>) {
+        self.inner.lock().unwrap().inherited.extend(listeners);
+    }
+
+    // The live listeners holding a socket.
+    fn bound_sockets(&self) -> Vec<(ListenerConfig, Arc<ListenerBuilder>)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .bound
+            .iter()
+            .filter_map(|(config, builder)| Some((config.clone(), builder.upgrade()?)))
+            .filter(|(_, builder)| builder.as_fd().is_some())
+            .collect()
+    }
+}
+
+/// Connection to the running process, which keeps serving until the new one is ready.
+pub struct Handoff(UnixStream);
+
+impl Handoff {
+    /// Receive the listeners of the running process into the registry.
+    pub fn take_over(socket: &Path, registry: &ListenerRegistry) -> anyhow::Result<Self> {
+        let mut stream = UnixStream::connect(socket)
+            .with_context(|| format!("connect upgrade socket {socket:?} failed"))?;
+        let listeners = recv_listeners(&mut stream)?;
+        tracing::info!("took over {} listener(s)", listeners.len());
+        registry.inherit(listeners);
+        Ok(Self(stream))
+    }
+
+    /// Tell the running process to drain and exit.
+    pub fn ready(mut self) -> io::Result<()> {
+        self.0.write_all(&[READY])
+    }
+}
+
+/// Fail if a running process serves the upgrade socket.
+pub fn check_not_running(socket: &Path) -> anyhow::Result<()> {
+    if UnixStream::connect(socket).is_ok() {
+        bail!(
+            "monolake is already running with upgrade socket {socket:?}, start with --upgrade to \
+             take it over"
+        );
+    }
+    Ok(())
+}
+
+/// Serve the upgrade socket on a dedicated thread. [`WatchEvent::Upgraded`] is sent once the
+/// listeners are handed off to a new process which is ready.
+pub fn serve(
+    socket: &Path,
+    registry: Arc<ListenerRegistry>,
+    events: UnboundedSender<WatchEvent>,
+) -> anyhow::Result<()> {
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("bind upgrade socket {socket:?} failed"))?;
+    std::thread::Builder::new()
+        .name("monolake-upgrade".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream
+                    .map_err(Into::into)
+                    .and_then(|stream| hand_off(stream, &registry))
+                {
+                    Ok(_) => {
+                        let _ = events.unbounded_send(WatchEvent::Upgraded);
+                        return;
+                    }
+                    Err(e) => tracing::warn!("upgrade failed: {e:#}"),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn hand_off(mut stream: UnixStream, registry: &ListenerRegistry) -> anyhow::Result<()> {
+    let listeners = registry.bound_sockets();
+    tracing::info!(
+        "handing off {} listener(s) to a new process",
+        listeners.len()
+    );
+    send_listeners(&mut stream, &listeners)?;
+
+    let mut ready = [0];
+    stream
+        .read_exact(&mut ready)
+        .context("new process exited before getting ready")?;
+    if ready[0] != READY {
+        bail!("unexpected message {} from new process", ready[0]);
+    }
+    Ok(())
+}
+
+// The listener configs are sent as a length-prefixed JSON array, with the fds in the same order
+// attached to the first bytes.
+fn send_listeners(
+    stream: &mut UnixStream,
+    listeners: &[(ListenerConfig, Arc<ListenerBuilder>)],
+) -> anyhow::Result<()> {
+    if listeners.len() > MAX_FDS {
+        bail!("can not hand off more than {MAX_FDS} listeners");
+    }
+    let configs: Vec<_> = listeners.iter().map(|(config, _)| config).collect();
+    let json = serde_json::to_vec(&configs)?;
+    let mut data = (json.len() as u32).to_be_bytes().to_vec();
+    data.extend(json);
+
+    let fds: Vec<RawFd> = listeners
+        .iter()
+        .filter_map(|(_, builder)| builder.as_fd())
+        .map(|fd| fd.as_raw_fd())
+        .collect();
+    let cmsgs: &[ControlMessage] = if fds.is_empty() {
+        &[]
+    } else {
+        &[ControlMessage::ScmRights(&fds)]
+    };
+    let sent = sendmsg::<UnixAddr>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&data)],
+        cmsgs,
+        MsgFlags::empty(),
+        None,
+    )?;
+    stream.write_all(&data[sent..])?;
+    Ok(())
+}
+
+fn recv_listeners(stream: &mut UnixStream) -> anyhow::Result<Vec<(ListenerConfig, OwnedFd)>> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let mut fds = Vec::new();
+    let received = {
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // Safety: the received fds are new and owned by this process.
+                fds.extend(
+                    received
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        msg.bytes
+    };
+    if received == 0 {
+        bail!("running process closed the upgrade socket");
+    }
+
+    let mut data = buf[..received].to_vec();
+    read_to_len(stream, &mut data, 4)?;
+    let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    read_to_len(stream, &mut data, 4 + len)?;
+    let configs: Vec<ListenerConfig> = serde_json::from_slice(&data[4..4 + len])?;
+    if configs.len() != fds.len() {
+        bail!("received {} fds for {} listeners", fds.len(), configs.len());
+    }
+    Ok(configs.into_iter().zip(fds).collect())
+}
+
+fn read_to_len(stream: &mut UnixStream, data: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    if data.len() < len {
+        let start = data.len();
+        data.resize(len, 0);
+        stream.read_exact(&mut data[start..])?;
+    }
+    Ok(())
+}